@@ -1,11 +1,18 @@
 // simple assembler for rv32i subset
 
+use crate::decoder::{AMOS, CSRS};
 use std::collections::HashMap;
 
 pub struct Assembler {
     labels: HashMap<String, u32>,
 }
 
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Assembler {
     pub fn new() -> Self {
         Assembler {
@@ -13,6 +20,11 @@ impl Assembler {
         }
     }
 
+    // label -> offset from the start of the assembled code
+    pub fn labels(&self) -> &HashMap<String, u32> {
+        &self.labels
+    }
+
     pub fn assemble(&mut self, source: &str) -> Result<Vec<u8>, String> {
         // two-pass assembly: first pass collects labels, second pass generates code
         let lines: Vec<&str> = source.lines().collect();
@@ -27,9 +39,8 @@ impl Assembler {
                 continue;
             }
             
-            if line.ends_with(':') {
-                let label = line[..line.len()-1].to_string();
-                self.labels.insert(label, pc);
+            if let Some(label) = line.strip_suffix(':') {
+                self.labels.insert(label.to_string(), pc);
             } else {
                 cleaned_lines.push(line);
                 pc += 4;
@@ -58,21 +69,34 @@ impl Assembler {
         let op = parts[0];
         
         match op {
-            "add" | "sub" | "and" | "or" | "xor" | "sll" | "srl" | "sra" => {
+            "add" | "sub" | "and" | "or" | "xor" | "sll" | "srl" | "sra" | "slt" | "sltu"
+            | "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => {
                 self.assemble_rtype(op, &parts[1..])
             }
-            "addi" | "andi" | "ori" | "xori" | "slli" | "srli" | "srai" => {
+            "addi" | "andi" | "ori" | "xori" | "slli" | "srli" | "srai" | "slti" | "sltiu" => {
                 self.assemble_itype(op, &parts[1..])
             }
-            "lw" => self.assemble_load(&parts[1..]),
-            "sw" => self.assemble_store(&parts[1..]),
-            "beq" | "bne" | "blt" | "bge" => {
+            "lb" | "lh" | "lw" | "lbu" | "lhu" => self.assemble_load(op, &parts[1..]),
+            "sb" | "sh" | "sw" => self.assemble_store(op, &parts[1..]),
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
                 self.assemble_branch(op, &parts[1..], pc)
             }
             "lui" => self.assemble_lui(&parts[1..]),
             "auipc" => self.assemble_auipc(&parts[1..]),
             "jal" => self.assemble_jal(&parts[1..], pc),
             "jalr" => self.assemble_jalr(&parts[1..]),
+            "ecall" => Ok(0x0000_0073),
+            "ebreak" => Ok(0x0010_0073),
+            // fence with no operands is fence iorw, iorw
+            "fence" => Ok(0x0ff0_000f),
+            "fence.i" => Ok(0x0000_100f),
+            "mret" => Ok(0x3020_0073),
+            "sret" => Ok(0x1020_0073),
+            "wfi" => Ok(0x1050_0073),
+            "sfence.vma" => self.assemble_sfence_vma(&parts[1..]),
+            op if op.starts_with("csr") => self.assemble_csr(op, &parts[1..]),
+            "lr.w" | "sc.w" => self.assemble_lr_sc(op, &parts[1..]),
+            op if op.starts_with("amo") => self.assemble_amo(op, &parts[1..]),
             _ => Err(format!("unknown instruction: {}", op)),
         }
     }
@@ -95,6 +119,16 @@ impl Assembler {
             "sll" => (0x1, 0x00),
             "srl" => (0x5, 0x00),
             "sra" => (0x5, 0x20),
+            "slt" => (0x2, 0x00),
+            "sltu" => (0x3, 0x00),
+            "mul" => (0x0, 0x01),
+            "mulh" => (0x1, 0x01),
+            "mulhsu" => (0x2, 0x01),
+            "mulhu" => (0x3, 0x01),
+            "div" => (0x4, 0x01),
+            "divu" => (0x5, 0x01),
+            "rem" => (0x6, 0x01),
+            "remu" => (0x7, 0x01),
             _ => return Err(format!("unknown r-type: {}", op)),
         };
         
@@ -118,6 +152,8 @@ impl Assembler {
             "slli" => 0x1,
             "srli" => 0x5,
             "srai" => 0x5,
+            "slti" => 0x2,
+            "sltiu" => 0x3,
             _ => return Err(format!("unknown i-type: {}", op)),
         };
         
@@ -130,20 +166,29 @@ impl Assembler {
         Ok((imm << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x13)
     }
     
-    fn assemble_load(&self, args: &[&str]) -> Result<u32, String> {
+    fn assemble_load(&self, op: &str, args: &[&str]) -> Result<u32, String> {
         if args.len() < 2 {
-            return Err("not enough args for lw".to_string());
+            return Err(format!("not enough args for {}", op));
         }
         
         let rd = parse_reg(args[0])?;
         let (imm, rs1) = parse_mem_operand(args[1])?;
         
-        Ok(((imm & 0xfff) << 20) | (rs1 << 15) | (0x2 << 12) | (rd << 7) | 0x03)
+        let funct3 = match op {
+            "lb" => 0x0,
+            "lh" => 0x1,
+            "lw" => 0x2,
+            "lbu" => 0x4,
+            "lhu" => 0x5,
+            _ => return Err(format!("unknown load: {}", op)),
+        };
+        
+        Ok(((imm & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0x03)
     }
     
-    fn assemble_store(&self, args: &[&str]) -> Result<u32, String> {
+    fn assemble_store(&self, op: &str, args: &[&str]) -> Result<u32, String> {
         if args.len() < 2 {
-            return Err("not enough args for sw".to_string());
+            return Err(format!("not enough args for {}", op));
         }
         
         let rs2 = parse_reg(args[0])?;
@@ -152,7 +197,14 @@ impl Assembler {
         let imm_low = imm & 0x1f;
         let imm_high = (imm >> 5) & 0x7f;
         
-        Ok((imm_high << 25) | (rs2 << 20) | (rs1 << 15) | (0x2 << 12) | (imm_low << 7) | 0x23)
+        let funct3 = match op {
+            "sb" => 0x0,
+            "sh" => 0x1,
+            "sw" => 0x2,
+            _ => return Err(format!("unknown store: {}", op)),
+        };
+        
+        Ok((imm_high << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (imm_low << 7) | 0x23)
     }
     
     fn assemble_branch(&self, op: &str, args: &[&str], pc: u32) -> Result<u32, String> {
@@ -166,7 +218,7 @@ impl Assembler {
         let target = if let Some(addr) = self.labels.get(args[2]) {
             *addr
         } else {
-            parse_imm(args[2])? as u32
+            parse_imm(args[2])?
         };
         
         let offset = target.wrapping_sub(pc);
@@ -181,6 +233,8 @@ impl Assembler {
             "bne" => 0x1,
             "blt" => 0x4,
             "bge" => 0x5,
+            "bltu" => 0x6,
+            "bgeu" => 0x7,
             _ => return Err(format!("unknown branch: {}", op)),
         };
         
@@ -220,7 +274,7 @@ impl Assembler {
         let target = if let Some(addr) = self.labels.get(args[1]) {
             *addr
         } else {
-            parse_imm(args[1])? as u32
+            parse_imm(args[1])?
         };
         
         let offset = target.wrapping_sub(pc);
@@ -244,6 +298,77 @@ impl Assembler {
         
         Ok(((imm & 0xfff) << 20) | (rs1 << 15) | (rd << 7) | 0x67)
     }
+
+    fn assemble_lr_sc(&self, op: &str, args: &[&str]) -> Result<u32, String> {
+        let (rd, rs2, mem) = match (op, args) {
+            ("lr.w", [rd, mem]) => (parse_reg(rd)?, 0, mem),
+            ("sc.w", [rd, rs2, mem]) => (parse_reg(rd)?, parse_reg(rs2)?, mem),
+            _ => return Err(format!("wrong number of args for {}", op)),
+        };
+        let (offset, rs1) = parse_mem_operand(mem)?;
+        if offset != 0 {
+            return Err(format!("{} takes no offset", op));
+        }
+        let funct5 = if op == "lr.w" { 0x02 } else { 0x03 };
+        Ok((funct5 << 27) | (rs2 << 20) | (rs1 << 15) | (0x2 << 12) | (rd << 7) | 0x2f)
+    }
+
+    // amoadd.w rd, rs2, (rs1) and friends
+    fn assemble_amo(&self, op: &str, args: &[&str]) -> Result<u32, String> {
+        let funct5 = AMOS.iter().find(|a| a.0 == op).map(|a| a.2)
+            .ok_or_else(|| format!("unknown instruction: {}", op))?;
+        let [rd, rs2, mem] = args else {
+            return Err(format!("wrong number of args for {}", op));
+        };
+        let (rd, rs2) = (parse_reg(rd)?, parse_reg(rs2)?);
+        let (offset, rs1) = parse_mem_operand(mem)?;
+        if offset != 0 {
+            return Err(format!("{} takes no offset", op));
+        }
+        Ok((funct5 << 27) | (rs2 << 20) | (rs1 << 15) | (0x2 << 12) | (rd << 7) | 0x2f)
+    }
+
+    // sfence.vma with no operands flushes every address and asid
+    fn assemble_sfence_vma(&self, args: &[&str]) -> Result<u32, String> {
+        let (rs1, rs2) = match args {
+            [] => (0, 0),
+            [rs1] => (parse_reg(rs1)?, 0),
+            [rs1, rs2] => (parse_reg(rs1)?, parse_reg(rs2)?),
+            _ => return Err("too many args for sfence.vma".to_string()),
+        };
+        Ok((0x09 << 25) | (rs2 << 20) | (rs1 << 15) | 0x73)
+    }
+
+    // csrrw rd, csr, rs1 and the immediate forms, plus the csrr, csrw, csrs,
+    // csrc (and csrwi, ...) shorthands that leave out rd or the source
+    fn assemble_csr(&self, op: &str, args: &[&str]) -> Result<u32, String> {
+        let (funct3, rd, csr, src) = match (op, args) {
+            ("csrr", [rd, csr]) => (0x2, parse_reg(rd)?, *csr, "x0"),
+            ("csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci", [csr, src]) => {
+                (csr_funct3(&format!("csrr{}", &op[3..]))?, 0, *csr, *src)
+            }
+            (_, [rd, csr, src]) => (csr_funct3(op)?, parse_reg(rd)?, *csr, *src),
+            _ => return Err(format!("wrong number of args for {}", op)),
+        };
+        let csr = match CSRS.iter().find(|c| c.0 == csr.trim_end_matches(',')) {
+            Some(c) => c.1,
+            None => parse_imm(csr)? & 0xfff,
+        };
+        let src = if funct3 & 0x4 != 0 { parse_imm(src)? & 0x1f } else { parse_reg(src)? };
+        Ok((csr << 20) | (src << 15) | (funct3 << 12) | (rd << 7) | 0x73)
+    }
+}
+
+fn csr_funct3(op: &str) -> Result<u32, String> {
+    match op {
+        "csrrw" => Ok(0x1),
+        "csrrs" => Ok(0x2),
+        "csrrc" => Ok(0x3),
+        "csrrwi" => Ok(0x5),
+        "csrrsi" => Ok(0x6),
+        "csrrci" => Ok(0x7),
+        _ => Err(format!("unknown instruction: {}", op)),
+    }
 }
 
 fn parse_reg(s: &str) -> Result<u32, String> {
@@ -307,4 +432,41 @@ mod tests {
         let code = asm.assemble(source).unwrap();
         assert_eq!(code.len(), 8);
     }
+
+    #[test]
+    fn test_assemble_sized_and_unsigned() {
+        let code = Assembler::new().assemble("lb x1, 4(x2)\nlhu x1, -2(x2)\nsb x3, 1(x2)\nsltu x1, x2, x3\nbgeu x1, x2, 24").unwrap();
+        let words: Vec<u32> = code.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(words, [0x00410083, 0xffe15083, 0x003100a3, 0x003130b3, 0x0020f463]);
+    }
+
+    #[test]
+    fn test_assemble_muldiv() {
+        let code = Assembler::new().assemble("mul x1, x2, x3\nmulhsu x5, x6, x7\nremu x10, x11, x12").unwrap();
+        let words: Vec<u32> = code.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(words, [0x023100b3, 0x027322b3, 0x02c5f533]);
+    }
+
+    #[test]
+    fn test_assemble_lr_sc_csrr() {
+        let code = Assembler::new().assemble("lr.w x5, (x10)\nsc.w x6, x7, (x10)\ncsrr x1, mhartid").unwrap();
+        let words: Vec<u32> = code.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(words, [0x100522af, 0x1875232f, 0xf14020f3]);
+    }
+
+    #[test]
+    fn test_assemble_csr() {
+        let code = Assembler::new().assemble("csrw mtvec, x5\ncsrrci x0, mstatus, 8\ncsrrc x1, 0x7c0, x2\ncsrr x1, mhartid\nmret\nsret\nwfi\nsfence.vma x5, x6").unwrap();
+        let words: Vec<u32> = code.chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(words, [0x30529073, 0x30047073, 0x7c0130f3, 0xf14020f3, 0x30200073,
+                           0x10200073, 0x10500073, 0x12628073]);
+        assert!(Assembler::new().assemble("csrrx x1, mstatus, x2").is_err());
+    }
+
+    #[test]
+    fn test_assemble_amo() {
+        let code = Assembler::new().assemble("amoadd.w x5, x6, (x10)\namomaxu.w x1, x2, (x3)").unwrap();
+        assert_eq!(code, [0xaf, 0x22, 0x65, 0x00, 0xaf, 0xa0, 0x21, 0xe0]);
+        assert!(Assembler::new().assemble("amofoo.w x1, x2, (x3)").is_err());
+    }
 }
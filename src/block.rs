@@ -0,0 +1,241 @@
+// basic-block translation
+//
+// a block is a run of straight-line instructions from some start address up
+// to and including the first branch or jump, at most MAX_BLOCK_LEN long.
+// each instruction becomes an Op with everything that only depends on its
+// address already worked out: lui/auipc are constants, branch and jal
+// targets and link values are absolute. the executor runs a block's ops in
+// a tight loop and then follows the terminator, which remembers the block
+// it led to last time so hot loops go from block to block without a lookup.
+//
+// blocks are only built from ram, through the predecode cache. when the
+// cache's generation moves (a store hit decoded code, fence.i, a reload)
+// every block is thrown away. anything the block loop doesn't handle
+// (ecall, ebreak, fence.i, unknown instructions, rom and mmio code) ends the
+// block and goes through Executor::step instead.
+
+use crate::cpu::Cpu;
+use crate::decoder::Opcode;
+use std::collections::HashMap;
+
+pub const MAX_BLOCK_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpKind {
+    // rd = rs1 op rs2, with executor::alu semantics
+    Alu(Opcode),
+    // rd = rs1 op imm
+    AluImm(Opcode),
+    // rd = imm, for lui and auipc
+    Li,
+    // rd = mem[rs1 + imm], sized and extended as the opcode says
+    Load(Opcode),
+    // mem[rs1 + imm] = rs2
+    Store(Opcode),
+    Nop,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    pub kind: OpKind,
+    pub rd: u8,
+    pub rs1: u8,
+    pub rs2: u8,
+    pub imm: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Term {
+    // conditional branch (the opcode says which), both targets resolved
+    Branch { cond: Opcode, rs1: u8, rs2: u8, taken: u32, not_taken: u32 },
+    // jal: write the link and go
+    Jump { rd: u8, link: u32, target: u32 },
+    // jalr: target known only at run time
+    Indirect { rd: u8, rs1: u8, imm: u32, link: u32 },
+    // ran into the length limit, or something only step() handles; carry on at `next`
+    Fallthrough { next: u32 },
+}
+
+pub struct Block {
+    pub start: u32,
+    pub ops: Vec<Op>,
+    pub term: Term,
+    // instructions in the block, terminator included
+    pub len: u64,
+    // opcode of every instruction, for metrics when a block stops early
+    pub opcodes: Vec<Opcode>,
+    // per-opcode counts for the metrics, so they're recorded once per block
+    pub mix: Vec<(Opcode, u64)>,
+    // chained successors: the taken/jump target and the fall-through
+    pub links: [Option<usize>; 2],
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: Vec<Block>,
+    by_start: HashMap<u32, usize>,
+    generation: u64,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn block(&self, idx: usize) -> &Block {
+        &self.blocks[idx]
+    }
+
+    pub fn set_link(&mut self, idx: usize, slot: usize, next: usize) {
+        self.blocks[idx].links[slot] = Some(next);
+    }
+
+    // drop everything if the code underneath changed since the blocks were
+    // built. returns true if it did
+    pub fn sync(&mut self, cpu: &Cpu) -> bool {
+        if self.generation == cpu.icache.generation() {
+            return false;
+        }
+        self.blocks.clear();
+        self.by_start.clear();
+        self.generation = cpu.icache.generation();
+        true
+    }
+
+    // the block starting at `pc`, translating it if needed. None means pc
+    // isn't somewhere blocks can be built (outside ram), or the first
+    // instruction is one only step() handles
+    pub fn lookup(&mut self, cpu: &mut Cpu, pc: u32) -> Option<usize> {
+        if let Some(&idx) = self.by_start.get(&pc) {
+            return Some(idx);
+        }
+        let block = translate(cpu, pc)?;
+        // decoding may have filled the cache, but that never bumps the
+        // generation, so the blocks we have are still good
+        let idx = self.blocks.len();
+        self.blocks.push(block);
+        self.by_start.insert(pc, idx);
+        Some(idx)
+    }
+}
+
+fn op(kind: OpKind, rd: usize, rs1: usize, rs2: usize, imm: u32) -> Op {
+    Op { kind, rd: rd as u8, rs1: rs1 as u8, rs2: rs2 as u8, imm }
+}
+
+fn translate(cpu: &mut Cpu, start: u32) -> Option<Block> {
+    let mut ops = Vec::new();
+    let mut opcodes = Vec::new();
+    let mut pc = start;
+    let term = loop {
+        if ops.len() == MAX_BLOCK_LEN {
+            break Term::Fallthrough { next: pc };
+        }
+        if cpu.ram_offset(pc, 4).is_none() {
+            break Term::Fallthrough { next: pc };
+        }
+        let inst = match cpu.fetch_decoded(pc) {
+            Ok(inst) => inst,
+            Err(_) => break Term::Fallthrough { next: pc },
+        };
+        let (rd, rs1, rs2, imm) = (inst.rd, inst.rs1, inst.rs2, inst.imm as u32);
+        let kind = match inst.opcode {
+            op @ (Opcode::Add | Opcode::Sub | Opcode::And | Opcode::Or | Opcode::Xor
+            | Opcode::Sll | Opcode::Srl | Opcode::Sra | Opcode::Slt | Opcode::Sltu) => OpKind::Alu(op),
+            op if op.is_muldiv() => OpKind::Alu(op),
+            op @ (Opcode::Addi | Opcode::Andi | Opcode::Ori | Opcode::Xori
+            | Opcode::Slli | Opcode::Srli | Opcode::Srai | Opcode::Slti | Opcode::Sltiu) => OpKind::AluImm(op),
+            op if op.is_load() => OpKind::Load(op),
+            op if op.is_store() => OpKind::Store(op),
+            Opcode::Fence => OpKind::Nop,
+            Opcode::Lui | Opcode::Auipc => OpKind::Li,
+            op if op.is_branch() || op == Opcode::Jal || op == Opcode::Jalr => {
+                opcodes.push(inst.opcode);
+                let link = pc.wrapping_add(4);
+                break match inst.opcode {
+                    Opcode::Jal => Term::Jump { rd: rd as u8, link, target: pc.wrapping_add(imm) },
+                    Opcode::Jalr => Term::Indirect { rd: rd as u8, rs1: rs1 as u8, imm, link },
+                    cond => Term::Branch {
+                        cond,
+                        rs1: rs1 as u8,
+                        rs2: rs2 as u8,
+                        taken: pc.wrapping_add(imm),
+                        not_taken: link,
+                    },
+                };
+            }
+            // ecall, ebreak, fence.i and unknown instructions
+            _ => break Term::Fallthrough { next: pc },
+        };
+        let imm = match inst.opcode {
+            Opcode::Auipc => pc.wrapping_add(imm),
+            _ => imm,
+        };
+        opcodes.push(inst.opcode);
+        ops.push(op(kind, rd, rs1, rs2, imm));
+        pc = pc.wrapping_add(4);
+    };
+    let len = ops.len() as u64 + match term {
+        Term::Fallthrough { .. } => 0,
+        _ => 1,
+    };
+    if len == 0 {
+        return None;
+    }
+    let mut mix: Vec<(Opcode, u64)> = Vec::new();
+    for &opcode in &opcodes {
+        match mix.iter_mut().find(|(op, _)| *op == opcode) {
+            Some((_, c)) => *c += 1,
+            None => mix.push((opcode, 1)),
+        }
+    }
+    Some(Block { start, ops, term, len, opcodes, mix, links: [None; 2] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn cpu_with(src: &str) -> Cpu {
+        let code = Assembler::new().assemble(src).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&code, 0x100);
+        cpu
+    }
+
+    #[test]
+    fn test_block_ends_at_branch() {
+        let mut cpu = cpu_with("top:\nlui x1, 0x1\nauipc x2, 0x2\naddi x1, x1, 1\nbne x1, x0, top\naddi x3, x0, 1");
+        let block = translate(&mut cpu, 0x100).unwrap();
+        assert_eq!(block.ops.len(), 3);
+        assert_eq!(block.len, 4);
+        assert_eq!(block.ops[1].kind, OpKind::Li);
+        assert_eq!(block.ops[1].imm, 0x2104);
+        match block.term {
+            Term::Branch { cond, taken, not_taken, .. } => {
+                assert_eq!(cond, Opcode::Bne);
+                assert_eq!((taken, not_taken), (0x100, 0x110));
+            }
+            t => panic!("unexpected terminator {:?}", t),
+        }
+    }
+
+    #[test]
+    fn test_system_instructions_end_the_block() {
+        let mut cpu = cpu_with("addi x1, x0, 1\necall\naddi x1, x0, 2");
+        let block = translate(&mut cpu, 0x100).unwrap();
+        assert_eq!(block.len, 1);
+        assert!(matches!(block.term, Term::Fallthrough { next: 0x104 }));
+        // nothing to translate at the ecall itself
+        assert!(translate(&mut cpu, 0x104).is_none());
+    }
+}
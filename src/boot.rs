@@ -0,0 +1,423 @@
+// virt-like boot machine
+//
+// the layout follows qemu's riscv32 virt board so firmware and kernels built
+// for it find things where they expect:
+//
+//   0x0200_0000  clint
+//   0x0c00_0000  plic
+//   0x1000_0000  ns16550a uart (reg-shift 2)
+//   0x8000_0000  ram: firmware at the base, kernel 4m in (opensbi's
+//                fw_jump address on rv32), initrd halfway up, device tree
+//                and fw_dynamic info at the top
+//
+// every hart starts at the firmware, or straight at the kernel if there's
+// no firmware, with a0 = hart id, a1 = dtb and a2 = fw_dynamic info. the
+// clint and plic have a software interrupt, timer and m and s external
+// interrupt for each of them.
+
+use crate::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use crate::cpu::Cpu;
+use crate::elf::{self, ElfFile};
+use crate::fdt::{self, Chosen};
+use crate::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+use crate::process::PAGE_SIZE;
+use crate::uart::{Ns16550, UART_BASE, UART_SIZE};
+
+pub const RAM_BASE: u32 = 0x8000_0000;
+pub const DEFAULT_RAM_SIZE: usize = 128 * 1024 * 1024;
+pub const KERNEL_OFFSET: u32 = 0x40_0000;
+
+// the initrd goes this far above the kernel, or halfway up ram if that's
+// lower, like qemu does
+const INITRD_MAX_OFFSET: u32 = 128 * 1024 * 1024;
+
+// opensbi's struct fw_dynamic_info, for firmware built as fw_dynamic
+const FW_DYNAMIC_MAGIC: u32 = 0x4942_534f; // "OSBI"
+const FW_DYNAMIC_VERSION: u32 = 2;
+const FW_DYNAMIC_NEXT_MODE_S: u32 = 1;
+
+#[derive(Default)]
+pub struct BootImages<'a> {
+    pub firmware: Option<&'a [u8]>,
+    pub kernel: Option<&'a [u8]>,
+    pub initrd: Option<&'a [u8]>,
+    pub bootargs: Option<String>,
+}
+
+// where everything ended up, for the startup banner and the debugger
+#[derive(Debug, Clone, Default)]
+pub struct BootInfo {
+    pub entry: u32,
+    pub kernel: Option<u32>,
+    pub initrd: Option<(u32, u32)>,
+    pub dtb: u32,
+    pub dtb_size: usize,
+}
+
+// load an elf where its headers say, or a flat image at `addr`. returns the
+// address execution should go to
+fn load_image(cpu: &mut Cpu, data: &[u8], addr: u32, what: &str) -> Result<u32, String> {
+    if elf::is_elf(data) {
+        let file = ElfFile::parse(data).map_err(|e| format!("{}: {}", what, e))?;
+        file.load(cpu).map_err(|e| format!("{}: {}", what, e))?;
+        // a kernel's entry is a virtual address and it's entered with the
+        // mmu off, so go to where the segment holding it was loaded. an
+        // entry outside every segment falls back to the lowest one
+        let entry = file.segments.iter()
+            .find(|s| file.entry.wrapping_sub(s.vaddr) < s.memsz)
+            .map(|s| s.paddr.wrapping_add(file.entry - s.vaddr))
+            .or_else(|| file.segments.iter().map(|s| s.paddr).min());
+        return Ok(entry.unwrap_or(file.entry));
+    }
+    if cpu.ram_offset(addr, data.len()).is_none() {
+        return Err(format!("{} doesn't fit in ram at 0x{:x}", what, addr));
+    }
+    cpu.load_program(data, addr);
+    Ok(addr)
+}
+
+// build a machine with `harts` harts, load the images and set up hart 0
+// for entry; smp.rs starts the others from it. the uart is passed in so
+// callers decide where the console goes
+pub fn build(ram_size: usize, harts: usize, images: &BootImages, console: Ns16550) -> Result<(Cpu, BootInfo), String> {
+    if ram_size == 0 || RAM_BASE as u64 + ram_size as u64 > 1 << 32 {
+        return Err(format!("ram size 0x{:x} doesn't fit above 0x{:x}", ram_size, RAM_BASE));
+    }
+    if images.firmware.is_none() && images.kernel.is_none() {
+        return Err("nothing to boot: need firmware, a kernel or both".to_string());
+    }
+    if harts == 0 {
+        return Err("need at least one hart".to_string());
+    }
+    let mut cpu = Cpu::with_ram(RAM_BASE, ram_size);
+    cpu.set_harts(harts);
+    cpu.attach_device(CLINT_BASE, CLINT_SIZE, Box::new(Clint::new(harts)))?;
+    cpu.attach_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(harts)))?;
+    cpu.attach_device(UART_BASE, UART_SIZE, Box::new(console))?;
+
+    let mut info = BootInfo::default();
+    let firmware = match images.firmware {
+        Some(data) => Some(load_image(&mut cpu, data, RAM_BASE, "firmware")?),
+        None => None,
+    };
+    if let Some(data) = images.kernel {
+        info.kernel = Some(load_image(&mut cpu, data, RAM_BASE + KERNEL_OFFSET, "kernel")?);
+    }
+    info.entry = firmware.or(info.kernel).unwrap_or(RAM_BASE);
+
+    if let Some(data) = images.initrd {
+        let kernel = info.kernel.unwrap_or(RAM_BASE + KERNEL_OFFSET);
+        let offset = (ram_size as u32 / 2).min(INITRD_MAX_OFFSET);
+        let start = kernel.checked_add(offset)
+            .map(|a| a & !(PAGE_SIZE - 1))
+            .filter(|&a| cpu.ram_offset(a, data.len()).is_some())
+            .ok_or_else(|| format!("initrd doesn't fit in ram at 0x{:x}", kernel.wrapping_add(offset)))?;
+        cpu.load_program(data, start);
+        info.initrd = Some((start, start + data.len() as u32));
+    }
+
+    let chosen = Chosen { bootargs: images.bootargs.clone(), initrd: info.initrd };
+    let blob = fdt::generate(&cpu, &chosen);
+    info.dtb = fdt::install(&mut cpu, &blob)?;
+    info.dtb_size = blob.len();
+    if let Some((_, end)) = info.initrd {
+        if end > info.dtb {
+            return Err("initrd overlaps the device tree, use more ram".to_string());
+        }
+    }
+
+    // fw_dynamic info sits just below the tree: magic, version, next_addr,
+    // next_mode, options, boot_hart
+    let dynamic = (info.dtb - 6 * 4) & !7;
+    let next = info.kernel.unwrap_or(0);
+    for (i, w) in [FW_DYNAMIC_MAGIC, FW_DYNAMIC_VERSION, next, FW_DYNAMIC_NEXT_MODE_S, 0, 0]
+        .iter()
+        .enumerate()
+    {
+        cpu.write_word(dynamic + i as u32 * 4, *w);
+    }
+    cpu.write_reg(12, dynamic); // a2
+
+    cpu.pc = info.entry;
+    cpu.reset_vector = info.entry;
+    Ok((cpu, info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::executor::Executor;
+    use crate::htif::Htif;
+    use crate::metrics::Metrics;
+    use crate::uart::UART_IRQ;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+
+    fn quiet_uart() -> Ns16550 {
+        Ns16550::new(Box::new(std::io::sink()), None)
+    }
+
+    // a Write that the test can look at after handing it to the uart
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // the opensbi side of a boot, cut down: delegate the supervisor
+    // interrupts and page faults, drop to s-mode at the kernel, and serve
+    // the legacy sbi calls set_timer (0), console_putchar (1) and shutdown
+    // (8), which exits through htif at 0x8000_1000 with status 0. anything
+    // else it doesn't expect exits with status 1. the machine timer
+    // interrupt is passed on as stip, the way opensbi does it. x28-x31 are
+    // the firmware's own
+    const FIRMWARE: &str = "jal x0, start
+        mtrap:
+        csrr x28, mcause
+        blt x28, x0, irq
+        addi x29, x0, 9
+        bne x28, x29, fail
+        csrr x29, mepc
+        addi x29, x29, 4
+        csrw mepc, x29
+        beq x17, x0, set_timer
+        addi x29, x0, 1
+        beq x17, x29, putchar
+        addi x29, x0, 8
+        beq x17, x29, shutdown
+        fail:
+        addi x29, x0, 3
+        jal x0, exit
+        shutdown:
+        addi x29, x0, 1
+        exit:
+        lui x28, 0x80001
+        sw x29, 0(x28)
+        sw x0, 4(x28)
+        jal x0, exit
+        putchar:
+        lui x29, 0x10000
+        sw x10, 0(x29)
+        mret
+        set_timer:
+        lui x29, 0x2004
+        sw x11, 4(x29)
+        sw x10, 0(x29)
+        addi x29, x0, 32
+        csrc mip, x29
+        addi x29, x0, 128
+        csrs mie, x29
+        mret
+        irq:
+        addi x29, x0, 128
+        csrc mie, x29
+        addi x29, x0, 32
+        csrs mip, x29
+        mret
+        start:
+        lui x28, 0x80000
+        addi x28, x28, 4
+        csrw mtvec, x28
+        addi x28, x0, 0x222
+        csrw mideleg, x28
+        lui x28, 0xb
+        csrw medeleg, x28
+        csrwi mcounteren, 7
+        lui x28, 1
+        addi x28, x28, -2048
+        csrw mstatus, x28
+        lui x28, 0x80400
+        csrw mepc, x28
+        mret";
+
+    // the linux side: map itself at 0xc000_0000 with an sv32 megapage,
+    // carry on up there, print through the sbi, then wait for a timer
+    // interrupt and print again from its handler before shutting down.
+    // the root page table goes at 0x8041_0000, which also maps the kernel
+    // where it is so the jump up happens with paging on
+    const KERNEL: &str = "jal x0, start
+        strap:
+        csrr x5, scause
+        lui x6, 0x80000
+        addi x6, x6, 5
+        bne x5, x6, fail
+        addi x10, x0, 33
+        addi x17, x0, 1
+        ecall
+        addi x17, x0, 8
+        ecall
+        fail:
+        addi x10, x0, 70
+        addi x17, x0, 1
+        ecall
+        addi x17, x0, 8
+        ecall
+        start:
+        lui x6, 0x20100
+        addi x6, x6, 0xf
+        lui x7, 0x80411
+        addi x7, x7, -2044
+        sw x6, 0(x7)
+        lui x7, 0x80411
+        addi x7, x7, -1024
+        sw x6, 0(x7)
+        lui x5, 0x80080
+        addi x5, x5, 0x410
+        csrw satp, x5
+        sfence.vma
+        auipc x5, 0
+        lui x6, 0x3fc00
+        add x5, x5, x6
+        addi x5, x5, 20
+        jalr x0, 0(x5)
+        lui x5, 0xc0000
+        addi x5, x5, 4
+        csrw stvec, x5
+        addi x10, x0, 104
+        addi x17, x0, 1
+        ecall
+        addi x10, x0, 105
+        ecall
+        csrr x10, time
+        addi x10, x10, 100
+        addi x11, x0, 0
+        addi x17, x0, 0
+        ecall
+        addi x5, x0, 32
+        csrw sie, x5
+        csrsi sstatus, 2
+        wait:
+        wfi
+        jal x0, wait";
+
+    // machine-mode interrupt handling for the uart: route plic source 10
+    // to hart 0's m context, turn on the receive interrupt, and wait. the
+    // handler claims the source, reads the byte and completes it, counting
+    // interrupts in x22
+    const UART_IRQ_FIRMWARE: &str = "jal x0, start
+        mtrap:
+        lui x5, 0xc200
+        lw x20, 4(x5)
+        lui x6, 0x10000
+        lw x21, 0(x6)
+        sw x20, 4(x5)
+        addi x22, x22, 1
+        mret
+        start:
+        lui x5, 0x80000
+        addi x5, x5, 4
+        csrw mtvec, x5
+        lui x5, 0xc000
+        addi x6, x0, 1
+        sw x6, 40(x5)
+        lui x5, 0xc002
+        addi x6, x0, 1024
+        sw x6, 0(x5)
+        lui x5, 0x10000
+        addi x6, x0, 1
+        sw x6, 4(x5)
+        lui x5, 1
+        addi x5, x5, -2048
+        csrw mie, x5
+        csrsi mstatus, 8
+        wait:
+        wfi
+        jal x0, wait";
+
+    #[test]
+    fn test_layout() {
+        let firmware = [0x13, 0, 0, 0];
+        let kernel = [0x6f, 0, 0, 0];
+        let initrd = [0xaa; 100];
+        let images = BootImages {
+            firmware: Some(&firmware),
+            kernel: Some(&kernel),
+            initrd: Some(&initrd),
+            bootargs: Some("console=ttyS0".to_string()),
+        };
+        let (cpu, info) = build(16 * 1024 * 1024, 1, &images, quiet_uart()).unwrap();
+        assert_eq!(cpu.pc, RAM_BASE);
+        assert_eq!(info.kernel, Some(RAM_BASE + KERNEL_OFFSET));
+        assert_eq!(cpu.read_word(RAM_BASE + KERNEL_OFFSET), 0x6f);
+        assert_eq!(info.initrd, Some((0x8080_0000 + KERNEL_OFFSET, 0x8080_0000 + KERNEL_OFFSET + 100)));
+        assert_eq!(cpu.regs[11], info.dtb);
+        assert_eq!(cpu.read_word(cpu.regs[12]), FW_DYNAMIC_MAGIC);
+        assert_eq!(cpu.read_word(cpu.regs[12] + 8), RAM_BASE + KERNEL_OFFSET);
+    }
+
+    #[test]
+    fn test_kernel_only_starts_at_kernel() {
+        let kernel = [0x6f, 0, 0, 0];
+        let images = BootImages { kernel: Some(&kernel), ..Default::default() };
+        let (cpu, _) = build(8 * 1024 * 1024, 1, &images, quiet_uart()).unwrap();
+        assert_eq!(cpu.pc, RAM_BASE + KERNEL_OFFSET);
+        assert!(build(8 * 1024 * 1024, 1, &BootImages::default(), quiet_uart()).is_err());
+    }
+
+    #[test]
+    fn test_boot_to_sbi_console() {
+        let firmware = Assembler::new().assemble(FIRMWARE).unwrap();
+        let kernel = Assembler::new().assemble(KERNEL).unwrap();
+        let images = BootImages { firmware: Some(&firmware), kernel: Some(&kernel), ..Default::default() };
+        let out = Capture::default();
+        let console = Ns16550::new(Box::new(out.clone()), None);
+        let (mut cpu, _) = build(16 * 1024 * 1024, 1, &images, console).unwrap();
+        let mut exec = Executor::new();
+        exec.htif = Some(Htif::new(0x8000_1000, None, Box::new(std::io::sink())));
+        exec.run(&mut cpu, &mut Metrics::new(), 100_000).unwrap();
+        assert!(exec.halted);
+        assert_eq!(exec.exit_code, Some(0));
+        assert_eq!(out.0.borrow().as_slice(), b"hi!");
+        // the kernel ran up in its own mapping
+        assert_eq!(cpu.csrs.mepc & 0xf000_0000, 0xc000_0000);
+    }
+    #[test]
+    fn test_jump_to_zero_faults() {
+        // ram is at 0x8000_0000, so address 0 is nothing to fetch from
+        let firmware = Assembler::new().assemble("jal x0, start
+            mtrap:
+            csrr x20, mcause
+            csrr x21, mtval
+            spin:
+            jal x0, spin
+            start:
+            lui x5, 0x80000
+            addi x5, x5, 4
+            csrw mtvec, x5
+            addi x21, x0, -1
+            jalr x0, 0(x0)").unwrap();
+        let images = BootImages { firmware: Some(&firmware), ..Default::default() };
+        let (mut cpu, _) = build(8 * 1024 * 1024, 1, &images, quiet_uart()).unwrap();
+        let mut exec = Executor::new();
+        exec.run(&mut cpu, &mut Metrics::new(), 100).unwrap();
+        assert!(!exec.halted);
+        assert_eq!(cpu.regs[20], 1);
+        assert_eq!(cpu.regs[21], 0);
+    }
+
+    #[test]
+    fn test_uart_interrupt_through_plic() {
+        let firmware = Assembler::new().assemble(UART_IRQ_FIRMWARE).unwrap();
+        let images = BootImages { firmware: Some(&firmware), ..Default::default() };
+        let mut console = quiet_uart();
+        console.push_input(b"a");
+        let (mut cpu, _) = build(8 * 1024 * 1024, 1, &images, console).unwrap();
+        let mut exec = Executor::new();
+        exec.run(&mut cpu, &mut Metrics::new(), 10_000).unwrap();
+        assert_eq!(cpu.regs[20], UART_IRQ);
+        assert_eq!(cpu.regs[21], b'a' as u32);
+        // reading the byte dropped the line, so completing it didn't bring
+        // the interrupt back
+        assert_eq!(cpu.regs[22], 1);
+    }
+}
@@ -0,0 +1,201 @@
+// sifive clint: the machine timer and software interrupt for every hart
+//
+// mtime runs at the device tree's timebase-frequency off the same virtual
+// clock as the rtc, so timer interrupts land on the same instruction every
+// run. each hart has its own msip word and mtimecmp; hart_irqs hands their
+// lines to the hart as mip.msip and mip.mtip.
+
+use crate::devices::{Device, DeviceInfo, GuestRam};
+use crate::fdt::{self, FdtBuilder, TIMEBASE_FREQ};
+use crate::rtc::NS_PER_INSTRUCTION;
+use crate::snapshot::{Reader, Writer};
+
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x1_0000;
+
+// hart n's msip is at REG_MSIP + 4n and its mtimecmp at REG_MTIMECMP + 8n
+const REG_MSIP: u32 = 0x0000;
+const REG_MTIMECMP: u32 = 0x4000;
+const REG_MTIME_LOW: u32 = 0xbff8;
+const REG_MTIME_HIGH: u32 = 0xbffc;
+
+// cpu-intc interrupt numbers the clint drives
+pub const IRQ_M_SOFT: u32 = 3;
+pub const IRQ_M_TIMER: u32 = 7;
+
+const NS_PER_TICK: u64 = 1_000_000_000 / TIMEBASE_FREQ as u64;
+
+pub struct Clint {
+    instructions: u64,
+    // what the guest wrote to mtime, relative to the virtual clock
+    mtime_offset: u64,
+    // one per hart
+    mtimecmp: Vec<u64>,
+    msip: Vec<bool>,
+}
+
+impl Clint {
+    pub fn new(harts: usize) -> Self {
+        Clint {
+            instructions: 0,
+            mtime_offset: 0,
+            // reset value is unspecified; all ones keeps the timer quiet
+            // until firmware programs it
+            mtimecmp: vec![u64::MAX; harts.max(1)],
+            msip: vec![false; harts.max(1)],
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        (self.instructions * NS_PER_INSTRUCTION / NS_PER_TICK).wrapping_add(self.mtime_offset)
+    }
+
+    fn set_mtime(&mut self, val: u64) {
+        self.mtime_offset = val.wrapping_sub(self.mtime().wrapping_sub(self.mtime_offset));
+    }
+
+    pub fn timer_pending(&self, hart: usize) -> bool {
+        self.mtimecmp.get(hart).is_some_and(|&cmp| self.mtime() >= cmp)
+    }
+
+    pub fn software_pending(&self, hart: usize) -> bool {
+        self.msip.get(hart).copied().unwrap_or(false)
+    }
+
+    fn harts(&self) -> u32 {
+        self.msip.len() as u32
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            REG_MTIME_LOW => self.mtime() as u32,
+            REG_MTIME_HIGH => (self.mtime() >> 32) as u32,
+            o if o < REG_MSIP + 4 * self.harts() => self.msip[(o / 4) as usize] as u32,
+            o if (REG_MTIMECMP..REG_MTIMECMP + 8 * self.harts()).contains(&o) => {
+                let cmp = self.mtimecmp[((o - REG_MTIMECMP) / 8) as usize];
+                (cmp >> ((o & 4) * 8)) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _ram: &mut GuestRam) {
+        match offset {
+            o if o < REG_MSIP + 4 * self.harts() => self.msip[(o / 4) as usize] = val & 1 != 0,
+            o if (REG_MTIMECMP..REG_MTIMECMP + 8 * self.harts()).contains(&o) => {
+                let cmp = &mut self.mtimecmp[((o - REG_MTIMECMP) / 8) as usize];
+                let shift = (o & 4) * 8;
+                *cmp = (*cmp & !(0xffff_ffff << shift)) | (val as u64) << shift;
+            }
+            REG_MTIME_LOW => {
+                let t = self.mtime();
+                self.set_mtime((t & !0xffff_ffff) | val as u64);
+            }
+            REG_MTIME_HIGH => {
+                let t = self.mtime();
+                self.set_mtime((t & 0xffff_ffff) | (val as u64) << 32);
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, _ram: &mut GuestRam) {
+        self.instructions += 1;
+    }
+
+    fn irq_pending(&self) -> bool {
+        (0..self.msip.len()).any(|h| self.timer_pending(h) || self.software_pending(h))
+    }
+
+    fn hart_irqs(&self, hart: u32) -> u32 {
+        let hart = hart as usize;
+        (self.software_pending(hart) as u32) << IRQ_M_SOFT | (self.timer_pending(hart) as u32) << IRQ_M_TIMER
+    }
+
+    fn counters(&self) -> Option<(u64, u64)> {
+        Some((self.instructions, self.mtime()))
+    }
+
+    fn info(&self) -> Option<DeviceInfo> {
+        Some(DeviceInfo { name: "clint", compatible: "riscv,clint0" })
+    }
+
+    fn fdt_props(&self, fdt: &mut FdtBuilder) {
+        let cells: Vec<u32> = (0..self.harts())
+            .flat_map(|h| [fdt::intc_phandle(h), IRQ_M_SOFT, fdt::intc_phandle(h), IRQ_M_TIMER])
+            .collect();
+        fdt.prop_cells("interrupts-extended", &cells);
+    }
+
+    // the hart count comes from the machine options, so restore() only
+    // reads back as many harts as this clint was built with
+    fn save(&self, w: &mut Writer) {
+        w.put_u64(self.instructions);
+        w.put_u64(self.mtime_offset);
+        for (&cmp, &msip) in self.mtimecmp.iter().zip(&self.msip) {
+            w.put_u64(cmp);
+            w.put_bool(msip);
+        }
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.instructions = r.get_u64()?;
+        self.mtime_offset = r.get_u64()?;
+        for (cmp, msip) in self.mtimecmp.iter_mut().zip(self.msip.iter_mut()) {
+            *cmp = r.get_u64()?;
+            *msip = r.get_bool()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timer_fires_at_mtimecmp() {
+        let mut clint = Clint::new(2);
+        let mut mem = GuestRam::new(0, &mut []);
+        let per_tick = NS_PER_TICK / NS_PER_INSTRUCTION;
+        // hart 1's mtimecmp
+        clint.write(REG_MTIMECMP + 12, 0, &mut mem);
+        clint.write(REG_MTIMECMP + 8, 5, &mut mem);
+        for _ in 0..5 * per_tick - 1 {
+            clint.tick(&mut mem);
+        }
+        assert!(!clint.timer_pending(1));
+        clint.tick(&mut mem);
+        assert_eq!(clint.read(REG_MTIME_LOW), 5);
+        assert!(clint.timer_pending(1) && !clint.timer_pending(0));
+        assert_eq!(clint.hart_irqs(1), 1 << IRQ_M_TIMER);
+        assert_eq!(clint.hart_irqs(0), 0);
+    }
+
+    #[test]
+    fn test_msip_per_hart() {
+        let mut clint = Clint::new(2);
+        let mut mem = GuestRam::new(0, &mut []);
+        clint.write(REG_MSIP + 4, 1, &mut mem);
+        assert_eq!((clint.read(REG_MSIP), clint.read(REG_MSIP + 4)), (0, 1));
+        assert_eq!(clint.hart_irqs(1), 1 << IRQ_M_SOFT);
+        // past the last hart there's nothing
+        clint.write(REG_MSIP + 8, 1, &mut mem);
+        assert_eq!(clint.read(REG_MSIP + 8), 0);
+    }
+
+    #[test]
+    fn test_mtime_is_writable() {
+        let mut clint = Clint::new(1);
+        let mut mem = GuestRam::new(0, &mut []);
+        clint.write(REG_MTIME_HIGH, 1, &mut mem);
+        clint.write(REG_MTIME_LOW, 0x10, &mut mem);
+        assert_eq!(clint.mtime(), (1 << 32) | 0x10);
+        for _ in 0..NS_PER_TICK / NS_PER_INSTRUCTION {
+            clint.tick(&mut mem);
+        }
+        assert_eq!(clint.mtime(), (1 << 32) | 0x11);
+    }
+}
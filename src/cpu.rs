@@ -1,38 +1,114 @@
 // core cpu state: registers, memory, pc
 
+use crate::csr::{Csrs, Exception, CAUSE_FETCH_ACCESS, CAUSE_LOAD_ACCESS, CAUSE_STORE_ACCESS};
+use crate::decoder::Instruction;
+use crate::devices::{Device, GuestRam, MmioRegion};
+use crate::icache::ICache;
+use crate::replay::Journal;
+
 pub const NREGS: usize = 32;
 pub const MEM_SIZE: usize = 1024 * 1024; // 1mb for now
 
+// psabi register names, indexed by register number
+pub const ABI_NAMES: [&str; NREGS] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// "x10", "a0" or "fp" to a register number
+pub fn reg_index(name: &str) -> Option<usize> {
+    if let Some(n) = name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+        return (n < NREGS).then_some(n);
+    }
+    if name == "fp" {
+        return Some(8);
+    }
+    ABI_NAMES.iter().position(|&r| r == name)
+}
+
 pub struct Cpu {
     pub regs: [u32; NREGS],
     pub pc: u32,
     pub mem: Vec<u8>,
+    // guest physical address of mem[0]
+    pub ram_base: u32,
+    pub devices: Vec<MmioRegion>,
+    pub reset_vector: u32,
+    pub csrs: Csrs,
+    // the exception behind the error the last failed access or instruction
+    // returned, for the executor to turn into a trap; see csr.rs
+    pub fault: Option<Exception>,
+    pub icache: ICache,
+    // which hart's registers are in regs/pc; see smp.rs
+    pub hartid: u32,
+    // lr.w reservations, one per hart. any store to a reserved word
+    // clears it
+    pub reservations: Vec<Option<u32>>,
+    // set while recording or replaying a run; see replay.rs
+    pub journal: Option<Journal>,
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cpu {
     pub fn new() -> Self {
+        Self::with_ram(0, MEM_SIZE)
+    }
+
+    // ram of `size` bytes starting at guest address `base`
+    pub fn with_ram(base: u32, size: usize) -> Self {
         Cpu {
             regs: [0; NREGS],
-            pc: 0,
-            mem: vec![0; MEM_SIZE],
+            pc: base,
+            mem: vec![0; size],
+            ram_base: base,
+            devices: Vec::new(),
+            reset_vector: base,
+            csrs: Csrs::default(),
+            fault: None,
+            icache: ICache::new(size),
+            hartid: 0,
+            reservations: vec![None],
+            journal: None,
         }
     }
 
+    pub fn harts(&self) -> usize {
+        self.reservations.len()
+    }
+
+    // size the machine for `n` harts; the device tree lists them all
+    pub fn set_harts(&mut self, n: usize) {
+        self.reservations.resize(n.max(1), None);
+    }
+
+    // one past the last ram address, as a u64 since ram can end at 4g
+    pub fn ram_end(&self) -> u64 {
+        self.ram_base as u64 + self.mem.len() as u64
+    }
+
+    // index into mem for `len` bytes at `addr`, if they're all ram
+    pub fn ram_offset(&self, addr: u32, len: usize) -> Option<usize> {
+        let off = addr.checked_sub(self.ram_base)? as usize;
+        (off.checked_add(len)? <= self.mem.len()).then_some(off)
+    }
+
     pub fn load_program(&mut self, data: &[u8], addr: u32) {
-        let start = addr as usize;
-        let end = start + data.len();
-        if end > self.mem.len() {
-            panic!("program too large");
-        }
-        self.mem[start..end].copy_from_slice(data);
+        let start = self.ram_offset(addr, data.len()).expect("program too large");
+        self.mem[start..start + data.len()].copy_from_slice(data);
+        self.icache.invalidate(start, data.len());
     }
 
     pub fn read_word(&self, addr: u32) -> u32 {
-        let addr = addr as usize;
         // TODO: add misaligned access trap
-        if addr + 4 > self.mem.len() {
-            panic!("memory access out of bounds: 0x{:x}", addr);
-        }
+        let addr = self.ram_offset(addr, 4)
+            .unwrap_or_else(|| panic!("memory access out of bounds: 0x{:x}", addr));
         u32::from_le_bytes([
             self.mem[addr],
             self.mem[addr + 1],
@@ -42,12 +118,182 @@ impl Cpu {
     }
 
     pub fn write_word(&mut self, addr: u32, val: u32) {
-        let addr = addr as usize;
-        if addr + 4 > self.mem.len() {
-            panic!("memory write out of bounds: 0x{:x}", addr);
+        self.write_bytes(addr, &val.to_le_bytes());
+    }
+
+    // a store of 1, 2 or 4 bytes to ram. the words it touches lose their
+    // reservations and whatever was predecoded there
+    fn write_bytes(&mut self, addr: u32, bytes: &[u8]) {
+        let last = addr.wrapping_add(bytes.len() as u32 - 1) & !3;
+        for r in self.reservations.iter_mut() {
+            if *r == Some(addr & !3) || *r == Some(last) {
+                *r = None;
+            }
+        }
+        let off = self.ram_offset(addr, bytes.len())
+            .unwrap_or_else(|| panic!("memory write out of bounds: 0x{:x}", addr));
+        self.mem[off..off + bytes.len()].copy_from_slice(bytes);
+        self.icache.invalidate_word(off);
+        self.icache.invalidate_word(off + bytes.len() - 1);
+    }
+
+    pub fn attach_device(&mut self, base: u32, size: u32, mut dev: Box<dyn Device>) -> Result<(), String> {
+        let end = base as u64 + size as u64;
+        if ((base as u64) < self.ram_end() && (self.ram_base as u64) < end) || end > u32::MAX as u64 + 1 {
+            return Err(format!("device region 0x{:x}+0x{:x} overlaps ram or wraps", base, size));
+        }
+        if self.devices.iter().any(|r| (base as u64) < r.base as u64 + r.size as u64 && (r.base as u64) < end) {
+            return Err(format!("device region 0x{:x}+0x{:x} overlaps another device", base, size));
+        }
+        if let Some(journal) = &self.journal {
+            dev.set_journal(journal);
+        }
+        self.devices.push(MmioRegion { base, size, dev });
+        Ok(())
+    }
+
+    // record or replay this machine's input from now on. devices attached
+    // later get the journal too
+    pub fn set_journal(&mut self, journal: Journal) {
+        for r in self.devices.iter_mut() {
+            r.dev.set_journal(&journal);
+        }
+        self.journal = Some(journal);
+    }
+
+    pub fn fetch(&self, addr: u32) -> Result<u32, String> {
+        if self.ram_offset(addr, 4).is_some() {
+            return Ok(self.read_word(addr));
         }
-        let bytes = val.to_le_bytes();
-        self.mem[addr..addr + 4].copy_from_slice(&bytes);
+        self.devices
+            .iter()
+            .find(|r| r.contains(addr) && r.dev.executable())
+            .and_then(|r| r.dev.peek(addr - r.base))
+            .ok_or_else(|| format!("instruction access fault at 0x{:x}", addr))
+    }
+
+    // fetch and decode, going through the predecode cache for ram
+    pub fn fetch_decoded(&mut self, addr: u32) -> Result<Instruction, String> {
+        let Some(off) = self.ram_offset(addr, 4) else {
+            return self.fetch(addr)
+                .map(Instruction::decode)
+                .map_err(|e| self.raise(Exception::new(CAUSE_FETCH_ACCESS, addr), e));
+        };
+        if let Some(inst) = self.icache.get(off) {
+            return Ok(inst);
+        }
+        let inst = Instruction::decode(self.read_word(addr));
+        self.icache.insert(off, inst);
+        Ok(inst)
+    }
+
+    // what the debugger uses: ram, or a device that can be read without side effects
+    pub fn peek_word(&self, addr: u32) -> Option<u32> {
+        if self.ram_offset(addr, 4).is_some() {
+            return Some(self.read_word(addr));
+        }
+        self.devices
+            .iter()
+            .find(|r| r.contains(addr))
+            .and_then(|r| r.dev.peek(addr - r.base))
+    }
+
+    // loads and stores issued by the guest go through these so they can hit
+    // mmio. read_word/write_word stay ram-only for the loader and debugger
+    pub fn load_word(&mut self, addr: u32) -> Result<u32, String> {
+        self.load(addr, 4)
+    }
+
+    pub fn store_word(&mut self, addr: u32, val: u32) -> Result<(), String> {
+        self.store(addr, 4, val)
+    }
+
+    // `size` bytes (1, 2 or 4) at `addr`, zero-extended. devices only see
+    // words, so a narrower load reads the word around it and keeps its bytes
+    pub fn load(&mut self, addr: u32, size: u32) -> Result<u32, String> {
+        if let Some(off) = self.ram_offset(addr, size as usize) {
+            let mut bytes = [0; 4];
+            bytes[..size as usize].copy_from_slice(&self.mem[off..off + size as usize]);
+            return Ok(u32::from_le_bytes(bytes));
+        }
+        let word = if size == 4 { addr } else { addr & !3 };
+        match self.devices.iter_mut().find(|r| r.contains(word)) {
+            Some(r) if size == 4 => Ok(r.dev.read(word - r.base)),
+            Some(r) if (addr & 3) + size <= 4 => {
+                let val = r.dev.read(word - r.base) >> ((addr & 3) * 8);
+                Ok(val & (u32::MAX >> (32 - size * 8)))
+            }
+            _ => Err(self.raise(Exception::new(CAUSE_LOAD_ACCESS, addr), format!("load access fault at 0x{:x}", addr))),
+        }
+    }
+
+    // stores narrower than a word only reach devices that take them
+    pub fn store(&mut self, addr: u32, size: u32, val: u32) -> Result<(), String> {
+        if self.ram_offset(addr, size as usize).is_some() {
+            self.write_bytes(addr, &val.to_le_bytes()[..size as usize]);
+            return Ok(());
+        }
+        let stored = match self.devices.iter_mut().find(|r| r.contains(addr)) {
+            Some(r) if r.dev.read_only() => false,
+            Some(r) if size == 4 => {
+                r.dev.write(addr - r.base, val, &mut GuestRam::new(self.ram_base, &mut self.mem));
+                true
+            }
+            Some(r) => r.dev.write_narrow(addr - r.base, size, val),
+            None => false,
+        };
+        if stored {
+            return Ok(());
+        }
+        Err(self.raise(Exception::new(CAUSE_STORE_ACCESS, addr), format!("store access fault at 0x{:x}", addr)))
+    }
+
+    // note `exc` as the reason for the error `e` that's about to be
+    // returned, so the guest can take it as a trap
+    pub fn raise(&mut self, exc: Exception, e: String) -> String {
+        self.fault = Some(exc);
+        e
+    }
+
+    // tick every device, then hand the interrupt controller the levels of
+    // the lines wired to it
+    pub fn tick_devices(&mut self) {
+        if let Some(journal) = &self.journal {
+            journal.tick();
+        }
+        let mut sources = 0u32;
+        let mut lines = 0u64;
+        for (i, r) in self.devices.iter_mut().enumerate() {
+            r.dev.tick(&mut GuestRam::new(self.ram_base, &mut self.mem));
+            let pending = r.dev.irq_pending();
+            if let Some(src) = r.dev.irq().filter(|_| pending) {
+                sources |= 1 << src;
+            }
+            lines |= (pending as u64) << i.min(63);
+        }
+        for r in self.devices.iter_mut() {
+            r.dev.set_irq_lines(sources);
+        }
+        if let Some(journal) = &self.journal {
+            journal.irq_lines(lines);
+        }
+    }
+
+    // the mip bits the devices drive on the running hart
+    pub fn device_irqs(&self) -> u32 {
+        self.devices.iter().fold(0, |irqs, r| irqs | r.dev.hart_irqs(self.hartid))
+    }
+
+    // instructions and mtime from the machine timer, if there is one
+    pub fn counters(&self) -> Option<(u64, u64)> {
+        self.devices.iter().find_map(|r| r.dev.counters())
+    }
+
+    // test programs stop by jumping to address 0, on machines whose ram
+    // starts there. anywhere else 0 is an ordinary address, and fetching
+    // from it is an access fault
+    pub fn returned_to_zero(&self) -> bool {
+        self.pc == 0 && self.ram_base == 0
     }
 
     pub fn write_reg(&mut self, rd: usize, val: u32) {
@@ -62,6 +308,8 @@ impl Cpu {
 
     pub fn reset(&mut self) {
         self.regs = [0; NREGS];
-        self.pc = 0;
+        self.pc = self.reset_vector;
+        self.csrs = Csrs::default();
+        self.fault = None;
     }
 }
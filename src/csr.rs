@@ -0,0 +1,494 @@
+// control and status registers, privilege levels and the traps they steer
+//
+// a hart starts in machine mode and can drop to supervisor or user mode
+// with mret and sret. exceptions and interrupts go to m-mode unless medeleg
+// or mideleg hand them to s-mode, as long as the hart isn't already in m.
+// the guest takes a trap only once it has pointed the target mode's tvec at
+// a handler; until then an exception is an error that stops the run, the
+// way it was before there were traps, so bare programs still report the
+// instruction that went wrong. that makes a handler at address 0, where
+// run() stops anyway, the one thing a guest can't have.
+//
+// the machine raises an exception by setting Cpu::fault alongside the error
+// it returns; Executor decides whether the guest gets it. interrupts come
+// from the devices' hart_irqs and from the software-writable bits of mip,
+// and Executor checks for one before each instruction (or block).
+
+use crate::clint::{IRQ_M_SOFT, IRQ_M_TIMER};
+use crate::cpu::Cpu;
+use crate::decoder::*;
+use crate::plic::{IRQ_M_EXT, IRQ_S_EXT};
+use crate::snapshot::{Reader, Writer};
+
+// privilege levels, as mstatus.mpp encodes them
+pub const PRV_U: u8 = 0;
+pub const PRV_S: u8 = 1;
+pub const PRV_M: u8 = 3;
+
+// exception causes, as mcause reports them
+pub const CAUSE_FETCH_ACCESS: u32 = 1;
+pub const CAUSE_ILLEGAL_INSTRUCTION: u32 = 2;
+pub const CAUSE_BREAKPOINT: u32 = 3;
+pub const CAUSE_MISALIGNED_LOAD: u32 = 4;
+pub const CAUSE_LOAD_ACCESS: u32 = 5;
+pub const CAUSE_MISALIGNED_STORE: u32 = 6;
+pub const CAUSE_STORE_ACCESS: u32 = 7;
+// plus the privilege level the ecall came from
+pub const CAUSE_USER_ECALL: u32 = 8;
+pub const CAUSE_MACHINE_ECALL: u32 = 11;
+pub const CAUSE_FETCH_PAGE_FAULT: u32 = 12;
+pub const CAUSE_LOAD_PAGE_FAULT: u32 = 13;
+pub const CAUSE_STORE_PAGE_FAULT: u32 = 15;
+
+// set in the cause of an interrupt, whose low bits are its mip bit number
+pub const CAUSE_INTERRUPT: u32 = 1 << 31;
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 3 << 11;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+
+const MSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_MIE | MSTATUS_SPIE | MSTATUS_MPIE | MSTATUS_SPP | MSTATUS_MPP
+    | MSTATUS_MPRV | MSTATUS_SUM | MSTATUS_MXR | MSTATUS_TVM | MSTATUS_TW | MSTATUS_TSR;
+// the part of mstatus sstatus shows
+const SSTATUS_MASK: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+
+// mip and mie bits: software, timer and external interrupts for s and m
+pub const MIP_SSIP: u32 = 1 << 1;
+pub const MIP_STIP: u32 = 1 << 5;
+pub const MIP_SEIP: u32 = 1 << IRQ_S_EXT;
+const S_INTERRUPTS: u32 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+const M_INTERRUPTS: u32 = (1 << IRQ_M_SOFT) | (1 << IRQ_M_TIMER) | (1 << IRQ_M_EXT);
+
+// the order pending interrupts are taken in when several are
+const INTERRUPT_PRIORITY: [u32; 6] = [IRQ_M_EXT, IRQ_M_SOFT, IRQ_M_TIMER, IRQ_S_EXT, 1, 5];
+
+// exceptions s-mode can be handed: everything but an ecall from m-mode and
+// the reserved causes
+const MEDELEG_MASK: u32 = 0xb3ff;
+
+// a synchronous exception: its mcause and mtval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Exception {
+    pub cause: u32,
+    pub tval: u32,
+}
+
+impl Exception {
+    pub fn new(cause: u32, tval: u32) -> Self {
+        Exception { cause, tval }
+    }
+
+    // mtval could hold the instruction, but zero is allowed and leaves
+    // handlers to read it from mepc
+    pub fn illegal() -> Self {
+        Self::new(CAUSE_ILLEGAL_INSTRUCTION, 0)
+    }
+}
+
+// one hart's csrs and privilege level. mhartid, misa and the counters
+// aren't stored; mip only holds the bits software sets, the devices'
+// lines are ored in when it's read
+#[derive(Debug, Clone, PartialEq)]
+pub struct Csrs {
+    pub privilege: u8,
+    pub mstatus: u32,
+    pub medeleg: u32,
+    pub mideleg: u32,
+    pub mie: u32,
+    pub mip: u32,
+    pub mtvec: u32,
+    pub mcounteren: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    pub stvec: u32,
+    pub scounteren: u32,
+    pub sscratch: u32,
+    pub sepc: u32,
+    pub scause: u32,
+    pub stval: u32,
+    pub satp: u32,
+}
+
+impl Default for Csrs {
+    fn default() -> Self {
+        Csrs {
+            privilege: PRV_M,
+            mstatus: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+        }
+    }
+}
+
+impl Csrs {
+    // everything but the privilege level, in snapshot order
+    fn fields(&mut self) -> [&mut u32; 18] {
+        [&mut self.mstatus, &mut self.medeleg, &mut self.mideleg, &mut self.mie, &mut self.mip,
+         &mut self.mtvec, &mut self.mcounteren, &mut self.mscratch, &mut self.mepc, &mut self.mcause,
+         &mut self.mtval, &mut self.stvec, &mut self.scounteren, &mut self.sscratch, &mut self.sepc,
+         &mut self.scause, &mut self.stval, &mut self.satp]
+    }
+
+    pub fn save(&self, w: &mut Writer) {
+        w.put_u32(self.privilege as u32);
+        let mut csrs = self.clone();
+        for v in csrs.fields() {
+            w.put_u32(*v);
+        }
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.privilege = match r.get_u32()? {
+            p @ (0 | 1 | 3) => p as u8,
+            p => return Err(format!("bad privilege level {} in snapshot", p)),
+        };
+        for v in self.fields() {
+            *v = r.get_u32()?;
+        }
+        Ok(())
+    }
+}
+
+// rv32 plus a bit per standard extension, and s and u for the modes
+fn misa() -> u32 {
+    EXTENSIONS.iter().chain(&['i', 's', 'u']).fold(1 << 30, |isa, &c| isa | 1 << (c as u32 - 'a' as u32))
+}
+
+// mip as the hart sees it: what software set, plus the devices' lines
+fn mip(cpu: &Cpu) -> u32 {
+    cpu.csrs.mip | cpu.device_irqs()
+}
+
+// whether the current privilege level may touch `csr`: bits 9:8 of the
+// number are the lowest level that can, satp is off limits to s-mode under
+// mstatus.tvm, and the counters need the enable bits of every level above
+pub fn accessible(cpu: &Cpu, csr: u32) -> bool {
+    let c = &cpu.csrs;
+    if (csr >> 8) & 3 > c.privilege as u32 {
+        return false;
+    }
+    match csr {
+        CSR_SATP => c.privilege != PRV_S || c.mstatus & MSTATUS_TVM == 0,
+        0xc00..=0xc1f | 0xc80..=0xc9f => {
+            let bit = 1 << (csr & 0x1f);
+            (c.privilege == PRV_M || c.mcounteren & bit != 0) && (c.privilege != PRV_U || c.scounteren & bit != 0)
+        }
+        _ => true,
+    }
+}
+
+// the value of `csr` on the current hart, or None if it doesn't exist or
+// the hart's privilege level can't read it. the counters exist when the
+// machine has a timer to take them from
+pub fn read(cpu: &Cpu, csr: u32) -> Option<u32> {
+    if !accessible(cpu, csr) {
+        return None;
+    }
+    let c = &cpu.csrs;
+    Some(match csr {
+        CSR_SSTATUS => c.mstatus & SSTATUS_MASK,
+        CSR_SIE => c.mie & c.mideleg,
+        CSR_STVEC => c.stvec,
+        CSR_SCOUNTEREN => c.scounteren,
+        CSR_SSCRATCH => c.sscratch,
+        CSR_SEPC => c.sepc,
+        CSR_SCAUSE => c.scause,
+        CSR_STVAL => c.stval,
+        CSR_SIP => mip(cpu) & c.mideleg,
+        CSR_SATP => c.satp,
+        CSR_MSTATUS => c.mstatus,
+        // little-endian m and s modes
+        CSR_MSTATUSH => 0,
+        CSR_MISA => misa(),
+        CSR_MEDELEG => c.medeleg,
+        CSR_MIDELEG => c.mideleg,
+        CSR_MIE => c.mie,
+        CSR_MTVEC => c.mtvec,
+        CSR_MCOUNTEREN => c.mcounteren,
+        CSR_MSCRATCH => c.mscratch,
+        CSR_MEPC => c.mepc,
+        CSR_MCAUSE => c.mcause,
+        CSR_MTVAL => c.mtval,
+        CSR_MIP => mip(cpu),
+        // one instruction, one cycle
+        CSR_MCYCLE | CSR_MINSTRET | CSR_CYCLE | CSR_INSTRET => cpu.counters()?.0 as u32,
+        CSR_MCYCLEH | CSR_MINSTRETH | CSR_CYCLEH | CSR_INSTRETH => (cpu.counters()?.0 >> 32) as u32,
+        CSR_TIME => cpu.counters()?.1 as u32,
+        CSR_TIMEH => (cpu.counters()?.1 >> 32) as u32,
+        CSR_MVENDORID | CSR_MARCHID | CSR_MIMPID => 0,
+        CSR_MHARTID => cpu.hartid,
+        _ => return None,
+    })
+}
+
+// write `val` to `csr`, keeping only the bits it implements. None if the
+// csr doesn't exist, is read-only or is out of the hart's reach
+pub fn write(cpu: &mut Cpu, csr: u32, val: u32) -> Option<()> {
+    // the top two bits of the number are 3 for read-only csrs
+    if csr >> 10 == 3 || !accessible(cpu, csr) {
+        return None;
+    }
+    let c = &mut cpu.csrs;
+    match csr {
+        CSR_SSTATUS => c.mstatus = (c.mstatus & !SSTATUS_MASK) | (val & SSTATUS_MASK),
+        CSR_SIE => c.mie = (c.mie & !c.mideleg) | (val & c.mideleg),
+        CSR_STVEC => c.stvec = val & !2,
+        CSR_SCOUNTEREN => c.scounteren = val & 7,
+        CSR_SSCRATCH => c.sscratch = val,
+        CSR_SEPC => c.sepc = val & !3,
+        CSR_SCAUSE => c.scause = val,
+        CSR_STVAL => c.stval = val,
+        // s-mode can only post itself a software interrupt
+        CSR_SIP => {
+            let writable = c.mideleg & MIP_SSIP;
+            c.mip = (c.mip & !writable) | (val & writable);
+        }
+        // bare or sv32. the asid is kept but there's no tlb for it to tag
+        CSR_SATP => c.satp = val,
+        CSR_MSTATUS => {
+            let mut v = val & MSTATUS_MASK;
+            // 2 isn't a privilege level, so mpp keeps what it had
+            if v & MSTATUS_MPP == 2 << 11 {
+                v = (v & !MSTATUS_MPP) | (c.mstatus & MSTATUS_MPP);
+            }
+            c.mstatus = v;
+        }
+        // misa and mstatush can't be changed, so writes are ignored
+        CSR_MISA | CSR_MSTATUSH => {}
+        CSR_MEDELEG => c.medeleg = val & MEDELEG_MASK,
+        CSR_MIDELEG => c.mideleg = val & S_INTERRUPTS,
+        CSR_MIE => c.mie = val & (S_INTERRUPTS | M_INTERRUPTS),
+        // direct or vectored mode
+        CSR_MTVEC => c.mtvec = val & !2,
+        // cycle, time and instret
+        CSR_MCOUNTEREN => c.mcounteren = val & 7,
+        CSR_MSCRATCH => c.mscratch = val,
+        // without compressed instructions every pc is 4-aligned
+        CSR_MEPC => c.mepc = val & !3,
+        CSR_MCAUSE => c.mcause = val,
+        CSR_MTVAL => c.mtval = val,
+        // the m-mode bits follow the clint and plic
+        CSR_MIP => c.mip = val & S_INTERRUPTS,
+        // the counters follow the machine timer, so writes are ignored
+        CSR_MCYCLE | CSR_MINSTRET | CSR_MCYCLEH | CSR_MINSTRETH => {}
+        _ => return None,
+    }
+    Some(())
+}
+
+// the privilege level a trap with `cause` goes to
+fn target(cpu: &Cpu, cause: u32) -> u8 {
+    let c = &cpu.csrs;
+    let deleg = if cause & CAUSE_INTERRUPT != 0 { c.mideleg } else { c.medeleg };
+    if c.privilege <= PRV_S && (deleg >> (cause & 0x1f)) & 1 != 0 { PRV_S } else { PRV_M }
+}
+
+// whether the guest has a handler to take an exception or interrupt with
+// `cause`
+pub fn handles(cpu: &Cpu, cause: u32) -> bool {
+    match target(cpu, cause) {
+        PRV_S => cpu.csrs.stvec != 0,
+        _ => cpu.csrs.mtvec != 0,
+    }
+}
+
+// enter the trap handler for `cause`, with cpu.pc the instruction that
+// raised it or, for an interrupt, the one it came before
+pub fn trap(cpu: &mut Cpu, cause: u32, tval: u32) {
+    let to = target(cpu, cause);
+    let c = &mut cpu.csrs;
+    // vectored mode only spreads out interrupts
+    let vector = |tvec: u32| match tvec & 1 {
+        1 if cause & CAUSE_INTERRUPT != 0 => (tvec & !3).wrapping_add(4 * (cause & 0x1f)),
+        _ => tvec & !3,
+    };
+    if to == PRV_S {
+        c.sepc = cpu.pc;
+        c.scause = cause;
+        c.stval = tval;
+        // sie moves to spie and interrupts are off in the handler
+        let sie = c.mstatus & MSTATUS_SIE;
+        c.mstatus &= !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP);
+        c.mstatus |= (sie << 4) | ((c.privilege as u32) << 8);
+        cpu.pc = vector(c.stvec);
+    } else {
+        c.mepc = cpu.pc;
+        c.mcause = cause;
+        c.mtval = tval;
+        let mie = c.mstatus & MSTATUS_MIE;
+        c.mstatus &= !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP);
+        c.mstatus |= (mie << 4) | ((c.privilege as u32) << 11);
+        cpu.pc = vector(c.mtvec);
+    }
+    c.privilege = to;
+}
+
+// take the highest priority interrupt that's pending, enabled in mie and
+// not masked at the hart's privilege level. m-mode interrupts are masked by
+// mstatus.mie only in m-mode; delegated ones by mstatus.sie in s-mode, and
+// always in m-mode
+#[inline]
+pub fn interrupt(cpu: &mut Cpu) -> bool {
+    cpu.csrs.mie != 0 && take_interrupt(cpu)
+}
+
+fn take_interrupt(cpu: &mut Cpu) -> bool {
+    let c = &cpu.csrs;
+    let pending = mip(cpu) & c.mie;
+    if pending == 0 {
+        return false;
+    }
+    let m_on = c.privilege < PRV_M || c.mstatus & MSTATUS_MIE != 0;
+    let s_on = c.privilege < PRV_S || (c.privilege == PRV_S && c.mstatus & MSTATUS_SIE != 0);
+    let enabled = (pending & !c.mideleg & if m_on { !0 } else { 0 }) | (pending & c.mideleg & if s_on { !0 } else { 0 });
+    let Some(irq) = INTERRUPT_PRIORITY.into_iter().find(|irq| enabled & (1 << irq) != 0) else {
+        return false;
+    };
+    let cause = CAUSE_INTERRUPT | irq;
+    if !handles(cpu, cause) {
+        return false;
+    }
+    trap(cpu, cause, 0);
+    true
+}
+
+// mret: back to mepc, in the mode and with the interrupt enable from
+// before the trap
+pub fn mret(cpu: &mut Cpu) {
+    let c = &mut cpu.csrs;
+    let mpp = ((c.mstatus & MSTATUS_MPP) >> 11) as u8;
+    let mpie = c.mstatus & MSTATUS_MPIE;
+    c.mstatus = (c.mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) | (mpie >> 4) | MSTATUS_MPIE;
+    if mpp != PRV_M {
+        c.mstatus &= !MSTATUS_MPRV;
+    }
+    c.privilege = mpp;
+    cpu.pc = c.mepc;
+}
+
+// sret: the same for s-mode, from sepc
+pub fn sret(cpu: &mut Cpu) {
+    let c = &mut cpu.csrs;
+    let spp = ((c.mstatus & MSTATUS_SPP) >> 8) as u8;
+    let spie = c.mstatus & MSTATUS_SPIE;
+    c.mstatus = (c.mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | (spie >> 4) | MSTATUS_SPIE;
+    c.privilege = spp;
+    cpu.pc = c.sepc;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trap_and_mret() {
+        let mut cpu = Cpu::new();
+        cpu.pc = 0x40;
+        write(&mut cpu, CSR_MTVEC, 0x201).unwrap();
+        write(&mut cpu, CSR_MSTATUS, MSTATUS_MIE).unwrap();
+        assert!(handles(&cpu, CAUSE_ILLEGAL_INSTRUCTION));
+        trap(&mut cpu, CAUSE_LOAD_ACCESS, 0x1234);
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!((cpu.csrs.mepc, cpu.csrs.mcause, cpu.csrs.mtval), (0x40, CAUSE_LOAD_ACCESS, 0x1234));
+        assert_eq!(read(&cpu, CSR_MSTATUS), Some(MSTATUS_MPIE | MSTATUS_MPP));
+        write(&mut cpu, CSR_MEPC, 0x44).unwrap();
+        mret(&mut cpu);
+        assert_eq!(cpu.pc, 0x44);
+        assert_eq!(read(&cpu, CSR_MSTATUS), Some(MSTATUS_MIE | MSTATUS_MPIE));
+        assert_eq!(cpu.csrs.privilege, PRV_M);
+    }
+
+    #[test]
+    fn test_delegation_to_s_mode() {
+        let mut cpu = Cpu::new();
+        write(&mut cpu, CSR_MTVEC, 0x100).unwrap();
+        write(&mut cpu, CSR_STVEC, 0x200).unwrap();
+        write(&mut cpu, CSR_MEDELEG, 1 << CAUSE_LOAD_PAGE_FAULT).unwrap();
+        // mret into s-mode at 0x40
+        write(&mut cpu, CSR_MSTATUS, (PRV_S as u32) << 11 | MSTATUS_SIE).unwrap();
+        write(&mut cpu, CSR_MEPC, 0x40).unwrap();
+        mret(&mut cpu);
+        assert_eq!((cpu.pc, cpu.csrs.privilege), (0x40, PRV_S));
+        // s-mode can't see m-mode csrs
+        assert_eq!(read(&cpu, CSR_MSTATUS), None);
+        assert_eq!(write(&mut cpu, CSR_MEPC, 0), None);
+
+        trap(&mut cpu, CAUSE_LOAD_PAGE_FAULT, 0x1000);
+        assert_eq!((cpu.pc, cpu.csrs.privilege), (0x200, PRV_S));
+        assert_eq!((cpu.csrs.sepc, cpu.csrs.scause, cpu.csrs.stval), (0x40, CAUSE_LOAD_PAGE_FAULT, 0x1000));
+        assert_eq!(read(&cpu, CSR_SSTATUS), Some(MSTATUS_SPIE | MSTATUS_SPP));
+        // an ecall from s-mode isn't delegated
+        trap(&mut cpu, CAUSE_USER_ECALL + PRV_S as u32, 0);
+        assert_eq!((cpu.pc, cpu.csrs.privilege), (0x100, PRV_M));
+        assert_eq!(cpu.csrs.mstatus & MSTATUS_MPP, (PRV_S as u32) << 11);
+        mret(&mut cpu);
+        write(&mut cpu, CSR_SEPC, 0x80).unwrap();
+        sret(&mut cpu);
+        assert_eq!((cpu.pc, cpu.csrs.privilege), (0x80, PRV_S));
+        assert_eq!(read(&cpu, CSR_SSTATUS), Some(MSTATUS_SIE | MSTATUS_SPIE));
+    }
+
+    #[test]
+    fn test_interrupt_masking() {
+        let mut cpu = Cpu::new();
+        write(&mut cpu, CSR_MTVEC, 0x101).unwrap();
+        write(&mut cpu, CSR_STVEC, 0x200).unwrap();
+        write(&mut cpu, CSR_MIDELEG, MIP_SSIP).unwrap();
+        write(&mut cpu, CSR_MIE, MIP_SSIP | MIP_STIP).unwrap();
+        write(&mut cpu, CSR_MIP, MIP_SSIP | MIP_STIP).unwrap();
+        // in m-mode with mstatus.mie clear, nothing
+        assert!(!interrupt(&mut cpu));
+        // stip isn't delegated, so it goes to m-mode through the vector
+        write(&mut cpu, CSR_MSTATUS, MSTATUS_MIE).unwrap();
+        cpu.pc = 0x40;
+        assert!(interrupt(&mut cpu));
+        assert_eq!(cpu.pc, 0x100 + 4 * 5);
+        assert_eq!(cpu.csrs.mcause, CAUSE_INTERRUPT | 5);
+        // the delegated ssip is masked in m-mode however mstatus is set
+        write(&mut cpu, CSR_MIP, MIP_SSIP).unwrap();
+        write(&mut cpu, CSR_MSTATUS, MSTATUS_MIE | MSTATUS_SIE).unwrap();
+        assert!(!interrupt(&mut cpu));
+        cpu.csrs.privilege = PRV_U;
+        assert!(interrupt(&mut cpu));
+        assert_eq!((cpu.pc, cpu.csrs.privilege, cpu.csrs.scause), (0x200, PRV_S, CAUSE_INTERRUPT | 1));
+    }
+
+    #[test]
+    fn test_read_only_and_missing_csrs() {
+        let mut cpu = Cpu::new();
+        cpu.hartid = 2;
+        assert_eq!(read(&cpu, CSR_MHARTID), Some(2));
+        assert_eq!(write(&mut cpu, CSR_MHARTID, 0), None);
+        assert_eq!(read(&cpu, 0x7c0), None);
+        // rv32 with i, m, a, s and u
+        assert_eq!(read(&cpu, CSR_MISA), Some(0x4014_1101));
+        // no clint, no time
+        assert_eq!(read(&cpu, CSR_TIME), None);
+    }
+}
@@ -1,27 +1,109 @@
 // interactive debugger
 
-use crate::cpu::Cpu;
-use crate::decoder::Instruction;
+use crate::cpu::{self, Cpu};
+use crate::decoder::{Instruction, Opcode};
+use crate::dwarf::LineTable;
 use crate::executor::Executor;
-use crate::metrics::Metrics;
+use crate::history::History;
+use crate::metrics::{Instrument, Metrics, NoMetrics};
+use crate::mmu::{self, Access};
+use crate::smp::Smp;
+use crate::snapshot;
+use crate::symbols::SymbolMap;
 use std::collections::HashSet;
+use std::fs;
 use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+// something a watchpoint or `last` looks for writes to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    // a register of one hart
+    Reg { hart: u32, reg: usize },
+    // an aligned memory word
+    Word(u32),
+}
+
+impl Target {
+    // the value, or None for a register of a hart that isn't current
+    fn read(self, cpu: &Cpu) -> Option<u32> {
+        match self {
+            Target::Reg { hart, reg } => (cpu.hartid == hart).then(|| cpu.regs[reg]),
+            Target::Word(addr) => cpu.peek_word(addr),
+        }
+    }
+
+    // whether `inst`, about to run on `hart`, writes here, even if it
+    // writes the value that's already there. `store` is the address and
+    // size it stores to, if it does
+    fn written_by(self, hart: u32, inst: &Instruction, store: Option<(u32, u32)>) -> bool {
+        match self {
+            Target::Reg { hart: h, reg } => h == hart && reg != 0 && inst.rd == reg && inst.opcode.writes_rd(),
+            Target::Word(addr) => store.is_some_and(|(s, size)| s.wrapping_sub(addr) < 4 || addr.wrapping_sub(s) < size),
+        }
+    }
+}
+
+// the physical address and size of what the instruction at pc stores, for
+// sc.w only if it's going to succeed
+fn store_address(cpu: &Cpu, inst: &Instruction) -> Option<(u32, u32)> {
+    let base = cpu.regs[inst.rs1];
+    let (addr, size) = match inst.opcode {
+        op if op.is_store() => (base.wrapping_add(inst.imm as u32), op.access_size()),
+        Opcode::ScW => (cpu.reservations[cpu.hartid as usize] == Some(base & !3)).then_some((base, 4))?,
+        op if op.is_amo() => (base, 4),
+        _ => return None,
+    };
+    mmu::peek(cpu, addr, Access::Store).map(|pa| (pa, size))
+}
+
+// the instruction the hart would fetch from virtual address `pc`, found
+// without touching the page tables. anything it can't fetch shows as 0
+fn fetch(cpu: &Cpu, pc: u32) -> Instruction {
+    let raw = mmu::peek(cpu, pc, Access::Fetch).and_then(|pa| cpu.peek_word(pa));
+    Instruction::decode(raw.unwrap_or(0))
+}
 
 pub struct Debugger {
     breakpoints: HashSet<u32>,
+    watchpoints: Vec<Target>,
     pub executor: Executor,
+    pub symbols: Arc<SymbolMap>,
+    pub lines: LineTable,
+    // set for multi-hart machines; stepping follows the same schedule as run
+    pub smp: Option<Smp>,
+    // instructions the machine has run, carried through save and load
+    pub steps: u64,
+    // checkpoints and recorded input for going backwards, once started
+    history: Option<History>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Debugger {
     pub fn new() -> Self {
         Debugger {
             breakpoints: HashSet::new(),
+            watchpoints: Vec::new(),
             executor: Executor::new(),
+            symbols: Arc::new(SymbolMap::new()),
+            lines: LineTable::new(),
+            smp: None,
+            steps: 0,
+            history: None,
         }
     }
 
     pub fn run(&mut self, cpu: &mut Cpu, metrics: &mut Metrics) {
         println!("debugger started. type 'help' for commands");
+        if let Err(e) = self.start_history(cpu) {
+            println!("no reverse execution: {}", e);
+        }
         
         loop {
             print!("(dbg) ");
@@ -36,62 +118,200 @@ impl Debugger {
             if input.is_empty() {
                 continue;
             }
-            
-            let parts: Vec<&str> = input.split_whitespace().collect();
-            let cmd = parts[0];
-            
-            match cmd {
-                "help" | "h" => self.print_help(),
-                "step" | "s" => self.step(cpu, metrics),
-                "continue" | "c" => {
-                    if self.continue_exec(cpu, metrics) {
-                        break;
-                    }
-                }
-                "break" | "b" => self.set_breakpoint(&parts[1..]),
-                "regs" | "r" => self.dump_regs(cpu),
-                "mem" | "m" => self.dump_mem(cpu, &parts[1..]),
-                "dis" | "d" => self.disassemble(cpu, &parts[1..]),
-                "pc" => println!("pc = 0x{:08x}", cpu.pc),
-                "quit" | "q" => break,
-                _ => println!("unknown command: {}", cmd),
+            if self.command(cpu, metrics, input) {
+                break;
             }
         }
     }
+
+    // keep checkpoints and input from here on so rs, rc and last can go
+    // back to anywhere after this point
+    pub fn start_history(&mut self, cpu: &mut Cpu) -> Result<(), String> {
+        self.history = Some(History::new(cpu, &self.executor, self.smp.as_ref(), self.steps)?);
+        Ok(())
+    }
+
+    // run one command line. returns true when the session is over
+    pub fn command(&mut self, cpu: &mut Cpu, metrics: &mut Metrics, input: &str) -> bool {
+        let parts: Vec<&str> = input.split_whitespace().collect();
+        let Some(&cmd) = parts.first() else {
+            return false;
+        };
+        
+        match cmd {
+            "help" | "h" => self.print_help(),
+            "step" | "s" if !self.lines.is_empty() => return self.step_line(cpu, metrics, false),
+            "next" | "n" if !self.lines.is_empty() => return self.step_line(cpu, metrics, true),
+            "step" | "s" | "stepi" | "si" | "next" | "n" => self.step(cpu, metrics),
+            "continue" | "c" => return self.continue_exec(cpu, metrics),
+            "rs" | "reverse-step" => self.reverse_step(cpu),
+            "rc" | "reverse-continue" => self.reverse_continue(cpu),
+            "break" | "b" => self.set_breakpoint(&parts[1..]),
+            "watch" | "w" => self.watch(cpu, &parts[1..]),
+            "last" => self.last_write(cpu, &parts[1..]),
+            "regs" | "r" => self.dump_regs(cpu),
+            "hart" => self.hart(cpu, &parts[1..]),
+            "save" => self.save(cpu, &parts[1..]),
+            "load" => self.load(cpu, &parts[1..]),
+            "mem" | "m" => self.dump_mem(cpu, &parts[1..]),
+            "dis" | "d" => self.disassemble(cpu, &parts[1..]),
+            "pc" => {
+                println!("pc = 0x{:08x}{}", cpu.pc, self.describe(cpu.pc));
+                self.show_source(cpu.pc);
+            }
+            "quit" | "q" => return true,
+            _ => println!("unknown command: {}", cmd),
+        }
+        false
+    }
     
     fn print_help(&self) {
         println!("commands:");
-        println!("  step (s)         - execute one instruction");
-        println!("  continue (c)     - continue execution until breakpoint");
-        println!("  break (b) <addr> - set breakpoint at address");
+        println!("  step (s)         - execute one source line (one instruction without line info)");
+        println!("  next (n)         - like step, but run calls to completion");
+        println!("  stepi (si)       - execute one instruction");
+        println!("  continue (c)     - continue execution until breakpoint or watchpoint");
+        println!("  rs               - go back one instruction");
+        println!("  rc               - go back to the previous breakpoint or watchpoint hit");
+        println!("  break (b) <loc>  - set breakpoint at address, symbol[+off] or file:line");
+        println!("  watch (w) <what> - stop after writes to a register or the memory word at loc");
+        println!("  last <what>      - go back to the last write to a register or memory word");
         println!("  regs (r)         - dump register file");
-        println!("  mem (m) <addr>   - dump memory at address");
-        println!("  dis (d) [addr]   - disassemble instructions");
+        println!("  mem (m) <loc>    - dump memory at address or symbol");
+        println!("  dis (d) [loc]    - disassemble instructions");
         println!("  pc               - show program counter");
+        println!("  hart [n]         - list harts, or switch to hart n");
+        println!("  save <file>      - write a snapshot of the machine");
+        println!("  load <file>      - go back to a snapshot (breakpoints stay)");
         println!("  quit (q)         - exit debugger");
     }
     
+    // one instruction on the current hart, then on to the next hart if its
+    // turn is over. returns which of `targets`, if any, the instruction wrote
+    fn exec_step<M: Instrument>(&mut self, cpu: &mut Cpu, metrics: &mut M, targets: &[Target]) -> Result<Option<usize>, String> {
+        let before: Vec<Option<u32>> = targets.iter().map(|t| t.read(cpu)).collect();
+        let watched = (!targets.is_empty()).then(|| {
+            let inst = fetch(cpu, cpu.pc);
+            (cpu.hartid, store_address(cpu, &inst), inst)
+        });
+        self.executor.step(cpu, metrics)?;
+        self.steps += 1;
+        // a value that changed without a store was written by an ecall, a
+        // semihosting call or a device
+        let hit = watched.and_then(|(hart, store, inst)| {
+            targets.iter().zip(&before).position(|(t, old)| t.read(cpu) != *old || t.written_by(hart, &inst, store))
+        });
+        if let Some(smp) = self.smp.as_mut() {
+            metrics.record_hart(smp.current(), 1);
+            smp.tick(cpu, &mut self.executor);
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.record(cpu, &self.executor, self.smp.as_ref(), self.steps);
+        }
+        Ok(hit)
+    }
+
+    // "hart 1: " on multi-hart machines
+    fn hart_prefix(&self) -> String {
+        match &self.smp {
+            Some(smp) => format!("hart {}: ", smp.current()),
+            None => String::new(),
+        }
+    }
+
     fn step(&mut self, cpu: &mut Cpu, metrics: &mut Metrics) {
         let pc_before = cpu.pc;
-        match self.executor.step(cpu, metrics) {
+        let prefix = self.hart_prefix();
+        // decoded first: a trap can change what pc_before translates to
+        let inst = fetch(cpu, pc_before);
+        match self.exec_step(cpu, metrics, &[]) {
             Ok(_) => {
-                let raw = cpu.read_word(pc_before);
-                let inst = Instruction::decode(raw);
-                println!("0x{:08x}: {}", pc_before, inst.disassemble());
+                println!("{}0x{:08x}{}: {}", prefix, pc_before, self.describe(pc_before), self.format_inst(&inst, pc_before));
             }
             Err(e) => println!("error: {}", e),
         }
     }
     
+    // run until the pc reaches the start of a different source line. calls
+    // into code without line info (libc, hand-written asm) are always run to
+    // completion; `over_calls` does the same for every call. returns true if
+    // the program stopped for good
+    fn step_line(&mut self, cpu: &mut Cpu, metrics: &mut Metrics, over_calls: bool) -> bool {
+        let start = self.lines.lookup(cpu.pc).map(|l| (l.file.to_string(), l.line));
+        loop {
+            let pc = cpu.pc;
+            let inst = fetch(cpu, pc);
+            let is_call = matches!(inst.opcode, Opcode::Jal | Opcode::Jalr) && inst.rd == 1;
+            if let Err(e) = self.exec_step(cpu, metrics, &[]) {
+                println!("stopped: {}", e);
+                return true;
+            }
+            if is_call && (over_calls || self.lines.lookup(cpu.pc).is_none()) {
+                // jal doesn't touch sp, so a recursive call returning to the
+                // same address still has a lower sp than ours
+                let (ret, sp) = (pc.wrapping_add(4), cpu.regs[2]);
+                while !(cpu.pc == ret && cpu.regs[2] >= sp) {
+                    if self.breakpoints.contains(&cpu.pc) || cpu.returned_to_zero() {
+                        break;
+                    }
+                    if let Err(e) = self.exec_step(cpu, metrics, &[]) {
+                        println!("stopped: {}", e);
+                        return true;
+                    }
+                }
+            }
+            if cpu.returned_to_zero() {
+                println!("program returned to 0x0");
+                return true;
+            }
+            if self.breakpoints.contains(&cpu.pc) {
+                println!("{}hit breakpoint at 0x{:08x}{}", self.hart_prefix(), cpu.pc, self.describe(cpu.pc));
+                self.show_source(cpu.pc);
+                return false;
+            }
+            if self.lines.is_stmt_start(cpu.pc) {
+                let here = self.lines.lookup(cpu.pc).map(|l| (l.file.to_string(), l.line));
+                if here != start {
+                    break;
+                }
+            }
+        }
+        println!("0x{:08x}{}", cpu.pc, self.describe(cpu.pc));
+        self.show_source(cpu.pc);
+        false
+    }
+
+    // "main.c:42" plus the line itself when the source file can be found
+    fn show_source(&self, addr: u32) {
+        let Some(loc) = self.lines.lookup(addr) else {
+            return;
+        };
+        let text = fs::read_to_string(loc.file)
+            .ok()
+            .and_then(|src| src.lines().nth(loc.line as usize - 1).map(|l| l.to_string()));
+        match text {
+            Some(t) => println!("{}\t{}", loc, t.trim_end()),
+            None => println!("{}", loc),
+        }
+    }
+
     fn continue_exec(&mut self, cpu: &mut Cpu, metrics: &mut Metrics) -> bool {
+        let watchpoints = self.watchpoints.clone();
         loop {
             if self.breakpoints.contains(&cpu.pc) {
-                println!("hit breakpoint at 0x{:08x}", cpu.pc);
+                println!("{}hit breakpoint at 0x{:08x}{}", self.hart_prefix(), cpu.pc, self.describe(cpu.pc));
+                self.show_source(cpu.pc);
                 return false;
             }
             
-            match self.executor.step(cpu, metrics) {
-                Ok(_) => {},
+            let (pc, prefix) = (cpu.pc, self.hart_prefix());
+            let inst = fetch(cpu, pc);
+            match self.exec_step(cpu, metrics, &watchpoints) {
+                Ok(None) => {},
+                Ok(Some(i)) => {
+                    println!("{}watchpoint {} written by 0x{:08x}{}: {}", prefix, self.show_target(watchpoints[i]), pc, self.describe(pc), self.format_inst(&inst, pc));
+                    return false;
+                }
                 Err(e) => {
                     println!("stopped: {}", e);
                     return true;
@@ -99,23 +319,246 @@ impl Debugger {
             }
         }
     }
+
+    // get the machine to where it was after `to` instructions: back to the
+    // checkpoint before it, then forward
+    fn travel(&mut self, cpu: &mut Cpu, to: u64) -> Result<(), String> {
+        let history = self.history.as_ref().ok_or("no history to go back through")?;
+        self.steps = history.rewind(cpu, &mut self.executor, self.smp.as_mut(), to)?;
+        while self.steps < to {
+            self.exec_step(cpu, &mut NoMetrics, &[])?;
+        }
+        Ok(())
+    }
+
+    // the last point before now where a breakpoint (with `breakpoints`)
+    // would have stopped the machine or an instruction was about to write
+    // one of `targets`, and which target. searches one checkpoint interval
+    // at a time, latest first, and leaves the machine there; with nothing
+    // found it's wherever the search gave up
+    fn search_back(&mut self, cpu: &mut Cpu, targets: &[Target], breakpoints: bool) -> Result<Option<(u64, Option<usize>)>, String> {
+        let now = self.steps;
+        let starts = self.history.as_ref().map_or(Vec::new(), |h| h.before(now));
+        for (i, &start) in starts.iter().enumerate().rev() {
+            let end = starts.get(i + 1).copied().unwrap_or(now);
+            self.travel(cpu, start)?;
+            let mut found = None;
+            while self.steps < end {
+                let at = self.steps;
+                if breakpoints && self.breakpoints.contains(&cpu.pc) {
+                    found = Some((at, None));
+                }
+                if let Some(t) = self.exec_step(cpu, &mut NoMetrics, targets)? {
+                    found = Some((at, Some(t)));
+                }
+            }
+            if let Some((at, hit)) = found {
+                self.travel(cpu, at)?;
+                return Ok(Some((at, hit)));
+            }
+        }
+        Ok(None)
+    }
+
+    // "0x00001000: addi x5, x5, 1", the instruction about to run
+    fn show_position(&self, cpu: &Cpu) {
+        let inst = fetch(cpu, cpu.pc);
+        println!("{}0x{:08x}{}: {}", self.hart_prefix(), cpu.pc, self.describe(cpu.pc), self.format_inst(&inst, cpu.pc));
+        self.show_source(cpu.pc);
+    }
+
+    fn reverse_step(&mut self, cpu: &mut Cpu) {
+        let Some(start) = self.history.as_ref().map(|h| h.start()) else {
+            println!("no history to go back through");
+            return;
+        };
+        if self.steps <= start {
+            println!("at the start of history (after {} instructions)", start);
+            return;
+        }
+        match self.travel(cpu, self.steps - 1) {
+            Ok(()) => self.show_position(cpu),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    fn reverse_continue(&mut self, cpu: &mut Cpu) {
+        let watchpoints = self.watchpoints.clone();
+        match self.search_back(cpu, &watchpoints, true) {
+            Ok(Some((_, None))) => {
+                println!("{}hit breakpoint at 0x{:08x}{}", self.hart_prefix(), cpu.pc, self.describe(cpu.pc));
+                self.show_source(cpu.pc);
+            }
+            Ok(Some((_, Some(i)))) => {
+                println!("watchpoint {} is written by the next instruction:", self.show_target(watchpoints[i]));
+                self.show_position(cpu);
+            }
+            Ok(None) => {
+                let start = self.history.as_ref().map_or(0, |h| h.start());
+                match self.travel(cpu, start) {
+                    Ok(()) => {
+                        println!("reached the start of history (after {} instructions)", start);
+                        self.show_position(cpu);
+                    }
+                    Err(e) => println!("error: {}", e),
+                }
+            }
+            Err(e) => println!("error: {}", e),
+        }
+    }
+
+    // a register name or a memory location
+    fn parse_target(&self, cpu: &Cpu, s: &str) -> Option<Target> {
+        match cpu::reg_index(s) {
+            Some(reg) => Some(Target::Reg { hart: cpu.hartid, reg }),
+            None => self.parse_location(s).map(|addr| Target::Word(addr & !3)),
+        }
+    }
+
+    fn show_target(&self, target: Target) -> String {
+        match target {
+            Target::Reg { hart, reg } if self.smp.is_some() => format!("hart {} x{}", hart, reg),
+            Target::Reg { reg, .. } => format!("x{}", reg),
+            Target::Word(addr) => format!("0x{:08x}{}", addr, self.describe(addr)),
+        }
+    }
+
+    fn watch(&mut self, cpu: &Cpu, args: &[&str]) {
+        let [what] = args else {
+            println!("usage: watch <register|address|symbol[+off]>");
+            return;
+        };
+        match self.parse_target(cpu, what) {
+            Some(target) => {
+                self.watchpoints.push(target);
+                println!("watchpoint set on {}", self.show_target(target));
+            }
+            None => println!("invalid register, address or unknown symbol: {}", what),
+        }
+    }
+
+    fn last_write(&mut self, cpu: &mut Cpu, args: &[&str]) {
+        let [what] = args else {
+            println!("usage: last <register|address|symbol[+off]>");
+            return;
+        };
+        let Some(target) = self.parse_target(cpu, what) else {
+            println!("invalid register, address or unknown symbol: {}", what);
+            return;
+        };
+        let now = self.steps;
+        match self.search_back(cpu, &[target], false) {
+            Ok(Some((at, _))) => {
+                println!("{} was last written by instruction {}:", self.show_target(target), at + 1);
+                self.show_position(cpu);
+            }
+            Ok(None) => {
+                let start = self.history.as_ref().map_or(0, |h| h.start());
+                println!("{} hasn't been written since the start of history (after {} instructions)", self.show_target(target), start);
+                if let Err(e) = self.travel(cpu, now) {
+                    println!("error: {}", e);
+                }
+            }
+            Err(e) => println!("error: {}", e),
+        }
+    }
     
     fn set_breakpoint(&mut self, args: &[&str]) {
         if args.is_empty() {
-            println!("usage: break <address>");
+            println!("usage: break <address|symbol[+off]|file:line>");
             return;
         }
         
-        if let Ok(addr) = parse_addr(args[0]) {
+        if let Some((file, line)) = args[0].rsplit_once(':') {
+            match line.parse::<u32>().ok().and_then(|l| self.lines.resolve(file, l)) {
+                Some((addr, actual)) => {
+                    self.breakpoints.insert(addr);
+                    println!("breakpoint set at 0x{:08x}{}: {}:{}", addr, self.describe(addr), file, actual);
+                }
+                None => println!("no code for {}", args[0]),
+            }
+        } else if let Some(addr) = self.parse_location(args[0]) {
             self.breakpoints.insert(addr);
-            println!("breakpoint set at 0x{:08x}", addr);
+            println!("breakpoint set at 0x{:08x}{}", addr, self.describe(addr));
         } else {
-            println!("invalid address: {}", args[0]);
+            println!("invalid address or unknown symbol: {}", args[0]);
         }
     }
     
+    fn hart(&mut self, cpu: &mut Cpu, args: &[&str]) {
+        // taken out for the duration so describe() can borrow self
+        let Some(mut smp) = self.smp.take() else {
+            println!("only one hart");
+            return;
+        };
+        match args.first() {
+            None => {
+                for h in 0..smp.len() {
+                    let (pc, halted) = smp.status(cpu, &self.executor, h);
+                    let marker = if h == smp.current() { "*" } else { " " };
+                    let state = if halted { " (halted)" } else { "" };
+                    println!("{} hart {}  pc = 0x{:08x}{}{}", marker, h, pc, self.describe(pc), state);
+                }
+            }
+            Some(arg) => match arg.parse::<usize>().map_err(|_| format!("invalid hart: {}", arg))
+                .and_then(|h| smp.select(cpu, &mut self.executor, h))
+            {
+                Ok(()) => {
+                    println!("hart {}  pc = 0x{:08x}{}", smp.current(), cpu.pc, self.describe(cpu.pc));
+                    self.smp = Some(smp);
+                    self.restart_history(cpu);
+                    return;
+                }
+                Err(e) => println!("{}", e),
+            },
+        }
+        self.smp = Some(smp);
+    }
+
+    // the machine got here in a way a rerun from an earlier checkpoint
+    // wouldn't repeat, so history starts over
+    fn restart_history(&mut self, cpu: &Cpu) {
+        if let Some(history) = self.history.as_mut() {
+            if let Err(e) = history.restart(cpu, &self.executor, self.smp.as_ref(), self.steps) {
+                println!("no reverse execution from here: {}", e);
+                self.history = None;
+            }
+        }
+    }
+
+    fn save(&self, cpu: &Cpu, args: &[&str]) {
+        let [file] = args else {
+            println!("usage: save <file>");
+            return;
+        };
+        match snapshot::save_file(Path::new(file), cpu, &self.executor, self.smp.as_ref(), self.steps) {
+            Ok(()) => println!("saved snapshot after {} instructions to {}", self.steps, file),
+            Err(e) => println!("{}", e),
+        }
+    }
+
+    fn load(&mut self, cpu: &mut Cpu, args: &[&str]) {
+        let [file] = args else {
+            println!("usage: load <file>");
+            return;
+        };
+        match snapshot::restore_file(Path::new(file), cpu, &mut self.executor, self.smp.as_mut()) {
+            Ok(steps) => {
+                self.steps = steps;
+                self.restart_history(cpu);
+                println!("restored snapshot taken after {} instructions", steps);
+                println!("{}pc = 0x{:08x}{}", self.hart_prefix(), cpu.pc, self.describe(cpu.pc));
+            }
+            // a failed load can leave the machine half restored
+            Err(e) => println!("{} (machine state may be inconsistent)", e),
+        }
+    }
+
     fn dump_regs(&self, cpu: &Cpu) {
-        println!("registers:");
+        match &self.smp {
+            Some(smp) => println!("registers (hart {}):", smp.current()),
+            None => println!("registers:"),
+        }
         for i in 0..32 {
             if i % 4 == 0 && i > 0 {
                 println!();
@@ -132,10 +575,10 @@ impl Debugger {
             return;
         }
         
-        let addr = match parse_addr(args[0]) {
-            Ok(a) => a,
-            Err(_) => {
-                println!("invalid address: {}", args[0]);
+        let addr = match self.parse_location(args[0]) {
+            Some(a) => a,
+            None => {
+                println!("invalid address or unknown symbol: {}", args[0]);
                 return;
             }
         };
@@ -148,9 +591,8 @@ impl Debugger {
         
         println!("memory at 0x{:08x}:", addr);
         for i in 0..count {
-            let a = addr + (i * 4) as u32;
-            if a as usize + 4 <= cpu.mem.len() {
-                let val = cpu.read_word(a);
+            let a = addr.wrapping_add((i * 4) as u32);
+            if let Some(val) = cpu.peek_word(a) {
                 println!("  0x{:08x}: 0x{:08x}", a, val);
             }
         }
@@ -160,24 +602,49 @@ impl Debugger {
         let addr = if args.is_empty() {
             cpu.pc
         } else {
-            match parse_addr(args[0]) {
-                Ok(a) => a,
-                Err(_) => {
-                    println!("invalid address: {}", args[0]);
+            match self.parse_location(args[0]) {
+                Some(a) => a,
+                None => {
+                    println!("invalid address or unknown symbol: {}", args[0]);
                     return;
                 }
             }
         };
         
+        // addresses are virtual, as the hart fetches them
         println!("disassembly at 0x{:08x}:", addr);
         for i in 0..10 {
-            let a = addr + (i * 4);
-            if a as usize + 4 <= cpu.mem.len() {
-                let raw = cpu.read_word(a);
+            let a = addr.wrapping_add(i * 4);
+            if let Some(raw) = mmu::peek(cpu, a, Access::Fetch).and_then(|pa| cpu.peek_word(pa)) {
                 let inst = Instruction::decode(raw);
                 let marker = if a == cpu.pc { "=>" } else { "  " };
-                println!("  {} 0x{:08x}: {}", marker, a, inst.disassemble());
+                println!("  {} 0x{:08x}{}: {}", marker, a, self.describe(a), self.format_inst(&inst, a));
+            }
+        }
+    }
+    
+    // numeric address, or a symbol with an optional +offset
+    fn parse_location(&self, s: &str) -> Option<u32> {
+        parse_addr(s).ok().or_else(|| self.symbols.resolve(s))
+    }
+    
+    // " <main+0x1c>", or nothing if no symbol covers the address
+    fn describe(&self, addr: u32) -> String {
+        match self.symbols.lookup(addr) {
+            Some(loc) => format!(" {}", loc),
+            None => String::new(),
+        }
+    }
+    
+    // disassembly plus the resolved target for pc-relative control flow
+    fn format_inst(&self, inst: &Instruction, pc: u32) -> String {
+        let text = inst.disassemble();
+        match inst.opcode {
+            op if op == Opcode::Jal || op.is_branch() => {
+                let target = pc.wrapping_add(inst.imm as u32);
+                format!("{:<24} # 0x{:x}{}", text, target, self.describe(target))
             }
+            _ => text,
         }
     }
 }
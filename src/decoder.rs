@@ -1,24 +1,144 @@
 // instruction decode logic
 
+// standard extensions the decoder understands on top of rv32i, in canonical
+// order. the device tree isa string is built from this, so keep it honest
+pub const EXTENSIONS: &[char] = &['m', 'a'];
+
+// same for multi-letter extensions (zicsr, zifencei, ...)
+pub const MULTI_LETTER_EXTENSIONS: &[&str] = &["zicsr", "zifencei"];
+
+pub fn isa_string() -> String {
+    let mut isa = String::from("rv32i");
+    isa.extend(EXTENSIONS.iter());
+    for ext in MULTI_LETTER_EXTENSIONS {
+        isa.push('_');
+        isa.push_str(ext);
+    }
+    isa
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Opcode {
     // r-type
-    Add, Sub, And, Or, Xor, Sll, Srl, Sra,
+    Add, Sub, And, Or, Xor, Sll, Srl, Sra, Slt, Sltu,
+    // multiply and divide (the m extension), also r-type
+    Mul, Mulh, Mulhsu, Mulhu, Div, Divu, Rem, Remu,
     // i-type
-    Addi, Andi, Ori, Xori, Slli, Srli, Srai,
-    Lw, Jalr,
-    // s-type  
-    Sw,
+    Addi, Andi, Ori, Xori, Slli, Srli, Srai, Slti, Sltiu,
+    Lb, Lh, Lw, Lbu, Lhu, Jalr,
+    // s-type
+    Sb, Sh, Sw,
     // b-type
-    Beq, Bne, Blt, Bge,
+    Beq, Bne, Blt, Bge, Bltu, Bgeu,
     // u-type
     Lui, Auipc,
     // j-type
     Jal,
+    // memory ordering
+    Fence, FenceI,
+    // atomics (the a extension)
+    LrW, ScW,
+    AmoswapW, AmoaddW, AmoxorW, AmoandW, AmoorW,
+    AmominW, AmomaxW, AmominuW, AmomaxuW,
+    // system
+    Ecall, Ebreak,
+    // csr access (zicsr), with imm holding the csr number and, for the
+    // immediate forms, rs1 the 5-bit immediate
+    Csrrw, Csrrs, Csrrc, Csrrwi, Csrrsi, Csrrci,
+    // privileged: trap return, wait for interrupt, address-translation fence
+    Mret, Sret, Wfi, SfenceVma,
     // unknown
     Unknown,
 }
 
+impl Opcode {
+    // every opcode, in declaration order, so `opcode as usize` indexes it
+    pub const ALL: [Opcode; 71] = [
+        Opcode::Add, Opcode::Sub, Opcode::And, Opcode::Or, Opcode::Xor,
+        Opcode::Sll, Opcode::Srl, Opcode::Sra, Opcode::Slt, Opcode::Sltu,
+        Opcode::Mul, Opcode::Mulh, Opcode::Mulhsu, Opcode::Mulhu,
+        Opcode::Div, Opcode::Divu, Opcode::Rem, Opcode::Remu,
+        Opcode::Addi, Opcode::Andi, Opcode::Ori, Opcode::Xori,
+        Opcode::Slli, Opcode::Srli, Opcode::Srai, Opcode::Slti, Opcode::Sltiu,
+        Opcode::Lb, Opcode::Lh, Opcode::Lw, Opcode::Lbu, Opcode::Lhu, Opcode::Jalr,
+        Opcode::Sb, Opcode::Sh, Opcode::Sw,
+        Opcode::Beq, Opcode::Bne, Opcode::Blt, Opcode::Bge, Opcode::Bltu, Opcode::Bgeu,
+        Opcode::Lui, Opcode::Auipc, Opcode::Jal,
+        Opcode::Fence, Opcode::FenceI, Opcode::LrW, Opcode::ScW,
+        Opcode::AmoswapW, Opcode::AmoaddW, Opcode::AmoxorW, Opcode::AmoandW, Opcode::AmoorW,
+        Opcode::AmominW, Opcode::AmomaxW, Opcode::AmominuW, Opcode::AmomaxuW,
+        Opcode::Ecall, Opcode::Ebreak,
+        Opcode::Csrrw, Opcode::Csrrs, Opcode::Csrrc, Opcode::Csrrwi, Opcode::Csrrsi, Opcode::Csrrci,
+        Opcode::Mret, Opcode::Sret, Opcode::Wfi, Opcode::SfenceVma,
+        Opcode::Unknown,
+    ];
+    pub const COUNT: usize = Self::ALL.len();
+
+    // whether the instruction puts a result in rd. ecall doesn't, though
+    // the environment behind it may change registers
+    pub fn writes_rd(self) -> bool {
+        !matches!(self,
+            Opcode::Sb | Opcode::Sh | Opcode::Sw
+            | Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge | Opcode::Bltu | Opcode::Bgeu
+            | Opcode::Fence | Opcode::FenceI | Opcode::Ecall | Opcode::Ebreak | Opcode::Mret
+            | Opcode::Sret | Opcode::Wfi | Opcode::SfenceVma | Opcode::Unknown)
+    }
+
+    pub fn is_load(self) -> bool {
+        matches!(self, Opcode::Lb | Opcode::Lh | Opcode::Lw | Opcode::Lbu | Opcode::Lhu)
+    }
+
+    pub fn is_store(self) -> bool {
+        matches!(self, Opcode::Sb | Opcode::Sh | Opcode::Sw)
+    }
+
+    pub fn is_branch(self) -> bool {
+        matches!(self, Opcode::Beq | Opcode::Bne | Opcode::Blt | Opcode::Bge | Opcode::Bltu | Opcode::Bgeu)
+    }
+
+    // bytes a load or store moves
+    pub fn access_size(self) -> u32 {
+        match self {
+            Opcode::Lb | Opcode::Lbu | Opcode::Sb => 1,
+            Opcode::Lh | Opcode::Lhu | Opcode::Sh => 2,
+            _ => 4,
+        }
+    }
+
+    pub fn is_muldiv(self) -> bool {
+        matches!(self,
+            Opcode::Mul | Opcode::Mulh | Opcode::Mulhsu | Opcode::Mulhu
+            | Opcode::Div | Opcode::Divu | Opcode::Rem | Opcode::Remu)
+    }
+
+    pub fn is_csr(self) -> bool {
+        matches!(self,
+            Opcode::Csrrw | Opcode::Csrrs | Opcode::Csrrc | Opcode::Csrrwi | Opcode::Csrrsi | Opcode::Csrrci)
+    }
+
+    pub fn is_amo(self) -> bool {
+        matches!(self,
+            Opcode::AmoswapW | Opcode::AmoaddW | Opcode::AmoxorW | Opcode::AmoandW | Opcode::AmoorW
+            | Opcode::AmominW | Opcode::AmomaxW | Opcode::AmominuW | Opcode::AmomaxuW)
+    }
+}
+
+// what an amo stores, given the word in memory and rs2
+pub fn amo_value(opcode: Opcode, old: u32, src: u32) -> u32 {
+    match opcode {
+        Opcode::AmoswapW => src,
+        Opcode::AmoaddW => old.wrapping_add(src),
+        Opcode::AmoxorW => old ^ src,
+        Opcode::AmoandW => old & src,
+        Opcode::AmoorW => old | src,
+        Opcode::AmominW => (old as i32).min(src as i32) as u32,
+        Opcode::AmomaxW => (old as i32).max(src as i32) as u32,
+        Opcode::AmominuW => old.min(src),
+        Opcode::AmomaxuW => old.max(src),
+        _ => unreachable!("{:?} isn't an amo", opcode),
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Instruction {
     pub opcode: Opcode,
@@ -49,6 +169,16 @@ impl Instruction {
                     (0x1, 0x00) => Opcode::Sll,
                     (0x5, 0x00) => Opcode::Srl,
                     (0x5, 0x20) => Opcode::Sra,
+                    (0x2, 0x00) => Opcode::Slt,
+                    (0x3, 0x00) => Opcode::Sltu,
+                    (0x0, 0x01) => Opcode::Mul,
+                    (0x1, 0x01) => Opcode::Mulh,
+                    (0x2, 0x01) => Opcode::Mulhsu,
+                    (0x3, 0x01) => Opcode::Mulhu,
+                    (0x4, 0x01) => Opcode::Div,
+                    (0x5, 0x01) => Opcode::Divu,
+                    (0x6, 0x01) => Opcode::Rem,
+                    (0x7, 0x01) => Opcode::Remu,
                     _ => Opcode::Unknown,
                 };
                 Instruction { opcode, rd, rs1, rs2, imm: 0 }
@@ -61,7 +191,9 @@ impl Instruction {
                     0x7 => Opcode::Andi,
                     0x6 => Opcode::Ori,
                     0x4 => Opcode::Xori,
-                    0x1 => Opcode::Slli,
+                    0x2 => Opcode::Slti,
+                    0x3 => Opcode::Sltiu,
+                    0x1 if funct7 == 0x00 => Opcode::Slli,
                     0x5 => {
                         if funct7 == 0x00 {
                             Opcode::Srli
@@ -78,14 +210,28 @@ impl Instruction {
             0x03 => {
                 // load
                 let imm = sign_extend(raw >> 20, 12);
-                Instruction { opcode: Opcode::Lw, rd, rs1, rs2: 0, imm }
+                let opcode = match funct3 {
+                    0x0 => Opcode::Lb,
+                    0x1 => Opcode::Lh,
+                    0x2 => Opcode::Lw,
+                    0x4 => Opcode::Lbu,
+                    0x5 => Opcode::Lhu,
+                    _ => Opcode::Unknown,
+                };
+                Instruction { opcode, rd, rs1, rs2: 0, imm }
             }
             0x23 => {
                 // store
                 let imm_low = (raw >> 7) & 0x1f;
                 let imm_high = (raw >> 25) & 0x7f;
                 let imm = sign_extend((imm_high << 5) | imm_low, 12);
-                Instruction { opcode: Opcode::Sw, rd: 0, rs1, rs2, imm }
+                let opcode = match funct3 {
+                    0x0 => Opcode::Sb,
+                    0x1 => Opcode::Sh,
+                    0x2 => Opcode::Sw,
+                    _ => Opcode::Unknown,
+                };
+                Instruction { opcode, rd: 0, rs1, rs2, imm }
             }
             0x63 => {
                 // branch
@@ -100,6 +246,8 @@ impl Instruction {
                     0x1 => Opcode::Bne,
                     0x4 => Opcode::Blt,
                     0x5 => Opcode::Bge,
+                    0x6 => Opcode::Bltu,
+                    0x7 => Opcode::Bgeu,
                     _ => Opcode::Unknown,
                 };
                 Instruction { opcode, rd: 0, rs1, rs2, imm }
@@ -129,6 +277,65 @@ impl Instruction {
                 let imm = sign_extend(raw >> 20, 12);
                 Instruction { opcode: Opcode::Jalr, rd, rs1, rs2: 0, imm }
             }
+            0x0f => {
+                // misc-mem. a single hart sees its own memory in order, so
+                // fence only matters for the predecode cache (fence.i)
+                let opcode = match funct3 {
+                    0x0 => Opcode::Fence,
+                    0x1 => Opcode::FenceI,
+                    _ => Opcode::Unknown,
+                };
+                Instruction { opcode, rd: 0, rs1: 0, rs2: 0, imm: 0 }
+            }
+            0x2f => {
+                // amo. aq/rl are ignored: one hart at a time is sequentially
+                // consistent anyway, and threaded harts make every atomic seqcst
+                let opcode = match (funct3, raw >> 27) {
+                    (0x2, 0x02) if rs2 == 0 => Opcode::LrW,
+                    (0x2, 0x03) => Opcode::ScW,
+                    (0x2, 0x01) => Opcode::AmoswapW,
+                    (0x2, 0x00) => Opcode::AmoaddW,
+                    (0x2, 0x04) => Opcode::AmoxorW,
+                    (0x2, 0x0c) => Opcode::AmoandW,
+                    (0x2, 0x08) => Opcode::AmoorW,
+                    (0x2, 0x10) => Opcode::AmominW,
+                    (0x2, 0x14) => Opcode::AmomaxW,
+                    (0x2, 0x18) => Opcode::AmominuW,
+                    (0x2, 0x1c) => Opcode::AmomaxuW,
+                    _ => Opcode::Unknown,
+                };
+                Instruction { opcode, rd, rs1, rs2, imm: 0 }
+            }
+            0x73 if funct3 != 0 => {
+                // csr access
+                let opcode = match funct3 {
+                    0x1 => Opcode::Csrrw,
+                    0x2 => Opcode::Csrrs,
+                    0x3 => Opcode::Csrrc,
+                    0x5 => Opcode::Csrrwi,
+                    0x6 => Opcode::Csrrsi,
+                    0x7 => Opcode::Csrrci,
+                    _ => Opcode::Unknown,
+                };
+                Instruction { opcode, rd, rs1, rs2: 0, imm: (raw >> 20) as i32 }
+            }
+            0x73 => {
+                // system
+                let opcode = match raw {
+                    0x0000_0073 => Opcode::Ecall,
+                    0x0010_0073 => Opcode::Ebreak,
+                    0x3020_0073 => Opcode::Mret,
+                    0x1020_0073 => Opcode::Sret,
+                    0x1050_0073 => Opcode::Wfi,
+                    // sfence.vma names an address and an asid in rs1 and rs2
+                    _ if raw & 0xfe00_7fff == 0x1200_0073 => Opcode::SfenceVma,
+                    _ => Opcode::Unknown,
+                };
+                if opcode == Opcode::SfenceVma {
+                    return Instruction { opcode, rd: 0, rs1, rs2, imm: 0 };
+                }
+                Instruction { opcode, rd: 0, rs1: 0, rs2: 0, imm: 0 }
+            }
             _ => Instruction {
                 opcode: Opcode::Unknown,
                 rd: 0,
@@ -149,6 +356,16 @@ impl Instruction {
             Opcode::Sll => format!("sll x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
             Opcode::Srl => format!("srl x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
             Opcode::Sra => format!("sra x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Slt => format!("slt x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Sltu => format!("sltu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Mul => format!("mul x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Mulh => format!("mulh x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Mulhsu => format!("mulhsu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Mulhu => format!("mulhu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Div => format!("div x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Divu => format!("divu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Rem => format!("rem x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
+            Opcode::Remu => format!("remu x{}, x{}, x{}", self.rd, self.rs1, self.rs2),
             Opcode::Addi => format!("addi x{}, x{}, {}", self.rd, self.rs1, self.imm),
             Opcode::Andi => format!("andi x{}, x{}, {}", self.rd, self.rs1, self.imm),
             Opcode::Ori => format!("ori x{}, x{}, {}", self.rd, self.rs1, self.imm),
@@ -156,21 +373,167 @@ impl Instruction {
             Opcode::Slli => format!("slli x{}, x{}, {}", self.rd, self.rs1, self.imm & 0x1f),
             Opcode::Srli => format!("srli x{}, x{}, {}", self.rd, self.rs1, self.imm & 0x1f),
             Opcode::Srai => format!("srai x{}, x{}, {}", self.rd, self.rs1, self.imm & 0x1f),
+            Opcode::Slti => format!("slti x{}, x{}, {}", self.rd, self.rs1, self.imm),
+            Opcode::Sltiu => format!("sltiu x{}, x{}, {}", self.rd, self.rs1, self.imm),
+            Opcode::Lb => format!("lb x{}, {}(x{})", self.rd, self.imm, self.rs1),
+            Opcode::Lh => format!("lh x{}, {}(x{})", self.rd, self.imm, self.rs1),
             Opcode::Lw => format!("lw x{}, {}(x{})", self.rd, self.imm, self.rs1),
+            Opcode::Lbu => format!("lbu x{}, {}(x{})", self.rd, self.imm, self.rs1),
+            Opcode::Lhu => format!("lhu x{}, {}(x{})", self.rd, self.imm, self.rs1),
+            Opcode::Sb => format!("sb x{}, {}(x{})", self.rs2, self.imm, self.rs1),
+            Opcode::Sh => format!("sh x{}, {}(x{})", self.rs2, self.imm, self.rs1),
             Opcode::Sw => format!("sw x{}, {}(x{})", self.rs2, self.imm, self.rs1),
             Opcode::Beq => format!("beq x{}, x{}, {}", self.rs1, self.rs2, self.imm),
             Opcode::Bne => format!("bne x{}, x{}, {}", self.rs1, self.rs2, self.imm),
             Opcode::Blt => format!("blt x{}, x{}, {}", self.rs1, self.rs2, self.imm),
             Opcode::Bge => format!("bge x{}, x{}, {}", self.rs1, self.rs2, self.imm),
+            Opcode::Bltu => format!("bltu x{}, x{}, {}", self.rs1, self.rs2, self.imm),
+            Opcode::Bgeu => format!("bgeu x{}, x{}, {}", self.rs1, self.rs2, self.imm),
             Opcode::Lui => format!("lui x{}, 0x{:x}", self.rd, (self.imm as u32) >> 12),
             Opcode::Auipc => format!("auipc x{}, 0x{:x}", self.rd, (self.imm as u32) >> 12),
             Opcode::Jal => format!("jal x{}, {}", self.rd, self.imm),
             Opcode::Jalr => format!("jalr x{}, {}(x{})", self.rd, self.imm, self.rs1),
-            Opcode::Unknown => format!("unknown"),
+            Opcode::Fence => "fence".to_string(),
+            Opcode::FenceI => "fence.i".to_string(),
+            Opcode::LrW => format!("lr.w x{}, (x{})", self.rd, self.rs1),
+            Opcode::ScW => format!("sc.w x{}, x{}, (x{})", self.rd, self.rs2, self.rs1),
+            Opcode::AmoswapW | Opcode::AmoaddW | Opcode::AmoxorW | Opcode::AmoandW | Opcode::AmoorW
+            | Opcode::AmominW | Opcode::AmomaxW | Opcode::AmominuW | Opcode::AmomaxuW => {
+                format!("{} x{}, x{}, (x{})", amo_mnemonic(self.opcode), self.rd, self.rs2, self.rs1)
+            }
+            Opcode::Csrrs if self.rs1 == 0 => format!("csrr x{}, {}", self.rd, csr_name(self.imm as u32)),
+            Opcode::Csrrw if self.rd == 0 => format!("csrw {}, x{}", csr_name(self.imm as u32), self.rs1),
+            Opcode::Csrrw | Opcode::Csrrs | Opcode::Csrrc => {
+                format!("{} x{}, {}, x{}", csr_mnemonic(self.opcode), self.rd, csr_name(self.imm as u32), self.rs1)
+            }
+            Opcode::Csrrwi | Opcode::Csrrsi | Opcode::Csrrci => {
+                format!("{} x{}, {}, {}", csr_mnemonic(self.opcode), self.rd, csr_name(self.imm as u32), self.rs1)
+            }
+            Opcode::Ecall => "ecall".to_string(),
+            Opcode::Ebreak => "ebreak".to_string(),
+            Opcode::Mret => "mret".to_string(),
+            Opcode::Sret => "sret".to_string(),
+            Opcode::Wfi => "wfi".to_string(),
+            Opcode::SfenceVma => format!("sfence.vma x{}, x{}", self.rs1, self.rs2),
+            Opcode::Unknown => "unknown".to_string(),
         }
     }
 }
 
+pub const CSR_SSTATUS: u32 = 0x100;
+pub const CSR_SIE: u32 = 0x104;
+pub const CSR_STVEC: u32 = 0x105;
+pub const CSR_SCOUNTEREN: u32 = 0x106;
+pub const CSR_SSCRATCH: u32 = 0x140;
+pub const CSR_SEPC: u32 = 0x141;
+pub const CSR_SCAUSE: u32 = 0x142;
+pub const CSR_STVAL: u32 = 0x143;
+pub const CSR_SIP: u32 = 0x144;
+pub const CSR_SATP: u32 = 0x180;
+pub const CSR_MSTATUS: u32 = 0x300;
+pub const CSR_MISA: u32 = 0x301;
+pub const CSR_MEDELEG: u32 = 0x302;
+pub const CSR_MIDELEG: u32 = 0x303;
+pub const CSR_MIE: u32 = 0x304;
+pub const CSR_MTVEC: u32 = 0x305;
+pub const CSR_MCOUNTEREN: u32 = 0x306;
+pub const CSR_MSTATUSH: u32 = 0x310;
+pub const CSR_MSCRATCH: u32 = 0x340;
+pub const CSR_MEPC: u32 = 0x341;
+pub const CSR_MCAUSE: u32 = 0x342;
+pub const CSR_MTVAL: u32 = 0x343;
+pub const CSR_MIP: u32 = 0x344;
+pub const CSR_MCYCLE: u32 = 0xb00;
+pub const CSR_MINSTRET: u32 = 0xb02;
+pub const CSR_MCYCLEH: u32 = 0xb80;
+pub const CSR_MINSTRETH: u32 = 0xb82;
+pub const CSR_CYCLE: u32 = 0xc00;
+pub const CSR_TIME: u32 = 0xc01;
+pub const CSR_INSTRET: u32 = 0xc02;
+pub const CSR_CYCLEH: u32 = 0xc80;
+pub const CSR_TIMEH: u32 = 0xc81;
+pub const CSR_INSTRETH: u32 = 0xc82;
+pub const CSR_MVENDORID: u32 = 0xf11;
+pub const CSR_MARCHID: u32 = 0xf12;
+pub const CSR_MIMPID: u32 = 0xf13;
+pub const CSR_MHARTID: u32 = 0xf14;
+
+// assembler names of the csrs the executor implements
+pub const CSRS: [(&str, u32); 37] = [
+    ("sstatus", CSR_SSTATUS),
+    ("sie", CSR_SIE),
+    ("stvec", CSR_STVEC),
+    ("scounteren", CSR_SCOUNTEREN),
+    ("sscratch", CSR_SSCRATCH),
+    ("sepc", CSR_SEPC),
+    ("scause", CSR_SCAUSE),
+    ("stval", CSR_STVAL),
+    ("sip", CSR_SIP),
+    ("satp", CSR_SATP),
+    ("mstatus", CSR_MSTATUS),
+    ("misa", CSR_MISA),
+    ("medeleg", CSR_MEDELEG),
+    ("mideleg", CSR_MIDELEG),
+    ("mie", CSR_MIE),
+    ("mtvec", CSR_MTVEC),
+    ("mcounteren", CSR_MCOUNTEREN),
+    ("mstatush", CSR_MSTATUSH),
+    ("mscratch", CSR_MSCRATCH),
+    ("mepc", CSR_MEPC),
+    ("mcause", CSR_MCAUSE),
+    ("mtval", CSR_MTVAL),
+    ("mip", CSR_MIP),
+    ("mcycle", CSR_MCYCLE),
+    ("minstret", CSR_MINSTRET),
+    ("mcycleh", CSR_MCYCLEH),
+    ("minstreth", CSR_MINSTRETH),
+    ("cycle", CSR_CYCLE),
+    ("time", CSR_TIME),
+    ("instret", CSR_INSTRET),
+    ("cycleh", CSR_CYCLEH),
+    ("timeh", CSR_TIMEH),
+    ("instreth", CSR_INSTRETH),
+    ("mvendorid", CSR_MVENDORID),
+    ("marchid", CSR_MARCHID),
+    ("mimpid", CSR_MIMPID),
+    ("mhartid", CSR_MHARTID),
+];
+
+fn csr_mnemonic(opcode: Opcode) -> &'static str {
+    match opcode {
+        Opcode::Csrrw => "csrrw",
+        Opcode::Csrrs => "csrrs",
+        Opcode::Csrrc => "csrrc",
+        Opcode::Csrrwi => "csrrwi",
+        Opcode::Csrrsi => "csrrsi",
+        _ => "csrrci",
+    }
+}
+
+// assembler name and funct5 of each amo
+pub const AMOS: [(&str, Opcode, u32); 9] = [
+    ("amoswap.w", Opcode::AmoswapW, 0x01),
+    ("amoadd.w", Opcode::AmoaddW, 0x00),
+    ("amoxor.w", Opcode::AmoxorW, 0x04),
+    ("amoand.w", Opcode::AmoandW, 0x0c),
+    ("amoor.w", Opcode::AmoorW, 0x08),
+    ("amomin.w", Opcode::AmominW, 0x10),
+    ("amomax.w", Opcode::AmomaxW, 0x14),
+    ("amominu.w", Opcode::AmominuW, 0x18),
+    ("amomaxu.w", Opcode::AmomaxuW, 0x1c),
+];
+
+fn amo_mnemonic(opcode: Opcode) -> &'static str {
+    AMOS.iter().find(|a| a.1 == opcode).map_or("amo?", |a| a.0)
+}
+
+fn csr_name(csr: u32) -> String {
+    match CSRS.iter().find(|c| c.1 == csr) {
+        Some(c) => c.0.to_string(),
+        None => format!("0x{:x}", csr),
+    }
+}
+
 fn sign_extend(val: u32, bits: u32) -> i32 {
     let shift = 32 - bits;
     ((val << shift) as i32) >> shift
@@ -202,6 +565,77 @@ mod tests {
         assert_eq!(inst.imm, 42);
     }
 
+    #[test]
+    fn test_decode_sized_and_unsigned() {
+        // lb x1, 4(x2); lhu x1, -2(x2); sb x3, 1(x2); sltu x1, x2, x3; bgeu x1, x2, 8
+        let lb = Instruction::decode(0x00410083);
+        assert_eq!((lb.opcode, lb.rd, lb.rs1, lb.imm), (Opcode::Lb, 1, 2, 4));
+        assert_eq!(Instruction::decode(0xffe15083).disassemble(), "lhu x1, -2(x2)");
+        assert_eq!(Instruction::decode(0x003100a3).disassemble(), "sb x3, 1(x2)");
+        assert_eq!(Instruction::decode(0x003130b3).opcode, Opcode::Sltu);
+        assert_eq!(Instruction::decode(0x0020f463).disassemble(), "bgeu x1, x2, 8");
+        // funct3 3 isn't a load on rv32
+        assert_eq!(Instruction::decode(0x00413083).opcode, Opcode::Unknown);
+    }
+
+    #[test]
+    fn test_decode_muldiv() {
+        // mul x1, x2, x3; mulhsu x5, x6, x7; remu x10, x11, x12
+        let mul = Instruction::decode(0x023100b3);
+        assert_eq!((mul.opcode, mul.rd, mul.rs1, mul.rs2), (Opcode::Mul, 1, 2, 3));
+        assert_eq!(Instruction::decode(0x027322b3).disassemble(), "mulhsu x5, x6, x7");
+        assert_eq!(Instruction::decode(0x02c5f533).opcode, Opcode::Remu);
+        assert_eq!(isa_string(), "rv32ima_zicsr_zifencei");
+    }
+
+    #[test]
+    fn test_decode_lr_sc_csrr() {
+        // lr.w x5, (x10); sc.w x6, x7, (x10); csrr x1, mhartid
+        assert_eq!(Instruction::decode(0x100522af).opcode, Opcode::LrW);
+        let sc = Instruction::decode(0x1875232f);
+        assert_eq!((sc.opcode, sc.rd, sc.rs1, sc.rs2), (Opcode::ScW, 6, 10, 7));
+        let csrr = Instruction::decode(0xf14020f3);
+        assert_eq!((csrr.opcode, csrr.rd, csrr.imm), (Opcode::Csrrs, 1, 0xf14));
+        assert_eq!(csrr.disassemble(), "csrr x1, mhartid");
+    }
+
+    #[test]
+    fn test_decode_csr_and_mret() {
+        // csrw mtvec, x5; csrrci x0, mstatus, 8; csrrc x1, 0x7c0, x2; mret
+        let csrw = Instruction::decode(0x30529073);
+        assert_eq!((csrw.opcode, csrw.rs1, csrw.imm), (Opcode::Csrrw, 5, 0x305));
+        assert_eq!(csrw.disassemble(), "csrw mtvec, x5");
+        let csrci = Instruction::decode(0x30047073);
+        assert_eq!((csrci.opcode, csrci.rs1), (Opcode::Csrrci, 8));
+        assert_eq!(csrci.disassemble(), "csrrci x0, mstatus, 8");
+        assert_eq!(Instruction::decode(0x7c0130f3).disassemble(), "csrrc x1, 0x7c0, x2");
+        assert_eq!(Instruction::decode(0xf14020f3).disassemble(), "csrr x1, mhartid");
+        assert_eq!(Instruction::decode(0x30200073).opcode, Opcode::Mret);
+        assert_eq!(Instruction::decode(0x10200073).opcode, Opcode::Sret);
+        assert_eq!(Instruction::decode(0x10500073).opcode, Opcode::Wfi);
+        assert_eq!(Instruction::decode(0x12628073).disassemble(), "sfence.vma x5, x6");
+        assert_eq!(Instruction::decode(0x180020f3).disassemble(), "csrr x1, satp");
+        // funct3 4 isn't a csr instruction
+        assert_eq!(Instruction::decode(0x30524073).opcode, Opcode::Unknown);
+    }
+
+    #[test]
+    fn test_decode_amo() {
+        // amoadd.w x5, x6, (x10); amomaxu.w x1, x2, (x3)
+        let add = Instruction::decode(0x006522af);
+        assert_eq!((add.opcode, add.rd, add.rs1, add.rs2), (Opcode::AmoaddW, 5, 10, 6));
+        assert_eq!(Instruction::decode(0xe021a0af).disassemble(), "amomaxu.w x1, x2, (x3)");
+        assert_eq!(amo_value(Opcode::AmominW, 5, (-3i32) as u32), (-3i32) as u32);
+        assert_eq!(amo_value(Opcode::AmominuW, 5, (-3i32) as u32), 5);
+    }
+
+    #[test]
+    fn test_opcode_table_order() {
+        for (i, op) in Opcode::ALL.iter().enumerate() {
+            assert_eq!(*op as usize, i);
+        }
+    }
+
     #[test]
     fn test_sign_extend_negative() {
         let val = 0xfff; // -1 in 12-bit
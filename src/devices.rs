@@ -0,0 +1,204 @@
+// memory-mapped device plumbing
+//
+// ram lives in Cpu::mem and is accessed directly. anything outside ram is
+// routed to whichever device region claims the address. registers are
+// word-sized: byte and halfword loads read the word around them, and byte
+// and halfword stores are access faults unless the device takes them
+// through write_narrow, the way memory-like regions such as framebuffer
+// pixels do.
+
+use crate::fdt::FdtBuilder;
+use crate::replay::Journal;
+use crate::snapshot::{Reader, Writer};
+
+// what the device tree generator needs to describe a device
+pub struct DeviceInfo {
+    pub name: &'static str,
+    pub compatible: &'static str,
+}
+
+pub trait Device {
+    // read a register at `offset` bytes from the device base
+    fn read(&mut self, offset: u32) -> u32;
+
+    // write a register. `ram` is guest ram, for devices that dma
+    fn write(&mut self, offset: u32, val: u32, ram: &mut GuestRam);
+
+    // a byte or halfword store (`size` 1 or 2) at `offset`. false leaves it
+    // an access fault, which is right for registers
+    fn write_narrow(&mut self, _offset: u32, _size: u32, _val: u32) -> bool {
+        false
+    }
+
+    // side-effect free read for the debugger and instruction fetch. only
+    // memory-like devices need this; registers with read side effects don't
+    fn peek(&self, _offset: u32) -> Option<u32> {
+        None
+    }
+
+    // instruction fetch is allowed from this region (goes through peek)
+    fn executable(&self) -> bool {
+        false
+    }
+
+    // guest stores raise a store access fault instead of reaching write()
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    // called once per executed instruction so devices can poll host backends
+    fn tick(&mut self, _ram: &mut GuestRam) {}
+
+    // level of the device's interrupt line. it reaches an interrupt
+    // controller if irq() names a source for it; otherwise guests have to poll
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // the interrupt controller source the line is wired to
+    fn irq(&self) -> Option<u32> {
+        None
+    }
+
+    // for the interrupt controller: the level of every wired line after a
+    // tick, bit n for source n
+    fn set_irq_lines(&mut self, _lines: u32) {}
+
+    // the mip bits this device drives on `hart`: a timer's or an interrupt
+    // controller's
+    fn hart_irqs(&self, _hart: u32) -> u32 {
+        0
+    }
+
+    // for the machine timer: instructions so far and mtime, which the
+    // cycle, instret and time csrs read
+    fn counters(&self) -> Option<(u64, u64)> {
+        None
+    }
+
+    // devices that return None are left out of the generated device tree
+    fn info(&self) -> Option<DeviceInfo> {
+        None
+    }
+
+    // extra properties for the device's node, after compatible and reg.
+    // interrupt wiring goes here, using the phandles in fdt
+    fn fdt_props(&self, _fdt: &mut FdtBuilder) {}
+
+    // guest-visible state for a snapshot. host backends (sockets, the
+    // console, output paths) aren't part of it; restore() gets called on a
+    // device built from the same options and has to read back exactly what
+    // save() wrote. devices whose state never changes keep the defaults
+    fn save(&self, _w: &mut Writer) {}
+
+    fn restore(&mut self, _r: &mut Reader) -> Result<(), String> {
+        Ok(())
+    }
+
+    // devices that take input from the host keep the journal and go
+    // through it for that input, so a run can be recorded and replayed
+    fn set_journal(&mut self, _journal: &Journal) {}
+}
+
+pub struct MmioRegion {
+    pub base: u32,
+    pub size: u32,
+    pub dev: Box<dyn Device>,
+}
+
+impl MmioRegion {
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.base && addr - self.base < self.size
+    }
+}
+
+// guest ram as a device sees it when it does dma: addressed with guest
+// physical addresses, wherever ram happens to be mapped. accesses return None
+// instead of panicking so a buggy guest driver can't take down the host
+pub struct GuestRam<'a> {
+    base: u32,
+    mem: &'a mut [u8],
+}
+
+impl<'a> GuestRam<'a> {
+    pub fn new(base: u32, mem: &'a mut [u8]) -> Self {
+        GuestRam { base, mem }
+    }
+
+    // ram offset of a guest physical address
+    fn offset(&self, addr: u64) -> Option<u64> {
+        addr.checked_sub(self.base as u64)
+    }
+
+    pub fn read_u16(&self, addr: u64) -> Option<u16> {
+        mem_read_u16(self.mem, self.offset(addr)?)
+    }
+
+    pub fn read_u32(&self, addr: u64) -> Option<u32> {
+        mem_read_u32(self.mem, self.offset(addr)?)
+    }
+
+    pub fn read_u64(&self, addr: u64) -> Option<u64> {
+        mem_read_u64(self.mem, self.offset(addr)?)
+    }
+
+    pub fn write_u16(&mut self, addr: u64, val: u16) -> Option<()> {
+        mem_write_u16(self.mem, self.offset(addr)?, val)
+    }
+
+    pub fn write_u32(&mut self, addr: u64, val: u32) -> Option<()> {
+        mem_write_u32(self.mem, self.offset(addr)?, val)
+    }
+
+    pub fn slice(&self, addr: u64, len: u32) -> Option<&[u8]> {
+        mem_slice(self.mem, self.offset(addr)?, len)
+    }
+
+    pub fn slice_mut(&mut self, addr: u64, len: u32) -> Option<&mut [u8]> {
+        let off = self.offset(addr)?;
+        mem_slice_mut(self.mem, off, len)
+    }
+}
+
+// the same accesses by ram offset, for code that already has one (the
+// syscall layer, whose processes always have ram at 0)
+
+pub fn mem_read_u16(mem: &[u8], addr: u64) -> Option<u16> {
+    let a = usize::try_from(addr).ok()?;
+    let b = mem.get(a..a.checked_add(2)?)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+pub fn mem_read_u32(mem: &[u8], addr: u64) -> Option<u32> {
+    let a = usize::try_from(addr).ok()?;
+    let b = mem.get(a..a.checked_add(4)?)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub fn mem_read_u64(mem: &[u8], addr: u64) -> Option<u64> {
+    let lo = mem_read_u32(mem, addr)? as u64;
+    let hi = mem_read_u32(mem, addr.checked_add(4)?)? as u64;
+    Some((hi << 32) | lo)
+}
+
+pub fn mem_write_u16(mem: &mut [u8], addr: u64, val: u16) -> Option<()> {
+    let a = usize::try_from(addr).ok()?;
+    mem.get_mut(a..a.checked_add(2)?)?.copy_from_slice(&val.to_le_bytes());
+    Some(())
+}
+
+pub fn mem_write_u32(mem: &mut [u8], addr: u64, val: u32) -> Option<()> {
+    let a = usize::try_from(addr).ok()?;
+    mem.get_mut(a..a.checked_add(4)?)?.copy_from_slice(&val.to_le_bytes());
+    Some(())
+}
+
+pub fn mem_slice(mem: &[u8], addr: u64, len: u32) -> Option<&[u8]> {
+    let a = usize::try_from(addr).ok()?;
+    mem.get(a..a.checked_add(len as usize)?)
+}
+
+pub fn mem_slice_mut(mem: &mut [u8], addr: u64, len: u32) -> Option<&mut [u8]> {
+    let a = usize::try_from(addr).ok()?;
+    mem.get_mut(a..a.checked_add(len as usize)?)
+}
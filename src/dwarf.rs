@@ -0,0 +1,583 @@
+// dwarf .debug_line reader for source-level debugging
+//
+// runs the line number program of every unit (dwarf 2 through 5) and keeps the
+// resulting address -> file:line rows. that's all the debugger needs for
+// `break file.c:42` and line stepping; no .debug_info, no types, no frames.
+
+use crate::elf::{self, ElfFile};
+use std::fmt;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK2: u64 = 0x03;
+const DW_FORM_BLOCK4: u64 = 0x04;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRow {
+    pub addr: u32,
+    pub file: usize,
+    pub line: u32,
+    pub is_stmt: bool,
+    // first address past a sequence; covers nothing itself
+    pub end_sequence: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    // paths of every unit's files, deduplicated
+    files: Vec<String>,
+    // sorted by address
+    rows: Vec<LineRow>,
+}
+
+// `file.c:42` as printed by the debugger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceLine<'a> {
+    pub file: &'a str,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let b = self
+            .data
+            .get(self.pos..self.pos.checked_add(n).ok_or("truncated .debug_line")?)
+            .ok_or("truncated .debug_line")?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn uleb(&mut self) -> Result<u64, String> {
+        let mut val = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, String> {
+        let mut val = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    val |= -1i64 << shift;
+                }
+                return Ok(val);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Result<&'a str, String> {
+        let s = elf::str_at(self.data, self.pos)?;
+        self.pos += s.len() + 1;
+        Ok(s)
+    }
+}
+
+// string sections that dwarf 5 file tables point into
+struct StrSections<'a> {
+    str: &'a [u8],
+    line_str: &'a [u8],
+}
+
+// one attribute of a dwarf 5 directory/file entry. only paths and directory
+// indexes matter; everything else (md5, sizes) is skipped by form
+enum Attr<'a> {
+    Str(&'a str),
+    Num(u64),
+    Skipped,
+}
+
+fn read_form<'a>(r: &mut Reader<'a>, form: u64, strs: &StrSections<'a>) -> Result<Attr<'a>, String> {
+    Ok(match form {
+        DW_FORM_STRING => Attr::Str(r.cstr()?),
+        DW_FORM_LINE_STRP => Attr::Str(elf::str_at(strs.line_str, r.u32()? as usize)?),
+        DW_FORM_STRP => Attr::Str(elf::str_at(strs.str, r.u32()? as usize)?),
+        DW_FORM_UDATA => Attr::Num(r.uleb()?),
+        DW_FORM_DATA1 => Attr::Num(r.u8()? as u64),
+        DW_FORM_DATA2 => Attr::Num(r.u16()? as u64),
+        DW_FORM_DATA4 => Attr::Num(r.u32()? as u64),
+        DW_FORM_DATA8 => {
+            r.bytes(8)?;
+            Attr::Skipped
+        }
+        DW_FORM_DATA16 => {
+            r.bytes(16)?;
+            Attr::Skipped
+        }
+        DW_FORM_BLOCK | DW_FORM_BLOCK1 | DW_FORM_BLOCK2 | DW_FORM_BLOCK4 => {
+            let len = match form {
+                DW_FORM_BLOCK1 => r.u8()? as usize,
+                DW_FORM_BLOCK2 => r.u16()? as usize,
+                DW_FORM_BLOCK4 => r.u32()? as usize,
+                _ => r.uleb()? as usize,
+            };
+            r.bytes(len)?;
+            Attr::Skipped
+        }
+        _ => return Err(format!("unsupported form 0x{:x} in .debug_line header", form)),
+    })
+}
+
+// dwarf 5 directory or file table: a format description then the entries.
+// returns (path, directory index) per entry
+fn read_entry_table<'a>(r: &mut Reader<'a>, strs: &StrSections<'a>) -> Result<Vec<(&'a str, usize)>, String> {
+    let format_count = r.u8()?;
+    let mut format = Vec::new();
+    for _ in 0..format_count {
+        format.push((r.uleb()?, r.uleb()?));
+    }
+    let count = r.uleb()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (mut path, mut dir) = ("", 0);
+        for &(kind, form) in &format {
+            match (kind, read_form(r, form, strs)?) {
+                (DW_LNCT_PATH, Attr::Str(s)) => path = s,
+                (DW_LNCT_DIRECTORY_INDEX, Attr::Num(n)) => dir = n as usize,
+                _ => {}
+            }
+        }
+        entries.push((path, dir));
+    }
+    Ok(entries)
+}
+
+fn join(dir: Option<&str>, name: &str) -> String {
+    match dir {
+        Some(d) if !d.is_empty() && !name.starts_with('/') => format!("{}/{}", d, name),
+        _ => name.to_string(),
+    }
+}
+
+impl LineTable {
+    pub fn new() -> Self {
+        LineTable { files: Vec::new(), rows: Vec::new() }
+    }
+
+    // empty table if the file wasn't built with -g
+    pub fn from_elf(file: &ElfFile) -> Result<Self, String> {
+        let Some(sec) = file.section(".debug_line") else {
+            return Ok(LineTable::new());
+        };
+        let section_bytes = |name| match file.section(name) {
+            Some(s) => file.section_data(s),
+            None => Ok(&[][..]),
+        };
+        let strs = StrSections {
+            str: section_bytes(".debug_str")?,
+            line_str: section_bytes(".debug_line_str")?,
+        };
+        LineTable::parse(file.section_data(sec)?, &strs)
+    }
+
+    fn parse(data: &[u8], strs: &StrSections) -> Result<Self, String> {
+        let mut table = LineTable::new();
+        let mut r = Reader { data, pos: 0 };
+        while r.pos < data.len() {
+            let len = r.u32()?;
+            if len == 0xffff_ffff {
+                return Err("64-bit dwarf isn't supported".to_string());
+            }
+            let end = r.pos + len as usize;
+            if end > data.len() {
+                return Err("truncated .debug_line".to_string());
+            }
+            let mut unit = Reader { data: &data[..end], pos: r.pos };
+            table.parse_unit(&mut unit, strs)?;
+            r.pos = end;
+        }
+        // end_sequence first, so a sequence starting where another ends wins
+        table.rows.sort_by_key(|row| (row.addr, !row.end_sequence));
+        Ok(table)
+    }
+
+    fn add_file(&mut self, path: String) -> usize {
+        match self.files.iter().position(|f| *f == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    fn parse_unit<'a>(&mut self, r: &mut Reader<'a>, strs: &StrSections<'a>) -> Result<(), String> {
+        let version = r.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(format!("unsupported .debug_line version {}", version));
+        }
+        if version >= 5 {
+            let addr_size = r.u8()?;
+            if addr_size != 4 {
+                return Err(format!("unexpected address size {} in .debug_line", addr_size));
+            }
+            r.u8()?; // segment selector size
+        }
+        let header_len = r.u32()? as usize;
+        let program_start = r.pos + header_len;
+        let min_inst_len = r.u8()? as u32;
+        if version >= 4 {
+            r.u8()?; // max ops per instruction, always 1 outside vliw
+        }
+        let default_is_stmt = r.u8()? != 0;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()?;
+        let opcode_base = r.u8()?;
+        if line_range == 0 {
+            return Err("line_range of 0 in .debug_line".to_string());
+        }
+        let std_lengths = r.bytes(opcode_base.saturating_sub(1) as usize)?.to_vec();
+
+        // unit file index -> index into self.files
+        let mut files = Vec::new();
+        if version >= 5 {
+            let dirs = read_entry_table(r, strs)?;
+            for (name, dir) in read_entry_table(r, strs)? {
+                let path = join(dirs.get(dir).map(|d| d.0), name);
+                files.push(self.add_file(path));
+            }
+        } else {
+            let mut dirs = Vec::new();
+            loop {
+                let d = r.cstr()?;
+                if d.is_empty() {
+                    break;
+                }
+                dirs.push(d);
+            }
+            // file numbers start at 1 before dwarf 5
+            files.push(usize::MAX);
+            loop {
+                let name = r.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()? as usize;
+                r.uleb()?; // mtime
+                r.uleb()?; // length
+                // directory 0 is the compilation dir, which we don't know
+                let path = join(dir.checked_sub(1).and_then(|d| dirs.get(d)).copied(), name);
+                files.push(self.add_file(path));
+            }
+        }
+        r.pos = program_start;
+
+        let mut addr = 0u32;
+        let mut file = 1usize;
+        let mut line = 1i64;
+        let mut is_stmt = default_is_stmt;
+
+        while r.pos < r.data.len() {
+            let op = r.u8()?;
+            if op >= opcode_base {
+                let adj = op - opcode_base;
+                addr = addr.wrapping_add(((adj / line_range) as u32).wrapping_mul(min_inst_len));
+                line = line.wrapping_add(line_base + (adj % line_range) as i64);
+                self.push_row(&files, addr, file, line, is_stmt, false);
+                continue;
+            }
+            match op {
+                0 => {
+                    let len = r.uleb()? as usize;
+                    let next = r.pos.saturating_add(len);
+                    match r.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            self.push_row(&files, addr, file, line, is_stmt, true);
+                            addr = 0;
+                            file = 1;
+                            line = 1;
+                            is_stmt = default_is_stmt;
+                        }
+                        DW_LNE_SET_ADDRESS => addr = r.u32()?,
+                        DW_LNE_DEFINE_FILE => {
+                            // dwarf 2-4 only; the file gets the next index
+                            let path = r.cstr()?.to_string();
+                            files.push(self.add_file(path));
+                        }
+                        _ => {} // discriminators and vendor extensions
+                    }
+                    r.pos = next;
+                }
+                DW_LNS_COPY => self.push_row(&files, addr, file, line, is_stmt, false),
+                DW_LNS_ADVANCE_PC => addr = addr.wrapping_add((r.uleb()? as u32).wrapping_mul(min_inst_len)),
+                DW_LNS_ADVANCE_LINE => line = line.wrapping_add(r.sleb()?),
+                DW_LNS_SET_FILE => file = r.uleb()? as usize,
+                DW_LNS_NEGATE_STMT => is_stmt = !is_stmt,
+                DW_LNS_CONST_ADD_PC => {
+                    let adj = 255 - opcode_base;
+                    addr = addr.wrapping_add(((adj / line_range) as u32).wrapping_mul(min_inst_len));
+                }
+                DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(r.u16()? as u32),
+                _ => {
+                    // column, basic block, prologue markers, isa, and any
+                    // standard opcode we don't know: skip the operands
+                    let n = std_lengths.get(op as usize - 1).copied().unwrap_or(0);
+                    for _ in 0..n {
+                        r.uleb()?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // rows naming a file the unit never declared are dropped
+    fn push_row(&mut self, files: &[usize], addr: u32, file: usize, line: i64, is_stmt: bool, end_sequence: bool) {
+        if let Some(&f) = files.get(file).filter(|&&f| f != usize::MAX) {
+            self.rows.push(LineRow { addr, file: f, line: line as u32, is_stmt, end_sequence });
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    pub fn rows(&self) -> &[LineRow] {
+        &self.rows
+    }
+
+    // source line covering addr, if any sequence does
+    pub fn lookup(&self, addr: u32) -> Option<SourceLine<'_>> {
+        let i = self.rows.partition_point(|r| r.addr <= addr);
+        let row = self.rows[..i].last()?;
+        if row.end_sequence || row.line == 0 {
+            return None;
+        }
+        Some(SourceLine { file: &self.files[row.file], line: row.line })
+    }
+
+    // true if addr is where the code for a statement starts
+    pub fn is_stmt_start(&self, addr: u32) -> bool {
+        let i = self.rows.partition_point(|r| r.addr < addr);
+        self.rows[i..]
+            .iter()
+            .take_while(|r| r.addr == addr)
+            .any(|r| r.is_stmt && !r.end_sequence && r.line != 0)
+    }
+
+    // first address of `file:line`. the file matches by full path or any
+    // trailing path components, and a line with no code moves down to the
+    // next one that has some, like gdb. returns the line actually used
+    pub fn resolve(&self, file: &str, line: u32) -> Option<(u32, u32)> {
+        let matches = |path: &str| {
+            path == file
+                || path
+                    .strip_suffix(file)
+                    .is_some_and(|rest| rest.ends_with('/'))
+        };
+        self.rows
+            .iter()
+            .filter(|r| r.is_stmt && !r.end_sequence && r.line >= line && matches(&self.files[r.file]))
+            .min_by_key(|r| (r.line, r.addr))
+            .map(|r| (r.addr, r.line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uleb(mut v: u32, out: &mut Vec<u8>) {
+        loop {
+            let b = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                out.push(b);
+                return;
+            }
+            out.push(b | 0x80);
+        }
+    }
+
+    // a dwarf 4 unit for src/main.c: line 3 at 0x100, 4 at 0x108, 7 at 0x10c,
+    // then back to 4 at 0x114 (a loop), ending at 0x118
+    fn v4_unit() -> Vec<u8> {
+        let mut header = vec![
+            1,    // min inst length
+            1,    // max ops
+            1,    // default is_stmt
+            0xfb, // line base -5
+            14,   // line range
+            13,   // opcode base
+        ];
+        header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+        header.extend_from_slice(b"src\0\0");
+        header.extend_from_slice(b"main.c\0\x01\0\0\0");
+
+        let mut prog = vec![0, 5, DW_LNE_SET_ADDRESS];
+        prog.extend_from_slice(&0x100u32.to_le_bytes());
+        prog.push(DW_LNS_ADVANCE_LINE);
+        prog.push(2); // line 3
+        prog.push(DW_LNS_COPY);
+        // special opcode: addr += 8, line += 1
+        prog.push(13 + (1 + 5) + 14 * 8);
+        // addr += 4, line += 3
+        prog.push(13 + (3 + 5) + 14 * 4);
+        prog.push(DW_LNS_ADVANCE_LINE);
+        prog.push(0x7d); // -3
+        prog.push(DW_LNS_ADVANCE_PC);
+        uleb(8, &mut prog);
+        prog.push(DW_LNS_COPY);
+        prog.push(DW_LNS_ADVANCE_PC);
+        uleb(4, &mut prog);
+        prog.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+
+        let mut unit = 4u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&header);
+        unit.extend_from_slice(&prog);
+        let mut out = (unit.len() as u32).to_le_bytes().to_vec();
+        out.extend_from_slice(&unit);
+        out
+    }
+
+    fn parse(data: &[u8]) -> LineTable {
+        LineTable::parse(data, &StrSections { str: &[], line_str: &[] }).unwrap()
+    }
+
+    #[test]
+    fn test_lookup_lines() {
+        let table = parse(&v4_unit());
+        let at = |a| table.lookup(a).map(|l| l.to_string());
+        assert_eq!(at(0x100).as_deref(), Some("src/main.c:3"));
+        assert_eq!(at(0x104).as_deref(), Some("src/main.c:3"));
+        assert_eq!(at(0x108).as_deref(), Some("src/main.c:4"));
+        assert_eq!(at(0x10c).as_deref(), Some("src/main.c:7"));
+        assert_eq!(at(0x114).as_deref(), Some("src/main.c:4"));
+        assert_eq!(at(0x118), None); // past the end of the sequence
+        assert_eq!(at(0xfc), None);
+        assert!(table.is_stmt_start(0x10c));
+        assert!(!table.is_stmt_start(0x110));
+    }
+
+    #[test]
+    fn test_resolve_file_line() {
+        let table = parse(&v4_unit());
+        assert_eq!(table.resolve("main.c", 4), Some((0x108, 4)));
+        assert_eq!(table.resolve("src/main.c", 3), Some((0x100, 3)));
+        // no code on 5 or 6
+        assert_eq!(table.resolve("main.c", 5), Some((0x10c, 7)));
+        assert_eq!(table.resolve("ain.c", 3), None);
+        assert_eq!(table.resolve("main.c", 8), None);
+    }
+
+    #[test]
+    fn test_huge_operands_dont_panic() {
+        let mut data = v4_unit();
+        data[10] = 4; // min inst length
+        // advance_pc and advance_line by 2^64 - 1, then an extended opcode
+        // claiming as many bytes
+        for op in [DW_LNS_ADVANCE_PC, DW_LNS_ADVANCE_LINE, 0] {
+            data.push(op);
+            data.extend_from_slice(&[0xff; 9]);
+            data.push(0x01);
+        }
+        data.push(DW_LNE_END_SEQUENCE);
+        let len = (data.len() - 4) as u32;
+        data[..4].copy_from_slice(&len.to_le_bytes());
+        assert!(LineTable::parse(&data, &StrSections { str: &[], line_str: &[] }).is_ok());
+    }
+
+    #[test]
+    fn test_v5_file_table() {
+        // directory and file entries as inline strings, file 0 is the primary
+        let mut header = vec![4, 0]; // address size, segment selector size
+        let mut tables = Vec::new();
+        tables.extend_from_slice(&[1, DW_LNCT_PATH as u8, DW_FORM_STRING as u8, 1]);
+        tables.extend_from_slice(b"/work\0");
+        tables.extend_from_slice(&[
+            2,
+            DW_LNCT_PATH as u8,
+            DW_FORM_STRING as u8,
+            DW_LNCT_DIRECTORY_INDEX as u8,
+            DW_FORM_UDATA as u8,
+            1,
+        ]);
+        tables.extend_from_slice(b"boot.c\0\0");
+
+        let mut fixed = vec![4, 1, 1, 0xfb, 14, 13];
+        fixed.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+        fixed.extend_from_slice(&tables);
+
+        let mut prog = vec![DW_LNS_SET_FILE, 0, 0, 5, DW_LNE_SET_ADDRESS];
+        prog.extend_from_slice(&0x2000u32.to_le_bytes());
+        prog.extend_from_slice(&[DW_LNS_ADVANCE_LINE, 9, DW_LNS_COPY]);
+        prog.extend_from_slice(&[DW_LNS_ADVANCE_PC, 1, 0, 1, DW_LNE_END_SEQUENCE]);
+
+        let mut unit = 5u16.to_le_bytes().to_vec();
+        unit.append(&mut header);
+        unit.extend_from_slice(&(fixed.len() as u32).to_le_bytes());
+        unit.extend_from_slice(&fixed);
+        unit.extend_from_slice(&prog);
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&unit);
+
+        let table = parse(&data);
+        // min inst length 4: advance_pc 1 moves a whole instruction
+        assert_eq!(table.lookup(0x2000).unwrap().to_string(), "/work/boot.c:10");
+        assert!(table.lookup(0x2004).is_none());
+    }
+}
@@ -0,0 +1,388 @@
+// elf32 little-endian risc-v loader
+//
+// only executables are accepted. every PT_LOAD segment is copied to its
+// physical address with the bss tail zeroed, and the entry point becomes
+// the pc. binaries that need isa features we don't have (compressed
+// instructions, hard float, extensions in .riscv.attributes) are rejected
+// before anything is loaded.
+
+use crate::cpu::Cpu;
+use crate::decoder;
+
+pub const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const SHT_RISCV_ATTRIBUTES: u32 = 0x7000_0003;
+
+const EF_RISCV_RVC: u32 = 0x1;
+const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+
+const TAG_FILE: u64 = 1;
+const TAG_RISCV_ARCH: u64 = 5;
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub paddr: u32,
+    pub vaddr: u32,
+    pub offset: u32,
+    pub filesz: u32,
+    pub memsz: u32,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub sh_type: u32,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub link: u32,
+    pub entsize: u32,
+}
+
+pub struct ElfFile<'a> {
+    pub data: &'a [u8],
+    pub entry: u32,
+    pub flags: u32,
+    pub phoff: u32,
+    pub phentsize: u16,
+    pub phnum: u16,
+    // has PT_INTERP or PT_DYNAMIC, i.e. wants a dynamic linker
+    pub dynamic: bool,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+}
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(ELF_MAGIC)
+}
+
+fn u16_at(data: &[u8], off: usize) -> Result<u16, String> {
+    data.get(off..off + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("elf truncated at offset 0x{:x}", off))
+}
+
+fn u32_at(data: &[u8], off: usize) -> Result<u32, String> {
+    data.get(off..off + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| format!("elf truncated at offset 0x{:x}", off))
+}
+
+fn bytes_at(data: &[u8], off: u32, len: u32) -> Result<&[u8], String> {
+    let start = off as usize;
+    data.get(start..start + len as usize)
+        .ok_or_else(|| format!("elf range 0x{:x}+0x{:x} past end of file", off, len))
+}
+
+// nul-terminated string out of a string table
+pub fn str_at(data: &[u8], off: usize) -> Result<&str, String> {
+    let tail = data.get(off..).ok_or("string offset past end of table")?;
+    let end = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+    std::str::from_utf8(&tail[..end]).map_err(|_| "string table entry is not utf-8".to_string())
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, String> {
+        if !is_elf(data) {
+            return Err("not an elf file".to_string());
+        }
+        if data.len() < 52 {
+            return Err("elf header truncated".to_string());
+        }
+        if data[4] != ELFCLASS32 {
+            return Err("not a 32-bit elf (rv64 binaries aren't supported)".to_string());
+        }
+        if data[5] != ELFDATA2LSB {
+            return Err("not a little-endian elf".to_string());
+        }
+        let e_type = u16_at(data, 16)?;
+        let machine = u16_at(data, 18)?;
+        if machine != EM_RISCV {
+            return Err(format!("not a risc-v elf (e_machine = {})", machine));
+        }
+        if e_type != ET_EXEC {
+            return Err(format!("only executables can be loaded (e_type = {})", e_type));
+        }
+
+        let entry = u32_at(data, 24)?;
+        let phoff = u32_at(data, 28)?;
+        let shoff = u32_at(data, 32)?;
+        let flags = u32_at(data, 36)?;
+        let phentsize = u16_at(data, 42)?;
+        let phnum = u16_at(data, 44)?;
+        let shentsize = u16_at(data, 46)?;
+        let shnum = u16_at(data, 48)?;
+        let shstrndx = u16_at(data, 50)?;
+
+        let mut segments = Vec::new();
+        let mut dynamic = false;
+        if phnum > 0 && phentsize < 32 {
+            return Err(format!("bad program header size {}", phentsize));
+        }
+        for i in 0..phnum as usize {
+            let ph = phoff as usize + i * phentsize as usize;
+            match u32_at(data, ph)? {
+                PT_LOAD => {}
+                PT_DYNAMIC | PT_INTERP => {
+                    dynamic = true;
+                    continue;
+                }
+                _ => continue,
+            }
+            let seg = Segment {
+                offset: u32_at(data, ph + 4)?,
+                vaddr: u32_at(data, ph + 8)?,
+                paddr: u32_at(data, ph + 12)?,
+                filesz: u32_at(data, ph + 16)?,
+                memsz: u32_at(data, ph + 20)?,
+                flags: u32_at(data, ph + 24)?,
+            };
+            if seg.filesz > seg.memsz {
+                return Err(format!("segment at 0x{:x} has filesz > memsz", seg.paddr));
+            }
+            bytes_at(data, seg.offset, seg.filesz)?;
+            segments.push(seg);
+        }
+
+        // section headers are optional for loading but carry the isa
+        // attributes (and symbols, debug info)
+        let mut sections = Vec::new();
+        if shnum > 0 && shoff != 0 {
+            if shentsize < 40 {
+                return Err(format!("bad section header size {}", shentsize));
+            }
+            let mut raw = Vec::new();
+            for i in 0..shnum as usize {
+                let sh = shoff as usize + i * shentsize as usize;
+                raw.push((
+                    u32_at(data, sh)?,
+                    Section {
+                        name: String::new(),
+                        sh_type: u32_at(data, sh + 4)?,
+                        addr: u32_at(data, sh + 12)?,
+                        offset: u32_at(data, sh + 16)?,
+                        size: u32_at(data, sh + 20)?,
+                        link: u32_at(data, sh + 24)?,
+                        entsize: u32_at(data, sh + 36)?,
+                    },
+                ));
+            }
+            let strtab = raw.get(shstrndx as usize).map(|(_, s)| (s.offset, s.size));
+            for (name_off, mut sec) in raw {
+                if let Some((off, size)) = strtab {
+                    let table = bytes_at(data, off, size)?;
+                    sec.name = str_at(table, name_off as usize).unwrap_or("").to_string();
+                }
+                sections.push(sec);
+            }
+        }
+
+        Ok(ElfFile { data, entry, flags, phoff, phentsize, phnum, dynamic, segments, sections })
+    }
+
+    // where the program headers end up in memory, for AT_PHDR. they're
+    // normally inside the first PT_LOAD segment
+    pub fn phdr_addr(&self) -> Option<u32> {
+        self.segments
+            .iter()
+            .find(|s| self.phoff >= s.offset && self.phoff - s.offset < s.filesz)
+            .map(|s| s.vaddr + (self.phoff - s.offset))
+    }
+
+    // first address past every segment, where the heap can start
+    pub fn image_end(&self) -> u32 {
+        self.segments
+            .iter()
+            .map(|s| s.vaddr.saturating_add(s.memsz))
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|s| s.name == name)
+    }
+
+    pub fn section_data(&self, sec: &Section) -> Result<&'a [u8], String> {
+        bytes_at(self.data, sec.offset, sec.size)
+    }
+
+    // the arch string from .riscv.attributes, e.g. "rv32i2p1_m2p0"
+    pub fn arch_attribute(&self) -> Result<Option<String>, String> {
+        let Some(sec) = self.sections.iter().find(|s| s.sh_type == SHT_RISCV_ATTRIBUTES) else {
+            return Ok(None);
+        };
+        parse_arch_attribute(self.section_data(sec)?)
+    }
+
+    // refuse binaries that would trip over something we can't execute
+    pub fn check_isa(&self) -> Result<(), String> {
+        if self.flags & EF_RISCV_RVC != 0 {
+            return Err("binary uses compressed instructions (rvc), which aren't supported".to_string());
+        }
+        if self.flags & EF_RISCV_FLOAT_ABI != 0 {
+            return Err("binary uses a hard-float abi, but there's no f/d support".to_string());
+        }
+        // EF_RISCV_RVE is fine: rve code only uses x0-x15, which runs on rv32i
+
+        if let Some(arch) = self.arch_attribute()? {
+            let missing = missing_extensions(&arch);
+            if !missing.is_empty() {
+                return Err(format!(
+                    "binary needs {} (arch {}), emulator implements {}",
+                    missing.join(", "),
+                    arch,
+                    decoder::isa_string()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // copy every PT_LOAD segment into ram and point the pc at the entry
+    pub fn load(&self, cpu: &mut Cpu) -> Result<(), String> {
+        self.check_isa()?;
+        for seg in &self.segments {
+            let start = cpu.ram_offset(seg.paddr, seg.memsz as usize).ok_or_else(|| {
+                format!(
+                    "segment 0x{:08x}-0x{:08x} doesn't fit in ram (0x{:x}-0x{:x})",
+                    seg.paddr,
+                    seg.paddr as u64 + seg.memsz as u64,
+                    cpu.ram_base,
+                    cpu.ram_end()
+                )
+            })?;
+            let file = bytes_at(self.data, seg.offset, seg.filesz)?;
+            cpu.mem[start..start + file.len()].copy_from_slice(file);
+            cpu.mem[start + file.len()..start + seg.memsz as usize].fill(0);
+            cpu.icache.invalidate(start, seg.memsz as usize);
+        }
+        cpu.pc = self.entry;
+        cpu.reset_vector = self.entry;
+        Ok(())
+    }
+}
+
+fn read_uleb(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = *data.get(*pos).ok_or("truncated uleb128")?;
+        *pos += 1;
+        if shift < 64 {
+            result |= ((b & 0x7f) as u64) << shift;
+        }
+        if b & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+fn parse_arch_attribute(data: &[u8]) -> Result<Option<String>, String> {
+    if data.first() != Some(&b'A') {
+        return Err("unknown .riscv.attributes format".to_string());
+    }
+    let mut pos = 1;
+    while pos + 4 <= data.len() {
+        let len = u32_at(data, pos)? as usize;
+        let sub = data.get(pos..pos + len).ok_or(".riscv.attributes truncated")?;
+        pos += len.max(4);
+        let vendor = str_at(sub, 4)?;
+        if vendor != "riscv" {
+            continue;
+        }
+        let mut p = 4 + vendor.len() + 1;
+        while p < sub.len() {
+            let tag = read_uleb(sub, &mut p)?;
+            let size = u32_at(sub, p)? as usize;
+            let body = sub.get(p + 4..p - 1 + size).ok_or(".riscv.attributes truncated")?;
+            p += size - 1;
+            if tag != TAG_FILE {
+                continue;
+            }
+            let mut q = 0;
+            while q < body.len() {
+                let attr = read_uleb(body, &mut q)?;
+                // odd tags are strings, even tags are uleb128
+                if attr % 2 == 1 {
+                    let s = str_at(body, q)?;
+                    q += s.len() + 1;
+                    if attr == TAG_RISCV_ARCH {
+                        return Ok(Some(s.to_string()));
+                    }
+                } else {
+                    read_uleb(body, &mut q)?;
+                }
+            }
+        }
+    }
+    Ok(None)
+}
+
+// extensions named in an arch string that the decoder doesn't implement
+pub fn missing_extensions(arch: &str) -> Vec<String> {
+    let arch = arch.to_ascii_lowercase();
+    let Some(rest) = arch.strip_prefix("rv32") else {
+        return vec![arch];
+    };
+    let mut missing = Vec::new();
+    let mut parts = rest.split('_');
+    if let Some(single) = parts.next() {
+        // single-letter extensions, each optionally followed by a version like 2p1
+        for c in single.chars().filter(|c| c.is_ascii_alphabetic() && *c != 'p') {
+            let ok = match c {
+                'i' | 'e' => true,
+                'g' => false, // imafd + zicsr + zifencei
+                _ => decoder::EXTENSIONS.contains(&c),
+            };
+            if !ok {
+                missing.push(c.to_string());
+            }
+        }
+    }
+    for multi in parts.filter(|p| !p.is_empty()) {
+        let name = multi.trim_end_matches(|c: char| c.is_ascii_digit() || c == 'p');
+        // gnu as writes single letters after underscores too (rv32i2p1_m2p0)
+        let known = match name.chars().collect::<Vec<_>>()[..] {
+            [c] => decoder::EXTENSIONS.contains(&c),
+            _ => decoder::MULTI_LETTER_EXTENSIONS.contains(&name),
+        };
+        if !known {
+            missing.push(name.to_string());
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_extensions() {
+        assert!(missing_extensions("rv32i2p1").is_empty());
+        assert!(missing_extensions("rv32e2p0").is_empty());
+        assert_eq!(missing_extensions("rv32i2p1_m2p0_c2p0"), vec!["c"]);
+        assert_eq!(missing_extensions("rv32imac"), vec!["c"]);
+        assert!(missing_extensions("rv32i2p1_m2p0_a2p1").is_empty());
+        assert_eq!(missing_extensions("rv32i2p1_zba1p0"), vec!["zba"]);
+        assert_eq!(missing_extensions("rv64i"), vec!["rv64i"]);
+    }
+
+    #[test]
+    fn test_parse_arch_attribute() {
+        // llvm-mc output for .attribute arch, "rv32i2p0_m2p0" + stack_align 16
+        let sec = b"A\x20\0\0\0riscv\0\x01\x16\0\0\0\x05rv32i2p0_m2p0\0\x04\x10";
+        assert_eq!(parse_arch_attribute(sec).unwrap().as_deref(), Some("rv32i2p0_m2p0"));
+    }
+}
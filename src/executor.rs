@@ -1,216 +1,330 @@
 // instruction execution
 
+use crate::block::{Block, BlockCache, OpKind, Term};
 use crate::cpu::Cpu;
-use crate::decoder::{Instruction, Opcode};
-use crate::metrics::Metrics;
+use crate::csr::{
+    self, Exception, CAUSE_BREAKPOINT, CAUSE_MISALIGNED_LOAD, CAUSE_MISALIGNED_STORE, CAUSE_USER_ECALL, MSTATUS_TSR,
+    MSTATUS_TVM, MSTATUS_TW, PRV_M, PRV_S,
+};
+use crate::decoder::{amo_value, Instruction, Opcode};
+use crate::htif::Htif;
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::replay;
+use crate::semihosting::{self, Semihosting};
+use crate::metrics::Instrument;
+use crate::mmu::{self, Access};
+
+// the execution environment behind ecall: linux syscalls, an sbi, ...
+pub trait EcallHandler {
+    // service the call described by the registers. Ok(Some(code)) ends the
+    // program with that exit status
+    fn ecall(&mut self, cpu: &mut Cpu) -> Result<Option<i32>, String>;
+}
 
 pub struct Executor {
     pub halted: bool,
+    // set when the program exits through the ecall handler
+    pub exit_code: Option<i32>,
+    pub ecall: Option<Box<dyn EcallHandler>>,
+    // watches guest stores for riscv-tests style tohost writes
+    pub htif: Option<Htif>,
+    // services ebreaks wrapped in the semihosting sequence
+    pub semihosting: Option<Semihosting>,
+    // translated basic blocks, used by run()
+    pub blocks: BlockCache,
+    // native code for the hottest of them
+    #[cfg(feature = "jit")]
+    pub jit: Jit,
+}
+
+// how a block run ended
+pub(crate) struct BlockExit {
+    // instructions executed
+    pub(crate) executed: usize,
+    // which link of the block leads on, if its successor is fixed
+    pub(crate) link: Option<usize>,
+    pub(crate) exit_code: Option<i32>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Executor {
     pub fn new() -> Self {
-        Executor { halted: false }
+        Executor {
+            halted: false,
+            exit_code: None,
+            ecall: None,
+            htif: None,
+            semihosting: None,
+            blocks: BlockCache::new(),
+            #[cfg(feature = "jit")]
+            jit: Jit::new(),
+        }
     }
 
-    pub fn step(&mut self, cpu: &mut Cpu, metrics: &mut Metrics) -> Result<(), String> {
+    pub fn step<M: Instrument>(&mut self, cpu: &mut Cpu, metrics: &mut M) -> Result<(), String> {
         if self.halted {
             return Err("cpu halted".to_string());
         }
 
-        let raw = cpu.read_word(cpu.pc);
-        let inst = Instruction::decode(raw);
+        cpu.tick_devices();
+        // an interrupt is taken before the instruction, which is then the
+        // first of its handler
+        csr::interrupt(cpu);
+
+        cpu.fault = None;
+        if let Err(e) = self.execute_one(cpu, metrics) {
+            deliver(cpu, e)?;
+        }
+
+        match &cpu.journal {
+            Some(journal) => journal.check(),
+            None => Ok(()),
+        }
+    }
+
+    fn execute_one<M: Instrument>(&mut self, cpu: &mut Cpu, metrics: &mut M) -> Result<(), String> {
+        let pc = mmu::translate(cpu, cpu.pc, Access::Fetch)?;
+        let inst = cpu.fetch_decoded(pc)?;
         metrics.record_instruction(&inst);
+        metrics.record_pc(cpu.pc);
 
-        match inst.opcode {
-            Opcode::Add => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                cpu.write_reg(inst.rd, rs1.wrapping_add(rs2));
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Sub => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                cpu.write_reg(inst.rd, rs1.wrapping_sub(rs2));
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::And => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                cpu.write_reg(inst.rd, rs1 & rs2);
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Or => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                cpu.write_reg(inst.rd, rs1 | rs2);
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Xor => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                cpu.write_reg(inst.rd, rs1 ^ rs2);
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Sll => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                let shamt = rs2 & 0x1f;
-                cpu.write_reg(inst.rd, rs1 << shamt);
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Srl => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                let shamt = rs2 & 0x1f;
-                cpu.write_reg(inst.rd, rs1 >> shamt);
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Sra => {
-                let rs1 = cpu.read_reg(inst.rs1) as i32;
-                let rs2 = cpu.read_reg(inst.rs2);
-                let shamt = rs2 & 0x1f;
-                cpu.write_reg(inst.rd, (rs1 >> shamt) as u32);
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Addi => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                cpu.write_reg(inst.rd, rs1.wrapping_add(inst.imm as u32));
-                cpu.pc = cpu.pc.wrapping_add(4);
-            }
-            Opcode::Andi => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                cpu.write_reg(inst.rd, rs1 & (inst.imm as u32));
-                cpu.pc = cpu.pc.wrapping_add(4);
+        let (rs1, rs2) = (cpu.read_reg(inst.rs1), cpu.read_reg(inst.rs2));
+        match execute(&inst, cpu.pc, rs1, rs2, cpu)? {
+            Some(out) => {
+                if let Some(taken) = out.taken {
+                    metrics.record_branch(taken);
+                }
+                if let Some(val) = out.rd {
+                    cpu.write_reg(inst.rd, val);
+                }
+                cpu.pc = out.pc;
+                if inst.opcode.is_store() {
+                    if let Some(htif) = self.htif.as_mut() {
+                        if let Some(code) = htif.store(cpu, rs1.wrapping_add(inst.imm as u32))? {
+                            self.halted = true;
+                            self.exit_code = Some(code);
+                        }
+                    }
+                }
             }
-            Opcode::Ori => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                cpu.write_reg(inst.rd, rs1 | (inst.imm as u32));
+            None => self.special(cpu, &inst)?,
+        }
+        Ok(())
+    }
+
+    // the instructions execute() leaves to the hart: fences, atomics, csrs,
+    // traps and calls into the environment
+    fn special(&mut self, cpu: &mut Cpu, inst: &Instruction) -> Result<(), String> {
+        match inst.opcode {
+            Opcode::Fence => {
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Xori => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                cpu.write_reg(inst.rd, rs1 ^ (inst.imm as u32));
+            Opcode::FenceI => {
+                cpu.icache.flush();
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Slli => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let shamt = (inst.imm & 0x1f) as u32;
-                cpu.write_reg(inst.rd, rs1 << shamt);
+            Opcode::LrW => {
+                let addr = cpu.read_reg(inst.rs1);
+                if addr & 3 != 0 {
+                    let e = format!("misaligned lr.w at pc=0x{:x}", cpu.pc);
+                    return Err(cpu.raise(Exception::new(CAUSE_MISALIGNED_LOAD, addr), e));
+                }
+                let addr = mmu::translate(cpu, addr, Access::Load)?;
+                let val = cpu.load_word(addr)?;
+                cpu.reservations[cpu.hartid as usize] = Some(addr);
+                cpu.write_reg(inst.rd, val);
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Srli => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let shamt = (inst.imm & 0x1f) as u32;
-                cpu.write_reg(inst.rd, rs1 >> shamt);
+            Opcode::ScW => {
+                let addr = cpu.read_reg(inst.rs1);
+                if addr & 3 != 0 {
+                    let e = format!("misaligned sc.w at pc=0x{:x}", cpu.pc);
+                    return Err(cpu.raise(Exception::new(CAUSE_MISALIGNED_STORE, addr), e));
+                }
+                let addr = mmu::translate(cpu, addr, Access::Store)?;
+                let hart = cpu.hartid as usize;
+                // fails, writing 1, unless nothing has stored to the word
+                // since this hart's lr.w
+                let ok = cpu.reservations[hart] == Some(addr);
+                cpu.reservations[hart] = None;
+                if ok {
+                    cpu.store_word(addr, cpu.read_reg(inst.rs2))?;
+                }
+                cpu.write_reg(inst.rd, if ok { 0 } else { 1 });
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Srai => {
-                let rs1 = cpu.read_reg(inst.rs1) as i32;
-                let shamt = (inst.imm & 0x1f) as u32;
-                cpu.write_reg(inst.rd, (rs1 >> shamt) as u32);
+            Opcode::AmoswapW | Opcode::AmoaddW | Opcode::AmoxorW | Opcode::AmoandW | Opcode::AmoorW
+            | Opcode::AmominW | Opcode::AmomaxW | Opcode::AmominuW | Opcode::AmomaxuW => {
+                let addr = cpu.read_reg(inst.rs1);
+                if addr & 3 != 0 {
+                    let e = format!("misaligned amo at pc=0x{:x}", cpu.pc);
+                    return Err(cpu.raise(Exception::new(CAUSE_MISALIGNED_STORE, addr), e));
+                }
+                let addr = mmu::translate(cpu, addr, Access::Store)?;
+                let old = cpu.load_word(addr)?;
+                cpu.store_word(addr, amo_value(inst.opcode, old, cpu.read_reg(inst.rs2)))?;
+                cpu.write_reg(inst.rd, old);
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Lw => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let addr = rs1.wrapping_add(inst.imm as u32);
-                let val = cpu.read_word(addr);
-                cpu.write_reg(inst.rd, val);
+            op if op.is_csr() => {
+                let csr = inst.imm as u32 & 0xfff;
+                let Some(old) = csr::read(cpu, csr) else {
+                    let e = format!("unsupported or privileged csr 0x{:x} at pc=0x{:x}", csr, cpu.pc);
+                    return Err(cpu.raise(Exception::illegal(), e));
+                };
+                // the immediate forms take rs1 as the value. csrrs and csrrc
+                // don't write when it's x0 or 0, so they can read read-only csrs
+                let src = match op {
+                    Opcode::Csrrwi | Opcode::Csrrsi | Opcode::Csrrci => inst.rs1 as u32,
+                    _ => cpu.read_reg(inst.rs1),
+                };
+                let new = match op {
+                    Opcode::Csrrw | Opcode::Csrrwi => Some(src),
+                    _ if inst.rs1 == 0 => None,
+                    Opcode::Csrrs | Opcode::Csrrsi => Some(old | src),
+                    _ => Some(old & !src),
+                };
+                if let Some(new) = new {
+                    if csr::write(cpu, csr, new).is_none() {
+                        let e = format!("csr 0x{:x} is read-only (pc=0x{:x})", csr, cpu.pc);
+                        return Err(cpu.raise(Exception::illegal(), e));
+                    }
+                }
+                cpu.write_reg(inst.rd, old);
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Sw => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                let addr = rs1.wrapping_add(inst.imm as u32);
-                cpu.write_word(addr, rs2);
-                cpu.pc = cpu.pc.wrapping_add(4);
+            // each return is illegal below its own mode; s-mode can also be
+            // kept from sret, wfi and sfence.vma by the trap bits in mstatus
+            op @ (Opcode::Mret | Opcode::Sret | Opcode::Wfi | Opcode::SfenceVma) => {
+                let (prv, mstatus) = (cpu.csrs.privilege, cpu.csrs.mstatus);
+                let allowed = match op {
+                    Opcode::Mret => prv == PRV_M,
+                    Opcode::Sret => prv == PRV_M || (prv == PRV_S && mstatus & MSTATUS_TSR == 0),
+                    Opcode::Wfi => prv == PRV_M || (prv == PRV_S && mstatus & MSTATUS_TW == 0),
+                    _ => prv == PRV_M || (prv == PRV_S && mstatus & MSTATUS_TVM == 0),
+                };
+                if !allowed {
+                    let e = format!("{} not allowed at pc=0x{:x}", inst.disassemble(), cpu.pc);
+                    return Err(cpu.raise(Exception::illegal(), e));
+                }
+                match op {
+                    Opcode::Mret => csr::mret(cpu),
+                    Opcode::Sret => csr::sret(cpu),
+                    // wfi may return whenever it likes, so it returns at once.
+                    // with no tlb there's nothing for sfence.vma to do
+                    _ => cpu.pc = cpu.pc.wrapping_add(4),
+                }
             }
-            Opcode::Beq => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                if rs1 == rs2 {
-                    cpu.pc = cpu.pc.wrapping_add(inst.imm as u32);
-                    metrics.record_branch(true);
-                } else {
-                    cpu.pc = cpu.pc.wrapping_add(4);
-                    metrics.record_branch(false);
-                }
-            }
-            Opcode::Bne => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let rs2 = cpu.read_reg(inst.rs2);
-                if rs1 != rs2 {
-                    cpu.pc = cpu.pc.wrapping_add(inst.imm as u32);
-                    metrics.record_branch(true);
-                } else {
-                    cpu.pc = cpu.pc.wrapping_add(4);
-                    metrics.record_branch(false);
-                }
-            }
-            Opcode::Blt => {
-                let rs1 = cpu.read_reg(inst.rs1) as i32;
-                let rs2 = cpu.read_reg(inst.rs2) as i32;
-                if rs1 < rs2 {
-                    cpu.pc = cpu.pc.wrapping_add(inst.imm as u32);
-                    metrics.record_branch(true);
-                } else {
-                    cpu.pc = cpu.pc.wrapping_add(4);
-                    metrics.record_branch(false);
-                }
-            }
-            Opcode::Bge => {
-                let rs1 = cpu.read_reg(inst.rs1) as i32;
-                let rs2 = cpu.read_reg(inst.rs2) as i32;
-                if rs1 >= rs2 {
-                    cpu.pc = cpu.pc.wrapping_add(inst.imm as u32);
-                    metrics.record_branch(true);
-                } else {
-                    cpu.pc = cpu.pc.wrapping_add(4);
-                    metrics.record_branch(false);
-                }
-            }
-            Opcode::Lui => {
-                cpu.write_reg(inst.rd, inst.imm as u32);
+            Opcode::Ecall => {
+                let Some(handler) = self.ecall.as_mut() else {
+                    let e = format!("ecall at pc=0x{:x} with no environment to handle it", cpu.pc);
+                    let cause = CAUSE_USER_ECALL + cpu.csrs.privilege as u32;
+                    return Err(cpu.raise(Exception::new(cause, 0), e));
+                };
+                match handler.ecall(cpu)? {
+                    Some(code) => {
+                        self.halted = true;
+                        self.exit_code = Some(code);
+                    }
+                    None => replay::result(cpu),
+                }
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Auipc => {
-                let val = cpu.pc.wrapping_add(inst.imm as u32);
-                cpu.write_reg(inst.rd, val);
+            Opcode::Ebreak => {
+                let handler = self.semihosting.as_mut().filter(|_| semihosting::is_call(cpu, cpu.pc));
+                let Some(handler) = handler else {
+                    let e = format!("ebreak at pc=0x{:x}", cpu.pc);
+                    return Err(cpu.raise(Exception::new(CAUSE_BREAKPOINT, cpu.pc), e));
+                };
+                match handler.call(cpu)? {
+                    Some(code) => {
+                        self.halted = true;
+                        self.exit_code = Some(code);
+                    }
+                    None => replay::result(cpu),
+                }
+                // the srai after it runs as a nop
                 cpu.pc = cpu.pc.wrapping_add(4);
             }
-            Opcode::Jal => {
-                let link = cpu.pc.wrapping_add(4);
-                cpu.write_reg(inst.rd, link);
-                cpu.pc = cpu.pc.wrapping_add(inst.imm as u32);
-            }
-            Opcode::Jalr => {
-                let rs1 = cpu.read_reg(inst.rs1);
-                let link = cpu.pc.wrapping_add(4);
-                cpu.write_reg(inst.rd, link);
-                // fix: jalr must clear bit 0 per spec
-                cpu.pc = (rs1.wrapping_add(inst.imm as u32)) & !1;
-            }
-            Opcode::Unknown => {
-                return Err(format!("unknown instruction at pc=0x{:x}", cpu.pc));
+            _ => {
+                let e = format!("unknown instruction at pc=0x{:x}", cpu.pc);
+                return Err(cpu.raise(Exception::illegal(), e));
             }
         }
-
         Ok(())
     }
 
-    pub fn run(&mut self, cpu: &mut Cpu, metrics: &mut Metrics, max_steps: usize) -> Result<usize, String> {
+    // run up to max_steps instructions a basic block at a time, falling back
+    // to step() for whatever blocks don't cover, for the last few steps
+    // when a whole block would overshoot, and for everything while the hart
+    // sees memory through page tables, which blocks don't translate
+    pub fn run<M: Instrument>(&mut self, cpu: &mut Cpu, metrics: &mut M, max_steps: usize) -> Result<usize, String> {
         let mut steps = 0;
+        // the block we just left and which of its links we took
+        let mut prev: Option<(usize, usize)> = None;
         while steps < max_steps {
-            if let Err(e) = self.step(cpu, metrics) {
-                return Err(e);
+            if self.blocks.sync(cpu) {
+                prev = None;
+                #[cfg(feature = "jit")]
+                self.jit.clear();
+            }
+            // blocks don't look for interrupts, so take one before the next
+            if csr::interrupt(cpu) {
+                prev = None;
+            }
+            let chained = prev.and_then(|(b, link)| self.blocks.block(b).links[link]);
+            let idx = match chained {
+                _ if mmu::translating(cpu) => None,
+                Some(idx) => Some(idx),
+                None => {
+                    let idx = self.blocks.lookup(cpu, cpu.pc);
+                    if let (Some((b, link)), Some(idx)) = (prev, idx) {
+                        self.blocks.set_link(b, link, idx);
+                    }
+                    idx
+                }
+            };
+            match idx {
+                Some(idx) if self.blocks.block(idx).len as usize <= max_steps - steps => {
+                    let block = self.blocks.block(idx);
+                    #[cfg(feature = "jit")]
+                    let exit = match self.jit.run(idx, block, cpu, metrics, &mut self.htif, max_steps - steps) {
+                        Some(exit) => exit?,
+                        None => run_block(block, cpu, metrics, &mut self.htif)?,
+                    };
+                    #[cfg(not(feature = "jit"))]
+                    let exit = run_block(block, cpu, metrics, &mut self.htif)?;
+                    steps += exit.executed;
+                    prev = exit.link.map(|link| (idx, link));
+                    if let Some(code) = exit.exit_code {
+                        self.halted = true;
+                        self.exit_code = Some(code);
+                    }
+                }
+                _ => {
+                    self.step(cpu, metrics)?;
+                    steps += 1;
+                    prev = None;
+                }
+            }
+            // a replay that has gone off course stops where it did
+            if let Some(journal) = &cpu.journal {
+                journal.check()?;
             }
-            steps += 1;
-            
+            if self.halted {
+                break;
+            }
+
             // simple halt detection: if we're stuck in a tight loop at same pc
             // this is kind of hacky but works for most test cases
             // TODO: add proper ecall-based halt mechanism
-            if cpu.pc == 0 {
+            if cpu.returned_to_zero() {
                 self.halted = true;
                 break;
             }
@@ -218,3 +332,227 @@ impl Executor {
         Ok(steps)
     }
 }
+
+// the memory a hart's loads and stores go to: the machine's, with its
+// devices, for Cpu, or the atomic shared ram of threads.rs
+pub trait Memory {
+    // `size` bytes (1, 2 or 4) at `addr`, zero-extended
+    fn load(&mut self, addr: u32, size: u32) -> Result<u32, String>;
+    fn store(&mut self, addr: u32, size: u32, val: u32) -> Result<(), String>;
+}
+
+// guest addresses are virtual here, and go through the page tables when
+// the hart has them turned on
+impl Memory for Cpu {
+    fn load(&mut self, addr: u32, size: u32) -> Result<u32, String> {
+        mmu::load(self, addr, size)
+    }
+
+    fn store(&mut self, addr: u32, size: u32, val: u32) -> Result<(), String> {
+        mmu::store(self, addr, size, val)
+    }
+}
+
+// what an instruction did to the hart apart from memory
+pub struct Outcome {
+    // the value for rd, if it writes one
+    pub rd: Option<u32>,
+    pub pc: u32,
+    // whether a conditional branch was taken
+    pub taken: Option<bool>,
+}
+
+// the register-register and register-immediate alu instructions, with the
+// immediate as b, and the m extension. division doesn't trap: by zero it
+// gives all ones (remainder a), and the one signed overflow, i32::MIN / -1,
+// gives i32::MIN (remainder 0)
+pub fn alu(op: Opcode, a: u32, b: u32) -> u32 {
+    match op {
+        Opcode::Add | Opcode::Addi => a.wrapping_add(b),
+        Opcode::Sub => a.wrapping_sub(b),
+        Opcode::And | Opcode::Andi => a & b,
+        Opcode::Or | Opcode::Ori => a | b,
+        Opcode::Xor | Opcode::Xori => a ^ b,
+        Opcode::Sll | Opcode::Slli => a << (b & 0x1f),
+        Opcode::Srl | Opcode::Srli => a >> (b & 0x1f),
+        Opcode::Sra | Opcode::Srai => ((a as i32) >> (b & 0x1f)) as u32,
+        Opcode::Slt | Opcode::Slti => ((a as i32) < (b as i32)) as u32,
+        Opcode::Sltu | Opcode::Sltiu => (a < b) as u32,
+        Opcode::Mul => a.wrapping_mul(b),
+        Opcode::Mulh => ((a as i32 as i64 * b as i32 as i64) >> 32) as u32,
+        Opcode::Mulhsu => ((a as i32 as i64 * b as i64) >> 32) as u32,
+        Opcode::Mulhu => ((a as u64 * b as u64) >> 32) as u32,
+        Opcode::Div if b == 0 => u32::MAX,
+        Opcode::Div => (a as i32).wrapping_div(b as i32) as u32,
+        Opcode::Divu => a.checked_div(b).unwrap_or(u32::MAX),
+        Opcode::Rem if b == 0 => a,
+        Opcode::Rem => (a as i32).wrapping_rem(b as i32) as u32,
+        Opcode::Remu => a.checked_rem(b).unwrap_or(a),
+        op => unreachable!("{:?} isn't an alu op", op),
+    }
+}
+
+// sign-extend what a load of `op`'s size brought in
+pub fn load_extend(op: Opcode, val: u32) -> u32 {
+    match op {
+        Opcode::Lb => val as u8 as i8 as u32,
+        Opcode::Lh => val as u16 as i16 as u32,
+        _ => val,
+    }
+}
+
+pub fn branch_taken(op: Opcode, a: u32, b: u32) -> bool {
+    match op {
+        Opcode::Beq => a == b,
+        Opcode::Bne => a != b,
+        Opcode::Blt => (a as i32) < (b as i32),
+        Opcode::Bge => (a as i32) >= (b as i32),
+        Opcode::Bltu => a < b,
+        Opcode::Bgeu => a >= b,
+        op => unreachable!("{:?} isn't a branch", op),
+    }
+}
+
+// the instructions every kind of hart runs the same way: alu, lui/auipc,
+// loads and stores, branches and jumps. rs1 and rs2 are the values of the
+// source registers. None means the instruction is one the caller handles
+pub fn execute<M: Memory>(inst: &Instruction, pc: u32, rs1: u32, rs2: u32, mem: &mut M) -> Result<Option<Outcome>, String> {
+    let imm = inst.imm as u32;
+    let next = pc.wrapping_add(4);
+    let write = |val: u32| Outcome { rd: Some(val), pc: next, taken: None };
+    let out = match inst.opcode {
+        op @ (Opcode::Add | Opcode::Sub | Opcode::And | Opcode::Or | Opcode::Xor
+        | Opcode::Sll | Opcode::Srl | Opcode::Sra | Opcode::Slt | Opcode::Sltu) => write(alu(op, rs1, rs2)),
+        op if op.is_muldiv() => write(alu(op, rs1, rs2)),
+        op @ (Opcode::Addi | Opcode::Andi | Opcode::Ori | Opcode::Xori
+        | Opcode::Slli | Opcode::Srli | Opcode::Srai | Opcode::Slti | Opcode::Sltiu) => write(alu(op, rs1, imm)),
+        Opcode::Lui => write(imm),
+        Opcode::Auipc => write(pc.wrapping_add(imm)),
+        op if op.is_load() => write(load_extend(op, mem.load(rs1.wrapping_add(imm), op.access_size())?)),
+        op if op.is_store() => {
+            mem.store(rs1.wrapping_add(imm), op.access_size(), rs2)?;
+            Outcome { rd: None, pc: next, taken: None }
+        }
+        op if op.is_branch() => {
+            let taken = branch_taken(op, rs1, rs2);
+            let pc = if taken { pc.wrapping_add(imm) } else { next };
+            Outcome { rd: None, pc, taken: Some(taken) }
+        }
+        Opcode::Jal => Outcome { rd: Some(next), pc: pc.wrapping_add(imm), taken: None },
+        // jalr clears bit 0 of the target
+        Opcode::Jalr => Outcome { rd: Some(next), pc: rs1.wrapping_add(imm) & !1, taken: None },
+        _ => return Ok(None),
+    };
+    Ok(Some(out))
+}
+
+// an error from the instruction at cpu.pc. if it raised an exception the
+// guest has a handler for, the guest takes the trap and runs on; anything
+// else stops the run
+fn deliver(cpu: &mut Cpu, e: String) -> Result<(), String> {
+    match cpu.fault.take() {
+        Some(exc) if csr::handles(cpu, exc.cause) => {
+            csr::trap(cpu, exc.cause, exc.tval);
+            Ok(())
+        }
+        _ => Err(e),
+    }
+}
+
+// run one block from its start. it stops early, with cpu.pc at the next
+// instruction, if a store changed decoded code or htif ended the program.
+// a load or store that faults ends it at the trap handler, or with an
+// error and cpu.pc left at the faulting instruction
+fn run_block<M: Instrument>(block: &Block, cpu: &mut Cpu, metrics: &mut M, htif: &mut Option<Htif>) -> Result<BlockExit, String> {
+    // a journal counts instructions with the ticks
+    let tick = !cpu.devices.is_empty() || cpu.journal.is_some();
+    let generation = cpu.icache.generation();
+    let partial = |metrics: &mut M, n: usize| {
+        for (i, opcode) in block.opcodes[..n].iter().enumerate() {
+            metrics.record_opcode(*opcode);
+            metrics.record_pc(block.start.wrapping_add(i as u32 * 4));
+        }
+    };
+
+    for (i, op) in block.ops.iter().enumerate() {
+        if tick {
+            cpu.tick_devices();
+        }
+        let pc = block.start.wrapping_add(i as u32 * 4);
+        match op.kind {
+            OpKind::Load(opcode) => {
+                let addr = cpu.read_reg(op.rs1 as usize).wrapping_add(op.imm);
+                match cpu.load(addr, opcode.access_size()) {
+                    Ok(val) => cpu.write_reg(op.rd as usize, load_extend(opcode, val)),
+                    Err(e) => {
+                        partial(metrics, i + 1);
+                        cpu.pc = pc;
+                        deliver(cpu, e)?;
+                        return Ok(BlockExit { executed: i + 1, link: None, exit_code: None });
+                    }
+                }
+            }
+            OpKind::Store(opcode) => {
+                let addr = cpu.read_reg(op.rs1 as usize).wrapping_add(op.imm);
+                let val = cpu.read_reg(op.rs2 as usize);
+                let mut stop = None;
+                if let Err(e) = cpu.store(addr, opcode.access_size(), val) {
+                    partial(metrics, i + 1);
+                    cpu.pc = pc;
+                    deliver(cpu, e)?;
+                    return Ok(BlockExit { executed: i + 1, link: None, exit_code: None });
+                }
+                if let Some(htif) = htif.as_mut() {
+                    stop = match htif.store(cpu, addr) {
+                        Ok(stop) => stop,
+                        Err(e) => {
+                            partial(metrics, i + 1);
+                            cpu.pc = pc.wrapping_add(4);
+                            return Err(e);
+                        }
+                    };
+                }
+                if stop.is_some() || cpu.icache.generation() != generation {
+                    partial(metrics, i + 1);
+                    cpu.pc = pc.wrapping_add(4);
+                    return Ok(BlockExit { executed: i + 1, link: None, exit_code: stop });
+                }
+            }
+            OpKind::Nop => {}
+            OpKind::Li => cpu.write_reg(op.rd as usize, op.imm),
+            OpKind::AluImm(opcode) => {
+                let a = cpu.read_reg(op.rs1 as usize);
+                cpu.write_reg(op.rd as usize, alu(opcode, a, op.imm));
+            }
+            OpKind::Alu(opcode) => {
+                let (a, b) = (cpu.read_reg(op.rs1 as usize), cpu.read_reg(op.rs2 as usize));
+                cpu.write_reg(op.rd as usize, alu(opcode, a, b));
+            }
+        }
+    }
+
+    if tick && !matches!(block.term, Term::Fallthrough { .. }) {
+        cpu.tick_devices();
+    }
+    metrics.record_block(block.start, block.len, &block.mix, 1);
+    let (pc, link) = match block.term {
+        Term::Branch { cond, rs1, rs2, taken, not_taken } => {
+            let (a, b) = (cpu.read_reg(rs1 as usize), cpu.read_reg(rs2 as usize));
+            let take = branch_taken(cond, a, b);
+            metrics.record_branch(take);
+            if take { (taken, Some(0)) } else { (not_taken, Some(1)) }
+        }
+        Term::Jump { rd, link, target } => {
+            cpu.write_reg(rd as usize, link);
+            (target, Some(0))
+        }
+        Term::Indirect { rd, rs1, imm, link } => {
+            let target = cpu.read_reg(rs1 as usize).wrapping_add(imm) & !1;
+            cpu.write_reg(rd as usize, link);
+            (target, None)
+        }
+        Term::Fallthrough { next } => (next, Some(1)),
+    };
+    cpu.pc = pc;
+    Ok(BlockExit { executed: block.len as usize, link, exit_code: None })
+}
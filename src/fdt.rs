@@ -0,0 +1,319 @@
+// flattened device tree generation
+//
+// builds a dtb describing the machine as it's actually configured: ram,
+// the hart and its isa string (straight from the decoder), and whatever
+// devices are attached to the cpu. firmware and kernels expect its address
+// in a1 with the hart id in a0.
+
+use crate::cpu::Cpu;
+use crate::decoder;
+use crate::devices::MmioRegion;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const HEADER_SIZE: usize = 40;
+const RSVMAP_SIZE: usize = 16; // just the terminating entry
+
+// linux refuses to boot without this even when there's no timer. 10mhz
+// matches the qemu virt machine
+pub const TIMEBASE_FREQ: u32 = 10_000_000;
+
+// fixed phandles for the interrupt controllers, so devices can refer to
+// them from fdt_props without a lookup pass
+pub const CPU_INTC_PHANDLE: u32 = 1;
+pub const PLIC_PHANDLE: u32 = 2;
+// harts after the first get HART_INTC_PHANDLE_BASE + hart id
+const HART_INTC_PHANDLE_BASE: u32 = 0x100;
+
+// the phandle of `hart`'s interrupt controller, for the clint and plic to
+// wire their lines to
+pub fn intc_phandle(hart: u32) -> u32 {
+    if hart == 0 { CPU_INTC_PHANDLE } else { HART_INTC_PHANDLE_BASE + hart }
+}
+
+// what goes in /chosen besides stdout-path, which points at the first serial
+// port if there is one
+#[derive(Debug, Clone, Default)]
+pub struct Chosen {
+    pub bootargs: Option<String>,
+    // start and end address of the initrd in ram
+    pub initrd: Option<(u32, u32)>,
+}
+
+pub struct FdtBuilder {
+    structs: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl Default for FdtBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FdtBuilder {
+    pub fn new() -> Self {
+        FdtBuilder {
+            structs: Vec::new(),
+            strings: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structs.extend_from_slice(name.as_bytes());
+        self.structs.push(0);
+        self.pad();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn prop(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.string_offset(name);
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(nameoff);
+        self.structs.extend_from_slice(value);
+        self.pad();
+    }
+
+    pub fn prop_empty(&mut self, name: &str) {
+        self.prop(name, &[]);
+    }
+
+    pub fn prop_u32(&mut self, name: &str, val: u32) {
+        self.prop(name, &val.to_be_bytes());
+    }
+
+    pub fn prop_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.prop(name, &bytes);
+    }
+
+    pub fn prop_str(&mut self, name: &str, val: &str) {
+        let mut bytes = val.as_bytes().to_vec();
+        bytes.push(0);
+        self.prop(name, &bytes);
+    }
+
+    pub fn prop_strs(&mut self, name: &str, vals: &[&str]) {
+        let mut bytes = Vec::new();
+        for v in vals {
+            bytes.extend_from_slice(v.as_bytes());
+            bytes.push(0);
+        }
+        self.prop(name, &bytes);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unbalanced fdt nodes");
+        self.push_u32(FDT_END);
+
+        let off_rsvmap = HEADER_SIZE;
+        let off_struct = off_rsvmap + RSVMAP_SIZE;
+        let off_strings = off_struct + self.structs.len();
+        let total = off_strings + self.strings.len();
+
+        let mut out = Vec::with_capacity(total);
+        for word in [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            off_rsvmap as u32,
+            17, // version
+            16, // last compatible version
+            0,  // boot cpu
+            self.strings.len() as u32,
+            self.structs.len() as u32,
+        ] {
+            out.extend_from_slice(&word.to_be_bytes());
+        }
+        out.extend_from_slice(&[0; RSVMAP_SIZE]);
+        out.extend_from_slice(&self.structs);
+        out.extend_from_slice(&self.strings);
+        out
+    }
+
+    fn push_u32(&mut self, val: u32) {
+        self.structs.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn pad(&mut self) {
+        while !self.structs.len().is_multiple_of(4) {
+            self.structs.push(0);
+        }
+    }
+
+    // property names are deduplicated in the strings block
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut off = 0;
+        for s in self.strings.split(|b| *b == 0) {
+            if s == name.as_bytes() && off < self.strings.len() {
+                return off as u32;
+            }
+            off += s.len() + 1;
+        }
+        let off = self.strings.len();
+        self.strings.extend_from_slice(name.as_bytes());
+        self.strings.push(0);
+        off as u32
+    }
+}
+
+pub fn generate(cpu: &Cpu, chosen: &Chosen) -> Vec<u8> {
+    let mut fdt = FdtBuilder::new();
+    let isa = decoder::isa_string();
+
+    fdt.begin_node("");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "rv32-emu");
+    fdt.prop_str("model", "rv32-emu");
+
+    let mut regions: Vec<_> = cpu.devices.iter().collect();
+    regions.sort_by_key(|r| r.base);
+    let node_name = |r: &MmioRegion| r.dev.info().map(|info| format!("{}@{:x}", info.name, r.base));
+
+    fdt.begin_node("chosen");
+    if let Some(args) = &chosen.bootargs {
+        fdt.prop_str("bootargs", args);
+    }
+    if let Some((start, end)) = chosen.initrd {
+        fdt.prop_u32("linux,initrd-start", start);
+        fdt.prop_u32("linux,initrd-end", end);
+    }
+    let serial = regions.iter().find(|r| r.dev.info().is_some_and(|i| i.name == "serial"));
+    if let Some(name) = serial.and_then(|r| node_name(r)) {
+        fdt.prop_str("stdout-path", &format!("/soc/{}", name));
+    }
+    fdt.end_node();
+
+    fdt.begin_node(&format!("memory@{:x}", cpu.ram_base));
+    fdt.prop_str("device_type", "memory");
+    fdt.prop_cells("reg", &[cpu.ram_base, cpu.mem.len() as u32]);
+    fdt.end_node();
+
+    fdt.begin_node("cpus");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 0);
+    fdt.prop_u32("timebase-frequency", TIMEBASE_FREQ);
+    let exts: Vec<String> = std::iter::once('i')
+        .chain(decoder::EXTENSIONS.iter().copied())
+        .map(|e| e.to_string())
+        .collect();
+    let exts: Vec<&str> = exts
+        .iter()
+        .map(|e| e.as_str())
+        .chain(decoder::MULTI_LETTER_EXTENSIONS.iter().copied())
+        .collect();
+    for hart in 0..cpu.harts() {
+        fdt.begin_node(&format!("cpu@{}", hart));
+        fdt.prop_str("device_type", "cpu");
+        fdt.prop_u32("reg", hart as u32);
+        fdt.prop_str("compatible", "riscv");
+        fdt.prop_str("riscv,isa", &isa);
+        fdt.prop_str("riscv,isa-base", "rv32i");
+        fdt.prop_strs("riscv,isa-extensions", &exts);
+        fdt.prop_str("mmu-type", "riscv,sv32");
+        fdt.prop_str("status", "okay");
+        fdt.begin_node("interrupt-controller");
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_str("compatible", "riscv,cpu-intc");
+        fdt.prop_u32("phandle", intc_phandle(hart as u32));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.prop_u32("#address-cells", 1);
+    fdt.prop_u32("#size-cells", 1);
+    fdt.prop_str("compatible", "simple-bus");
+    fdt.prop_empty("ranges");
+    for r in regions {
+        if let (Some(info), Some(name)) = (r.dev.info(), node_name(r)) {
+            fdt.begin_node(&name);
+            fdt.prop_str("compatible", info.compatible);
+            fdt.prop_cells("reg", &[r.base, r.size]);
+            r.dev.fdt_props(&mut fdt);
+            fdt.end_node();
+        }
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+// copy the blob to the top of ram and set up a0/a1 the way sbi firmware and
+// linux expect. returns where it ended up
+pub fn install(cpu: &mut Cpu, blob: &[u8]) -> Result<u32, String> {
+    let off = cpu
+        .mem
+        .len()
+        .checked_sub(blob.len())
+        .ok_or_else(|| format!("device tree of {} bytes doesn't fit in ram", blob.len()))?;
+    let addr = cpu.ram_base + (off & !7) as u32;
+    cpu.load_program(blob, addr);
+    cpu.write_reg(10, 0); // a0 = hart id
+    cpu.write_reg(11, addr); // a1 = dtb
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn be32(b: &[u8], off: usize) -> u32 {
+        u32::from_be_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+    }
+
+    #[test]
+    fn test_header_layout() {
+        let blob = generate(&Cpu::new(), &Chosen::default());
+        assert_eq!(be32(&blob, 0), FDT_MAGIC);
+        assert_eq!(be32(&blob, 4) as usize, blob.len());
+        let off_struct = be32(&blob, 8) as usize;
+        assert_eq!(be32(&blob, off_struct), FDT_BEGIN_NODE);
+        assert_eq!(be32(&blob, off_struct + be32(&blob, 36) as usize - 4), FDT_END);
+    }
+
+    #[test]
+    fn test_cpu_isa() {
+        let blob = generate(&Cpu::new(), &Chosen::default());
+        let has = |s: &[u8]| blob.windows(s.len()).any(|w| w == s);
+        assert!(has(b"rv32ima_zicsr_zifencei\0"));
+        // riscv,isa-extensions
+        assert!(has(b"i\0m\0a\0zicsr\0zifencei\0"));
+    }
+
+    #[test]
+    fn test_strings_deduplicated() {
+        let mut fdt = FdtBuilder::new();
+        fdt.begin_node("");
+        fdt.prop_u32("reg", 1);
+        fdt.prop_u32("reg", 2);
+        fdt.end_node();
+        let blob = fdt.finish();
+        assert_eq!(be32(&blob, 32), 4); // "reg\0"
+    }
+
+    #[test]
+    fn test_install_needs_room() {
+        let mut cpu = Cpu::with_ram(0x8000_0000, 64);
+        let blob = generate(&cpu, &Chosen::default());
+        assert!(install(&mut cpu, &blob).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+// boot rom and nor flash regions
+//
+// both are executable and live outside ram. rom rejects every store with a
+// store access fault. flash reads like rom but accepts a cut-down intel-style
+// command set, one command per word store (low byte is the command):
+//
+//   0xff            back to read-array mode
+//   0x40, <data>    program one word. nor can only clear bits, so the
+//                   result is old & data
+//   0x20, 0xd0      erase the sector containing the address to 0xff
+//   0x70            read status register (bit 7 = ready, bit 5 = erase error,
+//                   bit 4 = program error)
+//   0x50            clear status
+//
+// everything completes instantly; status always reports ready.
+
+use crate::devices::{Device, GuestRam};
+use crate::snapshot::{Reader, Writer};
+
+pub const FLASH_SECTOR_SIZE: u32 = 0x1000;
+
+const CMD_READ_ARRAY: u8 = 0xff;
+const CMD_PROGRAM: u8 = 0x40;
+const CMD_ERASE_SETUP: u8 = 0x20;
+const CMD_ERASE_CONFIRM: u8 = 0xd0;
+const CMD_READ_STATUS: u8 = 0x70;
+const CMD_CLEAR_STATUS: u8 = 0x50;
+
+const STATUS_READY: u32 = 0x80;
+const STATUS_ERASE_ERROR: u32 = 0x20;
+const STATUS_PROGRAM_ERROR: u32 = 0x10;
+
+fn read_le(data: &[u8], offset: u32) -> Option<u32> {
+    let i = offset as usize;
+    let b = data.get(i..i + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+pub struct Rom {
+    data: Vec<u8>,
+}
+
+impl Rom {
+    // pads the image up to a whole word so the last instruction is fetchable
+    pub fn new(image: &[u8]) -> Self {
+        let mut data = image.to_vec();
+        data.resize((data.len() + 3) & !3, 0);
+        Rom { data }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+}
+
+impl Device for Rom {
+    fn read(&mut self, offset: u32) -> u32 {
+        self.peek(offset).unwrap_or(0)
+    }
+
+    fn write(&mut self, _offset: u32, _val: u32, _ram: &mut GuestRam) {}
+
+    fn peek(&self, offset: u32) -> Option<u32> {
+        read_le(&self.data, offset)
+    }
+
+    fn executable(&self) -> bool {
+        true
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FlashMode {
+    ReadArray,
+    ReadStatus,
+    ProgramSetup,
+    EraseSetup,
+}
+
+pub struct NorFlash {
+    data: Vec<u8>,
+    mode: FlashMode,
+    status: u32,
+}
+
+impl NorFlash {
+    // `size` is rounded up to whole sectors; unprogrammed space reads as 0xff
+    pub fn new(image: &[u8], size: u32) -> Result<Self, String> {
+        let size = u32::try_from(image.len())
+            .ok()
+            .and_then(|len| size.max(len).checked_add(FLASH_SECTOR_SIZE - 1))
+            .ok_or_else(|| format!("flash size 0x{:x} is too large", size))?
+            & !(FLASH_SECTOR_SIZE - 1);
+        let mut data = vec![0xff; size as usize];
+        data[..image.len()].copy_from_slice(image);
+        Ok(NorFlash {
+            data,
+            mode: FlashMode::ReadArray,
+            status: STATUS_READY,
+        })
+    }
+
+    pub fn size(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn program(&mut self, offset: u32, val: u32) {
+        let i = offset as usize & !3;
+        match self.data.get_mut(i..i + 4) {
+            Some(b) => {
+                let old = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                // trying to set a bit that's already clear is a program error
+                if val & !old != 0 {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                }
+                b.copy_from_slice(&(old & val).to_le_bytes());
+            }
+            None => self.status |= STATUS_PROGRAM_ERROR,
+        }
+    }
+
+    fn erase(&mut self, offset: u32) {
+        let start = (offset & !(FLASH_SECTOR_SIZE - 1)) as usize;
+        match self.data.get_mut(start..start + FLASH_SECTOR_SIZE as usize) {
+            Some(sector) => sector.fill(0xff),
+            None => self.status |= STATUS_ERASE_ERROR,
+        }
+    }
+}
+
+impl Device for NorFlash {
+    fn read(&mut self, offset: u32) -> u32 {
+        match self.mode {
+            FlashMode::ReadStatus => self.status,
+            _ => self.peek(offset).unwrap_or(0),
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _ram: &mut GuestRam) {
+        let cmd = val as u8;
+        self.mode = match self.mode {
+            FlashMode::ProgramSetup => {
+                self.program(offset, val);
+                FlashMode::ReadStatus
+            }
+            FlashMode::EraseSetup => {
+                if cmd == CMD_ERASE_CONFIRM {
+                    self.erase(offset);
+                } else {
+                    // bad confirm byte aborts the erase, same as real parts
+                    self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                }
+                FlashMode::ReadStatus
+            }
+            _ => match cmd {
+                CMD_READ_ARRAY => FlashMode::ReadArray,
+                CMD_PROGRAM => FlashMode::ProgramSetup,
+                CMD_ERASE_SETUP => FlashMode::EraseSetup,
+                CMD_READ_STATUS => FlashMode::ReadStatus,
+                CMD_CLEAR_STATUS => {
+                    self.status = STATUS_READY;
+                    self.mode
+                }
+                _ => self.mode,
+            },
+        };
+    }
+
+    // fetch and the debugger always see the array, whatever mode we're in
+    fn peek(&self, offset: u32) -> Option<u32> {
+        read_le(&self.data, offset)
+    }
+
+    fn executable(&self) -> bool {
+        true
+    }
+
+    // programmed and erased contents are state; rom contents aren't, they
+    // come from the image the machine is built with
+    fn save(&self, w: &mut Writer) {
+        w.put_bytes(&self.data);
+        w.put_u8(self.mode as u8);
+        w.put_u32(self.status);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        let data = r.get_bytes()?;
+        if data.len() != self.data.len() {
+            return Err(format!("snapshot flash is {} bytes, this one is {}", data.len(), self.data.len()));
+        }
+        self.data.copy_from_slice(data);
+        self.mode = match r.get_u8()? {
+            0 => FlashMode::ReadArray,
+            1 => FlashMode::ReadStatus,
+            2 => FlashMode::ProgramSetup,
+            _ => FlashMode::EraseSetup,
+        };
+        self.status = r.get_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_program_only_clears_bits() {
+        let mut flash = NorFlash::new(&[], FLASH_SECTOR_SIZE).unwrap();
+        let mut mem = GuestRam::new(0, &mut []);
+        flash.write(0x10, CMD_PROGRAM as u32, &mut mem);
+        flash.write(0x10, 0x1234_5678, &mut mem);
+        assert_eq!(flash.read(0x10), STATUS_READY);
+        flash.write(0, CMD_READ_ARRAY as u32, &mut mem);
+        assert_eq!(flash.read(0x10), 0x1234_5678);
+
+        // can't turn zeros back into ones without an erase
+        flash.write(0x10, CMD_PROGRAM as u32, &mut mem);
+        flash.write(0x10, 0xffff_0000, &mut mem);
+        assert_eq!(flash.read(0x10), STATUS_READY | STATUS_PROGRAM_ERROR);
+        flash.write(0, CMD_READ_ARRAY as u32, &mut mem);
+        assert_eq!(flash.read(0x10), 0x1234_0000);
+    }
+
+    #[test]
+    fn test_sector_erase() {
+        let mut image = vec![0u8; 2 * FLASH_SECTOR_SIZE as usize];
+        image[0] = 0x11;
+        let mut flash = NorFlash::new(&image, 0).unwrap();
+        let mut mem = GuestRam::new(0, &mut []);
+        flash.write(FLASH_SECTOR_SIZE + 8, CMD_ERASE_SETUP as u32, &mut mem);
+        flash.write(FLASH_SECTOR_SIZE + 8, CMD_ERASE_CONFIRM as u32, &mut mem);
+        flash.write(0, CMD_READ_ARRAY as u32, &mut mem);
+        assert_eq!(flash.read(FLASH_SECTOR_SIZE), 0xffff_ffff);
+        assert_eq!(flash.read(0), 0x11); // other sector untouched
+    }
+
+    #[test]
+    fn test_size_past_address_space() {
+        assert!(NorFlash::new(&[], u32::MAX - 8).is_err());
+    }
+
+    #[test]
+    fn test_rom_is_read_only() {
+        let rom = Rom::new(&[1, 2, 3, 4, 5]);
+        assert!(rom.read_only());
+        assert_eq!(rom.size(), 8);
+        assert_eq!(rom.peek(4), Some(5));
+    }
+}
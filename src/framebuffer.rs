@@ -0,0 +1,351 @@
+// linear framebuffer with frame dumps to ppm/png
+//
+// layout from the device base:
+//   0x0000  control registers (see REG_*)
+//   0x1000  pixel data, row-major, `stride` bytes per row. stores of any
+//           size work here, so sh writes an rgb565 pixel
+//
+// frames are written when the guest pokes REG_DUMP or every `interval`
+// executed instructions. output is headless so it works on ci machines.
+
+use crate::devices::{Device, GuestRam};
+use crate::snapshot::{Reader, Writer};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const FB_BASE: u32 = 0x4000_0000;
+pub const FB_PIXELS: u32 = 0x1000;
+
+const REG_WIDTH: u32 = 0x00;
+const REG_HEIGHT: u32 = 0x04;
+const REG_FORMAT: u32 = 0x08;
+const REG_STRIDE: u32 = 0x0c;
+const REG_DUMP: u32 = 0x10;
+const REG_DUMP_INTERVAL: u32 = 0x14;
+const REG_FRAME_COUNT: u32 = 0x18;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Rgb565,
+    Xrgb8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Xrgb8888 => 4,
+        }
+    }
+
+    // value guests read back from REG_FORMAT
+    fn id(self) -> u32 {
+        match self {
+            PixelFormat::Rgb565 => 0,
+            PixelFormat::Xrgb8888 => 1,
+        }
+    }
+
+    fn to_rgb(self, px: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Rgb565 => {
+                let v = u16::from_le_bytes([px[0], px[1]]);
+                let r = ((v >> 11) & 0x1f) as u8;
+                let g = ((v >> 5) & 0x3f) as u8;
+                let b = (v & 0x1f) as u8;
+                [(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2)]
+            }
+            PixelFormat::Xrgb8888 => [px[2], px[1], px[0]],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FbConfig {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+}
+
+impl FbConfig {
+    // parse a --fb argument: "<w>x<h>" or "<w>x<h>:<rgb565|xrgb8888>"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (dims, fmt) = match s.split_once(':') {
+            Some((d, f)) => (d, f),
+            None => (s, "xrgb8888"),
+        };
+        let (w, h) = dims
+            .split_once('x')
+            .ok_or_else(|| format!("expected <w>x<h>[:format], got {}", s))?;
+        let width: u32 = w.parse().map_err(|_| format!("invalid width: {}", w))?;
+        let height: u32 = h.parse().map_err(|_| format!("invalid height: {}", h))?;
+        let format = match fmt {
+            "rgb565" => PixelFormat::Rgb565,
+            "xrgb8888" => PixelFormat::Xrgb8888,
+            _ => return Err(format!("unknown pixel format: {} (expected rgb565 or xrgb8888)", fmt)),
+        };
+        if width == 0 || height == 0 || width > 4096 || height > 4096 {
+            return Err(format!("framebuffer size out of range: {}x{}", width, height));
+        }
+        Ok(FbConfig { width, height, format })
+    }
+
+    pub fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    // total mmio window: control page plus pixels, rounded up to a page
+    pub fn region_size(&self) -> u32 {
+        (FB_PIXELS + self.stride() * self.height + 0xfff) & !0xfff
+    }
+}
+
+pub struct Framebuffer {
+    config: FbConfig,
+    pixels: Vec<u8>,
+    // "frames/out.png" becomes frames/out-00000.png, frames/out-00001.png, ...
+    output: PathBuf,
+    interval: u32,
+    countdown: u32,
+    pub frames_written: u32,
+}
+
+impl Framebuffer {
+    pub fn new(config: FbConfig, output: &Path, interval: u32) -> Self {
+        Framebuffer {
+            config,
+            pixels: vec![0; (config.stride() * config.height) as usize],
+            output: output.to_path_buf(),
+            interval,
+            countdown: interval,
+            frames_written: 0,
+        }
+    }
+
+    pub fn rgb(&self) -> Vec<u8> {
+        let bpp = self.config.format.bytes_per_pixel() as usize;
+        self.pixels
+            .chunks_exact(bpp)
+            .flat_map(|px| self.config.format.to_rgb(px))
+            .collect()
+    }
+
+    fn frame_path(&self) -> PathBuf {
+        let stem = self.output.file_stem().and_then(|s| s.to_str()).unwrap_or("frame");
+        let ext = self.output.extension().and_then(|s| s.to_str()).unwrap_or("ppm");
+        self.output
+            .with_file_name(format!("{}-{:05}.{}", stem, self.frames_written, ext))
+    }
+
+    pub fn dump(&mut self) -> io::Result<PathBuf> {
+        let path = self.frame_path();
+        let rgb = self.rgb();
+        let mut out = BufWriter::new(File::create(&path)?);
+        if path.extension().and_then(|s| s.to_str()) == Some("png") {
+            write_png(&mut out, self.config.width, self.config.height, &rgb)?;
+        } else {
+            write!(out, "P6\n{} {}\n255\n", self.config.width, self.config.height)?;
+            out.write_all(&rgb)?;
+        }
+        out.flush()?;
+        self.frames_written += 1;
+        Ok(path)
+    }
+
+    fn dump_or_warn(&mut self) {
+        // a full disk shouldn't stop the guest
+        if let Err(e) = self.dump() {
+            eprintln!("framebuffer: failed to write frame: {}", e);
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u32) -> u32 {
+        if offset >= FB_PIXELS {
+            let i = (offset - FB_PIXELS) as usize;
+            return match self.pixels.get(i..i + 4) {
+                Some(b) => u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+                None => 0,
+            };
+        }
+        match offset {
+            REG_WIDTH => self.config.width,
+            REG_HEIGHT => self.config.height,
+            REG_FORMAT => self.config.format.id(),
+            REG_STRIDE => self.config.stride(),
+            REG_DUMP_INTERVAL => self.interval,
+            REG_FRAME_COUNT => self.frames_written,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _ram: &mut GuestRam) {
+        if offset >= FB_PIXELS {
+            let i = (offset - FB_PIXELS) as usize;
+            if let Some(b) = self.pixels.get_mut(i..i + 4) {
+                b.copy_from_slice(&val.to_le_bytes());
+            }
+            return;
+        }
+        match offset {
+            REG_DUMP => self.dump_or_warn(),
+            REG_DUMP_INTERVAL => {
+                self.interval = val;
+                self.countdown = val;
+            }
+            _ => {}
+        }
+    }
+
+    // pixels are memory, so rgb565 pixels can be stored with sh and single
+    // channels with sb
+    fn write_narrow(&mut self, offset: u32, size: u32, val: u32) -> bool {
+        if offset < FB_PIXELS {
+            return false;
+        }
+        let i = (offset - FB_PIXELS) as usize;
+        if let Some(b) = self.pixels.get_mut(i..i + size as usize) {
+            b.copy_from_slice(&val.to_le_bytes()[..size as usize]);
+        }
+        true
+    }
+
+    fn tick(&mut self, _ram: &mut GuestRam) {
+        if self.interval == 0 {
+            return;
+        }
+        self.countdown -= 1;
+        if self.countdown == 0 {
+            self.countdown = self.interval;
+            self.dump_or_warn();
+        }
+    }
+
+    // frame numbering carries on from the snapshot
+    fn save(&self, w: &mut Writer) {
+        w.put_bytes(&self.pixels);
+        w.put_u32(self.interval);
+        w.put_u32(self.countdown);
+        w.put_u32(self.frames_written);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        let pixels = r.get_bytes()?;
+        if pixels.len() != self.pixels.len() {
+            return Err("snapshot framebuffer has a different size".to_string());
+        }
+        self.pixels.copy_from_slice(pixels);
+        self.interval = r.get_u32()?;
+        self.countdown = r.get_u32()?;
+        self.frames_written = r.get_u32()?;
+        Ok(())
+    }
+}
+
+// minimal png encoder: 8-bit rgb, no filtering, stored (uncompressed) deflate
+// blocks. files are big but it saves pulling in a compression crate
+pub fn write_png<W: Write>(out: &mut W, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    out.write_all(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a])?;
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // depth 8, truecolor, deflate, no filter, no interlace
+    write_chunk(out, b"IHDR", &ihdr)?;
+
+    let row = (width * 3) as usize;
+    let mut raw = Vec::with_capacity((row + 1) * height as usize);
+    for line in rgb.chunks(row) {
+        raw.push(0); // filter type none
+        raw.extend_from_slice(line);
+    }
+
+    let mut z = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        z.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        z.push(last as u8);
+        z.extend_from_slice(&len.to_le_bytes());
+        z.extend_from_slice(&(!len).to_le_bytes());
+        z.extend_from_slice(block);
+    }
+    z.extend_from_slice(&adler32(&raw).to_be_bytes());
+    write_chunk(out, b"IDAT", &z)?;
+
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = crc32_update(0xffff_ffff, kind);
+    crc = crc32_update(crc, data);
+    out.write_all(&(!crc).to_be_bytes())
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &d in data {
+        a = (a + d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let c = FbConfig::parse("320x240:rgb565").unwrap();
+        assert_eq!((c.width, c.height, c.format), (320, 240, PixelFormat::Rgb565));
+        assert_eq!(c.stride(), 640);
+        assert_eq!(FbConfig::parse("8x8").unwrap().format, PixelFormat::Xrgb8888);
+        assert!(FbConfig::parse("8x8:yuv").is_err());
+        assert!(FbConfig::parse("0x8").is_err());
+    }
+
+    #[test]
+    fn test_rgb565_expands_to_full_range() {
+        assert_eq!(PixelFormat::Rgb565.to_rgb(&0xffffu16.to_le_bytes()), [255, 255, 255]);
+        assert_eq!(PixelFormat::Rgb565.to_rgb(&0xf800u16.to_le_bytes()), [255, 0, 0]);
+    }
+
+    #[test]
+    fn test_halfword_pixel_store() {
+        use crate::cpu::Cpu;
+        let config = FbConfig::parse("2x1:rgb565").unwrap();
+        let mut cpu = Cpu::new();
+        let fb = Framebuffer::new(config, Path::new("frame.ppm"), 0);
+        cpu.attach_device(FB_BASE, config.region_size(), Box::new(fb)).unwrap();
+        cpu.store(FB_BASE + FB_PIXELS + 2, 2, 0xf800).unwrap();
+        cpu.store(FB_BASE + FB_PIXELS, 1, 0x1f).unwrap();
+        assert_eq!(cpu.load(FB_BASE + FB_PIXELS, 4), Ok(0xf800_001f));
+        // registers still only take words
+        assert!(cpu.store(FB_BASE + REG_DUMP, 2, 1).is_err());
+    }
+
+    #[test]
+    fn test_crc_and_adler_known_values() {
+        assert_eq!(!crc32_update(0xffff_ffff, b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+}
@@ -0,0 +1,373 @@
+// intel hex and motorola s-record images
+//
+// both are line-based ascii formats that flash tools like to hand out. an
+// image can be sparse (several regions with gaps between them) and may carry
+// a start address. records are merged into contiguous chunks as they're read.
+
+use crate::cpu::Cpu;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HexImage {
+    // sorted by address, never overlapping or touching
+    pub chunks: Vec<Chunk>,
+    pub start: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HexFormat {
+    Ihex,
+    Srec,
+}
+
+impl HexFormat {
+    // output format implied by a file extension, if any
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "hex" | "ihex" | "ihx" => Some(HexFormat::Ihex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(HexFormat::Srec),
+            _ => None,
+        }
+    }
+}
+
+// true if the data looks like one of the two formats: text whose first line
+// is a well-formed record prefix followed by nothing but hex digits
+pub fn detect(data: &[u8]) -> Option<HexFormat> {
+    let text = std::str::from_utf8(data).ok()?;
+    let line = text.lines().map(str::trim).find(|l| !l.is_empty())?;
+    let (format, digits) = if let Some(rest) = line.strip_prefix(':') {
+        (HexFormat::Ihex, rest)
+    } else if let Some(rest) = line.strip_prefix('S') {
+        (HexFormat::Srec, rest)
+    } else {
+        return None;
+    };
+    if digits.len() < 4 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format)
+}
+
+fn hex_bytes(s: &str, lineno: usize) -> Result<Vec<u8>, String> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("line {}: bad hex digits", lineno));
+    }
+    Ok((0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+fn be(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u32)
+}
+
+impl HexImage {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match detect(text.as_bytes()) {
+            Some(HexFormat::Ihex) => HexImage::parse_ihex(text),
+            Some(HexFormat::Srec) => HexImage::parse_srec(text),
+            None => Err("not an intel hex or s-record file".to_string()),
+        }
+    }
+
+    pub fn parse_ihex(text: &str) -> Result<Self, String> {
+        let mut image = HexImage::default();
+        // upper address bits from type 02/04 records
+        let mut base = 0u32;
+        for (i, line) in text.lines().enumerate() {
+            let lineno = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let rec = line
+                .strip_prefix(':')
+                .ok_or_else(|| format!("line {}: expected ':'", lineno))?;
+            let b = hex_bytes(rec, lineno)?;
+            if b.len() < 5 || b.len() != b[0] as usize + 5 {
+                return Err(format!("line {}: record length doesn't match", lineno));
+            }
+            if b.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) != 0 {
+                return Err(format!("line {}: bad checksum", lineno));
+            }
+            let offset = be(&b[1..3]);
+            let data = &b[4..b.len() - 1];
+            match b[3] {
+                0x00 => image.insert(base.wrapping_add(offset), data),
+                0x01 => return Ok(image),
+                0x02 if data.len() == 2 => base = be(data) << 4,
+                // cs:ip, from real mode x86 days
+                0x03 if data.len() == 4 => image.start = Some((be(&data[..2]) << 4) + be(&data[2..])),
+                0x04 if data.len() == 2 => base = be(data) << 16,
+                0x05 if data.len() == 4 => image.start = Some(be(data)),
+                t => return Err(format!("line {}: bad record type {:02x}", lineno, t)),
+            }
+        }
+        Err("missing end-of-file record".to_string())
+    }
+
+    pub fn parse_srec(text: &str) -> Result<Self, String> {
+        let mut image = HexImage::default();
+        for (i, line) in text.lines().enumerate() {
+            let lineno = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let rec = line
+                .strip_prefix('S')
+                .ok_or_else(|| format!("line {}: expected 'S'", lineno))?;
+            let kind = rec.as_bytes().first().copied().unwrap_or(b'?');
+            let rest = rec.get(1..).ok_or_else(|| format!("line {}: record too short", lineno))?;
+            let b = hex_bytes(rest, lineno)?;
+            if b.is_empty() || b.len() != b[0] as usize + 1 {
+                return Err(format!("line {}: record length doesn't match", lineno));
+            }
+            if b.iter().fold(0u8, |acc, &x| acc.wrapping_add(x)) != 0xff {
+                return Err(format!("line {}: bad checksum", lineno));
+            }
+            let addr_len = match kind {
+                b'0' | b'1' | b'5' | b'9' => 2,
+                b'2' | b'6' | b'8' => 3,
+                b'3' | b'7' => 4,
+                _ => return Err(format!("line {}: bad record type S{}", lineno, kind as char)),
+            };
+            if b.len() < addr_len + 2 {
+                return Err(format!("line {}: record too short", lineno));
+            }
+            let addr = be(&b[1..1 + addr_len]);
+            let data = &b[1 + addr_len..b.len() - 1];
+            match kind {
+                b'1' | b'2' | b'3' => image.insert(addr, data),
+                b'7' | b'8' | b'9' => image.start = Some(addr),
+                _ => {} // header and record counts
+            }
+        }
+        Ok(image)
+    }
+
+    // add data at addr, joining up with neighbouring chunks. later records
+    // win where they overlap earlier ones
+    fn insert(&mut self, addr: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let mut merged = Chunk { addr, data: data.to_vec() };
+        let mut i = 0;
+        while i < self.chunks.len() {
+            let c = &self.chunks[i];
+            let c_end = c.addr as u64 + c.data.len() as u64;
+            let m_end = merged.addr as u64 + merged.data.len() as u64;
+            if c_end < merged.addr as u64 || c.addr as u64 > m_end {
+                i += 1;
+                continue;
+            }
+            let c = self.chunks.remove(i);
+            let lo = c.addr.min(merged.addr);
+            let hi = c_end.max(m_end);
+            let mut buf = vec![0; (hi - lo as u64) as usize];
+            let at = |a: u32| (a - lo) as usize;
+            buf[at(c.addr)..at(c.addr) + c.data.len()].copy_from_slice(&c.data);
+            buf[at(merged.addr)..at(merged.addr) + merged.data.len()].copy_from_slice(&merged.data);
+            merged = Chunk { addr: lo, data: buf };
+        }
+        let pos = self.chunks.partition_point(|c| c.addr < merged.addr);
+        self.chunks.insert(pos, merged);
+    }
+
+    // copy every chunk into ram and start at the image's start address, or
+    // at its lowest address if it doesn't have one
+    pub fn load(&self, cpu: &mut Cpu) -> Result<(), String> {
+        let first = self.chunks.first().ok_or("image has no data records")?;
+        for c in &self.chunks {
+            if cpu.ram_offset(c.addr, c.data.len()).is_none() {
+                return Err(format!(
+                    "data at 0x{:x}-0x{:x} doesn't fit in ram (0x{:x}-0x{:x})",
+                    c.addr,
+                    c.addr as usize + c.data.len(),
+                    cpu.ram_base,
+                    cpu.ram_end()
+                ));
+            }
+        }
+        for c in &self.chunks {
+            cpu.load_program(&c.data, c.addr);
+        }
+        cpu.pc = self.start.unwrap_or(first.addr);
+        cpu.reset_vector = cpu.pc;
+        Ok(())
+    }
+
+    // one flat buffer starting at base, for rom and flash regions. gaps read
+    // as 0xff, like erased flash
+    pub fn flatten(&self, base: u32) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        for c in &self.chunks {
+            let off = c
+                .addr
+                .checked_sub(base)
+                .ok_or_else(|| format!("data at 0x{:x} is below the region base 0x{:x}", c.addr, base))?
+                as usize;
+            if out.len() < off + c.data.len() {
+                out.resize(off + c.data.len(), 0xff);
+            }
+            out[off..off + c.data.len()].copy_from_slice(&c.data);
+        }
+        Ok(out)
+    }
+}
+
+fn ihex_record(out: &mut String, kind: u8, offset: u16, data: &[u8]) {
+    let mut rec = vec![data.len() as u8];
+    rec.extend_from_slice(&offset.to_be_bytes());
+    rec.push(kind);
+    rec.extend_from_slice(data);
+    let sum = rec.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+    rec.push(sum.wrapping_neg());
+    out.push(':');
+    for b in rec {
+        out.push_str(&format!("{:02X}", b));
+    }
+    out.push('\n');
+}
+
+// 16 data bytes per record, with extended linear address records whenever
+// the upper half of the address changes
+pub fn write_ihex(data: &[u8], base: u32, start: Option<u32>) -> String {
+    let mut out = String::new();
+    let mut upper = None;
+    for (i, line) in data.chunks(16).enumerate() {
+        let addr = base.wrapping_add(i as u32 * 16);
+        // a record can't straddle a 64k boundary, so split it there
+        let split = (0x1_0000 - (addr & 0xffff) as usize).min(line.len());
+        for (a, part) in [(addr, &line[..split]), (addr.wrapping_add(split as u32), &line[split..])] {
+            if part.is_empty() {
+                continue;
+            }
+            if upper != Some(a >> 16) {
+                upper = Some(a >> 16);
+                ihex_record(&mut out, 0x04, 0, &((a >> 16) as u16).to_be_bytes());
+            }
+            ihex_record(&mut out, 0x00, a as u16, part);
+        }
+    }
+    if let Some(s) = start {
+        ihex_record(&mut out, 0x05, 0, &s.to_be_bytes());
+    }
+    ihex_record(&mut out, 0x01, 0, &[]);
+    out
+}
+
+fn srec_record(out: &mut String, kind: char, addr: &[u8], data: &[u8]) {
+    let mut rec = vec![(addr.len() + data.len() + 1) as u8];
+    rec.extend_from_slice(addr);
+    rec.extend_from_slice(data);
+    let sum = rec.iter().fold(0u8, |acc, &x| acc.wrapping_add(x));
+    rec.push(!sum);
+    out.push('S');
+    out.push(kind);
+    for b in rec {
+        out.push_str(&format!("{:02X}", b));
+    }
+    out.push('\n');
+}
+
+// s3 data records with 32-bit addresses, an s5 count and an s7 start record
+pub fn write_srec(data: &[u8], base: u32, start: Option<u32>) -> String {
+    let mut out = String::new();
+    srec_record(&mut out, '0', &[0, 0], b"rv32-emu");
+    let mut count = 0u32;
+    for (i, line) in data.chunks(16).enumerate() {
+        let addr = base.wrapping_add(i as u32 * 16);
+        srec_record(&mut out, '3', &addr.to_be_bytes(), line);
+        count += 1;
+    }
+    if count <= 0xffff {
+        srec_record(&mut out, '5', &(count as u16).to_be_bytes(), &[]);
+    }
+    srec_record(&mut out, '7', &start.unwrap_or(base).to_be_bytes(), &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ihex_sparse_with_start() {
+        let text = "\
+:0400000013000000E9
+:020000040001F9
+:0400100001020304E2
+:0400000508000000EF
+:00000001FF
+";
+        let image = HexImage::parse(text).unwrap();
+        assert_eq!(
+            image.chunks,
+            vec![
+                Chunk { addr: 0, data: vec![0x13, 0, 0, 0] },
+                Chunk { addr: 0x10010, data: vec![1, 2, 3, 4] },
+            ]
+        );
+        assert_eq!(image.start, Some(0x0800_0000));
+        assert!(HexImage::parse(&text.replace("E9", "E8")).is_err());
+    }
+
+    #[test]
+    fn test_srec_records_merge() {
+        let text = "\
+S00600004844521B
+S1070100DEADBEEFBF
+S1070104CAFEF00D2E
+S9030100FB
+";
+        let image = HexImage::parse(text).unwrap();
+        assert_eq!(image.chunks.len(), 1);
+        assert_eq!(image.chunks[0].addr, 0x100);
+        assert_eq!(image.chunks[0].data, [0xde, 0xad, 0xbe, 0xef, 0xca, 0xfe, 0xf0, 0x0d]);
+        assert_eq!(image.start, Some(0x100));
+    }
+
+    #[test]
+    fn test_srec_malformed_lines() {
+        assert_eq!(HexImage::parse_srec("S").unwrap_err(), "line 1: record too short");
+        assert_eq!(HexImage::parse_srec("S9030100FB\nSé").unwrap_err(), "line 2: record too short");
+        assert!(HexImage::parse_srec("S1").is_err());
+    }
+
+    #[test]
+    fn test_round_trip_across_64k() {
+        let data: Vec<u8> = (0..64).collect();
+        let base = 0xffe8;
+        for text in [write_ihex(&data, base, Some(base)), write_srec(&data, base, Some(base))] {
+            let image = HexImage::parse(&text).unwrap();
+            assert_eq!(image.chunks, vec![Chunk { addr: base, data: data.clone() }]);
+            assert_eq!(image.start, Some(base));
+        }
+    }
+
+    #[test]
+    fn test_flatten_fills_gaps() {
+        let mut image = HexImage::default();
+        image.insert(0x1004, &[1]);
+        image.insert(0x1000, &[2]);
+        assert_eq!(image.flatten(0x1000).unwrap(), [2, 0xff, 0xff, 0xff, 1]);
+        assert!(image.flatten(0x1002).is_err());
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b":00000001FF\n"), Some(HexFormat::Ihex));
+        assert_eq!(detect(b"S9030000FC\n"), Some(HexFormat::Srec));
+        assert_eq!(detect(&[0x13, 0, 0, 0]), None);
+        assert_eq!(detect(b"Some text"), None);
+    }
+}
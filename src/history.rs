@@ -0,0 +1,97 @@
+// checkpoints for running the debugger backwards
+//
+// an instruction can't be undone, but any earlier point can be got back to
+// by restoring the last snapshot before it and running forward again. that
+// only ends up in the same place if the rerun sees the same input, so the
+// machine runs on an in-memory replay journal: input is recorded the first
+// time through and handed back on reruns, which also keeps the console from
+// printing things twice.
+//
+// a snapshot is taken every `interval` instructions. once there are more
+// than MAX_CHECKPOINTS every other one goes and the interval doubles, so a
+// long session keeps a bounded number spread over the whole run, and going
+// back a long way just means a longer rerun.
+
+use crate::cpu::Cpu;
+use crate::executor::Executor;
+use crate::replay::Journal;
+use crate::smp::Smp;
+use crate::snapshot;
+
+pub const FIRST_INTERVAL: u64 = 10_000;
+const MAX_CHECKPOINTS: usize = 64;
+
+pub struct History {
+    journal: Journal,
+    // instruction count and snapshot, oldest first
+    checkpoints: Vec<(u64, Vec<u8>)>,
+    interval: u64,
+    // when to try for the next one; a snapshot can fail (an open
+    // semihosting file) and shouldn't be retried every instruction
+    next: u64,
+}
+
+impl History {
+    // start keeping history of a machine that has run `steps` instructions
+    pub fn new(cpu: &mut Cpu, exec: &Executor, smp: Option<&Smp>, steps: u64) -> Result<Self, String> {
+        let journal = Journal::recorder();
+        cpu.set_journal(journal.clone());
+        let mut history = History { journal, checkpoints: Vec::new(), interval: FIRST_INTERVAL, next: 0 };
+        history.restart(cpu, exec, smp, steps)?;
+        Ok(history)
+    }
+
+    // the earliest point that can be gone back to
+    pub fn start(&self) -> u64 {
+        self.checkpoints[0].0
+    }
+
+    // forget everything before `steps`, for when the machine got there in
+    // a way a rerun wouldn't repeat (a hart switched by hand, a snapshot
+    // loaded)
+    pub fn restart(&mut self, cpu: &Cpu, exec: &Executor, smp: Option<&Smp>, steps: u64) -> Result<(), String> {
+        let snap = snapshot::save(cpu, exec, smp, steps)?;
+        self.journal.restart(steps);
+        self.checkpoints = vec![(steps, snap)];
+        self.interval = FIRST_INTERVAL;
+        self.next = steps + self.interval;
+        Ok(())
+    }
+
+    // after every instruction the debugger runs
+    pub fn record(&mut self, cpu: &Cpu, exec: &Executor, smp: Option<&Smp>, steps: u64) {
+        // reruns go over ground that's already covered
+        if steps < self.next {
+            return;
+        }
+        self.next = steps + self.interval;
+        let Ok(snap) = snapshot::save(cpu, exec, smp, steps) else {
+            return;
+        };
+        self.checkpoints.push((steps, snap));
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            let mut i = 0;
+            self.checkpoints.retain(|_| {
+                i += 1;
+                i % 2 == 1
+            });
+            self.interval *= 2;
+            self.next = self.checkpoints.last().unwrap().0 + self.interval;
+        }
+    }
+
+    // where the checkpoints before instruction `to` are, oldest first
+    pub fn before(&self, to: u64) -> Vec<u64> {
+        self.checkpoints.iter().map(|c| c.0).filter(|&at| at < to).collect()
+    }
+
+    // put the machine back to the last checkpoint at or before `to`, and
+    // the journal with it. returns the checkpoint's instruction count
+    pub fn rewind(&self, cpu: &mut Cpu, exec: &mut Executor, smp: Option<&mut Smp>, to: u64) -> Result<u64, String> {
+        let (at, snap) = self.checkpoints.iter().rev().find(|c| c.0 <= to)
+            .ok_or_else(|| format!("history starts after {} instructions", self.start()))?;
+        snapshot::restore(snap, cpu, exec, smp)?;
+        self.journal.rewind(*at);
+        Ok(*at)
+    }
+}
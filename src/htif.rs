@@ -0,0 +1,187 @@
+// htif: the tohost/fromhost mailbox used by riscv-tests, spike and the
+// riscv-pk proxy kernel
+//
+// tohost and fromhost are 64-bit words in the program's own ram, found by
+// symbol name. a guest request is the word written to tohost:
+//
+//   device (8 bits) | command (8 bits) | payload (48 bits)
+//
+// device 0 command 0 with bit 0 of the payload set is exit, with the status
+// in the rest of the payload (riscv-tests report the failing test number
+// there, 0 means pass). without bit 0 the payload points at a syscall proxy
+// block of eight 64-bit words: number, arguments, with the result written
+// back to the first word. device 1 command 1 is console putchar.
+//
+// rv32 guests store the low half first, so a request is only acted on once
+// the high half has been written.
+
+use crate::cpu::Cpu;
+use crate::replay;
+use crate::symbols::SymbolMap;
+use std::io::Write;
+
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+const CMD_PUTCHAR: u64 = 1;
+
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+const ENOSYS: i64 = 38;
+const EBADF: i64 = 9;
+
+pub struct Htif {
+    pub tohost: u32,
+    pub fromhost: Option<u32>,
+    console: Box<dyn Write>,
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>, console: Box<dyn Write>) -> Self {
+        Htif { tohost, fromhost, console }
+    }
+
+    // htif for a program that defines a tohost symbol, with the console on
+    // stdout
+    pub fn from_symbols(syms: &SymbolMap) -> Option<Self> {
+        let tohost = syms.get("tohost")?.addr;
+        let fromhost = syms.get("fromhost").map(|s| s.addr);
+        Some(Self::new(tohost, fromhost, Box::new(std::io::stdout())))
+    }
+
+    fn read_u64(cpu: &Cpu, addr: u32) -> Result<u64, String> {
+        match (cpu.peek_word(addr), cpu.peek_word(addr.wrapping_add(4))) {
+            (Some(lo), Some(hi)) => Ok((hi as u64) << 32 | lo as u64),
+            _ => Err(format!("htif: 0x{:x} isn't in ram", addr)),
+        }
+    }
+
+    fn write_u64(cpu: &mut Cpu, addr: u32, val: u64) -> Result<(), String> {
+        cpu.store_word(addr, val as u32)?;
+        cpu.store_word(addr.wrapping_add(4), (val >> 32) as u32)
+    }
+
+    // called after every guest store. Ok(Some(code)) means the program exited
+    pub fn store(&mut self, cpu: &mut Cpu, addr: u32) -> Result<Option<i32>, String> {
+        if addr != self.tohost.wrapping_add(4) {
+            return Ok(None);
+        }
+        let req = Self::read_u64(cpu, self.tohost)?;
+        if req == 0 {
+            return Ok(None);
+        }
+        Self::write_u64(cpu, self.tohost, 0)?;
+
+        let device = req >> 56;
+        let command = (req >> 48) & 0xff;
+        let payload = req & 0xffff_ffff_ffff;
+        let response = match (device, command) {
+            (DEV_SYSCALL, 0) if payload & 1 == 1 => return Ok(Some((payload >> 1) as i32)),
+            (DEV_SYSCALL, 0) => {
+                if let Some(code) = self.syscall(cpu, payload as u32)? {
+                    return Ok(Some(code));
+                }
+                1
+            }
+            (DEV_CONSOLE, CMD_PUTCHAR) => {
+                // a console that's gone away isn't the guest's problem
+                if !replay::muted(cpu.journal.as_ref()) {
+                    let _ = self.console.write_all(&[payload as u8]);
+                    let _ = self.console.flush();
+                }
+                device << 56 | command << 48
+            }
+            _ => return Err(format!("htif: unknown request 0x{:016x}", req)),
+        };
+        if let Some(fromhost) = self.fromhost {
+            Self::write_u64(cpu, fromhost, response)?;
+        }
+        Ok(None)
+    }
+
+    fn syscall(&mut self, cpu: &mut Cpu, block: u32) -> Result<Option<i32>, String> {
+        let mut args = [0u64; 8];
+        for (i, a) in args.iter_mut().enumerate() {
+            *a = Self::read_u64(cpu, block.wrapping_add(i as u32 * 8))?;
+        }
+        let ret = match args[0] {
+            SYS_EXIT => return Ok(Some(args[1] as i32)),
+            SYS_WRITE => match args[1] {
+                1 | 2 => {
+                    let (buf, len) = (args[2] as u32, args[3] as u32);
+                    let off = cpu.ram_offset(buf, len as usize)
+                        .ok_or_else(|| format!("htif: write buffer 0x{:x}+{} isn't in ram", buf, len))?;
+                    if !replay::muted(cpu.journal.as_ref()) {
+                        let _ = self.console.write_all(&cpu.mem[off..off + len as usize]);
+                        let _ = self.console.flush();
+                    }
+                    len as i64
+                }
+                _ => -EBADF,
+            },
+            _ => -ENOSYS,
+        };
+        Self::write_u64(cpu, block, ret as u64)?;
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_exit_only_after_high_half() {
+        let mut cpu = Cpu::new();
+        let mut htif = Htif::new(0x1000, Some(0x1008), Box::new(Capture::default()));
+        cpu.write_word(0x1000, (3 << 1) | 1);
+        assert_eq!(htif.store(&mut cpu, 0x1000), Ok(None));
+        cpu.write_word(0x1004, 0);
+        assert_eq!(htif.store(&mut cpu, 0x1004), Ok(Some(3)));
+        assert_eq!(cpu.read_word(0x1000), 0);
+    }
+
+    #[test]
+    fn test_putchar_and_write_syscall() {
+        let mut cpu = Cpu::new();
+        let out = Capture::default();
+        let mut htif = Htif::new(0x1000, Some(0x1008), Box::new(out.clone()));
+        cpu.write_word(0x1000, b'>' as u32);
+        cpu.write_word(0x1004, 0x0101_0000);
+        assert_eq!(htif.store(&mut cpu, 0x1004), Ok(None));
+        assert_eq!(cpu.read_word(0x100c), 0x0101_0000);
+
+        cpu.load_program(b"hello", 0x2001);
+        for (i, w) in [SYS_WRITE as u32, 0, 1, 0, 0x2001, 0, 5, 0].iter().enumerate() {
+            cpu.write_word(0x3000 + i as u32 * 4, *w);
+        }
+        cpu.write_word(0x1000, 0x3000);
+        cpu.write_word(0x1004, 0);
+        assert_eq!(htif.store(&mut cpu, 0x1004), Ok(None));
+        assert_eq!(out.0.borrow().as_slice(), b">hello");
+        assert_eq!(cpu.read_word(0x3000), 5);
+        assert_eq!(cpu.read_word(0x1008), 1);
+
+        // a length running past ram is an error, not a huge buffer
+        cpu.write_word(0x3000, SYS_WRITE as u32);
+        cpu.write_word(0x3018, 0xffff_0000);
+        cpu.write_word(0x1000, 0x3000);
+        cpu.write_word(0x1004, 0);
+        assert!(htif.store(&mut cpu, 0x1004).unwrap_err().contains("isn't in ram"));
+    }
+}
@@ -0,0 +1,113 @@
+// predecoded instruction cache
+//
+// one lazily allocated page of decoded instructions per 4k of ram, indexed
+// by ram offset. stores through store_word/write_word clear the words they
+// hit (small programs keep data next to code, so dropping the whole page
+// on every store would thrash), load_program drops whole pages and fence.i
+// drops everything.
+// host-side bulk writes straight into Cpu::mem (image loading, syscall and
+// semihosting reads, mmap and brk) call invalidate() for what they cover,
+// so the guest sees them without a fence.i. device dma through GuestRam
+// isn't tracked, so a guest running code a device wrote needs one, as on
+// hardware. only ram is cached; rom and flash are decoded per fetch.
+
+use crate::decoder::Instruction;
+
+const PAGE_SHIFT: usize = 12;
+const PAGE_WORDS: usize = 1 << (PAGE_SHIFT - 2);
+
+type Page = Box<[Option<Instruction>; PAGE_WORDS]>;
+
+#[derive(Default)]
+pub struct ICache {
+    pages: Vec<Option<Page>>,
+    // bumped whenever cached code goes away, so anything built on top of
+    // the cache (translated blocks) knows to throw itself out too
+    generation: u64,
+}
+
+impl ICache {
+    pub fn new(ram_size: usize) -> Self {
+        let npages = ram_size.div_ceil(1 << PAGE_SHIFT);
+        ICache { pages: (0..npages).map(|_| None).collect(), generation: 0 }
+    }
+
+    // decoded instruction at ram offset `off`, if it's been cached
+    #[inline]
+    pub fn get(&self, off: usize) -> Option<Instruction> {
+        let page = self.pages.get(off >> PAGE_SHIFT)?.as_ref()?;
+        page[(off >> 2) & (PAGE_WORDS - 1)]
+    }
+
+    pub fn insert(&mut self, off: usize, inst: Instruction) {
+        if let Some(slot) = self.pages.get_mut(off >> PAGE_SHIFT) {
+            let page = slot.get_or_insert_with(|| Box::new([None; PAGE_WORDS]));
+            page[(off >> 2) & (PAGE_WORDS - 1)] = Some(inst);
+        }
+    }
+
+    // a word store at ram offset `off`
+    #[inline]
+    pub fn invalidate_word(&mut self, off: usize) {
+        if let Some(Some(page)) = self.pages.get_mut(off >> PAGE_SHIFT) {
+            if page[(off >> 2) & (PAGE_WORDS - 1)].take().is_some() {
+                self.generation += 1;
+            }
+        }
+    }
+
+    // a bulk write of `len` bytes at ram offset `off`
+    pub fn invalidate(&mut self, off: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let last = (off + len - 1) >> PAGE_SHIFT;
+        for page in (off >> PAGE_SHIFT)..=last {
+            if let Some(slot) = self.pages.get_mut(page) {
+                if slot.take().is_some() {
+                    self.generation += 1;
+                }
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.pages.iter_mut().for_each(|p| *p = None);
+        self.generation += 1;
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // pages that currently hold decoded code, for tests and metrics
+    pub fn cached_pages(&self) -> usize {
+        self.pages.iter().filter(|p| p.is_some()).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalidation() {
+        let mut cache = ICache::new(0x3000);
+        let nop = Instruction::decode(0x0000_0013);
+        cache.insert(0x0ffc, nop);
+        cache.insert(0x1000, nop);
+        cache.insert(0x1004, nop);
+        assert_eq!(cache.cached_pages(), 2);
+        cache.invalidate_word(0x1004);
+        assert!(cache.get(0x1000).is_some());
+        assert!(cache.get(0x1004).is_none());
+        assert_eq!(cache.generation(), 1);
+        // data that was never decoded doesn't count as a code change
+        cache.invalidate_word(0x1008);
+        assert_eq!(cache.generation(), 1);
+        // straddles both pages
+        cache.invalidate(0x0ffe, 4);
+        assert_eq!(cache.cached_pages(), 0);
+    }
+}
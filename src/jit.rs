@@ -0,0 +1,729 @@
+// x86-64 code generation for hot basic blocks
+//
+// a block that the interpreter has run HOT_THRESHOLD times is compiled to
+// native code. guest registers stay in Cpu::regs and are loaded and stored
+// around every operation, so the debugger, metrics and the interpreter all
+// see the same state whenever native code isn't running. a block whose
+// branch leads back to its own start loops natively until the step budget
+// runs out.
+//
+// native code only handles the common case:
+//   - loads from ram are inline; anything else leaves native code before
+//     the load, and the interpreter redoes it (mmio, faults)
+//   - stores call back into rust, which does the store, the htif check and
+//     notices stores that hit decoded code
+//   - machines with devices or a replay journal aren't compiled at all,
+//     since those tick once per instruction
+// compiled code is thrown away with the blocks it came from.
+//
+// calling convention of a compiled block (sysv):
+//   rdi = &mut cpu.regs, rsi = ram, rdx = ram length - 4, rcx = &mut Context,
+//   r8 = how many times the block may run
+// and inside it rbx, r12, r13, r14 and rbp hold those, r15 counts completed
+// runs of the block.
+
+use crate::block::{Block, OpKind, Term};
+use crate::cpu::Cpu;
+use crate::decoder::Opcode;
+use crate::executor::BlockExit;
+use crate::htif::Htif;
+use crate::metrics::Instrument;
+use std::ffi::c_void;
+use std::mem::offset_of;
+
+// interpreted runs before a block is compiled
+pub const HOT_THRESHOLD: u32 = 50;
+
+const CHUNK_SIZE: usize = 256 * 1024;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, off: i64) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+}
+
+// what jit_store tells native code
+const STORE_OK: u32 = 0;
+// stop after this store: it hit decoded code, or htif has news
+const STORE_STOP: u32 = 1;
+// not ram: stop before the store and let the interpreter do it
+const STORE_RETRY: u32 = 2;
+
+// shared between native code and jit_store
+struct Context {
+    iterations: u64,
+    partial: u64,
+    taken: u64,
+    not_taken: u64,
+    pc: u32,
+    cpu: *mut Cpu,
+    htif: *mut Option<Htif>,
+    generation: u64,
+    stop: Option<Result<i32, String>>,
+}
+
+type Entry = unsafe extern "sysv64" fn(*mut u32, *const u8, u64, *mut Context, u64);
+
+extern "sysv64" fn jit_store(ctx: *mut Context, addr: u32, val: u32, size: u32) -> u32 {
+    // native code only calls this while run() below holds both borrows
+    let ctx = unsafe { &mut *ctx };
+    let cpu = unsafe { &mut *ctx.cpu };
+    if cpu.ram_offset(addr, size as usize).is_none() || cpu.store(addr, size, val).is_err() {
+        return STORE_RETRY;
+    }
+    if let Some(htif) = unsafe { (*ctx.htif).as_mut() } {
+        match htif.store(cpu, addr) {
+            Ok(None) => {}
+            Ok(Some(code)) => {
+                ctx.stop = Some(Ok(code));
+                return STORE_STOP;
+            }
+            Err(e) => {
+                ctx.stop = Some(Err(e));
+                return STORE_STOP;
+            }
+        }
+    }
+    if cpu.icache.generation() != ctx.generation {
+        return STORE_STOP;
+    }
+    STORE_OK
+}
+
+struct Chunk {
+    ptr: *mut u8,
+    size: usize,
+    used: usize,
+}
+
+// executable memory, handed out in pieces and reset all at once. chunks are
+// never writable and executable at the same time: a chunk is made writable
+// while code is copied in and goes back to read+exec before anything runs
+#[derive(Default)]
+struct CodeArena {
+    chunks: Vec<Chunk>,
+}
+
+impl CodeArena {
+    fn alloc(&mut self, code: &[u8]) -> Result<*const u8, String> {
+        let fits = |c: &&mut Chunk| c.size - c.used >= code.len();
+        if self.chunks.last_mut().filter(fits).is_none() {
+            let size = code.len().max(CHUNK_SIZE);
+            let ptr = unsafe {
+                mmap(std::ptr::null_mut(), size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+            };
+            if ptr as isize == -1 {
+                return Err("jit: can't map code memory".to_string());
+            }
+            self.chunks.push(Chunk { ptr: ptr as *mut u8, size, used: 0 });
+        } else {
+            self.protect(PROT_READ | PROT_WRITE)?;
+        }
+        let chunk = self.chunks.last_mut().unwrap();
+        // the chunk has room for it, checked above
+        let dst = unsafe { chunk.ptr.add(chunk.used) };
+        unsafe { std::ptr::copy_nonoverlapping(code.as_ptr(), dst, code.len()) };
+        chunk.used += code.len();
+        self.protect(PROT_READ | PROT_EXEC)?;
+        Ok(dst)
+    }
+
+    // change the protection of the chunk being filled
+    fn protect(&mut self, prot: i32) -> Result<(), String> {
+        let chunk = self.chunks.last().unwrap();
+        if unsafe { mprotect(chunk.ptr as *mut c_void, chunk.size, prot) } != 0 {
+            return Err("jit: can't change code memory protection".to_string());
+        }
+        Ok(())
+    }
+
+    // keep the first chunk for the next round of compiles, give the rest back
+    fn reset(&mut self) {
+        for c in self.chunks.drain(1.min(self.chunks.len())..) {
+            unsafe { munmap(c.ptr as *mut c_void, c.size) };
+        }
+        if let Some(c) = self.chunks.first_mut() {
+            c.used = 0;
+        }
+    }
+}
+
+impl Drop for CodeArena {
+    fn drop(&mut self) {
+        for c in &self.chunks {
+            unsafe { munmap(c.ptr as *mut c_void, c.size) };
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Jit {
+    code: CodeArena,
+    // indexed like the block cache
+    counts: Vec<u32>,
+    compiled: Vec<Option<Entry>>,
+    // set if executable memory can't be had; everything stays interpreted
+    disabled: bool,
+}
+
+impl Jit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // forget everything; the blocks it was compiled from are gone
+    pub fn clear(&mut self) {
+        self.counts.clear();
+        self.compiled.clear();
+        self.code.reset();
+    }
+
+    pub fn compiled_blocks(&self) -> usize {
+        self.compiled.iter().filter(|c| c.is_some()).count()
+    }
+
+    // run block `idx` natively, compiling it first if it just got hot. None
+    // means the interpreter should run it: it isn't hot yet, the machine
+    // can't be compiled for, or native code couldn't make any progress
+    pub(crate) fn run<M: Instrument>(
+        &mut self,
+        idx: usize,
+        block: &Block,
+        cpu: &mut Cpu,
+        metrics: &mut M,
+        htif: &mut Option<Htif>,
+        budget: usize,
+    ) -> Option<Result<BlockExit, String>> {
+        if self.disabled || !cpu.devices.is_empty() || cpu.journal.is_some() || cpu.mem.len() < 4 {
+            return None;
+        }
+        if self.compiled.len() <= idx {
+            self.compiled.resize(idx + 1, None);
+            self.counts.resize(idx + 1, 0);
+        }
+        let entry = match self.compiled[idx] {
+            Some(entry) => entry,
+            None => {
+                self.counts[idx] += 1;
+                if self.counts[idx] < HOT_THRESHOLD {
+                    return None;
+                }
+                let code = compile(block, cpu.ram_base);
+                match self.code.alloc(&code) {
+                    // the arena holds a complete function made by compile()
+                    Ok(ptr) => {
+                        let entry = unsafe { std::mem::transmute::<*const u8, Entry>(ptr) };
+                        self.compiled[idx] = Some(entry);
+                        entry
+                    }
+                    Err(e) => {
+                        eprintln!("{}, using the interpreter", e);
+                        self.disabled = true;
+                        return None;
+                    }
+                }
+            }
+        };
+
+        let generation = cpu.icache.generation();
+        let cpu_ptr: *mut Cpu = cpu;
+        let mut ctx = Context {
+            iterations: 0,
+            partial: 0,
+            taken: 0,
+            not_taken: 0,
+            pc: block.start,
+            cpu: cpu_ptr,
+            htif,
+            generation,
+            stop: None,
+        };
+        let max_runs = (budget as u64 / block.len).max(1);
+        // registers and ram aren't touched from rust while native code runs,
+        // apart from jit_store going through ctx.cpu
+        unsafe {
+            let regs = (*cpu_ptr).regs.as_mut_ptr();
+            let mem = (*cpu_ptr).mem.as_ptr();
+            let limit = (*cpu_ptr).mem.len() as u64 - 4;
+            entry(regs, mem, limit, &mut ctx, max_runs);
+        }
+
+        let executed = ctx.iterations * block.len + ctx.partial;
+        if executed == 0 {
+            return None;
+        }
+        metrics.record_block(block.start, block.len, &block.mix, ctx.iterations);
+        for (i, opcode) in block.opcodes[..ctx.partial as usize].iter().enumerate() {
+            metrics.record_opcode(*opcode);
+            metrics.record_pc(block.start.wrapping_add(i as u32 * 4));
+        }
+        metrics.record_branches(ctx.taken, ctx.not_taken);
+        cpu.pc = ctx.pc;
+        let exit_code = match ctx.stop {
+            Some(Err(e)) => return Some(Err(e)),
+            Some(Ok(code)) => Some(code),
+            None => None,
+        };
+        Some(Ok(BlockExit { executed: executed as usize, link: None, exit_code }))
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Label(usize);
+
+// just enough of an x86-64 assembler for compile()
+#[derive(Default)]
+struct Emitter {
+    buf: Vec<u8>,
+    labels: Vec<Option<usize>>,
+    fixups: Vec<(usize, Label)>,
+}
+
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+
+const JB: u8 = 0x82;
+const JAE: u8 = 0x83;
+const JE: u8 = 0x84;
+const JNE: u8 = 0x85;
+const JA: u8 = 0x87;
+const JL: u8 = 0x8c;
+const JGE: u8 = 0x8d;
+
+impl Emitter {
+    fn bytes(&mut self, b: &[u8]) {
+        self.buf.extend_from_slice(b);
+    }
+
+    fn imm32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    fn bind(&mut self, l: Label) {
+        self.labels[l.0] = Some(self.buf.len());
+    }
+
+    fn rel32(&mut self, l: Label) {
+        self.fixups.push((self.buf.len(), l));
+        self.imm32(0);
+    }
+
+    fn jmp(&mut self, l: Label) {
+        self.bytes(&[0xe9]);
+        self.rel32(l);
+    }
+
+    fn jcc(&mut self, cc: u8, l: Label) {
+        self.bytes(&[0x0f, cc]);
+        self.rel32(l);
+    }
+
+    // mov r32, [rbx + 4 * reg]
+    fn load_reg(&mut self, r: u8, reg: u8) {
+        self.bytes(&[0x8b, 0x83 | r << 3]);
+        self.imm32(reg as u32 * 4);
+    }
+
+    // mov [rbx + 4 * reg], r32; x0 stays zero
+    fn store_reg(&mut self, r: u8, reg: u8) {
+        if reg != 0 {
+            self.bytes(&[0x89, 0x83 | r << 3]);
+            self.imm32(reg as u32 * 4);
+        }
+    }
+
+    // mov r32, imm32
+    fn mov_imm(&mut self, r: u8, v: u32) {
+        self.bytes(&[0xb8 + r]);
+        self.imm32(v);
+    }
+
+    // inc qword [r14 + off]
+    fn inc_ctx(&mut self, off: usize) {
+        self.bytes(&[0x49, 0xff, 0x86]);
+        self.imm32(off as u32);
+    }
+
+    // mov dword [r14 + off], imm32
+    fn set_ctx(&mut self, off: usize, v: u32) {
+        self.bytes(&[0x41, 0xc7, 0x86]);
+        self.imm32(off as u32);
+        self.imm32(v);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for (pos, l) in self.fixups {
+            let target = self.labels[l.0].expect("unbound label");
+            let rel = target as i64 - (pos as i64 + 4);
+            self.buf[pos..pos + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+        }
+        self.buf
+    }
+}
+
+// eax = eax op ecx
+fn alu(e: &mut Emitter, op: Opcode) {
+    match op {
+        Opcode::Add | Opcode::Addi => e.bytes(&[0x01, 0xc8]),
+        Opcode::Sub => e.bytes(&[0x29, 0xc8]),
+        Opcode::And | Opcode::Andi => e.bytes(&[0x21, 0xc8]),
+        Opcode::Or | Opcode::Ori => e.bytes(&[0x09, 0xc8]),
+        Opcode::Xor | Opcode::Xori => e.bytes(&[0x31, 0xc8]),
+        // x86 masks 32-bit shift counts to 5 bits, like risc-v
+        Opcode::Sll | Opcode::Slli => e.bytes(&[0xd3, 0xe0]),
+        Opcode::Srl | Opcode::Srli => e.bytes(&[0xd3, 0xe8]),
+        Opcode::Sra | Opcode::Srai => e.bytes(&[0xd3, 0xf8]),
+        // cmp eax, ecx; setl/setb al; movzx eax, al
+        Opcode::Slt | Opcode::Slti => e.bytes(&[0x39, 0xc8, 0x0f, 0x9c, 0xc0, 0x0f, 0xb6, 0xc0]),
+        Opcode::Sltu | Opcode::Sltiu => e.bytes(&[0x39, 0xc8, 0x0f, 0x92, 0xc0, 0x0f, 0xb6, 0xc0]),
+        Opcode::Mul => e.bytes(&[0x0f, 0xaf, 0xc1]), // imul eax, ecx
+        // widen both to 64 bits (movsxd, or mov to zero-extend), then
+        // imul rax, rcx; shr rax, 32
+        Opcode::Mulh => e.bytes(&[0x48, 0x63, 0xc0, 0x48, 0x63, 0xc9, 0x48, 0x0f, 0xaf, 0xc1, 0x48, 0xc1, 0xe8, 0x20]),
+        Opcode::Mulhsu => e.bytes(&[0x48, 0x63, 0xc0, 0x89, 0xc9, 0x48, 0x0f, 0xaf, 0xc1, 0x48, 0xc1, 0xe8, 0x20]),
+        Opcode::Mulhu => e.bytes(&[0x89, 0xc0, 0x89, 0xc9, 0x48, 0x0f, 0xaf, 0xc1, 0x48, 0xc1, 0xe8, 0x20]),
+        Opcode::Div | Opcode::Divu | Opcode::Rem | Opcode::Remu => {
+            // x86 faults where risc-v gives a result: by zero skip the divide,
+            // leaving all ones or a. signed divides are done on 64 bits, so
+            // i32::MIN / -1 doesn't overflow and truncates to the right thing
+            let zero = e.label();
+            let done = e.label();
+            e.bytes(&[0x85, 0xc9]); // test ecx, ecx
+            e.jcc(JE, zero);
+            match op {
+                // movsxd rax, eax; movsxd rcx, ecx; cqo; idiv rcx
+                Opcode::Div | Opcode::Rem => e.bytes(&[0x48, 0x63, 0xc0, 0x48, 0x63, 0xc9, 0x48, 0x99, 0x48, 0xf7, 0xf9]),
+                // xor edx, edx; div ecx
+                _ => e.bytes(&[0x31, 0xd2, 0xf7, 0xf1]),
+            }
+            if matches!(op, Opcode::Rem | Opcode::Remu) {
+                e.bytes(&[0x89, 0xd0]); // mov eax, edx
+            }
+            e.jmp(done);
+            e.bind(zero);
+            if matches!(op, Opcode::Div | Opcode::Divu) {
+                e.mov_imm(EAX, u32::MAX);
+            }
+            e.bind(done);
+        }
+        op => unreachable!("{:?} isn't an alu op", op),
+    }
+}
+
+// a run of the block is done: count it, then loop if the block goes back to
+// itself and the budget allows, or leave for `target`
+fn finish_run(e: &mut Emitter, block: &Block, top: Label, exit: Label, target: u32) {
+    e.bytes(&[0x49, 0xff, 0xc7]); // inc r15
+    if target == block.start {
+        e.bytes(&[0x49, 0x39, 0xef]); // cmp r15, rbp
+        e.jcc(JB, top);
+    }
+    e.mov_imm(EAX, target);
+    e.jmp(exit);
+}
+
+fn compile(block: &Block, ram_base: u32) -> Vec<u8> {
+    let mut e = Emitter::default();
+    let top = e.label();
+    let exit = e.label();
+    // (label, instructions done, pc to leave at)
+    let mut side_exits: Vec<(Label, usize, u32)> = Vec::new();
+    // (label, store index, pc of the store)
+    let mut store_stops: Vec<(Label, usize, u32)> = Vec::new();
+
+    // push rbp, rbx, r12-r15; sub rsp, 8 to keep the stack aligned for calls
+    e.bytes(&[0x55, 0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57, 0x48, 0x83, 0xec, 0x08]);
+    // mov rbx, rdi; mov r12, rsi; mov r13, rdx; mov r14, rcx; mov rbp, r8; xor r15d, r15d
+    e.bytes(&[0x48, 0x89, 0xfb, 0x49, 0x89, 0xf4, 0x49, 0x89, 0xd5, 0x49, 0x89, 0xce]);
+    e.bytes(&[0x4c, 0x89, 0xc5, 0x45, 0x31, 0xff]);
+    e.bind(top);
+
+    for (i, op) in block.ops.iter().enumerate() {
+        let pc = block.start.wrapping_add(i as u32 * 4);
+        match op.kind {
+            OpKind::Nop => {}
+            OpKind::Li => {
+                if op.rd != 0 {
+                    e.mov_imm(EAX, op.imm);
+                    e.store_reg(EAX, op.rd);
+                }
+            }
+            OpKind::Load(opcode) => {
+                let slow = e.label();
+                side_exits.push((slow, i, pc));
+                e.load_reg(EAX, op.rs1);
+                e.mov_imm(ECX, op.imm);
+                alu(&mut e, Opcode::Add);
+                // edx = addr - ram_base, zero-extended, checked against ram
+                e.bytes(&[0x89, 0xc2, 0x81, 0xea]);
+                e.imm32(ram_base);
+                e.bytes(&[0x4c, 0x39, 0xea]); // cmp rdx, r13
+                e.jcc(JA, slow);
+                match opcode {
+                    Opcode::Lb => e.bytes(&[0x41, 0x0f, 0xbe, 0x04, 0x14]), // movsx eax, byte [r12 + rdx]
+                    Opcode::Lh => e.bytes(&[0x41, 0x0f, 0xbf, 0x04, 0x14]), // movsx eax, word [r12 + rdx]
+                    Opcode::Lbu => e.bytes(&[0x41, 0x0f, 0xb6, 0x04, 0x14]), // movzx eax, byte [r12 + rdx]
+                    Opcode::Lhu => e.bytes(&[0x41, 0x0f, 0xb7, 0x04, 0x14]), // movzx eax, word [r12 + rdx]
+                    _ => e.bytes(&[0x41, 0x8b, 0x04, 0x14]), // mov eax, [r12 + rdx]
+                }
+                e.store_reg(EAX, op.rd);
+            }
+            OpKind::Store(opcode) => {
+                let stop = e.label();
+                store_stops.push((stop, i, pc));
+                e.load_reg(EAX, op.rs1);
+                e.mov_imm(ECX, op.imm);
+                alu(&mut e, Opcode::Add);
+                e.bytes(&[0x89, 0xc6]); // mov esi, eax
+                e.load_reg(EDX, op.rs2);
+                e.mov_imm(ECX, opcode.access_size());
+                e.bytes(&[0x4c, 0x89, 0xf7]); // mov rdi, r14
+                e.bytes(&[0x48, 0xb8]); // mov rax, jit_store
+                let helper = jit_store as extern "sysv64" fn(*mut Context, u32, u32, u32) -> u32;
+                e.bytes(&(helper as usize as u64).to_le_bytes());
+                e.bytes(&[0xff, 0xd0, 0x85, 0xc0]); // call rax; test eax, eax
+                e.jcc(JNE, stop);
+            }
+            OpKind::Alu(opcode) | OpKind::AluImm(opcode) => {
+                if op.rd != 0 {
+                    e.load_reg(EAX, op.rs1);
+                    match op.kind {
+                        OpKind::Alu(_) => e.load_reg(ECX, op.rs2),
+                        _ => e.mov_imm(ECX, op.imm),
+                    }
+                    alu(&mut e, opcode);
+                    e.store_reg(EAX, op.rd);
+                }
+            }
+        }
+    }
+
+    match block.term {
+        Term::Branch { cond, rs1, rs2, taken, not_taken } => {
+            let take = e.label();
+            e.load_reg(EAX, rs1);
+            e.bytes(&[0x3b, 0x83]); // cmp eax, [rbx + 4 * rs2]
+            e.imm32(rs2 as u32 * 4);
+            let cc = match cond {
+                Opcode::Beq => JE,
+                Opcode::Bne => JNE,
+                Opcode::Blt => JL,
+                Opcode::Bge => JGE,
+                Opcode::Bltu => JB,
+                Opcode::Bgeu => JAE,
+                op => unreachable!("{:?} isn't a branch", op),
+            };
+            e.jcc(cc, take);
+            e.inc_ctx(offset_of!(Context, not_taken));
+            finish_run(&mut e, block, top, exit, not_taken);
+            e.bind(take);
+            e.inc_ctx(offset_of!(Context, taken));
+            finish_run(&mut e, block, top, exit, taken);
+        }
+        Term::Jump { rd, link, target } => {
+            if rd != 0 {
+                e.mov_imm(EAX, link);
+                e.store_reg(EAX, rd);
+            }
+            finish_run(&mut e, block, top, exit, target);
+        }
+        Term::Indirect { rd, rs1, imm, link } => {
+            // target first, rd may be rs1
+            e.load_reg(EAX, rs1);
+            e.mov_imm(ECX, imm);
+            alu(&mut e, Opcode::Add);
+            e.bytes(&[0x83, 0xe0, 0xfe]); // and eax, ~1
+            if rd != 0 {
+                e.mov_imm(ECX, link);
+                e.store_reg(ECX, rd);
+            }
+            e.bytes(&[0x49, 0xff, 0xc7]); // inc r15
+            e.jmp(exit);
+        }
+        Term::Fallthrough { next } => finish_run(&mut e, block, top, exit, next),
+    }
+
+    for (label, done, pc) in side_exits {
+        e.bind(label);
+        e.set_ctx(offset_of!(Context, partial), done as u32);
+        e.mov_imm(EAX, pc);
+        e.jmp(exit);
+    }
+    for (label, i, pc) in store_stops {
+        let retry = e.label();
+        e.bind(label);
+        e.bytes(&[0x83, 0xf8, STORE_STOP as u8]); // cmp eax, STORE_STOP
+        e.jcc(JNE, retry);
+        e.set_ctx(offset_of!(Context, partial), i as u32 + 1);
+        e.mov_imm(EAX, pc.wrapping_add(4));
+        e.jmp(exit);
+        e.bind(retry);
+        e.set_ctx(offset_of!(Context, partial), i as u32);
+        e.mov_imm(EAX, pc);
+        e.jmp(exit);
+    }
+
+    // mov [r14 + iterations], r15; mov [r14 + pc], eax
+    e.bind(exit);
+    e.bytes(&[0x4d, 0x89, 0xbe]);
+    e.imm32(offset_of!(Context, iterations) as u32);
+    e.bytes(&[0x41, 0x89, 0x86]);
+    e.imm32(offset_of!(Context, pc) as u32);
+    // add rsp, 8; pop r15-r12, rbx, rbp; ret
+    e.bytes(&[0x48, 0x83, 0xc4, 0x08, 0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b, 0x5d, 0xc3]);
+    e.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::block::BlockCache;
+    use crate::metrics::Metrics;
+
+    fn run_native(src: &str, x7: u32, budget: usize) -> (Cpu, Metrics, BlockExit) {
+        let code = Assembler::new().assemble(src).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&code, 0);
+        cpu.regs[7] = x7;
+        let mut blocks = BlockCache::new();
+        let idx = blocks.lookup(&mut cpu, 0).unwrap();
+        let mut jit = Jit::new();
+        // already hot
+        jit.counts.resize(idx + 1, HOT_THRESHOLD - 1);
+        jit.compiled.resize(idx + 1, None);
+        let mut metrics = Metrics::new();
+        let exit = jit.run(idx, blocks.block(idx), &mut cpu, &mut metrics, &mut None, budget).unwrap().unwrap();
+        assert_eq!(jit.compiled_blocks(), 1);
+        (cpu, metrics, exit)
+    }
+
+    const LOOP: &str = "loop:
+        addi x1, x1, 3
+        xor x2, x2, x1
+        slli x3, x2, 2
+        sra x4, x3, x1
+        sw x3, 0x100(x0)
+        lw x5, 0x100(x0)
+        addi x6, x6, 1
+        blt x6, x7, loop";
+
+    #[test]
+    fn test_self_loop_runs_natively() {
+        let (cpu, metrics, exit) = run_native(LOOP, 10, 800);
+        assert_eq!(exit.executed, 80);
+        assert_eq!(cpu.pc, 0x20);
+        assert_eq!(cpu.regs[6], 10);
+        assert_eq!(cpu.regs[1], 30);
+        assert_eq!(cpu.regs[5], cpu.regs[3]);
+        assert_eq!(cpu.read_word(0x100), cpu.regs[3]);
+        assert_eq!((metrics.branch_taken, metrics.branch_not_taken), (9, 1));
+        assert_eq!(metrics.inst_count, 80);
+    }
+
+    #[test]
+    fn test_loop_stops_at_budget() {
+        let (cpu, _, exit) = run_native(LOOP, 10, 35);
+        assert_eq!(exit.executed, 32);
+        assert_eq!(cpu.pc, 0);
+        assert_eq!(cpu.regs[6], 4);
+    }
+
+    #[test]
+    fn test_load_outside_ram_leaves_before_it() {
+        let (cpu, metrics, exit) = run_native("addi x1, x0, 7\nlui x2, 0x80000\nlw x3, 0(x2)\naddi x4, x0, 1\njal x0, 0", 0, 100);
+        assert_eq!(exit.executed, 2);
+        assert_eq!(cpu.pc, 8);
+        assert_eq!(cpu.regs[1], 7);
+        assert_eq!(cpu.regs[3], 0);
+        assert_eq!(metrics.inst_count, 2);
+    }
+
+    #[test]
+    fn test_sized_loads_and_stores() {
+        let (cpu, _, exit) = run_native("loop:
+            addi x1, x1, -1
+            sh x1, 0x100(x0)
+            sb x6, 0x103(x0)
+            lb x2, 0x100(x0)
+            lhu x3, 0x100(x0)
+            lbu x4, 0x103(x0)
+            sltu x5, x6, x7
+            addi x6, x6, 1
+            bltu x6, x7, loop", 10, 800);
+        assert_eq!(exit.executed, 90);
+        assert_eq!((cpu.regs[2], cpu.regs[3], cpu.regs[4], cpu.regs[5]), (0xffff_fff6, 0xfff6, 9, 1));
+        assert_eq!(cpu.read_word(0x100), 0x0900_fff6);
+    }
+
+    #[test]
+    fn test_multiply_and_divide() {
+        let (cpu, _, exit) = run_native("lui x1, 0x80000
+            addi x2, x0, -3
+            addi x15, x0, 2
+            addi x16, x0, -1
+            mul x3, x2, x2
+            mulh x4, x1, x2
+            mulhsu x5, x2, x1
+            mulhu x6, x1, x2
+            div x8, x1, x16
+            rem x9, x1, x16
+            div x10, x2, x7
+            rem x11, x2, x7
+            divu x12, x2, x7
+            remu x13, x2, x7
+            div x14, x2, x15
+            rem x17, x2, x15
+            divu x18, x2, x15
+            remu x19, x2, x15
+            jal x0, 0", 0, 19);
+        assert_eq!(exit.executed, 19);
+        assert_eq!(&cpu.regs[3..7], &[9, 1, 0xffff_fffe, 0x7fff_fffe]);
+        // i32::MIN / -1 overflows to i32::MIN, then division by zero
+        assert_eq!(&cpu.regs[8..14], &[0x8000_0000, 0, u32::MAX, 0xffff_fffd, u32::MAX, 0xffff_fffd]);
+        assert_eq!((cpu.regs[14], cpu.regs[17], cpu.regs[18], cpu.regs[19]), (u32::MAX, u32::MAX, 0x7fff_fffe, 1));
+    }
+
+    // permissions of the mapping holding `addr`, from /proc/self/maps
+    fn perms(addr: *const u8) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        let addr = addr as usize;
+        maps.lines()
+            .find_map(|l| {
+                let (range, rest) = l.split_once(' ')?;
+                let (lo, hi) = range.split_once('-')?;
+                let (lo, hi) = (usize::from_str_radix(lo, 16).ok()?, usize::from_str_radix(hi, 16).ok()?);
+                (lo <= addr && addr < hi).then(|| rest[..4].to_string())
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_arena_is_write_xor_exec() {
+        let mut arena = CodeArena::default();
+        let first = arena.alloc(&[0xc3]).unwrap(); // ret
+        assert_eq!(perms(first), "r-xp");
+        let second = arena.alloc(&[0xc3]).unwrap();
+        assert_eq!(perms(second), "r-xp");
+        let f: unsafe extern "sysv64" fn() = unsafe { std::mem::transmute(second) };
+        unsafe { f() };
+
+        arena.alloc(&vec![0x90; CHUNK_SIZE]).unwrap();
+        assert_eq!(arena.chunks.len(), 2);
+        arena.reset();
+        assert_eq!(arena.chunks.len(), 1);
+        assert_eq!(arena.alloc(&[0xc3]).unwrap(), first);
+    }
+}
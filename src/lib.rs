@@ -1,6 +1,39 @@
 pub mod cpu;
+pub mod csr;
+pub mod mmu;
 pub mod decoder;
 pub mod executor;
 pub mod assembler;
 pub mod debugger;
 pub mod metrics;
+pub mod devices;
+pub mod virtio_net;
+pub mod fdt;
+pub mod rtc;
+pub mod framebuffer;
+pub mod flash;
+pub mod elf;
+pub mod symbols;
+pub mod dwarf;
+pub mod hexfile;
+pub mod process;
+pub mod syscalls;
+pub mod uart;
+pub mod clint;
+pub mod plic;
+pub mod boot;
+pub mod htif;
+pub mod semihosting;
+pub mod icache;
+pub mod block;
+pub mod smp;
+pub mod threads;
+pub mod snapshot;
+pub mod replay;
+pub mod history;
+pub mod recompile;
+#[cfg(feature = "jit")]
+pub mod jit;
+
+#[cfg(all(feature = "jit", not(all(target_arch = "x86_64", target_os = "linux"))))]
+compile_error!("the jit feature is only available on x86-64 linux");
@@ -1,11 +1,13 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use rv32_emu::*;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Parser)]
 #[command(name = "rv32-emu")]
-#[command(about = "risc-v rv32i emulator", long_about = None)]
+#[command(about = "risc-v rv32im emulator", long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -15,11 +17,11 @@ struct Cli {
 enum Commands {
     /// run a binary or assembly file
     Run {
-        /// input file (binary or .s assembly)
+        /// input file (elf, intel hex, s-record, raw binary or .s assembly)
         #[arg(short, long)]
         file: PathBuf,
         
-        /// load address (default: 0)
+        /// load address for raw binaries (default: 0)
         #[arg(short, long, default_value = "0")]
         addr: String,
         
@@ -30,6 +32,15 @@ enum Commands {
         /// show performance metrics
         #[arg(short = 'p', long)]
         perf: bool,
+        
+        #[command(flatten)]
+        run: Box<RunOpts>,
+        
+        #[command(flatten)]
+        machine: MachineOpts,
+        
+        #[command(flatten)]
+        user: UserOpts,
     },
     
     /// assemble a .s file to binary
@@ -38,60 +49,343 @@ enum Commands {
         #[arg(short, long)]
         input: PathBuf,
         
-        /// output binary file
+        /// output file: .hex for intel hex, .srec/.s19/.s28/.s37 for s-records, raw binary otherwise
         #[arg(short, long)]
         output: PathBuf,
+        
+        /// address the code is placed at in hex and s-record output
+        #[arg(short, long, default_value = "0")]
+        base: String,
     },
     
     /// run with interactive debugger
     Debug {
-        /// input file (binary or .s assembly)
+        /// input file (elf, intel hex, s-record, raw binary or .s assembly)
         #[arg(short, long)]
         file: PathBuf,
         
-        /// load address (default: 0)
+        /// load address for raw binaries (default: 0)
         #[arg(short, long, default_value = "0")]
         addr: String,
+        
+        #[command(flatten)]
+        machine: MachineOpts,
+    },
+    
+    /// boot firmware and a kernel on a virt-like machine with a uart console
+    Boot {
+        /// firmware (opensbi fw_jump or fw_dynamic), loaded at the start of ram
+        #[arg(long)]
+        firmware: Option<PathBuf>,
+        
+        /// kernel image, loaded 4m into ram; elf files go where they say
+        #[arg(long)]
+        kernel: Option<PathBuf>,
+        
+        /// initial ramdisk, placed in ram and passed in /chosen
+        #[arg(long)]
+        initrd: Option<PathBuf>,
+        
+        /// kernel command line
+        #[arg(long)]
+        append: Option<String>,
+        
+        /// ram size in mib
+        #[arg(long, default_value = "128")]
+        mem: usize,
+        
+        /// harts; they all start at the entry point with a0 = their hart id
+        #[arg(long, default_value_t = 1)]
+        harts: usize,
+        
+        /// max instructions to execute (0: no limit)
+        #[arg(short, long, default_value = "0")]
+        max_steps: usize,
+        
+        /// also write the generated device tree to a file
+        #[arg(long)]
+        dump_dtb: Option<PathBuf>,
+        
+        /// log console input to a file, for --replay
+        #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+        record: Option<PathBuf>,
+        
+        /// boot again exactly as recorded, with the same images and options
+        #[arg(long, value_name = "FILE")]
+        replay: Option<PathBuf>,
+    },
+    
+    /// translate a program's reachable code to c, to run without the emulator
+    Recompile {
+        /// input file (elf, intel hex, s-record, raw binary or .s assembly)
+        #[arg(short, long)]
+        file: PathBuf,
+        
+        /// load address for raw binaries (default: 0)
+        #[arg(short, long, default_value = "0")]
+        addr: String,
+        
+        /// output .c file; its header goes next to it with a .h extension
+        #[arg(short, long)]
+        output: PathBuf,
+        
+        /// more code to translate, beyond what the entry point and function symbols reach: an address or symbol (repeatable)
+        #[arg(long = "entry", value_name = "LOC")]
+        entry: Vec<String>,
+        
+        /// prefix for the names in the generated code
+        #[arg(long, default_value = "rv32")]
+        prefix: String,
+        
+        #[command(flatten)]
+        machine: MachineOpts,
     },
 }
 
+// devices and boot setup shared by run and debug
+#[derive(Args)]
+struct MachineOpts {
+    /// where the 1 mib of ram starts (riscv-tests are linked at 0x80000000)
+    #[arg(long, default_value = "0")]
+    ram_base: String,
+    
+    /// map a read-only boot rom: <file>@<addr>
+    #[arg(long)]
+    rom: Option<String>,
+    
+    /// map nor flash: <file>@<addr>[:size]
+    #[arg(long)]
+    flash: Option<String>,
+    
+    /// start executing here instead of at the load address
+    #[arg(long)]
+    reset_vector: Option<String>,
+    
+    /// attach virtio-net: unix:<local>,<peer> or pcap:<file>
+    #[arg(long)]
+    net: Option<String>,
+    
+    /// attach a goldfish rtc: host, virtual or virtual:<unix seconds>
+    #[arg(long)]
+    rtc: Option<String>,
+    
+    /// attach a framebuffer: <w>x<h>[:rgb565|xrgb8888]
+    #[arg(long)]
+    fb: Option<String>,
+    
+    /// where framebuffer frames go; .png or .ppm, numbered per frame
+    #[arg(long, default_value = "frame.ppm")]
+    fb_out: PathBuf,
+    
+    /// also dump a frame every n instructions (0: only when the guest asks)
+    #[arg(long, default_value = "0")]
+    fb_interval: u32,
+    
+    /// generate a device tree at the top of ram and pass it in a1
+    #[arg(long)]
+    dtb: bool,
+    
+    /// also write the generated device tree to a file
+    #[arg(long)]
+    dump_dtb: Option<PathBuf>,
+    
+    /// load another image: <file>[@<addr>]; elf and hex files carry their own addresses (repeatable)
+    #[arg(long = "load", value_name = "FILE[@ADDR]")]
+    load: Vec<String>,
+    
+    /// fill ram before anything is loaded: <addr>:<len>:<byte> (repeatable)
+    #[arg(long, value_name = "ADDR:LEN:BYTE")]
+    fill: Vec<String>,
+    
+    /// set a register before starting: <reg>=<value>, e.g. a0=0x1000, sp=stack_top or pc=0x200 (repeatable)
+    #[arg(long = "reg", value_name = "REG=VALUE")]
+    reg: Vec<String>,
+    
+    /// handle semihosting calls (the slli/ebreak/srai sequence)
+    #[arg(long)]
+    semihosting: bool,
+    
+    /// what SYS_GET_CMDLINE returns (default: the program path)
+    #[arg(long, requires = "semihosting")]
+    cmdline: Option<String>,
+    
+    /// harts sharing the machine; the others start like hart 0 with a0 = their hart id
+    #[arg(long, default_value_t = 1)]
+    harts: usize,
+    
+    /// instructions each hart runs before the next one gets a turn
+    #[arg(long, default_value_t = smp::DEFAULT_QUANTUM)]
+    quantum: usize,
+}
+
+// how `run` executes the machine
+#[derive(Args)]
+struct RunOpts {
+    /// run every hart on its own host thread: faster, but no longer deterministic
+    #[arg(long, conflicts_with = "user")]
+    threads: bool,
+    
+    /// write a snapshot once the machine has run this many instructions, then carry on
+    #[arg(long, value_name = "N", conflicts_with_all = ["user", "threads"])]
+    snapshot_at: Option<u64>,
+    
+    /// where --snapshot-at writes the snapshot
+    #[arg(long, value_name = "FILE", default_value = "rv32.snap")]
+    snapshot: PathBuf,
+    
+    /// resume from a snapshot taken with the same program and machine options
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["user", "threads"])]
+    restore: Option<PathBuf>,
+    
+    /// log all input from the host (console, network, clocks, stdin, random bytes) to a file
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["threads", "restore", "replay"])]
+    record: Option<PathBuf>,
+    
+    /// run again exactly as recorded, with the same program and options
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["threads", "restore"])]
+    replay: Option<PathBuf>,
+}
+
+// linux user-mode process options
+#[derive(Args)]
+struct UserOpts {
+    /// run a static linux elf as a user process, with argv/envp/auxv on the stack
+    #[arg(long, conflicts_with_all = ["ram_base", "semihosting", "rom", "flash", "reset_vector", "dtb", "dump_dtb", "harts"])]
+    user: bool,
+    
+    /// environment variable for the guest, KEY=VALUE (repeatable)
+    #[arg(short = 'E', long = "env", requires = "user")]
+    env: Vec<String>,
+    
+    /// host directory the guest sees as / for file syscalls
+    #[arg(long, default_value = ".", requires = "user")]
+    root: PathBuf,
+    
+    /// guest arguments, after `--`. argv[0] is the program path
+    #[arg(last = true, requires = "user")]
+    args: Vec<String>,
+}
+
 fn main() {
     let cli = Cli::parse();
     
     match cli.command {
-        Commands::Run { file, addr, max_steps, perf } => {
-            run_file(&file, &addr, max_steps, perf);
+        Commands::Run { file, addr, max_steps, perf, run, machine, user } => {
+            run_file(&file, &addr, max_steps, perf, &run, &machine, &user);
+        }
+        Commands::Asm { input, output, base } => {
+            assemble_file(&input, &output, &base);
+        }
+        Commands::Debug { file, addr, machine } => {
+            debug_file(&file, &addr, &machine);
         }
-        Commands::Asm { input, output } => {
-            assemble_file(&input, &output);
+        Commands::Boot { firmware, kernel, initrd, append, mem, harts, max_steps, dump_dtb, record, replay } => {
+            let opts = BootOpts { firmware, kernel, initrd, append, mem, harts, dump_dtb, record, replay };
+            boot_machine(&opts, max_steps);
         }
-        Commands::Debug { file, addr } => {
-            debug_file(&file, &addr);
+        Commands::Recompile { file, addr, output, entry, prefix, machine } => {
+            recompile_file(&file, &addr, &output, &entry, &prefix, &machine);
         }
     }
 }
 
-fn run_file(path: &PathBuf, addr_str: &str, max_steps: usize, show_perf: bool) {
-    let mut cpu = cpu::Cpu::new();
+fn run_file(path: &PathBuf, addr_str: &str, max_steps: usize, show_perf: bool, run: &RunOpts, machine: &MachineOpts, user: &UserOpts) {
+    let mut cpu = new_cpu(machine);
     let mut exec = executor::Executor::new();
     let mut metrics = metrics::Metrics::new();
     
-    let data = load_program(path);
-    let addr = parse_addr(addr_str).expect("invalid load address");
+    // set before anything is loaded: a user process's stack gets random bytes
+    let journal = open_journal(&run.record, &run.replay);
+    if let Some(journal) = &journal {
+        cpu.set_journal(journal.clone());
+    }
+    
+    let symbols = if user.user {
+        let (symbols, layout) = build_process(&mut cpu, path, user, machine);
+        match syscalls::LinuxSyscalls::new(&user.root, layout) {
+            Ok(sys) => exec.ecall = Some(Box::new(sys)),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        symbols
+    } else {
+        let addr = parse_addr(addr_str).expect("invalid load address");
+        let symbols = build_machine(&mut cpu, path, addr, machine).0;
+        exec.htif = htif::Htif::from_symbols(&symbols);
+        exec.semihosting = semihosting_for(path, machine);
+        symbols
+    };
     
-    cpu.load_program(&data, addr);
-    cpu.pc = addr;
+    if let Some(journal) = &journal {
+        begin_journal(journal, &cpu);
+    }
     
-    if show_perf {
-        metrics.start();
+    // every hart starts from the registers set up so far
+    let mut smp = (cpu.harts() > 1 && !run.threads).then(|| smp::Smp::new(&mut cpu, machine.quantum));
+    
+    // the machine is built as usual, then the snapshot overwrites its state
+    let mut done = 0;
+    if let Some(file) = &run.restore {
+        match snapshot::restore_file(file, &mut cpu, &mut exec, smp.as_mut()) {
+            Ok(steps) => {
+                println!("resumed from {} after {} instructions", file.display(), steps);
+                done = steps;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
     }
+    let snap = run.snapshot_at.map(|at| (at, run.snapshot.as_path(), done));
     
-    match exec.run(&mut cpu, &mut metrics, max_steps) {
+    // without -p the executor runs with no instrumentation at all
+    let result = if run.threads {
+        run_threads(&mut cpu, &exec, show_perf.then_some((&mut metrics, symbols)), max_steps)
+    } else if show_perf {
+        metrics.set_symbols(symbols);
+        metrics.start();
+        run_machine(&mut exec, &mut cpu, smp.as_mut(), &mut metrics, max_steps, snap)
+    } else {
+        run_machine(&mut exec, &mut cpu, smp.as_mut(), &mut metrics::NoMetrics, max_steps, snap)
+    };
+    finish_journal(journal.as_ref());
+    match result {
+        // user programs talk for themselves and hand back their exit status
+        Ok(steps) if user.user => {
+            if show_perf {
+                println!("executed {} instructions", steps);
+                metrics.print_summary();
+            }
+            match exec.exit_code {
+                Some(code) => std::process::exit(code),
+                None => {
+                    eprintln!("program didn't exit within {} instructions", steps);
+                    std::process::exit(1);
+                }
+            }
+        }
         Ok(steps) => {
             println!("executed {} instructions", steps);
             if show_perf {
                 metrics.print_summary();
             }
+            // riscv-tests put the failing test number in the exit code
+            match exec.exit_code {
+                Some(0) if exec.htif.is_some() => println!("htif: pass"),
+                Some(code) if exec.htif.is_some() => {
+                    println!("htif: fail (test {})", code);
+                    std::process::exit(code);
+                }
+                Some(code) => std::process::exit(code),
+                None if exec.htif.is_some() => {
+                    eprintln!("htif: program didn't write tohost within {} instructions", steps);
+                    std::process::exit(1);
+                }
+                None => {}
+            }
         }
         Err(e) => {
             eprintln!("execution error: {}", e);
@@ -100,14 +394,99 @@ fn run_file(path: &PathBuf, addr_str: &str, max_steps: usize, show_perf: bool) {
     }
 }
 
-fn assemble_file(input: &PathBuf, output: &PathBuf) {
+// run_harts, stopping along the way for --snapshot-at. `snap` is the
+// instruction count to take it at, where to write it and how many
+// instructions the machine had already run when this run started
+fn run_machine<M: metrics::Instrument>(
+    exec: &mut executor::Executor,
+    cpu: &mut cpu::Cpu,
+    mut smp: Option<&mut smp::Smp>,
+    metrics: &mut M,
+    max_steps: usize,
+    snap: Option<(u64, &Path, u64)>,
+) -> Result<usize, String> {
+    let Some((at, path, done)) = snap else {
+        return run_harts(exec, cpu, smp, metrics, max_steps);
+    };
+    if at < done {
+        return Err(format!("the machine is already past instruction {} ({})", at, done));
+    }
+    let first = usize::try_from(at - done).unwrap_or(usize::MAX).min(max_steps);
+    let steps = run_harts(exec, cpu, smp.as_deref_mut(), metrics, first)?;
+    if done + steps as u64 != at {
+        eprintln!("stopped after {} instructions, before --snapshot-at {}; no snapshot written", done + steps as u64, at);
+        return Ok(steps);
+    }
+    snapshot::save_file(path, cpu, exec, smp.as_deref(), at)?;
+    println!("snapshot after {} instructions written to {}", at, path.display());
+    if exec.halted || exec.exit_code.is_some() {
+        return Ok(steps);
+    }
+    Ok(steps + run_harts(exec, cpu, smp, metrics, max_steps - steps)?)
+}
+
+fn run_harts<M: metrics::Instrument>(
+    exec: &mut executor::Executor,
+    cpu: &mut cpu::Cpu,
+    smp: Option<&mut smp::Smp>,
+    metrics: &mut M,
+    max_steps: usize,
+) -> Result<usize, String> {
+    match smp {
+        Some(smp) => smp.run(exec, cpu, metrics, max_steps),
+        None => exec.run(cpu, metrics, max_steps),
+    }
+}
+
+// every hart on its own thread, with metrics kept per hart and added up
+// afterwards
+fn run_threads(
+    cpu: &mut cpu::Cpu,
+    exec: &executor::Executor,
+    perf: Option<(&mut metrics::Metrics, Arc<symbols::SymbolMap>)>,
+    max_steps: usize,
+) -> Result<usize, String> {
+    if exec.htif.is_some() || exec.semihosting.is_some() {
+        return Err("htif and semihosting don't work with --threads".to_string());
+    }
+    let Some((metrics, symbols)) = perf else {
+        let mut none: Vec<_> = (0..cpu.harts()).map(|_| metrics::NoMetrics).collect();
+        return threads::run(cpu, &mut none, max_steps);
+    };
+    let mut per_hart: Vec<_> = (0..cpu.harts())
+        .map(|_| {
+            let mut m = metrics::Metrics::new();
+            m.set_symbols(symbols.clone());
+            m
+        })
+        .collect();
+    metrics.set_symbols(symbols);
+    metrics.start();
+    let steps = threads::run(cpu, &mut per_hart, max_steps)?;
+    for (hart, m) in per_hart.iter().enumerate() {
+        metrics.merge_hart(hart, m);
+    }
+    Ok(steps)
+}
+
+fn assemble_file(input: &PathBuf, output: &PathBuf, base_str: &str) {
     let source = fs::read_to_string(input)
         .expect("failed to read input file");
+    let base = parse_addr(base_str).expect("invalid base address");
     
     let mut asm = assembler::Assembler::new();
     match asm.assemble(&source) {
         Ok(code) => {
-            fs::write(output, code)
+            let format = output.extension()
+                .and_then(|s| s.to_str())
+                .and_then(hexfile::HexFormat::from_extension);
+            // execution starts at the first instruction
+            let out = match format {
+                Some(hexfile::HexFormat::Ihex) => hexfile::write_ihex(&code, base, Some(base)).into_bytes(),
+                Some(hexfile::HexFormat::Srec) => hexfile::write_srec(&code, base, Some(base)).into_bytes(),
+                None => code,
+            };
+            fs::write(output, out)
                 .expect("failed to write output file");
             println!("assembled to {}", output.display());
         }
@@ -118,32 +497,415 @@ fn assemble_file(input: &PathBuf, output: &PathBuf) {
     }
 }
 
-fn debug_file(path: &PathBuf, addr_str: &str) {
-    let mut cpu = cpu::Cpu::new();
+fn debug_file(path: &PathBuf, addr_str: &str, machine: &MachineOpts) {
+    let mut cpu = new_cpu(machine);
     let mut metrics = metrics::Metrics::new();
     let mut dbg = debugger::Debugger::new();
     
-    let data = load_program(path);
     let addr = parse_addr(addr_str).expect("invalid load address");
-    
-    cpu.load_program(&data, addr);
-    cpu.pc = addr;
+    (dbg.symbols, dbg.lines) = build_machine(&mut cpu, path, addr, machine);
+    dbg.executor.semihosting = semihosting_for(path, machine);
+    dbg.smp = (cpu.harts() > 1).then(|| smp::Smp::new(&mut cpu, machine.quantum));
     
     dbg.run(&mut cpu, &mut metrics);
 }
 
-fn load_program(path: &PathBuf) -> Vec<u8> {
+fn recompile_file(path: &PathBuf, addr_str: &str, output: &Path, entries: &[String], prefix: &str, machine: &MachineOpts) {
+    let mut cpu = new_cpu(machine);
+    let addr = parse_addr(addr_str).expect("invalid load address");
+    let (syms, _) = build_machine(&mut cpu, path, addr, machine);
+    
+    let header = output.with_extension("h");
+    let result = entries.iter()
+        .map(|e| parse_addr(e).or_else(|err| syms.resolve(e).ok_or(err)))
+        .collect::<Result<Vec<u32>, String>>()
+        .and_then(|entries| {
+            let opts = recompile::Options {
+                prefix: prefix.to_string(),
+                header: header.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                source: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                entries,
+            };
+            recompile::recompile(&cpu, &syms, &opts)
+        })
+        .and_then(|out| {
+            fs::write(output, &out.source).map_err(|e| format!("{}: {}", output.display(), e))?;
+            fs::write(&header, &out.header).map_err(|e| format!("{}: {}", header.display(), e))?;
+            Ok(out)
+        });
+    match result {
+        Ok(out) => println!("recompiled {} instructions in {} blocks to {} and {}",
+            out.instructions, out.blocks, output.display(), header.display()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+struct BootOpts {
+    firmware: Option<PathBuf>,
+    kernel: Option<PathBuf>,
+    initrd: Option<PathBuf>,
+    append: Option<String>,
+    mem: usize,
+    harts: usize,
+    dump_dtb: Option<PathBuf>,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+}
+
+fn boot_machine(opts: &BootOpts, max_steps: usize) {
+    let read = |path: &Option<PathBuf>| -> Result<Option<Vec<u8>>, String> {
+        path.as_ref()
+            .map(|p| fs::read(p).map_err(|e| format!("{}: {}", p.display(), e)))
+            .transpose()
+    };
+    let result = read(&opts.firmware).and_then(|firmware| {
+        let kernel = read(&opts.kernel)?;
+        let initrd = read(&opts.initrd)?;
+        let images = boot::BootImages {
+            firmware: firmware.as_deref(),
+            kernel: kernel.as_deref(),
+            initrd: initrd.as_deref(),
+            bootargs: opts.append.clone(),
+        };
+        boot::build(opts.mem * 1024 * 1024, opts.harts, &images, uart::Ns16550::stdio())
+    });
+    let (mut cpu, info) = result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    
+    if let Some(path) = &opts.dump_dtb {
+        let blob = &cpu.mem[(info.dtb - cpu.ram_base) as usize..][..info.dtb_size];
+        if let Err(e) = fs::write(path, blob) {
+            eprintln!("failed to write {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+    // the console owns stdout, so the banner goes to stderr
+    eprintln!("ram 0x{:08x}-0x{:08x}, entry 0x{:08x}, device tree at 0x{:08x}",
+        cpu.ram_base, cpu.ram_end() - 1, info.entry, info.dtb);
+    if let Some((start, end)) = info.initrd {
+        eprintln!("initrd 0x{:08x}-0x{:08x}", start, end);
+    }
+    
+    let journal = open_journal(&opts.record, &opts.replay);
+    if let Some(journal) = &journal {
+        cpu.set_journal(journal.clone());
+        begin_journal(journal, &cpu);
+    }
+    
+    let mut exec = executor::Executor::new();
+    let mut smp = (cpu.harts() > 1).then(|| smp::Smp::new(&mut cpu, smp::DEFAULT_QUANTUM));
+    let limit = if max_steps == 0 { usize::MAX } else { max_steps };
+    let result = run_harts(&mut exec, &mut cpu, smp.as_mut(), &mut metrics::NoMetrics, limit);
+    finish_journal(journal.as_ref());
+    match result {
+        Ok(steps) => eprintln!("stopped after {} instructions", steps),
+        Err(e) => {
+            eprintln!("execution error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// the journal for --record or --replay, if either was given
+fn open_journal(record: &Option<PathBuf>, replay: &Option<PathBuf>) -> Option<replay::Journal> {
+    let journal = match (record, replay) {
+        (Some(path), _) => replay::Journal::record(path),
+        (None, Some(path)) => replay::Journal::replay(path),
+        (None, None) => return None,
+    };
+    Some(journal.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }))
+}
+
+// once the machine is built, before it runs
+fn begin_journal(journal: &replay::Journal, cpu: &cpu::Cpu) {
+    if let Err(e) = journal.begin(cpu) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+// flush a recording, or report how a replay went. a replay that diverged
+// ends here with the instruction it diverged at
+fn finish_journal(journal: Option<&replay::Journal>) {
+    match journal.map(|j| j.finish()) {
+        None | Some(Ok(0)) => {}
+        Some(Ok(left)) => eprintln!("replay stopped with {} logged events still to come", left),
+        Some(Err(e)) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn semihosting_for(path: &Path, machine: &MachineOpts) -> Option<semihosting::Semihosting> {
+    machine.semihosting.then(|| {
+        let cmdline = machine.cmdline.clone().unwrap_or_else(|| path.display().to_string());
+        semihosting::Semihosting::new(&cmdline)
+    })
+}
+
+fn new_cpu(machine: &MachineOpts) -> cpu::Cpu {
+    let base = parse_addr(&machine.ram_base).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if base as u64 + cpu::MEM_SIZE as u64 > 1 << 32 {
+        eprintln!("ram at 0x{:x} would run past 4g", base);
+        std::process::exit(1);
+    }
+    if machine.harts == 0 {
+        eprintln!("--harts must be at least 1");
+        std::process::exit(1);
+    }
+    let mut cpu = cpu::Cpu::with_ram(base, cpu::MEM_SIZE);
+    // before the device tree gets built, so it lists every hart
+    cpu.set_harts(machine.harts);
+    cpu
+}
+
+// load the program and set up devices, exiting on any error. returns every
+// symbol we found along the way, and the program's line table if it has one
+fn build_machine(cpu: &mut cpu::Cpu, path: &PathBuf, addr: u32, machine: &MachineOpts) -> (Arc<symbols::SymbolMap>, dwarf::LineTable) {
+    let mut syms = symbols::SymbolMap::new();
+    let mut lines = dwarf::LineTable::new();
+    let result = fill_ram(cpu, machine)
+        .and_then(|_| load_image(cpu, path, addr))
+        .and_then(|(s, l)| {
+            syms.extend(s);
+            lines = l;
+            setup_machine(cpu, machine, &mut syms)
+        });
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    (Arc::new(syms), lines)
+}
+
+// user-mode counterpart of build_machine: a static elf plus its startup stack
+fn build_process(cpu: &mut cpu::Cpu, path: &PathBuf, user: &UserOpts, machine: &MachineOpts) -> (Arc<symbols::SymbolMap>, process::Layout) {
+    let result = fill_ram(cpu, machine)
+        .and_then(|_| fs::read(path).map_err(|e| e.to_string()))
+        .and_then(|data| {
+            let file = elf::ElfFile::parse(&data)?;
+            let mut argv = vec![path.display().to_string()];
+            argv.extend(user.args.iter().cloned());
+            let layout = process::start(cpu, &file, &argv, &user.env)?;
+            Ok((symbols::SymbolMap::from_elf(&file)?, layout))
+        })
+        .map_err(|e| format!("{}: {}", path.display(), e))
+        .and_then(|(mut syms, layout)| {
+            setup_machine(cpu, machine, &mut syms)?;
+            Ok((Arc::new(syms), layout))
+        });
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+fn setup_machine(cpu: &mut cpu::Cpu, opts: &MachineOpts, syms: &mut symbols::SymbolMap) -> Result<(), String> {
+    // extra images keep the entry point of the main one
+    for spec in &opts.load {
+        let (pc, reset_vector) = (cpu.pc, cpu.reset_vector);
+        syms.extend(load_extra(cpu, spec)?);
+        (cpu.pc, cpu.reset_vector) = (pc, reset_vector);
+    }
+    
+    if let Some(spec) = &opts.rom {
+        let (path, base) = parse_image_spec(spec)?;
+        let (data, labels) = load_region_image(&path, base)?;
+        let rom = flash::Rom::new(&data);
+        cpu.attach_device(base, rom.size(), Box::new(rom))?;
+        syms.extend(symbols::SymbolMap::from_labels(&labels, base));
+    }
+    
+    if let Some(spec) = &opts.flash {
+        let (path, rest) = spec.split_once('@')
+            .ok_or_else(|| format!("expected <file>@<addr>[:size], got {}", spec))?;
+        let (base, size) = match rest.split_once(':') {
+            Some((a, sz)) => (parse_addr(a)?, parse_addr(sz)?),
+            None => (parse_addr(rest)?, 0),
+        };
+        let (data, labels) = load_region_image(&PathBuf::from(path), base)?;
+        let nor = flash::NorFlash::new(&data, size)?;
+        cpu.attach_device(base, nor.size(), Box::new(nor))?;
+        syms.extend(symbols::SymbolMap::from_labels(&labels, base));
+    }
+    
+    if let Some(spec) = &opts.net {
+        let backend = virtio_net::backend_from_spec(spec)?;
+        // locally administered mac, fixed so guest configs can hardcode it
+        let dev = virtio_net::VirtioNet::new(backend, [0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        cpu.attach_device(virtio_net::VIRTIO_NET_BASE, virtio_net::VIRTIO_MMIO_SIZE, Box::new(dev))?;
+    }
+    
+    if let Some(mode) = &opts.rtc {
+        let mode = rtc::RtcMode::parse(mode)?;
+        cpu.attach_device(rtc::RTC_BASE, rtc::RTC_SIZE, Box::new(rtc::GoldfishRtc::new(mode)))?;
+    }
+    
+    if let Some(spec) = &opts.fb {
+        let config = framebuffer::FbConfig::parse(spec)?;
+        let fb = framebuffer::Framebuffer::new(config, &opts.fb_out, opts.fb_interval);
+        cpu.attach_device(framebuffer::FB_BASE, config.region_size(), Box::new(fb))?;
+    }
+    
+    if let Some(v) = &opts.reset_vector {
+        cpu.reset_vector = parse_addr(v)?;
+        cpu.pc = cpu.reset_vector;
+    }
+    
+    // the tree describes the devices, so it has to come after they're attached
+    if opts.dtb || opts.dump_dtb.is_some() {
+        let blob = fdt::generate(cpu, &fdt::Chosen::default());
+        if let Some(path) = &opts.dump_dtb {
+            fs::write(path, &blob)
+                .map_err(|e| format!("failed to write {}: {}", path.display(), e))?;
+        }
+        if opts.dtb {
+            let addr = fdt::install(cpu, &blob)?;
+            println!("device tree at 0x{:08x} ({} bytes)", addr, blob.len());
+        }
+    }
+    
+    // last, so they win over the entry point and the dtb's a0/a1
+    for spec in &opts.reg {
+        let (name, value) = spec.split_once('=')
+            .ok_or_else(|| format!("expected <reg>=<value>, got {}", spec))?;
+        let value = parse_addr(value)
+            .or_else(|e| syms.resolve(value).ok_or(e))?;
+        if name == "pc" {
+            cpu.pc = value;
+            cpu.reset_vector = value;
+        } else {
+            let reg = cpu::reg_index(name)
+                .ok_or_else(|| format!("unknown register: {}", name))?;
+            cpu.write_reg(reg, value);
+        }
+    }
+    
+    Ok(())
+}
+
+// --fill regions, before any image goes in on top
+fn fill_ram(cpu: &mut cpu::Cpu, opts: &MachineOpts) -> Result<(), String> {
+    for spec in &opts.fill {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let [addr, len, byte] = parts[..] else {
+            return Err(format!("expected <addr>:<len>:<byte>, got {}", spec));
+        };
+        let (addr, len) = (parse_addr(addr)?, parse_addr(len)?);
+        let byte = u8::try_from(parse_addr(byte)?)
+            .map_err(|_| format!("fill byte out of range: {}", byte))?;
+        let start = cpu.ram_offset(addr, len as usize)
+            .ok_or_else(|| format!("fill 0x{:x}+0x{:x} isn't all ram", addr, len))?;
+        cpu.mem[start..start + len as usize].fill(byte);
+    }
+    Ok(())
+}
+
+// a --load image. only raw binaries and assembly need an address
+fn load_extra(cpu: &mut cpu::Cpu, spec: &str) -> Result<symbols::SymbolMap, String> {
+    let (path, addr) = match spec.split_once('@') {
+        Some((p, a)) => (PathBuf::from(p), Some(parse_addr(a)?)),
+        None => (PathBuf::from(spec), None),
+    };
+    let addr = match addr {
+        Some(a) => a,
+        None => {
+            let data = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let is_asm = path.extension().and_then(|s| s.to_str()) == Some("s");
+            if is_asm || !(elf::is_elf(&data) || hexfile::detect(&data).is_some()) {
+                return Err(format!("{}: needs a load address, <file>@<addr>", path.display()));
+            }
+            0
+        }
+    };
+    Ok(load_image(cpu, &path, addr)?.0)
+}
+
+// "<file>@<addr>"
+fn parse_image_spec(spec: &str) -> Result<(PathBuf, u32), String> {
+    let (path, addr) = spec.split_once('@')
+        .ok_or_else(|| format!("expected <file>@<addr>, got {}", spec))?;
+    Ok((PathBuf::from(path), parse_addr(addr)?))
+}
+
+// elf files go where their program headers say and set the pc from e_entry.
+// hex and s-record files carry their own addresses and start record.
+// anything else is a flat image at `addr`
+fn load_image(cpu: &mut cpu::Cpu, path: &PathBuf, addr: u32) -> Result<(symbols::SymbolMap, dwarf::LineTable), String> {
+    let (data, labels) = load_program(path);
+    if elf::is_elf(&data) {
+        let file = elf::ElfFile::parse(&data)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        file.load(cpu)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let syms = symbols::SymbolMap::from_elf(&file)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        // broken debug info shouldn't stop the program from running
+        let lines = dwarf::LineTable::from_elf(&file).unwrap_or_else(|e| {
+            eprintln!("{}: ignoring .debug_line: {}", path.display(), e);
+            dwarf::LineTable::new()
+        });
+        Ok((syms, lines))
+    } else if hexfile::detect(&data).is_some() {
+        let image = String::from_utf8(data)
+            .map_err(|e| e.to_string())
+            .and_then(|text| hexfile::HexImage::parse(&text))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        image.load(cpu)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok((symbols::SymbolMap::new(), dwarf::LineTable::new()))
+    } else {
+        if cpu.ram_offset(addr, data.len()).is_none() {
+            return Err(format!("{}: image doesn't fit in ram at 0x{:x}", path.display(), addr));
+        }
+        cpu.load_program(&data, addr);
+        cpu.pc = addr;
+        cpu.reset_vector = addr;
+        Ok((symbols::SymbolMap::from_labels(&labels, addr), dwarf::LineTable::new()))
+    }
+}
+
+// rom and flash contents. hex and s-record images are flattened so their
+// lowest possible address is `base`
+fn load_region_image(path: &PathBuf, base: u32) -> Result<(Vec<u8>, HashMap<String, u32>), String> {
+    let (data, labels) = load_program(path);
+    if hexfile::detect(&data).is_none() {
+        return Ok((data, labels));
+    }
+    let text = String::from_utf8(data).map_err(|e| e.to_string())?;
+    let flat = hexfile::HexImage::parse(&text)
+        .and_then(|image| image.flatten(base))
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok((flat, labels))
+}
+
+// file contents plus any labels, if it was assembly
+fn load_program(path: &PathBuf) -> (Vec<u8>, HashMap<String, u32>) {
     if path.extension().and_then(|s| s.to_str()) == Some("s") {
         // assemble on the fly
         let source = fs::read_to_string(path)
             .expect("failed to read assembly file");
         let mut asm = assembler::Assembler::new();
-        asm.assemble(&source)
-            .expect("failed to assemble")
+        let code = asm.assemble(&source)
+            .expect("failed to assemble");
+        (code, asm.labels().clone())
     } else {
-        // TODO: add proper elf32 loader instead of just raw binary
-        fs::read(path)
-            .expect("failed to read binary file")
+        // raw binary, elf, intel hex or s-record; load_image tells them apart
+        let data = fs::read(path)
+            .expect("failed to read binary file");
+        (data, HashMap::new())
     }
 }
 
@@ -1,24 +1,126 @@
 // performance metrics tracking
 
-use crate::decoder::Instruction;
+use crate::decoder::{Instruction, Opcode};
+use crate::symbols::SymbolMap;
 use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::Instant;
 
+// what the executor reports as it runs. it's a type parameter of the
+// executor's run and step, so an implementation whose methods are empty
+// (NoMetrics) compiles away entirely
+pub trait Instrument {
+    fn record_opcode(&mut self, opcode: Opcode);
+    fn record_pc(&mut self, pc: u32);
+    fn record_branch(&mut self, taken: bool);
+    // `times` runs of a whole translated block, `mix` being its per-opcode
+    // counts
+    fn record_block(&mut self, start: u32, len: u64, mix: &[(Opcode, u64)], times: u64);
+    // branch outcomes counted elsewhere, e.g. by native code
+    fn record_branches(&mut self, taken: u64, not_taken: u64);
+    // instructions run by one hart in its turn
+    fn record_hart(&mut self, hart: usize, instructions: u64);
+
+    fn record_instruction(&mut self, inst: &Instruction) {
+        self.record_opcode(inst.opcode);
+    }
+}
+
+// for runs without -p
+pub struct NoMetrics;
+
+impl Instrument for NoMetrics {
+    #[inline(always)]
+    fn record_opcode(&mut self, _opcode: Opcode) {}
+    #[inline(always)]
+    fn record_pc(&mut self, _pc: u32) {}
+    #[inline(always)]
+    fn record_branch(&mut self, _taken: bool) {}
+    #[inline(always)]
+    fn record_block(&mut self, _start: u32, _len: u64, _mix: &[(Opcode, u64)], _times: u64) {}
+    #[inline(always)]
+    fn record_branches(&mut self, _taken: u64, _not_taken: u64) {}
+    #[inline(always)]
+    fn record_hart(&mut self, _hart: usize, _instructions: u64) {}
+}
+
 pub struct Metrics {
     pub inst_count: u64,
-    pub inst_mix: HashMap<String, u64>,
+    // indexed by `opcode as usize`
+    pub inst_mix: [u64; Opcode::COUNT],
     pub branch_taken: u64,
     pub branch_not_taken: u64,
+    // per-pc counts, only collected once symbols are attached
+    pub pc_counts: HashMap<u32, u64>,
+    // instructions per hart, only filled in by multi-hart runs
+    pub hart_counts: Vec<u64>,
+    symbols: Option<Arc<SymbolMap>>,
     start_time: Option<Instant>,
 }
 
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Instrument for Metrics {
+    #[inline]
+    fn record_opcode(&mut self, opcode: Opcode) {
+        self.inst_count += 1;
+        self.inst_mix[opcode as usize] += 1;
+    }
+
+    #[inline]
+    fn record_pc(&mut self, pc: u32) {
+        if self.symbols.is_some() {
+            *self.pc_counts.entry(pc).or_insert(0) += 1;
+        }
+    }
+
+    #[inline]
+    fn record_branch(&mut self, taken: bool) {
+        if taken {
+            self.branch_taken += 1;
+        } else {
+            self.branch_not_taken += 1;
+        }
+    }
+
+    // function attribution goes by the block's start address
+    fn record_block(&mut self, start: u32, len: u64, mix: &[(Opcode, u64)], times: u64) {
+        self.inst_count += len * times;
+        for &(opcode, count) in mix {
+            self.inst_mix[opcode as usize] += count * times;
+        }
+        if self.symbols.is_some() {
+            *self.pc_counts.entry(start).or_insert(0) += len * times;
+        }
+    }
+
+    fn record_branches(&mut self, taken: u64, not_taken: u64) {
+        self.branch_taken += taken;
+        self.branch_not_taken += not_taken;
+    }
+
+    fn record_hart(&mut self, hart: usize, instructions: u64) {
+        if self.hart_counts.len() <= hart {
+            self.hart_counts.resize(hart + 1, 0);
+        }
+        self.hart_counts[hart] += instructions;
+    }
+}
+
 impl Metrics {
     pub fn new() -> Self {
         Metrics {
             inst_count: 0,
-            inst_mix: HashMap::new(),
+            inst_mix: [0; Opcode::COUNT],
             branch_taken: 0,
             branch_not_taken: 0,
+            pc_counts: HashMap::new(),
+            hart_counts: Vec::new(),
+            symbols: None,
             start_time: None,
         }
     }
@@ -27,18 +129,49 @@ impl Metrics {
         self.start_time = Some(Instant::now());
     }
 
-    pub fn record_instruction(&mut self, inst: &Instruction) {
-        self.inst_count += 1;
-        let name = format!("{:?}", inst.opcode);
-        *self.inst_mix.entry(name).or_insert(0) += 1;
+    // fold in the counts of a hart that ran with its own Metrics on
+    // another thread
+    pub fn merge_hart(&mut self, hart: usize, other: &Metrics) {
+        self.inst_count += other.inst_count;
+        for (total, n) in self.inst_mix.iter_mut().zip(other.inst_mix) {
+            *total += n;
+        }
+        self.branch_taken += other.branch_taken;
+        self.branch_not_taken += other.branch_not_taken;
+        for (&pc, &n) in &other.pc_counts {
+            *self.pc_counts.entry(pc).or_insert(0) += n;
+        }
+        self.record_hart(hart, other.inst_count);
     }
 
-    pub fn record_branch(&mut self, taken: bool) {
-        if taken {
-            self.branch_taken += 1;
-        } else {
-            self.branch_not_taken += 1;
+    // count of one opcode, for tests and tools
+    pub fn count(&self, opcode: Opcode) -> u64 {
+        self.inst_mix[opcode as usize]
+    }
+
+    // turn on per-function attribution in the summary
+    pub fn set_symbols(&mut self, symbols: Arc<SymbolMap>) {
+        if !symbols.is_empty() {
+            self.symbols = Some(symbols);
+        }
+    }
+
+    // instruction counts rolled up to the enclosing symbol
+    pub fn function_counts(&self) -> Vec<(String, u64)> {
+        let Some(symbols) = &self.symbols else {
+            return Vec::new();
+        };
+        let mut by_func: HashMap<String, u64> = HashMap::new();
+        for (pc, count) in &self.pc_counts {
+            let name = match symbols.lookup(*pc) {
+                Some(loc) => loc.sym.name.clone(),
+                None => "??".to_string(),
+            };
+            *by_func.entry(name).or_insert(0) += count;
         }
+        let mut sorted: Vec<_> = by_func.into_iter().collect();
+        sorted.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        sorted
     }
 
     pub fn mips(&self) -> f64 {
@@ -64,14 +197,36 @@ impl Metrics {
             println!("  not taken: {} ({:.1}%)", self.branch_not_taken, 100.0 - taken_pct);
         }
 
-        if !self.inst_mix.is_empty() {
+        let mut sorted: Vec<_> = Opcode::ALL.iter()
+            .map(|&op| (op, self.inst_mix[op as usize]))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        if !sorted.is_empty() {
             println!("\ninstruction mix:");
-            let mut sorted: Vec<_> = self.inst_mix.iter().collect();
-            sorted.sort_by(|a, b| b.1.cmp(a.1));
-            for (name, count) in sorted.iter().take(10) {
-                let pct = (**count as f64 / self.inst_count as f64) * 100.0;
+            sorted.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+            for (op, count) in sorted.iter().take(10) {
+                let pct = (*count as f64 / self.inst_count as f64) * 100.0;
+                // {:<12} doesn't pad Debug output, so name it first
+                let name = format!("{:?}", op);
                 println!("  {:<12} {:>8} ({:.1}%)", name, count, pct);
             }
         }
+
+        if self.hart_counts.len() > 1 {
+            println!("\nharts:");
+            for (hart, count) in self.hart_counts.iter().enumerate() {
+                let pct = (*count as f64 / self.inst_count as f64) * 100.0;
+                println!("  hart {:<7} {:>8} ({:.1}%)", hart, count, pct);
+            }
+        }
+
+        let funcs = self.function_counts();
+        if !funcs.is_empty() {
+            println!("\nfunctions:");
+            for (name, count) in funcs.iter().take(10) {
+                let pct = (*count as f64 / self.inst_count as f64) * 100.0;
+                println!("  {:<24} {:>8} ({:.1}%)", name, count, pct);
+            }
+        }
     }
 }
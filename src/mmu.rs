@@ -0,0 +1,272 @@
+// sv32 address translation
+//
+// a hart below m-mode with satp's mode bit set, or an m-mode hart whose
+// loads and stores mstatus.mprv sends through mpp's privilege, sees memory
+// through two-level page tables. there's no tlb: every access walks the
+// tables, so sfence.vma has nothing to flush and a guest that edits a live
+// page table sees the change at once. the walk sets the accessed and dirty
+// bits itself rather than faulting for software to do it. page tables have
+// to be in ram, and a leaf that maps past 4g is an access fault.
+//
+// Executor only runs blocks (and the jit) while nothing is translated; see
+// translating().
+
+use crate::cpu::Cpu;
+use crate::csr::{
+    Exception, CAUSE_FETCH_ACCESS, CAUSE_FETCH_PAGE_FAULT, CAUSE_LOAD_ACCESS, CAUSE_LOAD_PAGE_FAULT,
+    CAUSE_STORE_ACCESS, CAUSE_STORE_PAGE_FAULT, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SUM,
+    PRV_M, PRV_S, PRV_U,
+};
+
+const PAGE_SIZE: u32 = 4096;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    // stores and amos
+    Store,
+}
+
+impl Access {
+    fn page_fault(self) -> u32 {
+        match self {
+            Access::Fetch => CAUSE_FETCH_PAGE_FAULT,
+            Access::Load => CAUSE_LOAD_PAGE_FAULT,
+            Access::Store => CAUSE_STORE_PAGE_FAULT,
+        }
+    }
+
+    fn access_fault(self) -> u32 {
+        match self {
+            Access::Fetch => CAUSE_FETCH_ACCESS,
+            Access::Load => CAUSE_LOAD_ACCESS,
+            Access::Store => CAUSE_STORE_ACCESS,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Access::Fetch => "instruction",
+            Access::Load => "load",
+            Access::Store => "store",
+        }
+    }
+}
+
+// the privilege level `access` is checked at, if it goes through the page
+// tables at all
+#[inline]
+fn mode(cpu: &Cpu, access: Access) -> Option<u8> {
+    let c = &cpu.csrs;
+    if c.satp >> 31 == 0 {
+        return None;
+    }
+    let mut prv = c.privilege;
+    if access != Access::Fetch && prv == PRV_M && c.mstatus & MSTATUS_MPRV != 0 {
+        prv = ((c.mstatus & MSTATUS_MPP) >> 11) as u8;
+    }
+    (prv != PRV_M).then_some(prv)
+}
+
+// whether any access the hart makes right now could be translated
+#[inline]
+pub fn translating(cpu: &Cpu) -> bool {
+    mode(cpu, Access::Fetch).is_some() || mode(cpu, Access::Load).is_some()
+}
+
+// the physical address for an `access` to virtual address `addr`
+pub fn translate(cpu: &mut Cpu, addr: u32, access: Access) -> Result<u32, String> {
+    let Some(prv) = mode(cpu, access) else {
+        return Ok(addr);
+    };
+    match walk(cpu, addr, access, prv) {
+        Ok((pa, pte_addr, pte)) => {
+            let updated = pte | PTE_A | if access == Access::Store { PTE_D } else { 0 };
+            if updated != pte {
+                cpu.write_word(pte_addr, updated);
+            }
+            Ok(pa)
+        }
+        Err((cause, what)) => {
+            let e = format!("{} {} fault at 0x{:x}", access.name(), what, addr);
+            Err(cpu.raise(Exception::new(cause, addr), e))
+        }
+    }
+}
+
+// the same without side effects: no accessed or dirty bits, no trap. for
+// the debugger, which looks at memory the way the hart would
+pub fn peek(cpu: &Cpu, addr: u32, access: Access) -> Option<u32> {
+    match mode(cpu, access) {
+        Some(prv) => walk(cpu, addr, access, prv).ok().map(|(pa, _, _)| pa),
+        None => Some(addr),
+    }
+}
+
+// the page table walk for `access` at privilege `prv`: the physical
+// address and the leaf pte and where it is, or the fault's cause and kind
+fn walk(cpu: &Cpu, addr: u32, access: Access, prv: u8) -> Result<(u32, u32, u32), (u32, &'static str)> {
+    let mut table = (cpu.csrs.satp & 0x3f_ffff) as u64 * PAGE_SIZE as u64;
+    let mut level = 1;
+    let (pte_addr, pte) = loop {
+        let pte_addr = table + ((addr >> (12 + 10 * level)) & 0x3ff) as u64 * 4;
+        let Some(pte_addr) = u32::try_from(pte_addr).ok().filter(|&a| cpu.ram_offset(a, 4).is_some()) else {
+            return Err((access.access_fault(), "access"));
+        };
+        let pte = cpu.read_word(pte_addr);
+        // w without r is reserved
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Err((access.page_fault(), "page"));
+        }
+        if pte & (PTE_R | PTE_X) != 0 {
+            break (pte_addr, pte);
+        }
+        if level == 0 {
+            return Err((access.page_fault(), "page"));
+        }
+        level -= 1;
+        table = (pte >> 10) as u64 * PAGE_SIZE as u64;
+    };
+
+    // u-mode only gets u pages, and s-mode only gets to load and store
+    // them under mstatus.sum
+    let sum = cpu.csrs.mstatus & MSTATUS_SUM != 0;
+    let user_ok = match prv {
+        PRV_U => pte & PTE_U != 0,
+        PRV_S => pte & PTE_U == 0 || (sum && access != Access::Fetch),
+        _ => true,
+    };
+    let mxr = cpu.csrs.mstatus & MSTATUS_MXR != 0;
+    let allowed = match access {
+        Access::Fetch => pte & PTE_X != 0,
+        Access::Load => pte & PTE_R != 0 || (mxr && pte & PTE_X != 0),
+        Access::Store => pte & PTE_W != 0,
+    };
+    // a megapage has to be aligned to one
+    let misaligned = level == 1 && (pte >> 10) & 0x3ff != 0;
+    if !user_ok || !allowed || misaligned {
+        return Err((access.page_fault(), "page"));
+    }
+
+    let offset_bits = if level == 1 { 22 } else { 12 };
+    let base = ((pte >> 10) as u64) << 12;
+    let pa = base | (addr & ((1 << offset_bits) - 1)) as u64;
+    match u32::try_from(pa) {
+        Ok(pa) => Ok((pa, pte_addr, pte)),
+        Err(_) => Err((access.access_fault(), "access")),
+    }
+}
+
+// a load of `size` bytes at virtual address `addr`. one that crosses into
+// another page goes a byte at a time, each byte translated on its own
+#[inline]
+pub fn load(cpu: &mut Cpu, addr: u32, size: u32) -> Result<u32, String> {
+    if mode(cpu, Access::Load).is_none() {
+        return cpu.load(addr, size);
+    }
+    if (addr % PAGE_SIZE) + size <= PAGE_SIZE {
+        let pa = translate(cpu, addr, Access::Load)?;
+        return cpu.load(pa, size);
+    }
+    let mut val = 0;
+    for i in 0..size {
+        let pa = translate(cpu, addr.wrapping_add(i), Access::Load)?;
+        val |= cpu.load(pa, 1)? << (8 * i);
+    }
+    Ok(val)
+}
+
+// the same for a store. every byte is translated before any is written, so
+// a page fault on the second page leaves the first untouched
+#[inline]
+pub fn store(cpu: &mut Cpu, addr: u32, size: u32, val: u32) -> Result<(), String> {
+    if mode(cpu, Access::Store).is_none() {
+        return cpu.store(addr, size, val);
+    }
+    if (addr % PAGE_SIZE) + size <= PAGE_SIZE {
+        let pa = translate(cpu, addr, Access::Store)?;
+        return cpu.store(pa, size, val);
+    }
+    let mut pas = [0; 4];
+    for i in 0..size {
+        pas[i as usize] = translate(cpu, addr.wrapping_add(i), Access::Store)?;
+    }
+    for i in 0..size {
+        cpu.store(pas[i as usize], 1, val >> (8 * i))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SATP_SV32: u32 = 1 << 31;
+
+    // ram at 0 with the root page table at 0x1000 and a leaf table at
+    // 0x2000 mapping virtual 0x40_0000 to physical 0x5000, in s-mode
+    fn machine(leaf_flags: u32) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.write_word(0x1000 + 4, (0x2000 >> 12) << 10 | PTE_V);
+        cpu.write_word(0x2000, (0x5000 >> 12) << 10 | leaf_flags | PTE_V);
+        // a megapage at virtual 0x80_0000 onto physical 0
+        cpu.write_word(0x1000 + 8, PTE_R | PTE_W | PTE_V);
+        cpu.csrs.satp = SATP_SV32 | 1;
+        cpu.csrs.privilege = PRV_S;
+        cpu
+    }
+
+    #[test]
+    fn test_walk_and_accessed_dirty() {
+        let mut cpu = machine(PTE_R | PTE_W);
+        cpu.write_word(0x5008, 0x1234_5678);
+        assert_eq!(load(&mut cpu, 0x40_0008, 4), Ok(0x1234_5678));
+        assert_eq!(cpu.read_word(0x2000) & (PTE_A | PTE_D), PTE_A);
+        store(&mut cpu, 0x40_000c, 2, 0xbeef).unwrap();
+        assert_eq!(cpu.read_word(0x500c), 0xbeef);
+        assert_eq!(cpu.read_word(0x2000) & (PTE_A | PTE_D), PTE_A | PTE_D);
+        assert_eq!(translate(&mut cpu, 0x80_5008, Access::Load), Ok(0x5008));
+        // m-mode sees physical memory
+        cpu.csrs.privilege = PRV_M;
+        assert_eq!(translate(&mut cpu, 0x40_0008, Access::Load), Ok(0x40_0008));
+    }
+
+    #[test]
+    fn test_page_faults() {
+        let mut cpu = machine(PTE_R | PTE_U);
+        // unmapped
+        assert!(load(&mut cpu, 0x1000, 4).is_err());
+        assert_eq!(cpu.fault.take(), Some(Exception::new(CAUSE_LOAD_PAGE_FAULT, 0x1000)));
+        // a user page needs sum for s-mode to read it, and nobody can write it
+        assert!(load(&mut cpu, 0x40_0000, 4).is_err());
+        cpu.csrs.mstatus |= MSTATUS_SUM;
+        assert!(load(&mut cpu, 0x40_0000, 4).is_ok());
+        assert!(store(&mut cpu, 0x40_0000, 4, 0).is_err());
+        assert_eq!(cpu.fault.take(), Some(Exception::new(CAUSE_STORE_PAGE_FAULT, 0x40_0000)));
+        assert!(translate(&mut cpu, 0x40_0000, Access::Fetch).is_err());
+        cpu.csrs.privilege = PRV_U;
+        assert!(load(&mut cpu, 0x40_0000, 4).is_ok());
+        // a load straddling into the unmapped page faults on its address there
+        assert!(load(&mut cpu, 0x40_0ffe, 4).is_err());
+        assert_eq!(cpu.fault.take(), Some(Exception::new(CAUSE_LOAD_PAGE_FAULT, 0x40_1000)));
+    }
+
+    #[test]
+    fn test_peek_leaves_no_trace() {
+        let cpu = machine(PTE_R | PTE_X);
+        assert_eq!(peek(&cpu, 0x40_0010, Access::Fetch), Some(0x5010));
+        assert_eq!(cpu.read_word(0x2000) & PTE_A, 0);
+        assert_eq!(peek(&cpu, 0x40_0010, Access::Store), None);
+        assert_eq!(peek(&cpu, 0x1000, Access::Load), None);
+        assert_eq!(cpu.fault, None);
+    }
+}
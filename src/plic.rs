@@ -0,0 +1,292 @@
+// sifive plic: routes device interrupt lines to every hart's m and s contexts
+//
+// Cpu::tick_devices hands it the level of every device that names a source
+// (Device::irq) after each tick, and hart_irqs passes a context's external
+// interrupt on as mip.meip or mip.seip. the register interface is complete
+// enough for opensbi and the linux driver to probe and configure it.
+
+use crate::devices::{Device, DeviceInfo, GuestRam};
+use crate::fdt::{self, FdtBuilder, PLIC_PHANDLE};
+use crate::snapshot::{Reader, Writer};
+
+pub const PLIC_BASE: u32 = 0x0c00_0000;
+pub const PLIC_SIZE: u32 = 0x60_0000;
+
+// sources 1..=NDEV; 0 means "no interrupt". one pending word is plenty
+pub const PLIC_NDEV: u32 = 31;
+
+// context 2n is hart n's m-mode, context 2n + 1 its s-mode
+
+const PRIORITY_BASE: u32 = 0x00_0000;
+const PENDING_BASE: u32 = 0x00_1000;
+const ENABLE_BASE: u32 = 0x00_2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT_BASE: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+// cpu-intc interrupt numbers for the external interrupt of each context
+pub const IRQ_M_EXT: u32 = 11;
+pub const IRQ_S_EXT: u32 = 9;
+
+pub struct Plic {
+    priority: [u32; PLIC_NDEV as usize + 1],
+    // bit n is source n
+    pending: u32,
+    level: u32,
+    // claimed but not yet completed; the gateway holds these off
+    in_service: u32,
+    // one per context
+    enable: Vec<u32>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    pub fn new(harts: usize) -> Self {
+        let contexts = 2 * harts.max(1);
+        Plic {
+            priority: [0; PLIC_NDEV as usize + 1],
+            pending: 0,
+            level: 0,
+            in_service: 0,
+            enable: vec![0; contexts],
+            threshold: vec![0; contexts],
+        }
+    }
+
+    fn contexts(&self) -> usize {
+        self.enable.len()
+    }
+
+    // drive a level-triggered source
+    pub fn set_level(&mut self, source: u32, high: bool) {
+        if source == 0 || source > PLIC_NDEV {
+            return;
+        }
+        let bit = 1 << source;
+        if high {
+            self.level |= bit;
+            if self.in_service & bit == 0 {
+                self.pending |= bit;
+            }
+        } else {
+            self.level &= !bit;
+            self.pending &= !bit;
+        }
+    }
+
+    // highest priority source the context would claim right now, if any.
+    // ties go to the lowest source number
+    fn best(&self, ctx: usize) -> u32 {
+        let mut best = 0;
+        let mut best_prio = self.threshold[ctx];
+        for src in 1..=PLIC_NDEV {
+            let bit = 1 << src;
+            let prio = self.priority[src as usize];
+            if self.pending & self.enable[ctx] & bit != 0 && prio > best_prio {
+                best = src;
+                best_prio = prio;
+            }
+        }
+        best
+    }
+
+    // the external interrupt line into `ctx`
+    pub fn context_pending(&self, ctx: usize) -> bool {
+        ctx < self.contexts() && self.best(ctx) != 0
+    }
+
+    fn claim(&mut self, ctx: usize) -> u32 {
+        let src = self.best(ctx);
+        if src != 0 {
+            self.pending &= !(1 << src);
+            self.in_service |= 1 << src;
+        }
+        src
+    }
+
+    fn complete(&mut self, src: u32) {
+        if src == 0 || src > PLIC_NDEV {
+            return;
+        }
+        let bit = 1 << src;
+        self.in_service &= !bit;
+        // still asserted: it comes straight back
+        if self.level & bit != 0 {
+            self.pending |= bit;
+        }
+    }
+
+    // (context, register within the context block) for threshold/claim
+    fn context_reg(&self, offset: u32) -> Option<(usize, u32)> {
+        let rel = offset.checked_sub(CONTEXT_BASE)?;
+        let ctx = (rel / CONTEXT_STRIDE) as usize;
+        (ctx < self.contexts()).then_some((ctx, rel % CONTEXT_STRIDE))
+    }
+
+    fn enable_reg(&self, offset: u32) -> Option<usize> {
+        let rel = offset.checked_sub(ENABLE_BASE)?;
+        let ctx = (rel / ENABLE_STRIDE) as usize;
+        // only the first word of each context's enable bitmap exists
+        (ctx < self.contexts() && rel % ENABLE_STRIDE == 0).then_some(ctx)
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u32) -> u32 {
+        if offset < PENDING_BASE {
+            let src = ((offset - PRIORITY_BASE) / 4) as usize;
+            return self.priority.get(src).copied().unwrap_or(0);
+        }
+        if offset == PENDING_BASE {
+            return self.pending;
+        }
+        if let Some(ctx) = self.enable_reg(offset) {
+            return self.enable[ctx];
+        }
+        match self.context_reg(offset) {
+            Some((ctx, 0)) => self.threshold[ctx],
+            Some((ctx, 4)) => self.claim(ctx),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _ram: &mut GuestRam) {
+        if offset < PENDING_BASE {
+            let src = ((offset - PRIORITY_BASE) / 4) as usize;
+            if src != 0 && src < self.priority.len() {
+                self.priority[src] = val & 7;
+            }
+            return;
+        }
+        if let Some(ctx) = self.enable_reg(offset) {
+            // source 0 doesn't exist
+            self.enable[ctx] = val & !1;
+            return;
+        }
+        match self.context_reg(offset) {
+            Some((ctx, 0)) => self.threshold[ctx] = val & 7,
+            Some((_, 4)) => self.complete(val),
+            _ => {}
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        (0..self.contexts()).any(|ctx| self.context_pending(ctx))
+    }
+
+    fn set_irq_lines(&mut self, lines: u32) {
+        let mut changed = (lines ^ self.level) & !1;
+        while changed != 0 {
+            let src = changed.trailing_zeros();
+            self.set_level(src, lines & (1 << src) != 0);
+            changed &= changed - 1;
+        }
+    }
+
+    fn hart_irqs(&self, hart: u32) -> u32 {
+        let ctx = 2 * hart as usize;
+        (self.context_pending(ctx) as u32) << IRQ_M_EXT | (self.context_pending(ctx + 1) as u32) << IRQ_S_EXT
+    }
+
+    fn info(&self) -> Option<DeviceInfo> {
+        Some(DeviceInfo { name: "plic", compatible: "sifive,plic-1.0.0" })
+    }
+
+    fn fdt_props(&self, fdt: &mut FdtBuilder) {
+        fdt.prop_u32("#address-cells", 0);
+        fdt.prop_u32("#interrupt-cells", 1);
+        fdt.prop_empty("interrupt-controller");
+        fdt.prop_u32("riscv,ndev", PLIC_NDEV);
+        let cells: Vec<u32> = (0..self.contexts() as u32 / 2)
+            .flat_map(|h| [fdt::intc_phandle(h), IRQ_M_EXT, fdt::intc_phandle(h), IRQ_S_EXT])
+            .collect();
+        fdt.prop_cells("interrupts-extended", &cells);
+        fdt.prop_u32("phandle", PLIC_PHANDLE);
+    }
+
+    fn save(&self, w: &mut Writer) {
+        self.priority.iter().for_each(|&p| w.put_u32(p));
+        w.put_u32(self.pending);
+        w.put_u32(self.level);
+        w.put_u32(self.in_service);
+        self.enable.iter().for_each(|&e| w.put_u32(e));
+        self.threshold.iter().for_each(|&t| w.put_u32(t));
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        for p in self.priority.iter_mut() {
+            *p = r.get_u32()?;
+        }
+        self.pending = r.get_u32()?;
+        self.level = r.get_u32()?;
+        self.in_service = r.get_u32()?;
+        for e in self.enable.iter_mut() {
+            *e = r.get_u32()?;
+        }
+        for t in self.threshold.iter_mut() {
+            *t = r.get_u32()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim_reg(ctx: u32) -> u32 {
+        CONTEXT_BASE + ctx * CONTEXT_STRIDE + 4
+    }
+
+    #[test]
+    fn test_claim_complete() {
+        let mut plic = Plic::new(1);
+        let mut mem = GuestRam::new(0, &mut []);
+        plic.write(PRIORITY_BASE + 10 * 4, 1, &mut mem);
+        plic.write(ENABLE_BASE + ENABLE_STRIDE, 1 << 10, &mut mem);
+        plic.set_level(10, true);
+        assert!(plic.context_pending(1));
+        assert!(!plic.context_pending(0));
+
+        assert_eq!(plic.read(claim_reg(1)), 10);
+        assert!(!plic.context_pending(1));
+        // still high when completed, so it's pending again
+        plic.write(claim_reg(1), 10, &mut mem);
+        assert!(plic.context_pending(1));
+        plic.set_level(10, false);
+        assert!(!plic.context_pending(1));
+        assert_eq!(plic.read(claim_reg(1)), 0);
+    }
+
+    #[test]
+    fn test_priority_and_threshold() {
+        let mut plic = Plic::new(1);
+        let mut mem = GuestRam::new(0, &mut []);
+        plic.write(PRIORITY_BASE + 3 * 4, 2, &mut mem);
+        plic.write(PRIORITY_BASE + 5 * 4, 4, &mut mem);
+        plic.write(ENABLE_BASE, (1 << 3) | (1 << 5), &mut mem);
+        plic.set_level(3, true);
+        plic.set_level(5, true);
+        assert_eq!(plic.read(claim_reg(0)), 5);
+        plic.write(CONTEXT_BASE, 2, &mut mem);
+        // priority 2 doesn't beat a threshold of 2
+        assert_eq!(plic.read(claim_reg(0)), 0);
+    }
+
+    #[test]
+    fn test_lines_reach_each_harts_contexts() {
+        let mut plic = Plic::new(2);
+        let mut mem = GuestRam::new(0, &mut []);
+        plic.write(PRIORITY_BASE + 10 * 4, 1, &mut mem);
+        // hart 1's s-mode context
+        plic.write(ENABLE_BASE + 3 * ENABLE_STRIDE, 1 << 10, &mut mem);
+        plic.set_irq_lines(1 << 10);
+        assert_eq!(plic.hart_irqs(1), 1 << IRQ_S_EXT);
+        assert_eq!(plic.hart_irqs(0), 0);
+        assert_eq!(plic.read(claim_reg(3)), 10);
+        assert_eq!(plic.hart_irqs(1), 0);
+        plic.set_irq_lines(0);
+        plic.write(claim_reg(3), 10, &mut mem);
+        assert_eq!(plic.hart_irqs(1), 0);
+    }
+}
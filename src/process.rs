@@ -0,0 +1,189 @@
+// linux user-mode process startup
+//
+// loads a static elf and lays out the initial stack the way the kernel's
+// create_elf_tables() does, from the top of ram down:
+//
+//   execfn string, argv/envp strings, platform string, 16 random bytes
+//   (padding to 16 bytes)
+//   auxv pairs, terminated by AT_NULL
+//   envp pointers, NULL
+//   argv pointers, NULL
+//   argc                                  <- sp
+//
+// crt0 in musl and newlib reads everything it needs from there.
+
+use crate::cpu::{Cpu, NREGS};
+use crate::decoder;
+use crate::elf::ElfFile;
+use crate::replay;
+use std::fs::File;
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const PAGE_SIZE: u32 = 4096;
+
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_BASE: u32 = 7;
+const AT_FLAGS: u32 = 8;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_PLATFORM: u32 = 15;
+const AT_HWCAP: u32 = 16;
+const AT_CLKTCK: u32 = 17;
+const AT_SECURE: u32 = 23;
+const AT_RANDOM: u32 = 25;
+const AT_EXECFN: u32 = 31;
+
+pub const PLATFORM: &str = "riscv32";
+
+// what getuid() and friends report
+pub const GUEST_UID: u32 = 1000;
+pub const GUEST_GID: u32 = 1000;
+
+// reserved at the top of ram for the stack. the heap (brk) grows up from the
+// end of the image and mmap hands out pages downwards from below the stack
+pub const STACK_SIZE: u32 = 128 * 1024;
+
+// where the heap and mmap areas start, for the syscall layer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Layout {
+    pub brk_start: u32,
+    pub mmap_top: u32,
+}
+
+// fill buf from the host's entropy pool, falling back to the clock
+pub fn host_random(buf: &mut [u8]) {
+    if File::open("/dev/urandom").and_then(|mut f| f.read_exact(buf)).is_ok() {
+        return;
+    }
+    let mut x = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+        | 1;
+    for b in buf {
+        // xorshift64
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        *b = x as u8;
+    }
+}
+
+// AT_HWCAP: one bit per single-letter extension, 'a' is bit 0
+pub fn hwcap() -> u32 {
+    std::iter::once('i')
+        .chain(decoder::EXTENSIONS.iter().copied())
+        .fold(0, |acc, c| acc | 1 << (c as u8 - b'a'))
+}
+
+struct StackWriter<'a> {
+    cpu: &'a mut Cpu,
+    sp: u32,
+    limit: u32,
+}
+
+impl StackWriter<'_> {
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<u32, String> {
+        let sp = self
+            .sp
+            .checked_sub(bytes.len() as u32)
+            .filter(|&sp| sp >= self.limit)
+            .ok_or("arguments and environment don't fit on the stack")?;
+        self.sp = sp;
+        self.cpu.mem[sp as usize..sp as usize + bytes.len()].copy_from_slice(bytes);
+        Ok(sp)
+    }
+
+    fn push_str(&mut self, s: &str) -> Result<u32, String> {
+        self.push_bytes(&[0])?;
+        self.push_bytes(s.as_bytes())
+    }
+}
+
+// load `file` and build its startup stack at the top of ram. argv[0] should
+// be the program name, envp entries are "KEY=VALUE"
+pub fn start(cpu: &mut Cpu, file: &ElfFile, argv: &[String], envp: &[String]) -> Result<Layout, String> {
+    if file.dynamic {
+        return Err("dynamically linked programs aren't supported, link with -static".to_string());
+    }
+    file.load(cpu)?;
+
+    let top = cpu.mem.len() as u32;
+    let limit = top - STACK_SIZE;
+    let brk_start = file.image_end().saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+    if brk_start > limit {
+        return Err("program leaves no room for a stack".to_string());
+    }
+    let mut stack = StackWriter { cpu, sp: top, limit };
+
+    // the kernel leaves a null word at the very top
+    stack.push_bytes(&[0; 4])?;
+    let execfn = stack.push_str(argv.first().map_or("", |s| s.as_str()))?;
+    let mut env_ptrs = Vec::new();
+    for e in envp.iter().rev() {
+        env_ptrs.push(stack.push_str(e)?);
+    }
+    env_ptrs.reverse();
+    let mut arg_ptrs = Vec::new();
+    for a in argv.iter().rev() {
+        arg_ptrs.push(stack.push_str(a)?);
+    }
+    arg_ptrs.reverse();
+    let platform = stack.push_str(PLATFORM)?;
+    let mut random = [0u8; 16];
+    replay::random(stack.cpu.journal.as_ref(), &mut random);
+    let random = stack.push_bytes(&random)?;
+
+    let auxv = [
+        (AT_PHDR, file.phdr_addr().unwrap_or(0)),
+        (AT_PHENT, file.phentsize as u32),
+        (AT_PHNUM, file.phnum as u32),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, 0),
+        (AT_FLAGS, 0),
+        (AT_ENTRY, file.entry),
+        (AT_UID, GUEST_UID),
+        (AT_EUID, GUEST_UID),
+        (AT_GID, GUEST_GID),
+        (AT_EGID, GUEST_GID),
+        (AT_HWCAP, hwcap()),
+        (AT_CLKTCK, 100),
+        (AT_SECURE, 0),
+        (AT_RANDOM, random),
+        (AT_PLATFORM, platform),
+        (AT_EXECFN, execfn),
+        (AT_NULL, 0),
+    ];
+
+    let mut words = vec![arg_ptrs.len() as u32];
+    words.extend(&arg_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    for (key, val) in auxv {
+        words.extend([key, val]);
+    }
+
+    // the abi wants sp 16-byte aligned at entry
+    let sp = stack
+        .sp
+        .checked_sub(words.len() as u32 * 4)
+        .map(|sp| sp & !15)
+        .filter(|&sp| sp >= limit)
+        .ok_or("arguments and environment don't fit on the stack")?;
+    for (i, w) in words.iter().enumerate() {
+        cpu.write_word(sp + i as u32 * 4, *w);
+    }
+
+    cpu.regs = [0; NREGS];
+    cpu.regs[2] = sp;
+    Ok(Layout { brk_start, mmap_top: limit })
+}
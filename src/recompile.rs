@@ -0,0 +1,623 @@
+// ahead-of-time recompilation to c
+//
+// starting from the entry point, any extra entries asked for and every
+// function symbol, the decoder walks the code that can be reached: straight
+// lines up to a branch, jump or system instruction, following branch and jal
+// targets, the return address of every call and the instruction after an
+// ecall or ebreak. a jalr whose target register was set by lui, auipc or addi
+// earlier in the same straight line is followed too. where those lines meet
+// or get branched into, they're cut into basic blocks, and each block becomes
+// a labelled run of c statements on local copies of the registers.
+//
+// direct branches and jumps are gotos. a jalr whose target is only known at
+// run time goes through a dispatcher, a switch over every block start; a
+// target that isn't one hands control back to the caller, as does running
+// off the end of the code that was found.
+//
+// the output is a .c file and a header declaring a state struct laid out
+// like one hart of Cpu: registers, pc, ram and the lr/sc reservation, plus
+// hooks for loads and stores that miss ram. ecall, ebreak, csr accesses
+// other than reading mhartid, the privileged instructions and anything the
+// executor would trap on come back to the caller with pc left on them, so
+// the host can service them and carry on; recompiled code has no trap
+// handling or address translation of its own. code is taken to be fixed:
+// fence.i is a nop and stores over code don't change what runs.
+
+use crate::cpu::{Cpu, NREGS};
+use crate::decoder::{Instruction, Opcode, CSR_MHARTID};
+use crate::symbols::SymbolMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+// image data is kept in runs of lines that aren't all zero
+const IMAGE_LINE: usize = 64;
+
+pub struct Options {
+    // put in front of every name the output declares
+    pub prefix: String,
+    // what the .c file includes
+    pub header: String,
+    // where the program came from, for the banner
+    pub source: String,
+    // more places code starts, besides the entry point and function symbols
+    pub entries: Vec<u32>,
+}
+
+pub struct Output {
+    pub header: String,
+    pub source: String,
+    pub blocks: usize,
+    pub instructions: usize,
+}
+
+pub struct Block {
+    pub start: u32,
+    pub insts: Vec<(u32, Instruction)>,
+    // where a block that doesn't end in a jump carries on
+    pub next: Option<u32>,
+}
+
+// the reachable code, one block per start address
+pub fn recover(cpu: &Cpu, roots: &[u32]) -> BTreeMap<u32, Block> {
+    let mut code: BTreeMap<u32, Instruction> = BTreeMap::new();
+    let mut starts: BTreeSet<u32> = BTreeSet::new();
+    let mut work = roots.to_vec();
+    while let Some(start) = work.pop() {
+        if !starts.insert(start) || code.contains_key(&start) {
+            continue;
+        }
+        let mut consts = Consts::new();
+        let mut pc = start;
+        while let Some(inst) = fetch(cpu, pc) {
+            code.insert(pc, inst);
+            let link = pc.wrapping_add(4);
+            match inst.opcode {
+                op if op.is_branch() => {
+                    work.push(pc.wrapping_add(inst.imm as u32));
+                    work.push(link);
+                }
+                Opcode::Jal | Opcode::Jalr => {
+                    let target = match inst.opcode {
+                        Opcode::Jal => Some(pc.wrapping_add(inst.imm as u32)),
+                        _ => consts.jalr_target(&inst),
+                    };
+                    work.extend(target);
+                    // a call comes back, and a return lands here
+                    if inst.rd != 0 {
+                        work.push(link);
+                    }
+                }
+                Opcode::Ecall | Opcode::Ebreak => work.push(link),
+                _ if ends_block(&inst) => {}
+                _ => {
+                    consts.update(pc, &inst);
+                    pc = link;
+                    if code.contains_key(&pc) {
+                        // ran into code that's already been walked
+                        starts.insert(pc);
+                        break;
+                    }
+                    continue;
+                }
+            }
+            break;
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &start in &starts {
+        if !code.contains_key(&start) {
+            continue;
+        }
+        let mut insts = Vec::new();
+        let mut pc = start;
+        let next = loop {
+            let inst = code[&pc];
+            insts.push((pc, inst));
+            if ends_block(&inst) {
+                break None;
+            }
+            pc = pc.wrapping_add(4);
+            if starts.contains(&pc) || !code.contains_key(&pc) {
+                break Some(pc);
+            }
+        };
+        blocks.insert(start, Block { start, insts, next });
+    }
+    blocks
+}
+
+// the c source and header for everything reachable in `cpu` as it stands:
+// the program loaded, registers and pc set up
+pub fn recompile(cpu: &Cpu, symbols: &SymbolMap, opts: &Options) -> Result<Output, String> {
+    if !is_identifier(&opts.prefix) {
+        return Err(format!("prefix isn't a c identifier: {}", opts.prefix));
+    }
+    if cpu.ram_offset(cpu.pc, 4).is_none() {
+        return Err(format!("entry point 0x{:x} isn't in ram", cpu.pc));
+    }
+    let mut roots = vec![cpu.pc];
+    roots.extend(&opts.entries);
+    // function symbols, unless they're plainly not code
+    let funcs: Vec<_> = symbols.iter()
+        .filter(|s| s.is_func && fetch(cpu, s.addr).is_some_and(|i| i.opcode != Opcode::Unknown))
+        .collect();
+    roots.extend(funcs.iter().map(|s| s.addr));
+    let blocks = recover(cpu, &roots);
+
+    let names = Names::new(&opts.prefix);
+    let header = header(cpu, &funcs, opts, &names);
+    let mut source = format!("/* generated by rv32-emu recompile from {}; do not edit */\n", opts.source);
+    source += "#include <string.h>\n";
+    writeln!(source, "#include \"{}\"", opts.header).unwrap();
+    source += &names.apply(PRELUDE);
+    source += &image(cpu, &names);
+    source += &run(&blocks, symbols, &names);
+    source += &names.apply(CALL);
+    Ok(Output {
+        header,
+        source,
+        blocks: blocks.len(),
+        instructions: blocks.values().map(|b| b.insts.len()).sum(),
+    })
+}
+
+fn fetch(cpu: &Cpu, pc: u32) -> Option<Instruction> {
+    cpu.ram_offset(pc, 4).map(|_| Instruction::decode(cpu.read_word(pc)))
+}
+
+// whether nothing after the instruction runs straight on from it
+fn ends_block(inst: &Instruction) -> bool {
+    match inst.opcode {
+        op if op.is_branch() => true,
+        Opcode::Jal | Opcode::Jalr | Opcode::Ecall | Opcode::Ebreak | Opcode::Unknown => true,
+        Opcode::Mret | Opcode::Sret | Opcode::Wfi | Opcode::SfenceVma => true,
+        op if op.is_csr() => !reads_mhartid(inst),
+        _ => false,
+    }
+}
+
+// the one csr access recompiled code does itself
+fn reads_mhartid(inst: &Instruction) -> bool {
+    inst.opcode == Opcode::Csrrs && inst.rs1 == 0 && inst.imm as u32 == CSR_MHARTID
+}
+
+// registers whose value is known from the code alone
+struct Consts([Option<u32>; NREGS]);
+
+impl Consts {
+    fn new() -> Self {
+        let mut regs = [None; NREGS];
+        regs[0] = Some(0);
+        Consts(regs)
+    }
+
+    fn update(&mut self, pc: u32, inst: &Instruction) {
+        if inst.rd == 0 || !inst.opcode.writes_rd() {
+            return;
+        }
+        self.0[inst.rd] = match inst.opcode {
+            Opcode::Lui => Some(inst.imm as u32),
+            Opcode::Auipc => Some(pc.wrapping_add(inst.imm as u32)),
+            Opcode::Addi => self.0[inst.rs1].map(|v| v.wrapping_add(inst.imm as u32)),
+            _ => None,
+        };
+    }
+
+    fn jalr_target(&self, inst: &Instruction) -> Option<u32> {
+        self.0[inst.rs1].map(|v| v.wrapping_add(inst.imm as u32) & !1)
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// the templates below are written with rv32_ and RV32_ names
+struct Names {
+    lower: String,
+    upper: String,
+}
+
+impl Names {
+    fn new(prefix: &str) -> Self {
+        Names { lower: format!("{}_", prefix), upper: format!("{}_", prefix.to_ascii_uppercase()) }
+    }
+
+    fn apply(&self, template: &str) -> String {
+        template.replace("rv32_", &self.lower).replace("RV32_", &self.upper)
+    }
+}
+
+fn header(cpu: &Cpu, funcs: &[&crate::symbols::Symbol], opts: &Options, names: &Names) -> String {
+    let guard = format!("{}H", names.upper);
+    let mut out = format!("/* generated by rv32-emu recompile from {}; do not edit */\n", opts.source);
+    writeln!(out, "#ifndef {}\n#define {}\n", guard, guard).unwrap();
+    out += "#include <stdint.h>\n\n";
+    writeln!(out, "#define {}RAM_BASE 0x{:08x}u", names.upper, cpu.ram_base).unwrap();
+    writeln!(out, "#define {}RAM_SIZE 0x{:08x}u", names.upper, cpu.mem.len()).unwrap();
+    writeln!(out, "#define {}ENTRY 0x{:08x}u", names.upper, cpu.pc).unwrap();
+    let mut seen = BTreeSet::new();
+    for sym in funcs.iter().filter(|s| is_identifier(&s.name)) {
+        if seen.insert(&sym.name) {
+            writeln!(out, "#define {}SYM_{} 0x{:08x}u", names.upper, sym.name, sym.addr).unwrap();
+        }
+    }
+    out += &names.apply(API);
+    writeln!(out, "\n#endif").unwrap();
+    out
+}
+
+const API: &str = r#"
+/* rv32_call points ra here, so returning from the function lands on it */
+#define RV32_RETURN_ADDRESS 0xfffffffcu
+
+/* why rv32_run came back. pc is left on the instruction concerned */
+enum {
+    RV32_RETURNED,  /* the function rv32_call started has returned */
+    RV32_ECALL,     /* service it, add 4 to pc and run again */
+    RV32_EBREAK,    /* the same */
+    RV32_ILLEGAL,   /* an unknown instruction, a csr access or a privileged one */
+    RV32_FAULT,     /* a load or store the hooks didn't take, or a misaligned atomic */
+    RV32_JUMP,      /* a jump to somewhere that wasn't recompiled */
+};
+
+/* one hart of the emulator's cpu */
+struct rv32_state {
+    uint32_t regs[32];
+    uint32_t pc;
+    /* RV32_RAM_SIZE bytes, seen by the guest at RV32_RAM_BASE */
+    uint8_t *mem;
+    uint32_t hartid;
+    /* lr.w reservation, cleared by any store to the word */
+    int reserved;
+    uint32_t reservation;
+    /* loads and stores outside ram, for the host's devices. nonzero is a
+       fault, as is a null hook */
+    int (*load)(struct rv32_state *s, uint32_t addr, uint32_t *val);
+    int (*store)(struct rv32_state *s, uint32_t addr, uint32_t val);
+    void *user;
+};
+
+/* the machine as the emulator would start it: registers, pc and the
+   program image in mem, which must hold RV32_RAM_SIZE bytes. set the hooks
+   after this */
+void rv32_init(struct rv32_state *s, uint8_t *mem);
+
+/* run from s->pc until something needs the caller */
+int rv32_run(struct rv32_state *s);
+
+/* run the code at addr with its arguments already in a0-a7 until it
+   returns, giving RV32_RETURNED with the result in a0, or stops on the way */
+int rv32_call(struct rv32_state *s, uint32_t addr);
+"#;
+
+const PRELUDE: &str = r#"
+#define STOP(at, reason) do { pc = (at); why = (reason); goto stop; } while (0)
+
+/* size bytes (1, 2 or 4) at addr, zero-extended. the hooks only see
+   words, so a narrower load outside ram reads the word around it */
+static inline int load_mem(struct rv32_state *s, uint32_t addr, uint32_t size, uint32_t *val)
+{
+    uint32_t off = addr - RV32_RAM_BASE;
+    if (off <= RV32_RAM_SIZE - size) {
+        const uint8_t *p = s->mem + off;
+        uint32_t v = 0, i;
+        for (i = 0; i < size; i++)
+            v |= (uint32_t)p[i] << (8 * i);
+        *val = v;
+        return 0;
+    }
+    if (size == 4)
+        return s->load ? s->load(s, addr, val) : -1;
+    if ((addr & 3) + size > 4 || !s->load || s->load(s, addr & ~3u, val))
+        return -1;
+    *val = (*val >> (8 * (addr & 3))) & (0xffffffffu >> (32 - 8 * size));
+    return 0;
+}
+
+/* narrower stores than a word can't go to the hooks */
+static inline int store_mem(struct rv32_state *s, uint32_t addr, uint32_t size, uint32_t val)
+{
+    uint32_t off = addr - RV32_RAM_BASE;
+    if (off <= RV32_RAM_SIZE - size) {
+        uint8_t *p = s->mem + off;
+        uint32_t i;
+        if (s->reserved && (s->reservation == (addr & ~3u) || s->reservation == ((addr + size - 1) & ~3u)))
+            s->reserved = 0;
+        for (i = 0; i < size; i++)
+            p[i] = (uint8_t)(val >> (8 * i));
+        return 0;
+    }
+    return size == 4 && s->store ? s->store(s, addr, val) : -1;
+}
+"#;
+
+const CALL: &str = r#"
+int rv32_call(struct rv32_state *s, uint32_t addr)
+{
+    s->regs[1] = RV32_RETURN_ADDRESS;
+    s->pc = addr;
+    return rv32_run(s);
+}
+"#;
+
+// rv32_init, with the non-zero parts of ram as they were loaded
+fn image(cpu: &Cpu, names: &Names) -> String {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, line) in cpu.mem.chunks(IMAGE_LINE).enumerate() {
+        if line.iter().all(|&b| b == 0) {
+            continue;
+        }
+        let (start, end) = (i * IMAGE_LINE, i * IMAGE_LINE + line.len());
+        match runs.last_mut() {
+            Some(run) if run.1 == start => run.1 = end,
+            _ => runs.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    for (n, &(start, end)) in runs.iter().enumerate() {
+        writeln!(out, "\nstatic const uint8_t image{}[] = {{", n).unwrap();
+        for line in cpu.mem[start..end].chunks(16) {
+            let bytes: Vec<String> = line.iter().map(|b| format!("0x{:02x},", b)).collect();
+            writeln!(out, "    {}", bytes.join(" ")).unwrap();
+        }
+        out += "};\n";
+    }
+    out += "\nstatic const struct { uint32_t addr; uint32_t len; const uint8_t *data; } image[] = {\n";
+    for (n, &(start, end)) in runs.iter().enumerate() {
+        let addr = cpu.ram_base.wrapping_add(start as u32);
+        writeln!(out, "    {{ 0x{:08x}u, {}, image{} }},", addr, end - start, n).unwrap();
+    }
+    if runs.is_empty() {
+        out += "    { 0, 0, 0 },\n";
+    }
+    out += "};\n";
+
+    let regs: Vec<String> = cpu.regs.iter().map(|r| format!("0x{:08x}u", r)).collect();
+    out += &names.apply(&format!(r#"
+void rv32_init(struct rv32_state *s, uint8_t *mem)
+{{
+    static const uint32_t regs[32] = {{
+        {}
+    }};
+    unsigned i;
+    memset(s, 0, sizeof *s);
+    memcpy(s->regs, regs, sizeof regs);
+    s->pc = 0x{:08x}u;
+    s->mem = mem;
+    memset(mem, 0, RV32_RAM_SIZE);
+    for (i = 0; i < sizeof image / sizeof image[0]; i++)
+        memcpy(mem + (image[i].addr - RV32_RAM_BASE), image[i].data, image[i].len);
+}}
+"#, regs.chunks(4).map(|r| r.join(", ")).collect::<Vec<_>>().join(",\n        "), cpu.pc));
+    out
+}
+
+fn reg(r: usize) -> String {
+    if r == 0 {
+        "0u".to_string()
+    } else {
+        format!("x{}", r)
+    }
+}
+
+// rs1 plus an immediate, as c
+fn offset(rs1: usize, imm: i32) -> String {
+    match imm {
+        0 => reg(rs1),
+        _ if rs1 == 0 => format!("0x{:x}u", imm as u32),
+        _ if imm < 0 => format!("{} - {}u", reg(rs1), imm.unsigned_abs()),
+        _ => format!("{} + {}u", reg(rs1), imm),
+    }
+}
+
+// rv32_run: the dispatcher and every block
+fn run(blocks: &BTreeMap<u32, Block>, symbols: &SymbolMap, names: &Names) -> String {
+    let mut out = names.apply("\nint rv32_run(struct rv32_state *s)\n{\n");
+    for regs in (1..NREGS).collect::<Vec<_>>().chunks(4) {
+        let decls: Vec<String> = regs.iter().map(|r| format!("x{} = s->regs[{}]", r, r)).collect();
+        writeln!(out, "    uint32_t {};", decls.join(", ")).unwrap();
+    }
+    out += "    uint32_t pc = s->pc;\n    int why;\n\n";
+
+    let mut body = String::new();
+    let starts: Vec<u32> = blocks.keys().copied().collect();
+    for (i, block) in blocks.values().enumerate() {
+        let following = starts.get(i + 1).copied();
+        match symbols.lookup(block.start).filter(|l| l.offset == 0) {
+            Some(loc) => writeln!(body, "\nL_{:08x}: /* {} */", block.start, loc).unwrap(),
+            None => writeln!(body, "\nL_{:08x}:", block.start).unwrap(),
+        }
+        let mut consts = Consts::new();
+        for &(pc, inst) in &block.insts {
+            writeln!(body, "    /* {:08x}: {} */", pc, inst.disassemble()).unwrap();
+            body += &names.apply(&statement(pc, &inst, &consts, blocks, following));
+            consts.update(pc, &inst);
+        }
+        if let Some(next) = block.next {
+            body += &names.apply(&goto(next, blocks, following));
+        }
+    }
+
+    // the label is only wanted if some jalr goes back through it
+    if body.contains("goto dispatch;") {
+        out += "dispatch:\n";
+    }
+    out += "    switch (pc) {\n";
+    for start in blocks.keys() {
+        writeln!(out, "    case 0x{:08x}u: goto L_{:08x};", start, start).unwrap();
+    }
+    out += &names.apply("    }\n    if (pc == RV32_RETURN_ADDRESS)\n        STOP(pc, RV32_RETURNED);\n    STOP(pc, RV32_JUMP);\n");
+    out += &body;
+
+    out += "\nstop:\n";
+    for r in 1..NREGS {
+        writeln!(out, "    s->regs[{}] = x{};", r, r).unwrap();
+    }
+    out += "    s->pc = pc;\n    return why;\n}\n";
+    out
+}
+
+// carry on at `to`: nothing if it's the block that comes next anyway
+fn goto(to: u32, blocks: &BTreeMap<u32, Block>, following: Option<u32>) -> String {
+    if following == Some(to) {
+        String::new()
+    } else if blocks.contains_key(&to) {
+        format!("    goto L_{:08x};\n", to)
+    } else {
+        format!("    STOP(0x{:08x}u, RV32_JUMP);\n", to)
+    }
+}
+
+// one instruction, as c statements
+fn statement(pc: u32, inst: &Instruction, consts: &Consts, blocks: &BTreeMap<u32, Block>, following: Option<u32>) -> String {
+    let (rd, rs1, rs2) = (reg(inst.rd), reg(inst.rs1), reg(inst.rs2));
+    let imm = inst.imm as u32;
+    let link = pc.wrapping_add(4);
+    let stop = |reason: &str| format!("STOP(0x{:08x}u, RV32_{})", pc, reason);
+    let alu = |expr: String| {
+        if inst.rd == 0 {
+            String::new()
+        } else {
+            format!("    {} = {};\n", rd, expr)
+        }
+    };
+    let set_link = || alu(format!("0x{:08x}u", link));
+    match inst.opcode {
+        Opcode::Add => alu(format!("{} + {}", rs1, rs2)),
+        Opcode::Sub => alu(format!("{} - {}", rs1, rs2)),
+        Opcode::And => alu(format!("{} & {}", rs1, rs2)),
+        Opcode::Or => alu(format!("{} | {}", rs1, rs2)),
+        Opcode::Xor => alu(format!("{} ^ {}", rs1, rs2)),
+        Opcode::Sll => alu(format!("{} << ({} & 31)", rs1, rs2)),
+        Opcode::Srl => alu(format!("{} >> ({} & 31)", rs1, rs2)),
+        Opcode::Sra => alu(format!("(uint32_t)((int32_t){} >> ({} & 31))", rs1, rs2)),
+        Opcode::Slt => alu(format!("(int32_t){} < (int32_t){}", rs1, rs2)),
+        Opcode::Sltu => alu(format!("{} < {}", rs1, rs2)),
+        Opcode::Mul => alu(format!("{} * {}", rs1, rs2)),
+        Opcode::Mulh => alu(format!("(uint32_t)((int64_t)(int32_t){} * (int32_t){} >> 32)", rs1, rs2)),
+        Opcode::Mulhsu => alu(format!("(uint32_t)((int64_t)(int32_t){} * (int64_t){} >> 32)", rs1, rs2)),
+        Opcode::Mulhu => alu(format!("(uint32_t)((uint64_t){} * {} >> 32)", rs1, rs2)),
+        // c leaves division by zero and INT32_MIN / -1 undefined, risc-v doesn't
+        Opcode::Div => alu(format!("{b} == 0 ? 0xffffffffu : ({a} == 0x80000000u && {b} == 0xffffffffu) ? {a} \
+            : (uint32_t)((int32_t){a} / (int32_t){b})", a = rs1, b = rs2)),
+        Opcode::Divu => alu(format!("{b} == 0 ? 0xffffffffu : {a} / {b}", a = rs1, b = rs2)),
+        Opcode::Rem => alu(format!("{b} == 0 ? {a} : ({a} == 0x80000000u && {b} == 0xffffffffu) ? 0 \
+            : (uint32_t)((int32_t){a} % (int32_t){b})", a = rs1, b = rs2)),
+        Opcode::Remu => alu(format!("{b} == 0 ? {a} : {a} % {b}", a = rs1, b = rs2)),
+        Opcode::Addi => alu(offset(inst.rs1, inst.imm)),
+        Opcode::Andi => alu(format!("{} & 0x{:x}u", rs1, imm)),
+        Opcode::Ori => alu(format!("{} | 0x{:x}u", rs1, imm)),
+        Opcode::Xori => alu(format!("{} ^ 0x{:x}u", rs1, imm)),
+        Opcode::Slli => alu(format!("{} << {}", rs1, imm & 0x1f)),
+        Opcode::Srli => alu(format!("{} >> {}", rs1, imm & 0x1f)),
+        Opcode::Srai => alu(format!("(uint32_t)((int32_t){} >> {})", rs1, imm & 0x1f)),
+        Opcode::Slti => alu(format!("(int32_t){} < {}", rs1, inst.imm)),
+        Opcode::Sltiu => alu(format!("{} < 0x{:x}u", rs1, imm)),
+        Opcode::Lui => alu(format!("0x{:x}u", imm)),
+        Opcode::Auipc => alu(format!("0x{:08x}u", pc.wrapping_add(imm))),
+        // the load still happens for x0, it may be a device register
+        op if op.is_load() => {
+            let val = match op {
+                Opcode::Lb => "(uint32_t)(int8_t)v",
+                Opcode::Lh => "(uint32_t)(int16_t)v",
+                _ => "v",
+            };
+            format!("    {{\n        uint32_t v;\n        if (load_mem(s, {}, {}, &v))\n            {};\n{}    }}\n",
+                offset(inst.rs1, inst.imm), op.access_size(), stop("FAULT"), alu(val.to_string()).replace("    ", "        "))
+        }
+        op if op.is_store() => format!("    if (store_mem(s, {}, {}, {}))\n        {};\n",
+            offset(inst.rs1, inst.imm), op.access_size(), rs2, stop("FAULT")),
+        Opcode::Fence | Opcode::FenceI => String::new(),
+        Opcode::LrW => format!("    {{\n        uint32_t v;\n        if (({rs1} & 3) || load_mem(s, {rs1}, 4, &v))\n            {};\n        \
+            s->reserved = 1;\n        s->reservation = {rs1};\n{}    }}\n",
+            stop("FAULT"), alu("v".to_string()).replace("    ", "        ")),
+        Opcode::ScW => format!("    {{\n        int ok = s->reserved && s->reservation == {rs1};\n        if ({rs1} & 3)\n            {};\n        \
+            s->reserved = 0;\n        if (ok && store_mem(s, {rs1}, 4, {rs2}))\n            {};\n{}    }}\n",
+            stop("FAULT"), stop("FAULT"), alu("!ok".to_string()).replace("    ", "        ")),
+        op if op.is_amo() => {
+            let value = match op {
+                Opcode::AmoswapW => "b".to_string(),
+                Opcode::AmoaddW => "v + b".to_string(),
+                Opcode::AmoxorW => "v ^ b".to_string(),
+                Opcode::AmoandW => "v & b".to_string(),
+                Opcode::AmoorW => "v | b".to_string(),
+                Opcode::AmominW => "(int32_t)v < (int32_t)b ? v : b".to_string(),
+                Opcode::AmomaxW => "(int32_t)v > (int32_t)b ? v : b".to_string(),
+                Opcode::AmominuW => "v < b ? v : b".to_string(),
+                _ => "v > b ? v : b".to_string(),
+            };
+            format!("    {{\n        uint32_t v, b = {rs2};\n        if (({rs1} & 3) || load_mem(s, {rs1}, 4, &v) || store_mem(s, {rs1}, 4, {}))\n            \
+                {};\n{}    }}\n", value, stop("FAULT"), alu("v".to_string()).replace("    ", "        "))
+        }
+        op if op.is_csr() && reads_mhartid(inst) => alu("s->hartid".to_string()),
+        op if op.is_branch() => {
+            let cond = match op {
+                Opcode::Beq => format!("{} == {}", rs1, rs2),
+                Opcode::Bne => format!("{} != {}", rs1, rs2),
+                Opcode::Blt => format!("(int32_t){} < (int32_t){}", rs1, rs2),
+                Opcode::Bge => format!("(int32_t){} >= (int32_t){}", rs1, rs2),
+                Opcode::Bltu => format!("{} < {}", rs1, rs2),
+                _ => format!("{} >= {}", rs1, rs2),
+            };
+            let taken = goto(pc.wrapping_add(imm), blocks, None);
+            format!("    if ({})\n    {}{}", cond, taken, goto(link, blocks, following))
+        }
+        Opcode::Jal => set_link() + &goto(pc.wrapping_add(imm), blocks, following),
+        Opcode::Jalr => match consts.jalr_target(inst) {
+            Some(target) => set_link() + &goto(target, blocks, following),
+            None => format!("    pc = ({}) & ~1u;\n{}    goto dispatch;\n", offset(inst.rs1, inst.imm), set_link()),
+        },
+        Opcode::Ecall => format!("    {};\n", stop("ECALL")),
+        Opcode::Ebreak => format!("    {};\n", stop("EBREAK")),
+        _ => format!("    {};\n", stop("ILLEGAL")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    fn cpu_with(src: &str) -> Cpu {
+        let code = Assembler::new().assemble(src).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&code, 0x100);
+        cpu.pc = 0x100;
+        cpu
+    }
+
+    #[test]
+    fn test_recover_splits_at_branch_targets_and_returns() {
+        // 0x100 call f; 0x104 loop body; 0x10c ecall; 0x110 f
+        let cpu = cpu_with("jal x1, f
+            loop:
+            addi x5, x5, -1
+            bne x5, x0, loop
+            ecall
+            f:
+            jalr x0, 0(x1)");
+        let blocks = recover(&cpu, &[0x100]);
+        let starts: Vec<u32> = blocks.keys().copied().collect();
+        assert_eq!(starts, [0x100, 0x104, 0x10c, 0x110]);
+        assert_eq!(blocks[&0x104].insts.len(), 2);
+        assert_eq!(blocks[&0x104].next, None);
+    }
+
+    #[test]
+    fn test_recover_follows_constant_jalr() {
+        // auipc/addi/jalr to the ebreak, jumping over the addi
+        let cpu = cpu_with("auipc x6, 0
+            addi x6, x6, 16
+            jalr x0, 0(x6)
+            addi x7, x0, 1
+            ebreak");
+        let blocks = recover(&cpu, &[0x100]);
+        assert!(blocks.contains_key(&0x110));
+        assert!(!blocks.contains_key(&0x10c));
+    }
+}
@@ -0,0 +1,572 @@
+// record and replay of everything nondeterministic in a run
+//
+// what a run does depends on the program and on what comes in from the
+// host: console and network input, the wall clock, stdin and random bytes
+// behind syscalls. recording logs each of those as it arrives, stamped with
+// the instruction it arrived in (1 for the first instruction, 0 for before
+// it). replaying hands the logged values back in the same places instead of
+// asking the host, so the run goes exactly as it did.
+//
+// interrupt lines and the results of ecalls and semihosting calls follow
+// from the rest, but they're logged as well and compared on replay. a replay
+// that wanders off (different options, a host file that changed since) stops
+// at the instruction where it first differs instead of quietly doing
+// something else.
+//
+// the log file is the magic, a format version and then one event after
+// another: u64 instruction, u8 source, length-prefixed data, little endian.
+// events are read and written with the snapshot Reader and Writer.
+
+use crate::cpu::Cpu;
+use crate::snapshot::{Reader, Writer};
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+pub const MAGIC: &[u8; 8] = b"RV32RPLY";
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    // fed back on replay
+    Console = 1,
+    Net = 2,
+    Clock = 3,
+    Stdin = 4,
+    Random = 5,
+    // only compared on replay
+    Irq = 6,
+    Result = 7,
+    Machine = 8,
+}
+
+impl Source {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            1 => Source::Console,
+            2 => Source::Net,
+            3 => Source::Clock,
+            4 => Source::Stdin,
+            5 => Source::Random,
+            6 => Source::Irq,
+            7 => Source::Result,
+            8 => Source::Machine,
+            _ => return None,
+        })
+    }
+
+    // input that turns up whenever the host has some; the rest are answers
+    // the guest asked for and are always logged
+    fn arrives(self) -> bool {
+        matches!(self, Source::Console | Source::Net | Source::Stdin)
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Source::Console => "console input",
+            Source::Net => "a network frame",
+            Source::Clock => "a clock read",
+            Source::Stdin => "a stdin read",
+            Source::Random => "random bytes",
+            Source::Irq => "interrupt lines",
+            Source::Result => "a call result",
+            Source::Machine => "the machine",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub at: u64,
+    pub source: Source,
+    pub data: Vec<u8>,
+}
+
+// short form of an event's data for messages
+fn show(data: &[u8]) -> String {
+    if data.len() <= 8 {
+        let mut v = [0u8; 8];
+        v[..data.len()].copy_from_slice(data);
+        format!("0x{:x}", u64::from_le_bytes(v))
+    } else {
+        format!("{} bytes", data.len())
+    }
+}
+
+struct State {
+    replaying: bool,
+    // recording to a file; without one events are kept in `events`
+    out: Option<BufWriter<File>>,
+    events: Vec<Event>,
+    // next event to hand out when replaying
+    next: usize,
+    // the first thing that went wrong. the executor stops on it
+    failure: Option<String>,
+    irqs: u64,
+}
+
+struct Shared {
+    // instructions started so far
+    now: Cell<u64>,
+    // when the next logged event is due, u64::MAX when recording
+    due: Cell<u64>,
+    // how far a recording has got, and whether it has been rewound to
+    // somewhere before that; see rewind()
+    frontier: Cell<u64>,
+    rewound: Cell<bool>,
+    state: RefCell<State>,
+}
+
+// a handle on the run's log. the cpu and every device that takes input hold
+// one; clones share it
+#[derive(Clone)]
+pub struct Journal(Rc<Shared>);
+
+impl Journal {
+    fn new(replaying: bool, out: Option<BufWriter<File>>, events: Vec<Event>) -> Self {
+        let due = if replaying { events.first().map_or(u64::MAX, |e| e.at) } else { u64::MAX };
+        Journal(Rc::new(Shared {
+            now: Cell::new(0),
+            due: Cell::new(due),
+            frontier: Cell::new(if replaying { u64::MAX } else { 0 }),
+            rewound: Cell::new(false),
+            state: RefCell::new(State { replaying, out, events, next: 0, failure: None, irqs: 0 }),
+        }))
+    }
+
+    // record into memory; see events()
+    pub fn recorder() -> Self {
+        Self::new(false, None, Vec::new())
+    }
+
+    // record to a file, written as the run goes
+    pub fn record(path: &Path) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut out = BufWriter::new(file);
+        out.write_all(MAGIC)
+            .and_then(|_| out.write_all(&VERSION.to_le_bytes()))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Self::new(false, Some(out), Vec::new()))
+    }
+
+    pub fn replayer(events: Vec<Event>) -> Self {
+        Self::new(true, None, events)
+    }
+
+    pub fn replay(path: &Path) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let events = parse(&data).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(Self::replayer(events))
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.0.state.borrow().replaying
+    }
+
+    // the instruction being executed, counting from 1
+    pub fn now(&self) -> u64 {
+        self.0.now.get()
+    }
+
+    // what a recorder() has logged so far
+    pub fn events(&self) -> Vec<Event> {
+        self.0.state.borrow().events.clone()
+    }
+
+    // called at the start of every instruction
+    #[inline]
+    pub fn tick(&self) {
+        let now = self.0.now.get() + 1;
+        self.0.now.set(now);
+        if now > self.0.due.get() {
+            let next = self.peek().map(|e| e.source);
+            self.fail(format!("the log has {} before this", next.map_or("more".to_string(), |s| s.to_string())));
+        }
+        if now > self.0.frontier.get() {
+            self.0.frontier.set(now);
+            if self.0.rewound.get() {
+                self.catch_up();
+            }
+        }
+    }
+
+    // a rewound recording has been run forward to where it had got to, and
+    // goes on recording from there
+    fn catch_up(&self) {
+        self.0.state.borrow_mut().replaying = false;
+        self.0.rewound.set(false);
+        self.0.due.set(u64::MAX);
+    }
+
+    // go back to just after instruction `to` of a recording, with the machine
+    // put back there some other way (a snapshot). running forward again
+    // gets what was logged handed back instead of asked for, up to where the
+    // recording had got to
+    pub fn rewind(&self, to: u64) {
+        let mut st = self.0.state.borrow_mut();
+        let next = st.events.partition_point(|e| e.at <= to);
+        st.irqs = st.events[..next]
+            .iter()
+            .rfind(|e| e.source == Source::Irq)
+            .and_then(|e| e.data.clone().try_into().ok())
+            .map_or(0, u64::from_le_bytes);
+        st.next = next;
+        st.failure = None;
+        self.0.now.set(to);
+        if to < self.0.frontier.get() {
+            st.replaying = true;
+            self.0.rewound.set(true);
+            self.0.due.set(st.events.get(next).map_or(u64::MAX, |e| e.at));
+        }
+    }
+
+    // start a recording over at instruction `at`, forgetting what it had
+    pub fn restart(&self, at: u64) {
+        let mut st = self.0.state.borrow_mut();
+        st.events.clear();
+        st.next = 0;
+        st.failure = None;
+        st.irqs = 0;
+        st.replaying = false;
+        self.0.now.set(at);
+        self.0.frontier.set(at);
+        self.0.rewound.set(false);
+        self.0.due.set(u64::MAX);
+    }
+
+    // true while a rewound recording is run over again. whatever output
+    // those instructions make has been seen already
+    pub fn muted(&self) -> bool {
+        self.0.rewound.get()
+    }
+
+    fn peek(&self) -> Option<Event> {
+        let st = self.0.state.borrow();
+        st.events.get(st.next).cloned()
+    }
+
+    fn fail(&self, msg: String) {
+        let mut st = self.0.state.borrow_mut();
+        if st.failure.is_none() {
+            let what = if st.replaying { "replay diverged" } else { "recording failed" };
+            st.failure = Some(format!("{} at instruction {}: {}", what, self.now(), msg));
+        }
+        self.0.due.set(u64::MAX);
+    }
+
+    // Err once the replay has gone off course or the recording can't be
+    // written; the executor checks after every instruction or block
+    #[inline]
+    pub fn check(&self) -> Result<(), String> {
+        match &self.0.state.borrow().failure {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn log(&self, source: Source, data: &[u8]) {
+        let at = self.now();
+        let res = {
+            let mut st = self.0.state.borrow_mut();
+            match st.out.as_mut() {
+                Some(out) => {
+                    let mut w = Writer::new();
+                    w.put_u64(at);
+                    w.put_u8(source as u8);
+                    w.put_bytes(data);
+                    // input goes out straight away so a run that gets
+                    // killed still has everything up to it
+                    out.write_all(&w.into_bytes())
+                        .and_then(|_| if source.arrives() { out.flush() } else { Ok(()) })
+                }
+                None => {
+                    st.events.push(Event { at, source, data: data.to_vec() });
+                    Ok(())
+                }
+            }
+        };
+        if let Err(e) = res {
+            self.fail(e.to_string());
+        }
+    }
+
+    // the next logged event if it's `source` at this instruction
+    fn take(&self, source: Source) -> Option<Vec<u8>> {
+        let mut st = self.0.state.borrow_mut();
+        if st.failure.is_some() {
+            return None;
+        }
+        let ev = st.events.get(st.next).filter(|e| e.source == source && e.at == self.now())?;
+        let data = ev.data.clone();
+        st.next += 1;
+        self.0.due.set(st.events.get(st.next).map_or(u64::MAX, |e| e.at));
+        Some(data)
+    }
+
+    // what didn't match at this point, for a failure message
+    fn mismatch(&self, want: Source) -> String {
+        match self.peek() {
+            Some(e) if e.at == self.now() => format!("expected {}, the log has {}", want, e.source),
+            Some(e) => format!("expected {}, the log has nothing until instruction {}", want, e.at),
+            None => format!("expected {}, the log ends here", want),
+        }
+    }
+
+    // input that may or may not have arrived; empty means none
+    pub fn poll(&self, source: Source, live: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        if self.is_replaying() {
+            return self.take(source).unwrap_or_default();
+        }
+        let data = live();
+        if !data.is_empty() {
+            self.log(source, &data);
+        }
+        data
+    }
+
+    // a value the guest asked the host for. after a divergence the replay
+    // answers with nothing
+    pub fn query(&self, source: Source, live: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        if !self.is_replaying() {
+            let data = live();
+            self.log(source, &data);
+            return data;
+        }
+        self.take(source).unwrap_or_else(|| {
+            let msg = self.mismatch(source);
+            self.fail(msg);
+            Vec::new()
+        })
+    }
+
+    // something that follows from the input: logged, and on replay
+    // compared with the log
+    pub fn expect(&self, source: Source, data: &[u8]) {
+        if !self.is_replaying() {
+            self.log(source, data);
+            return;
+        }
+        match self.take(source) {
+            Some(logged) if logged == data => {}
+            Some(logged) => self.fail(format!("{} is {}, the log has {}", source, show(data), show(&logged))),
+            None => {
+                let msg = self.mismatch(source);
+                self.fail(msg);
+            }
+        }
+    }
+
+    // the devices' interrupt lines after this instruction's tick, one bit
+    // per device; only changes get logged
+    pub fn irq_lines(&self, lines: u64) {
+        let changed = {
+            let mut st = self.0.state.borrow_mut();
+            std::mem::replace(&mut st.irqs, lines) != lines
+        };
+        if changed {
+            self.expect(Source::Irq, &lines.to_le_bytes());
+        }
+    }
+
+    // mark the start of the run with a fingerprint of the machine, so a
+    // replay against a different program or different options fails up
+    // front rather than somewhere in the middle
+    pub fn begin(&self, cpu: &Cpu) -> Result<(), String> {
+        // fnv-1a, a word at a time, over the registers and ram
+        let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+        let regs = cpu.regs.into_iter().chain([cpu.pc, cpu.ram_base, cpu.harts() as u32, cpu.devices.len() as u32]);
+        let ram = cpu.mem.chunks(8).map(|c| {
+            let mut w = [0u8; 8];
+            w[..c.len()].copy_from_slice(c);
+            u64::from_le_bytes(w)
+        });
+        for w in regs.map(u64::from).chain(ram) {
+            h = (h ^ w).wrapping_mul(0x100_0000_01b3);
+        }
+        let data = h.to_le_bytes();
+        if !self.is_replaying() {
+            self.log(Source::Machine, &data);
+            return self.check();
+        }
+        match self.take(Source::Machine) {
+            Some(logged) if logged == data => Ok(()),
+            Some(_) => Err("the log was recorded with a different program or machine options".to_string()),
+            None => Err(format!("replay log doesn't start like a run: {}", self.mismatch(Source::Machine))),
+        }
+    }
+
+    // flush a recording. for a replay, how many logged events the run
+    // didn't get to
+    pub fn finish(&self) -> Result<usize, String> {
+        self.check()?;
+        let mut st = self.0.state.borrow_mut();
+        if let Some(out) = st.out.as_mut() {
+            out.flush().map_err(|e| format!("recording failed: {}", e))?;
+        }
+        Ok(if st.replaying { st.events.len() - st.next } else { 0 })
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<Vec<Event>, String> {
+    let truncated = |_| "replay log is truncated".to_string();
+    if data.len() < 12 || &data[..8] != MAGIC {
+        return Err("not a replay log".to_string());
+    }
+    let mut r = Reader::new(&data[8..]);
+    let version = r.get_u32().map_err(truncated)?;
+    if version != VERSION {
+        return Err(format!("replay log version {} (expected {})", version, VERSION));
+    }
+    let mut events = Vec::new();
+    while !r.is_empty() {
+        let at = r.get_u64().map_err(truncated)?;
+        let source = r.get_u8().map_err(truncated)?;
+        let source = Source::from_u8(source).ok_or_else(|| format!("unknown event source {}", source))?;
+        let data = r.get_bytes().map_err(truncated)?.to_vec();
+        events.push(Event { at, source, data });
+    }
+    Ok(events)
+}
+
+// a host clock read, in whatever unit `live` returns
+pub fn clock(journal: Option<&Journal>, live: impl FnOnce() -> u64) -> u64 {
+    let Some(journal) = journal else {
+        return live();
+    };
+    let data = journal.query(Source::Clock, || live().to_le_bytes().to_vec());
+    data.try_into().map(u64::from_le_bytes).unwrap_or(0)
+}
+
+// a read from the host's stdin. under a journal a failed read is taken as
+// the end of input, so that it replays the same way
+pub fn stdin(journal: Option<&Journal>, buf: &mut [u8]) -> io::Result<usize> {
+    let Some(journal) = journal else {
+        return io::stdin().read(buf);
+    };
+    let data = journal.query(Source::Stdin, || {
+        let mut data = vec![0; buf.len()];
+        let n = io::stdin().read(&mut data).unwrap_or(0);
+        data.truncate(n);
+        data
+    });
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+    Ok(n)
+}
+
+// output from a rerun of instructions that have already run
+pub fn muted(journal: Option<&Journal>) -> bool {
+    journal.is_some_and(|j| j.muted())
+}
+
+// an ecall or semihosting call has left its result in a0
+pub fn result(cpu: &Cpu) {
+    if let Some(journal) = &cpu.journal {
+        journal.expect(Source::Result, &cpu.regs[10].to_le_bytes());
+    }
+}
+
+// fill buf from the host's entropy pool
+pub fn random(journal: Option<&Journal>, buf: &mut [u8]) {
+    let Some(journal) = journal else {
+        return crate::process::host_random(buf);
+    };
+    let data = journal.query(Source::Random, || {
+        let mut data = vec![0; buf.len()];
+        crate::process::host_random(&mut data);
+        data
+    });
+    let n = data.len().min(buf.len());
+    buf[..n].copy_from_slice(&data[..n]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_replays_at_the_same_instruction() {
+        let rec = Journal::recorder();
+        let mut inputs = vec![vec![], b"hi".to_vec(), vec![]];
+        for _ in 0..3 {
+            rec.tick();
+            rec.poll(Source::Console, || inputs.remove(0));
+        }
+        assert_eq!(rec.events(), vec![Event { at: 2, source: Source::Console, data: b"hi".to_vec() }]);
+
+        let rep = Journal::replayer(rec.events());
+        let mut got = Vec::new();
+        for _ in 0..3 {
+            rep.tick();
+            got.push(rep.poll(Source::Console, || panic!("replay went to the host")));
+        }
+        assert_eq!(got, vec![vec![], b"hi".to_vec(), vec![]]);
+        assert_eq!(rep.finish(), Ok(0));
+    }
+
+    #[test]
+    fn test_divergence_names_the_instruction() {
+        let rec = Journal::recorder();
+        rec.tick();
+        rec.expect(Source::Result, &[1]);
+        assert_eq!(clock(Some(&rec), || 42), 42);
+
+        // a different result
+        let rep = Journal::replayer(rec.events());
+        rep.tick();
+        rep.expect(Source::Result, &[2]);
+        assert_eq!(rep.check(), Err("replay diverged at instruction 1: a call result is 0x2, the log has 0x1".to_string()));
+
+        // running past a logged event without asking for it
+        let rep = Journal::replayer(rec.events());
+        rep.tick();
+        rep.tick();
+        assert!(rep.check().unwrap_err().starts_with("replay diverged at instruction 2"));
+    }
+
+    #[test]
+    fn test_rewind_hands_back_then_records() {
+        let rec = Journal::recorder();
+        for i in 0..4u8 {
+            rec.tick();
+            rec.poll(Source::Console, || vec![i]);
+        }
+        rec.rewind(2);
+        assert!(rec.muted());
+        rec.tick();
+        assert_eq!(rec.poll(Source::Console, || panic!("asked the host again")), vec![2]);
+        rec.tick();
+        assert_eq!(rec.poll(Source::Console, || panic!("asked the host again")), vec![3]);
+        // past where the recording had got to
+        rec.tick();
+        assert!(!rec.muted());
+        assert_eq!(rec.poll(Source::Console, || vec![9]), vec![9]);
+        assert_eq!(rec.events().len(), 5);
+        assert_eq!(rec.check(), Ok(()));
+    }
+
+    #[test]
+    fn test_log_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("rv32-replay-{}.log", std::process::id()));
+        let rec = Journal::record(&path).unwrap();
+        rec.tick();
+        rec.poll(Source::Net, || vec![1, 2, 3]);
+        rec.tick();
+        assert_eq!(clock(Some(&rec), || 7), 7);
+        rec.finish().unwrap();
+        drop(rec);
+
+        let rep = Journal::replay(&path).unwrap();
+        rep.tick();
+        assert_eq!(rep.poll(Source::Net, Vec::new), vec![1, 2, 3]);
+        rep.tick();
+        assert_eq!(clock(Some(&rep), || 0), 7);
+        assert_eq!(rep.finish(), Ok(0));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+// goldfish rtc
+//
+// time is nanoseconds since the unix epoch. in host mode it's the host wall
+// clock; in virtual mode it starts at a fixed epoch and advances a fixed
+// amount per executed instruction, so runs are bit-for-bit reproducible.
+
+use crate::devices::{Device, DeviceInfo, GuestRam};
+use crate::fdt::{FdtBuilder, PLIC_PHANDLE};
+use crate::replay::{self, Journal};
+use crate::snapshot::{Reader, Writer};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// qemu virt puts it here, on plic source 11
+pub const RTC_BASE: u32 = 0x0010_1000;
+pub const RTC_SIZE: u32 = 0x1000;
+pub const RTC_IRQ: u32 = 11;
+
+const REG_TIME_LOW: u32 = 0x00;
+const REG_TIME_HIGH: u32 = 0x04;
+const REG_ALARM_LOW: u32 = 0x08;
+const REG_ALARM_HIGH: u32 = 0x0c;
+const REG_IRQ_ENABLED: u32 = 0x10;
+const REG_CLEAR_ALARM: u32 = 0x14;
+const REG_ALARM_STATUS: u32 = 0x18;
+const REG_CLEAR_INTERRUPT: u32 = 0x1c;
+
+// virtual clock rate: pretend to be a 100 mips machine
+pub const NS_PER_INSTRUCTION: u64 = 10;
+
+// host mode only looks at the wall clock for alarms this often
+const HOST_ALARM_POLL: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RtcMode {
+    Host,
+    // epoch in nanoseconds at instruction zero
+    Virtual { epoch_ns: u64 },
+}
+
+impl RtcMode {
+    // parse a --rtc argument: "host", "virtual" or "virtual:<unix seconds>"
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "host" => Ok(RtcMode::Host),
+            "virtual" => Ok(RtcMode::Virtual { epoch_ns: 0 }),
+            _ => {
+                let secs = s
+                    .strip_prefix("virtual:")
+                    .ok_or_else(|| format!("unknown rtc mode: {} (expected host or virtual[:secs])", s))?;
+                let epoch_ns = secs
+                    .parse::<u64>()
+                    .ok()
+                    .and_then(|s| s.checked_mul(1_000_000_000))
+                    .ok_or_else(|| format!("invalid rtc epoch: {}", secs))?;
+                Ok(RtcMode::Virtual { epoch_ns })
+            }
+        }
+    }
+}
+
+pub struct GoldfishRtc {
+    mode: RtcMode,
+    instructions: u64,
+    // reading TIME_LOW latches the high half so a 64-bit read is consistent
+    time_high_latch: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq: bool,
+    poll_countdown: u32,
+    journal: Option<Journal>,
+}
+
+impl GoldfishRtc {
+    pub fn new(mode: RtcMode) -> Self {
+        GoldfishRtc {
+            mode,
+            instructions: 0,
+            time_high_latch: 0,
+            alarm_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq: false,
+            poll_countdown: 0,
+            journal: None,
+        }
+    }
+
+    pub fn now_ns(&self) -> u64 {
+        match self.mode {
+            RtcMode::Host => replay::clock(self.journal.as_ref(), || {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_nanos() as u64)
+                    .unwrap_or(0)
+            }),
+            RtcMode::Virtual { epoch_ns } => {
+                epoch_ns.wrapping_add(self.instructions.wrapping_mul(NS_PER_INSTRUCTION))
+            }
+        }
+    }
+
+    fn check_alarm(&mut self) {
+        if let Some(alarm) = self.alarm {
+            if self.now_ns() >= alarm {
+                self.alarm = None;
+                if self.irq_enabled {
+                    self.irq = true;
+                }
+            }
+        }
+    }
+}
+
+impl Device for GoldfishRtc {
+    fn read(&mut self, offset: u32) -> u32 {
+        match offset {
+            REG_TIME_LOW => {
+                let now = self.now_ns();
+                self.time_high_latch = (now >> 32) as u32;
+                now as u32
+            }
+            REG_TIME_HIGH => self.time_high_latch,
+            REG_ALARM_LOW => self.alarm.map_or(0, |a| a as u32),
+            REG_ALARM_HIGH => self.alarm.map_or(0, |a| (a >> 32) as u32),
+            REG_IRQ_ENABLED => self.irq_enabled as u32,
+            REG_ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _ram: &mut GuestRam) {
+        match offset {
+            // linux writes high first; the low write arms the alarm
+            REG_ALARM_HIGH => self.alarm_high = val,
+            REG_ALARM_LOW => {
+                self.alarm = Some(((self.alarm_high as u64) << 32) | val as u64);
+                self.check_alarm();
+            }
+            REG_IRQ_ENABLED => self.irq_enabled = val & 1 != 0,
+            REG_CLEAR_ALARM => self.alarm = None,
+            REG_CLEAR_INTERRUPT => self.irq = false,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, _ram: &mut GuestRam) {
+        self.instructions += 1;
+        if self.alarm.is_none() {
+            return;
+        }
+        if self.mode == RtcMode::Host {
+            if self.poll_countdown > 0 {
+                self.poll_countdown -= 1;
+                return;
+            }
+            self.poll_countdown = HOST_ALARM_POLL;
+        }
+        self.check_alarm();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(RTC_IRQ)
+    }
+
+    fn info(&self) -> Option<DeviceInfo> {
+        Some(DeviceInfo { name: "rtc", compatible: "google,goldfish-rtc" })
+    }
+
+    fn fdt_props(&self, fdt: &mut FdtBuilder) {
+        fdt.prop_u32("interrupt-parent", PLIC_PHANDLE);
+        fdt.prop_u32("interrupts", RTC_IRQ);
+    }
+
+    fn save(&self, w: &mut Writer) {
+        w.put_u64(self.instructions);
+        w.put_u32(self.time_high_latch);
+        w.put_u32(self.alarm_high);
+        w.put_option_u64(self.alarm);
+        w.put_bool(self.irq_enabled);
+        w.put_bool(self.irq);
+        w.put_u32(self.poll_countdown);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.instructions = r.get_u64()?;
+        self.time_high_latch = r.get_u32()?;
+        self.alarm_high = r.get_u32()?;
+        self.alarm = r.get_option_u64()?;
+        self.irq_enabled = r.get_bool()?;
+        self.irq = r.get_bool()?;
+        self.poll_countdown = r.get_u32()?;
+        Ok(())
+    }
+
+    fn set_journal(&mut self, journal: &Journal) {
+        self.journal = Some(journal.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::plic::{Plic, IRQ_M_EXT, PLIC_BASE, PLIC_SIZE};
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(RtcMode::parse("host"), Ok(RtcMode::Host));
+        assert_eq!(RtcMode::parse("virtual:2"), Ok(RtcMode::Virtual { epoch_ns: 2_000_000_000 }));
+        assert!(RtcMode::parse("virtual:soon").is_err());
+        assert_eq!(RtcMode::parse("virtual:18446744073709551615"), Err("invalid rtc epoch: 18446744073709551615".to_string()));
+    }
+
+    #[test]
+    fn test_virtual_time_follows_instructions() {
+        let mut rtc = GoldfishRtc::new(RtcMode::Virtual { epoch_ns: 1 << 32 });
+        let mut mem = GuestRam::new(0, &mut []);
+        for _ in 0..100 {
+            rtc.tick(&mut mem);
+        }
+        assert_eq!(rtc.read(REG_TIME_LOW), 100 * NS_PER_INSTRUCTION as u32);
+        assert_eq!(rtc.read(REG_TIME_HIGH), 1);
+    }
+
+    #[test]
+    fn test_alarm_raises_irq() {
+        let mut rtc = GoldfishRtc::new(RtcMode::Virtual { epoch_ns: 0 });
+        let mut mem = GuestRam::new(0, &mut []);
+        rtc.write(REG_IRQ_ENABLED, 1, &mut mem);
+        rtc.write(REG_ALARM_HIGH, 0, &mut mem);
+        rtc.write(REG_ALARM_LOW, 50 * NS_PER_INSTRUCTION as u32, &mut mem);
+        assert_eq!(rtc.read(REG_ALARM_STATUS), 1);
+
+        for _ in 0..49 {
+            rtc.tick(&mut mem);
+        }
+        assert!(!rtc.irq_pending());
+        rtc.tick(&mut mem);
+        assert!(rtc.irq_pending());
+        assert_eq!(rtc.read(REG_ALARM_STATUS), 0);
+
+        rtc.write(REG_CLEAR_INTERRUPT, 1, &mut mem);
+        assert!(!rtc.irq_pending());
+    }
+    #[test]
+    fn test_alarm_is_claimable_at_the_plic() {
+        let mut cpu = Cpu::new();
+        cpu.attach_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new(1))).unwrap();
+        cpu.attach_device(RTC_BASE, RTC_SIZE, Box::new(GoldfishRtc::new(RtcMode::Virtual { epoch_ns: 0 }))).unwrap();
+        // source 11 at priority 1, enabled for hart 0's m context
+        cpu.store(PLIC_BASE + RTC_IRQ * 4, 4, 1).unwrap();
+        cpu.store(PLIC_BASE + 0x2000, 4, 1 << RTC_IRQ).unwrap();
+        cpu.store(RTC_BASE + REG_IRQ_ENABLED, 4, 1).unwrap();
+        cpu.store(RTC_BASE + REG_ALARM_HIGH, 4, 0).unwrap();
+        cpu.store(RTC_BASE + REG_ALARM_LOW, 4, 10 * NS_PER_INSTRUCTION as u32).unwrap();
+        for _ in 0..10 {
+            cpu.tick_devices();
+        }
+        assert_eq!(cpu.device_irqs(), 1 << IRQ_M_EXT);
+
+        let claim = PLIC_BASE + 0x20_0004;
+        assert_eq!(cpu.load(claim, 4).unwrap(), RTC_IRQ);
+        cpu.store(RTC_BASE + REG_CLEAR_INTERRUPT, 4, 1).unwrap();
+        cpu.store(claim, 4, RTC_IRQ).unwrap();
+        cpu.tick_devices();
+        assert_eq!(cpu.device_irqs(), 0);
+    }
+}
@@ -0,0 +1,329 @@
+// risc-v semihosting, the arm semihosting calls behind a magic ebreak
+//
+// a call is the sequence
+//
+//   slli x0, x0, 0x1f
+//   ebreak
+//   srai x0, x0, 7
+//
+// with the operation in a0 and a pointer to its parameter block (or the
+// parameter itself, for a few calls) in a1. the result comes back in a0.
+// files are opened on the host relative to the current directory, the way
+// a debug probe does it; ":tt" is the console.
+
+use crate::cpu::Cpu;
+use crate::replay::{self, Journal};
+use crate::snapshot::{Reader, Writer};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::time::Instant;
+
+pub const SEMIHOST_PRE: u32 = 0x01f0_1013; // slli x0, x0, 0x1f
+pub const SEMIHOST_POST: u32 = 0x4070_5013; // srai x0, x0, 7
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+// the only exit reason that means success
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x2_0026;
+
+// longest string SYS_WRITE0 will look for a terminator in
+const MAX_STRING: usize = 64 * 1024;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+pub struct Semihosting {
+    cmdline: String,
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    start: Instant,
+}
+
+// is the ebreak at `pc` a semihosting call?
+pub fn is_call(cpu: &Cpu, pc: u32) -> bool {
+    cpu.peek_word(pc.wrapping_sub(4)) == Some(SEMIHOST_PRE)
+        && cpu.peek_word(pc.wrapping_add(4)) == Some(SEMIHOST_POST)
+}
+
+impl Semihosting {
+    // `cmdline` is what SYS_GET_CMDLINE hands back, program name included
+    pub fn new(cmdline: &str) -> Self {
+        Semihosting {
+            cmdline: cmdline.to_string(),
+            handles: HashMap::new(),
+            // 0 is a valid handle, but leaving it unused catches guests
+            // that forget to check for -1
+            next_handle: 1,
+            start: Instant::now(),
+        }
+    }
+
+    // the open console handles, for snapshots. host files can't be carried
+    // over, so a snapshot with one open fails
+    pub fn save(&self, w: &mut Writer) -> Result<(), String> {
+        w.put_u32(self.next_handle);
+        w.put_u32(self.handles.len() as u32);
+        let mut handles: Vec<_> = self.handles.iter().collect();
+        handles.sort_by_key(|(h, _)| **h);
+        for (&h, handle) in handles {
+            let kind = match handle {
+                Handle::Stdin => 0,
+                Handle::Stdout => 1,
+                Handle::Stderr => 2,
+                Handle::File(_) => return Err(format!("semihosting file handle {} is open", h)),
+            };
+            w.put_u32(h);
+            w.put_u8(kind);
+        }
+        Ok(())
+    }
+
+    pub fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.next_handle = r.get_u32()?;
+        self.handles.clear();
+        for _ in 0..r.get_u32()? {
+            let h = r.get_u32()?;
+            let handle = match r.get_u8()? {
+                0 => Handle::Stdin,
+                1 => Handle::Stdout,
+                _ => Handle::Stderr,
+            };
+            self.handles.insert(h, handle);
+        }
+        Ok(())
+    }
+
+    fn bytes(cpu: &Cpu, addr: u32, len: u32) -> Result<&[u8], String> {
+        cpu.ram_offset(addr, len as usize)
+            .map(|off| &cpu.mem[off..off + len as usize])
+            .ok_or_else(|| format!("semihosting: buffer 0x{:x}+{} isn't in ram", addr, len))
+    }
+
+    // for writing, so anything decoded from there goes
+    fn bytes_mut(cpu: &mut Cpu, addr: u32, len: u32) -> Result<&mut [u8], String> {
+        let off = cpu.ram_offset(addr, len as usize)
+            .ok_or_else(|| format!("semihosting: buffer 0x{:x}+{} isn't in ram", addr, len))?;
+        cpu.icache.invalidate(off, len as usize);
+        Ok(&mut cpu.mem[off..off + len as usize])
+    }
+
+    fn param(cpu: &Cpu, block: u32, n: u32) -> Result<u32, String> {
+        let addr = block.wrapping_add(n * 4);
+        cpu.peek_word(addr)
+            .ok_or_else(|| format!("semihosting: parameter block at 0x{:x} isn't in ram", block))
+    }
+
+    fn open(&mut self, name: &str, mode: u32) -> u32 {
+        let handle = if name == ":tt" {
+            // the console mode says which stream: r, w or a
+            match mode / 4 {
+                0 => Handle::Stdin,
+                1 => Handle::Stdout,
+                _ => Handle::Stderr,
+            }
+        } else {
+            let mut opts = OpenOptions::new();
+            // mode is fopen's r, r+, w, w+, a, a+, each with and without b
+            match mode / 2 {
+                0 => opts.read(true),
+                1 => opts.read(true).write(true),
+                2 => opts.write(true).create(true).truncate(true),
+                3 => opts.read(true).write(true).create(true).truncate(true),
+                4 => opts.append(true).create(true),
+                5 => opts.read(true).append(true).create(true),
+                _ => return u32::MAX,
+            };
+            match opts.open(name) {
+                Ok(f) => Handle::File(f),
+                Err(_) => return u32::MAX,
+            }
+        };
+        let h = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(h, handle);
+        h
+    }
+
+    // `muted` skips the console, for reruns of calls that already printed
+    fn write(&mut self, h: u32, data: &[u8], muted: bool) -> Option<usize> {
+        let res = match self.handles.get_mut(&h)? {
+            Handle::Stdin => return None,
+            Handle::Stdout | Handle::Stderr if muted => Ok(()),
+            Handle::Stdout => io::stdout().write_all(data).and_then(|_| io::stdout().flush()),
+            Handle::Stderr => io::stderr().write_all(data),
+            Handle::File(f) => f.write_all(data),
+        };
+        res.ok().map(|_| data.len())
+    }
+
+    fn read(&mut self, h: u32, buf: &mut [u8], journal: Option<&Journal>) -> Option<usize> {
+        match self.handles.get_mut(&h)? {
+            Handle::Stdin => replay::stdin(journal, buf).ok(),
+            Handle::File(f) => f.read(buf).ok(),
+            _ => None,
+        }
+    }
+
+    // service the call at the current pc. Ok(Some(code)) ends the program
+    pub fn call(&mut self, cpu: &mut Cpu) -> Result<Option<i32>, String> {
+        let op = cpu.read_reg(10);
+        let arg = cpu.read_reg(11);
+        let muted = replay::muted(cpu.journal.as_ref());
+        let ret = match op {
+            SYS_OPEN => {
+                let (name, mode, len) = (Self::param(cpu, arg, 0)?, Self::param(cpu, arg, 1)?, Self::param(cpu, arg, 2)?);
+                let name = String::from_utf8_lossy(Self::bytes(cpu, name, len)?).into_owned();
+                self.open(&name, mode)
+            }
+            SYS_CLOSE => {
+                let h = Self::param(cpu, arg, 0)?;
+                if self.handles.remove(&h).is_some() { 0 } else { u32::MAX }
+            }
+            SYS_WRITEC => {
+                let c = Self::bytes(cpu, arg, 1)?[0];
+                if !muted {
+                    let _ = io::stdout().write_all(&[c]).and_then(|_| io::stdout().flush());
+                }
+                0
+            }
+            SYS_WRITE0 => {
+                let tail = cpu.ram_offset(arg, 1)
+                    .map(|off| &cpu.mem[off..cpu.mem.len().min(off + MAX_STRING)])
+                    .ok_or_else(|| format!("semihosting: string at 0x{:x} isn't in ram", arg))?;
+                let end = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+                if !muted {
+                    let _ = io::stdout().write_all(&tail[..end]).and_then(|_| io::stdout().flush());
+                }
+                0
+            }
+            // both return how many bytes were *not* transferred
+            SYS_WRITE => {
+                let (h, buf, len) = (Self::param(cpu, arg, 0)?, Self::param(cpu, arg, 1)?, Self::param(cpu, arg, 2)?);
+                let data = Self::bytes(cpu, buf, len)?.to_vec();
+                match self.write(h, &data, muted) {
+                    Some(n) => len - n as u32,
+                    None => u32::MAX,
+                }
+            }
+            SYS_READ => {
+                let (h, buf, len) = (Self::param(cpu, arg, 0)?, Self::param(cpu, arg, 1)?, Self::param(cpu, arg, 2)?);
+                // the buffer has to be in ram, which also caps what a bogus
+                // length can make us allocate
+                Self::bytes_mut(cpu, buf, len)?;
+                let mut data = vec![0; len as usize];
+                match self.read(h, &mut data, cpu.journal.as_ref()) {
+                    Some(n) => {
+                        Self::bytes_mut(cpu, buf, n as u32)?.copy_from_slice(&data[..n]);
+                        len - n as u32
+                    }
+                    None => u32::MAX,
+                }
+            }
+            SYS_CLOCK => replay::clock(cpu.journal.as_ref(), || (self.start.elapsed().as_millis() / 10) as u64) as u32,
+            SYS_GET_CMDLINE => {
+                let (buf, len) = (Self::param(cpu, arg, 0)?, Self::param(cpu, arg, 1)?);
+                let cmdline = self.cmdline.as_bytes();
+                if cmdline.len() as u32 >= len {
+                    u32::MAX
+                } else {
+                    let dst = Self::bytes_mut(cpu, buf, cmdline.len() as u32 + 1)?;
+                    dst[..cmdline.len()].copy_from_slice(cmdline);
+                    dst[cmdline.len()] = 0;
+                    cpu.store_word(arg.wrapping_add(4), cmdline.len() as u32)?;
+                    0
+                }
+            }
+            // on rv32 the reason is passed directly rather than in a block
+            SYS_EXIT => return Ok(Some(if arg == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 })),
+            SYS_EXIT_EXTENDED => {
+                let (reason, code) = (Self::param(cpu, arg, 0)?, Self::param(cpu, arg, 1)?);
+                return Ok(Some(if reason == ADP_STOPPED_APPLICATION_EXIT { code as i32 } else { 1 }));
+            }
+            _ => return Err(format!("semihosting: unsupported operation 0x{:x}", op)),
+        };
+        cpu.write_reg(10, ret);
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_cmdline() {
+        let mut cpu = Cpu::new();
+        let mut sh = Semihosting::new("prog --fast");
+        cpu.write_word(0x100, 0x200);
+        cpu.write_word(0x104, 64);
+        cpu.regs[10] = SYS_GET_CMDLINE;
+        cpu.regs[11] = 0x100;
+        assert_eq!(sh.call(&mut cpu), Ok(None));
+        assert_eq!(cpu.regs[10], 0);
+        assert_eq!(cpu.read_word(0x104), 11);
+        assert_eq!(&cpu.mem[0x200..0x20c], b"prog --fast\0");
+
+        // too small a buffer fails without touching it
+        cpu.write_word(0x104, 4);
+        cpu.regs[10] = SYS_GET_CMDLINE;
+        assert_eq!(sh.call(&mut cpu), Ok(None));
+        assert_eq!(cpu.regs[10], u32::MAX);
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("rv32-emu-semihost-{}", std::process::id()));
+        let name = path.to_str().unwrap().as_bytes();
+        let mut cpu = Cpu::new();
+        let mut sh = Semihosting::new("");
+        cpu.load_program(name, 0x1000);
+        cpu.load_program(b"data", 0x2000);
+
+        let mut call = |cpu: &mut Cpu, op: u32, params: &[u32]| {
+            for (i, p) in params.iter().enumerate() {
+                cpu.write_word(0x100 + i as u32 * 4, *p);
+            }
+            cpu.regs[10] = op;
+            cpu.regs[11] = 0x100;
+            assert_eq!(sh.call(cpu), Ok(None));
+            cpu.regs[10]
+        };
+        let h = call(&mut cpu, SYS_OPEN, &[0x1000, 4, name.len() as u32]);
+        assert_ne!(h, u32::MAX);
+        assert_eq!(call(&mut cpu, SYS_WRITE, &[h, 0x2000, 4]), 0);
+        assert_eq!(call(&mut cpu, SYS_CLOSE, &[h]), 0);
+
+        let h = call(&mut cpu, SYS_OPEN, &[0x1000, 0, name.len() as u32]);
+        // asked for 8, got 4
+        assert_eq!(call(&mut cpu, SYS_READ, &[h, 0x3000, 8]), 4);
+        assert_eq!(&cpu.mem[0x3000..0x3004], b"data");
+        assert_eq!(call(&mut cpu, SYS_CLOSE, &[h]), 0);
+        assert_eq!(call(&mut cpu, SYS_CLOSE, &[h]), u32::MAX);
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[test]
+    fn test_read_past_ram_fails() {
+        let mut cpu = Cpu::new();
+        let mut sh = Semihosting::new("");
+        cpu.write_word(0x100, 1);
+        cpu.write_word(0x104, 0x3000);
+        cpu.write_word(0x108, 0xffff_0000);
+        cpu.regs[10] = SYS_READ;
+        cpu.regs[11] = 0x100;
+        assert!(sh.call(&mut cpu).unwrap_err().contains("isn't in ram"));
+    }
+}
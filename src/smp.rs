@@ -0,0 +1,263 @@
+// symmetric multiprocessing: several harts sharing one machine
+//
+// Cpu is the machine (ram, devices, caches, lr/sc reservations) plus the
+// registers of whichever hart is running. the other harts' registers wait
+// here and are swapped in when their turn comes. harts take turns in hart
+// id order, `quantum` instructions each, so the interleaving only depends on
+// the program and the quantum and a run is the same every time.
+
+use crate::cpu::{Cpu, NREGS};
+use crate::csr::Csrs;
+use crate::executor::Executor;
+use crate::metrics::Instrument;
+use crate::snapshot::{Reader, Writer};
+
+pub const DEFAULT_QUANTUM: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct HartState {
+    pub regs: [u32; NREGS],
+    pub pc: u32,
+    pub csrs: Csrs,
+    // ran off to address 0
+    pub halted: bool,
+}
+
+// a hart's registers at the start: whatever was set up in cpu, except that
+// a0 holds the hart id on every hart after the first
+pub fn boot_regs(cpu: &Cpu, hart: usize) -> [u32; NREGS] {
+    let mut regs = cpu.regs;
+    if hart > 0 {
+        regs[10] = hart as u32;
+    }
+    regs
+}
+
+pub struct Smp {
+    harts: Vec<HartState>,
+    current: usize,
+    quantum: usize,
+    // instructions left in the current hart's turn
+    left: usize,
+}
+
+impl Smp {
+    // one hart per cpu.harts(), each starting from boot_regs()
+    pub fn new(cpu: &mut Cpu, quantum: usize) -> Self {
+        let harts = (0..cpu.harts())
+            .map(|i| HartState { regs: boot_regs(cpu, i), pc: cpu.pc, csrs: cpu.csrs.clone(), halted: false })
+            .collect();
+        cpu.hartid = 0;
+        let quantum = quantum.max(1);
+        Smp { harts, current: 0, quantum, left: quantum }
+    }
+
+    pub fn len(&self) -> usize {
+        self.harts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.harts.is_empty()
+    }
+
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    pub fn quantum(&self) -> usize {
+        self.quantum
+    }
+
+    // a hart's pc and whether it has halted; the current hart's live state
+    // is in cpu and exec
+    pub fn status(&self, cpu: &Cpu, exec: &Executor, hart: usize) -> (u32, bool) {
+        if hart == self.current {
+            (cpu.pc, exec.halted)
+        } else {
+            (self.harts[hart].pc, self.harts[hart].halted)
+        }
+    }
+
+    fn stash(&mut self, cpu: &Cpu, exec: &Executor) {
+        let h = &mut self.harts[self.current];
+        h.regs = cpu.regs;
+        h.pc = cpu.pc;
+        h.csrs = cpu.csrs.clone();
+        h.halted = exec.halted;
+    }
+
+    fn switch_to(&mut self, cpu: &mut Cpu, exec: &mut Executor, hart: usize) {
+        let h = &self.harts[hart];
+        cpu.regs = h.regs;
+        cpu.pc = h.pc;
+        cpu.csrs = h.csrs.clone();
+        cpu.hartid = hart as u32;
+        exec.halted = h.halted;
+        self.current = hart;
+        self.left = self.quantum;
+    }
+
+    // make `hart` the running one
+    pub fn select(&mut self, cpu: &mut Cpu, exec: &mut Executor, hart: usize) -> Result<(), String> {
+        if hart >= self.harts.len() {
+            return Err(format!("no hart {} (there are {})", hart, self.harts.len()));
+        }
+        self.stash(cpu, exec);
+        self.switch_to(cpu, exec, hart);
+        Ok(())
+    }
+
+    // hand over to the next hart that hasn't halted, the current one last.
+    // false if they all have
+    fn rotate(&mut self, cpu: &mut Cpu, exec: &mut Executor) -> bool {
+        self.stash(cpu, exec);
+        let n = self.harts.len();
+        let next = (1..=n).map(|i| (self.current + i) % n).find(|&h| !self.harts[h].halted);
+        match next {
+            Some(h) => {
+                self.switch_to(cpu, exec, h);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // the scheduler and the harts that aren't current, for snapshots. the
+    // current hart's registers and csrs are the ones in cpu
+    pub fn save(&self, w: &mut Writer) {
+        w.put_u32(self.current as u32);
+        w.put_u64(self.left as u64);
+        w.put_u32(self.harts.len() as u32);
+        for h in &self.harts {
+            w.put_u32(h.pc);
+            h.regs.iter().for_each(|&r| w.put_u32(r));
+            h.csrs.save(w);
+            w.put_bool(h.halted);
+        }
+    }
+
+    // the quantum stays the one this machine was set up with
+    pub fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        let current = r.get_u32()? as usize;
+        let left = r.get_u64()? as usize;
+        let n = r.get_u32()? as usize;
+        if n != self.harts.len() || current >= n {
+            return Err(format!("snapshot has {} harts, this machine has {}", n, self.harts.len()));
+        }
+        for h in self.harts.iter_mut() {
+            h.pc = r.get_u32()?;
+            for reg in h.regs.iter_mut() {
+                *reg = r.get_u32()?;
+            }
+            h.csrs.restore(r)?;
+            h.halted = r.get_bool()?;
+        }
+        self.current = current;
+        self.left = left.clamp(1, self.quantum);
+        Ok(())
+    }
+
+    // account for one instruction executed outside run(), the way the
+    // debugger steps; switches harts when the turn is used up
+    pub fn tick(&mut self, cpu: &mut Cpu, exec: &mut Executor) {
+        // step() doesn't stop at address 0 the way run() does
+        if cpu.returned_to_zero() {
+            exec.halted = true;
+        }
+        self.left = self.left.saturating_sub(1);
+        if self.left == 0 || exec.halted {
+            self.rotate(cpu, exec);
+        }
+    }
+
+    // run the harts in turn until they've all halted, one of them ends the
+    // program, or max_steps instructions have run in total. an error names
+    // the hart and leaves it current
+    pub fn run<M: Instrument>(
+        &mut self,
+        exec: &mut Executor,
+        cpu: &mut Cpu,
+        metrics: &mut M,
+        max_steps: usize,
+    ) -> Result<usize, String> {
+        let mut steps = 0;
+        while steps < max_steps {
+            let budget = self.left.min(max_steps - steps);
+            let ran = exec.run(cpu, metrics, budget)
+                .map_err(|e| format!("hart {}: {}", self.current, e))?;
+            metrics.record_hart(self.current, ran as u64);
+            steps += ran;
+            self.left -= ran.min(self.left);
+            if exec.exit_code.is_some() {
+                break;
+            }
+            if (self.left == 0 || exec.halted) && !self.rotate(cpu, exec) {
+                break;
+            }
+        }
+        Ok(steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::metrics::Metrics;
+
+    // every hart adds its id + 1 to a shared counter under an lr/sc loop,
+    // then returns to 0
+    const COUNTER: &str = "addi x10, x10, 1
+        addi x11, x0, 0x100
+        retry:
+        lr.w x5, (x11)
+        add x5, x5, x10
+        sc.w x6, x5, (x11)
+        bne x6, x0, retry
+        csrr x7, mhartid
+        jalr x0, 0(x0)";
+
+    fn machine(harts: usize) -> Cpu {
+        let code = Assembler::new().assemble(COUNTER).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&code, 0x1000);
+        cpu.pc = 0x1000;
+        cpu.set_harts(harts);
+        cpu
+    }
+
+    #[test]
+    fn test_harts_share_memory() {
+        let mut cpu = machine(3);
+        let mut smp = Smp::new(&mut cpu, 2);
+        let mut exec = Executor::new();
+        let mut metrics = Metrics::new();
+        smp.run(&mut exec, &mut cpu, &mut metrics, 1000).unwrap();
+        assert_eq!(cpu.read_word(0x100), 1 + 2 + 3);
+        for h in 0..3 {
+            assert_eq!(smp.status(&cpu, &exec, h), (0, true));
+        }
+        assert_eq!(metrics.hart_counts.iter().sum::<u64>(), metrics.inst_count);
+        smp.select(&mut cpu, &mut exec, 2).unwrap();
+        assert_eq!(cpu.regs[7], 2);
+    }
+
+    #[test]
+    fn test_reservation_lost_to_other_hart() {
+        // with a quantum of 3 both harts take a reservation, then hart 0's
+        // sc.w succeeds and breaks hart 1's
+        let mut cpu = machine(2);
+        let mut smp = Smp::new(&mut cpu, 3);
+        let mut exec = Executor::new();
+        let mut metrics = Metrics::new();
+        let steps = smp.run(&mut exec, &mut cpu, &mut metrics, 1000).unwrap();
+        assert_eq!(cpu.read_word(0x100), 3);
+        // the failed sc.w costs hart 1 one more trip round the loop
+        assert_eq!(steps, 2 * 8 + 4);
+        // same again
+        let mut cpu2 = machine(2);
+        let mut smp2 = Smp::new(&mut cpu2, 3);
+        let mut exec2 = Executor::new();
+        assert_eq!(smp2.run(&mut exec2, &mut cpu2, &mut Metrics::new(), 1000).unwrap(), steps);
+    }
+}
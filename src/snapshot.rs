@@ -0,0 +1,403 @@
+// whole-machine snapshots
+//
+// a snapshot file is the magic, a format version and then tagged sections,
+// each a 4-byte tag and a u64 length:
+//
+//   "cpu "  pc, registers, ram base and size, lr/sc reservations, csrs and
+//           privilege level
+//   "ram "  every 4k page of ram that isn't all zero, with its index
+//   "exec"  halted, exit code, instructions run so far, semihosting handles
+//   "smp "  the round-robin scheduler and the registers and csrs of the
+//           other harts
+//   "dev "  one per device region, in attach order: base, size and whatever
+//           Device::save wrote
+//
+// everything is little endian. what's derived (the predecode cache,
+// translated blocks, native code) isn't saved and gets rebuilt, and neither
+// is anything on the host side: files, sockets, the console. restoring goes
+// into a machine built from the same options, which supplies all of that,
+// and fails if its ram or devices don't line up with the snapshot's.
+// bump VERSION whenever a section's layout changes.
+
+use crate::cpu::Cpu;
+use crate::executor::Executor;
+use crate::smp::Smp;
+use std::fs;
+use std::path::Path;
+
+pub const MAGIC: &[u8; 8] = b"RV32SNAP";
+pub const VERSION: u32 = 1;
+
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn put_bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+
+    pub fn put_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    // length-prefixed
+    pub fn put_bytes(&mut self, data: &[u8]) {
+        self.put_u64(data.len() as u64);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn put_option_u32(&mut self, v: Option<u32>) {
+        self.put_bool(v.is_some());
+        self.put_u32(v.unwrap_or(0));
+    }
+
+    pub fn put_option_u64(&mut self, v: Option<u64>) {
+        self.put_bool(v.is_some());
+        self.put_u64(v.unwrap_or(0));
+    }
+
+    // a tagged section whose contents `f` writes
+    fn section(&mut self, tag: &[u8; 4], f: impl FnOnce(&mut Writer)) {
+        let mut inner = Writer::new();
+        f(&mut inner);
+        self.buf.extend_from_slice(tag);
+        self.put_bytes(&inner.buf);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < n {
+            return Err("snapshot is truncated".to_string());
+        }
+        let (head, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(head)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> Result<bool, String> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.get_u64()?;
+        let len = usize::try_from(len).map_err(|_| "snapshot is truncated".to_string())?;
+        self.take(len)
+    }
+
+    pub fn get_option_u32(&mut self) -> Result<Option<u32>, String> {
+        let some = self.get_bool()?;
+        let v = self.get_u32()?;
+        Ok(some.then_some(v))
+    }
+
+    pub fn get_option_u64(&mut self) -> Result<Option<u64>, String> {
+        let some = self.get_bool()?;
+        let v = self.get_u64()?;
+        Ok(some.then_some(v))
+    }
+
+    // the next section's tag and contents
+    fn section(&mut self) -> Result<([u8; 4], Reader<'a>), String> {
+        let tag = self.take(4)?.try_into().unwrap();
+        Ok((tag, Reader::new(self.get_bytes()?)))
+    }
+
+    // a device or section that didn't read all of its data is out of step
+    // with the writer
+    fn finish(&self, what: &str) -> Result<(), String> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("snapshot {} section has {} bytes left over", what, self.data.len()))
+        }
+    }
+}
+
+// the machine as it is after `steps` instructions. smp is the scheduler of
+// a multi-hart machine, whose other harts' registers live there
+pub fn save(cpu: &Cpu, exec: &Executor, smp: Option<&Smp>, steps: u64) -> Result<Vec<u8>, String> {
+    if exec.ecall.is_some() {
+        return Err("snapshots of user-mode processes aren't supported".to_string());
+    }
+    let mut w = Writer::new();
+    w.buf.extend_from_slice(MAGIC);
+    w.put_u32(VERSION);
+
+    w.section(b"cpu ", |w| {
+        w.put_u32(cpu.pc);
+        cpu.regs.iter().for_each(|&r| w.put_u32(r));
+        w.put_u32(cpu.ram_base);
+        w.put_u64(cpu.mem.len() as u64);
+        w.put_u32(cpu.reset_vector);
+        w.put_u32(cpu.hartid);
+        w.put_u32(cpu.reservations.len() as u32);
+        cpu.reservations.iter().for_each(|&r| w.put_option_u32(r));
+        cpu.csrs.save(w);
+    });
+    w.section(b"ram ", |w| {
+        for (i, page) in cpu.mem.chunks(PAGE_SIZE).enumerate() {
+            if page.iter().any(|&b| b != 0) {
+                w.put_u32(i as u32);
+                w.put_bytes(page);
+            }
+        }
+    });
+    let mut semihosting = Writer::new();
+    if let Some(sh) = &exec.semihosting {
+        sh.save(&mut semihosting)?;
+    }
+    w.section(b"exec", |w| {
+        w.put_bool(exec.halted);
+        w.put_option_u32(exec.exit_code.map(|c| c as u32));
+        w.put_u64(steps);
+        w.put_bytes(&semihosting.buf);
+    });
+    if let Some(smp) = smp {
+        w.section(b"smp ", |w| smp.save(w));
+    }
+    for region in &cpu.devices {
+        w.section(b"dev ", |w| {
+            w.put_u32(region.base);
+            w.put_u32(region.size);
+            region.dev.save(w);
+        });
+    }
+    Ok(w.into_bytes())
+}
+
+// load a snapshot into a machine built the same way as the one it came
+// from. returns the number of instructions the snapshot was taken after
+pub fn restore(data: &[u8], cpu: &mut Cpu, exec: &mut Executor, mut smp: Option<&mut Smp>) -> Result<u64, String> {
+    let mut r = Reader::new(data);
+    if r.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err("not a snapshot file".to_string());
+    }
+    let version = r.get_u32()?;
+    if version != VERSION {
+        return Err(format!("snapshot is version {}, this build reads version {}", version, VERSION));
+    }
+
+    let mut steps = None;
+    let mut devices = 0;
+    while !r.is_empty() {
+        let (tag, mut s) = r.section()?;
+        match &tag {
+            b"cpu " => {
+                let pc = s.get_u32()?;
+                let mut regs = [0; 32];
+                for reg in regs.iter_mut() {
+                    *reg = s.get_u32()?;
+                }
+                let (base, size) = (s.get_u32()?, s.get_u64()?);
+                if base != cpu.ram_base || size != cpu.mem.len() as u64 {
+                    return Err(format!(
+                        "snapshot has ram 0x{:x}+0x{:x}, this machine has 0x{:x}+0x{:x}",
+                        base, size, cpu.ram_base, cpu.mem.len()
+                    ));
+                }
+                cpu.reset_vector = s.get_u32()?;
+                cpu.hartid = s.get_u32()?;
+                let harts = s.get_u32()? as usize;
+                if harts != cpu.harts() {
+                    return Err(format!("snapshot has {} harts, this machine has {}", harts, cpu.harts()));
+                }
+                for i in 0..harts {
+                    cpu.reservations[i] = s.get_option_u32()?;
+                }
+                cpu.csrs.restore(&mut s)?;
+                cpu.pc = pc;
+                cpu.regs = regs;
+            }
+            b"ram " => {
+                cpu.mem.fill(0);
+                while !s.is_empty() {
+                    let i = s.get_u32()? as usize;
+                    let page = s.get_bytes()?;
+                    let start = i * PAGE_SIZE;
+                    cpu.mem.get_mut(start..start + page.len())
+                        .ok_or("snapshot ram page out of range")?
+                        .copy_from_slice(page);
+                }
+                // anything decoded or translated from the old contents is stale
+                cpu.icache.flush();
+            }
+            b"exec" => {
+                exec.halted = s.get_bool()?;
+                exec.exit_code = s.get_option_u32()?.map(|c| c as i32);
+                steps = Some(s.get_u64()?);
+                let semihosting = s.get_bytes()?;
+                match exec.semihosting.as_mut() {
+                    Some(sh) if !semihosting.is_empty() => sh.restore(&mut Reader::new(semihosting))?,
+                    None if !semihosting.is_empty() => {
+                        return Err("snapshot was taken with --semihosting".to_string());
+                    }
+                    _ => {}
+                }
+            }
+            b"smp " => {
+                let smp = smp.as_deref_mut()
+                    .ok_or("snapshot has several harts, this machine has one")?;
+                smp.restore(&mut s)?;
+            }
+            b"dev " => {
+                let (base, size) = (s.get_u32()?, s.get_u32()?);
+                let region = cpu.devices.get_mut(devices)
+                    .filter(|d| d.base == base && d.size == size)
+                    .ok_or_else(|| format!("snapshot has a device at 0x{:x}+0x{:x} that this machine doesn't", base, size))?;
+                region.dev.restore(&mut s)?;
+                devices += 1;
+            }
+            _ => return Err(format!("unknown snapshot section {:?}", String::from_utf8_lossy(&tag))),
+        }
+        s.finish(std::str::from_utf8(&tag).unwrap_or("?").trim_end())?;
+    }
+    if devices != cpu.devices.len() {
+        return Err(format!("snapshot has {} devices, this machine has {}", devices, cpu.devices.len()));
+    }
+    steps.ok_or_else(|| "snapshot is truncated".to_string())
+}
+
+pub fn save_file(path: &Path, cpu: &Cpu, exec: &Executor, smp: Option<&Smp>, steps: u64) -> Result<(), String> {
+    let data = save(cpu, exec, smp, steps)?;
+    fs::write(path, data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn restore_file(path: &Path, cpu: &mut Cpu, exec: &mut Executor, smp: Option<&mut Smp>) -> Result<u64, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    restore(&data, cpu, exec, smp).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+    use crate::flash::NorFlash;
+    use crate::metrics::NoMetrics;
+
+    const FLASH_BASE: u32 = 0x2000_0000;
+
+    // two harts bump a counter, program the flash word at their hart id * 4
+    // and read the clint's mtime
+    fn machine() -> (Cpu, Executor, Smp) {
+        let code = Assembler::new()
+            .assemble(
+                "lui x8, 0x1
+                addi x11, x0, 0x100
+                loop:
+                lw x5, 0(x11)
+                addi x5, x5, 1
+                sw x5, 0(x11)
+                addi x8, x8, -1
+                bne x8, x0, loop
+                lui x12, 0x20000
+                slli x13, x10, 2
+                add x12, x12, x13
+                addi x6, x0, 0x40
+                sw x6, 0(x12)
+                sw x5, 0(x12)
+                lui x14, 0x200c
+                lw x7, -8(x14)
+                jalr x0, 0(x0)",
+            )
+            .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&code, 0x1000);
+        cpu.pc = 0x1000;
+        cpu.set_harts(2);
+        cpu.attach_device(CLINT_BASE, CLINT_SIZE, Box::new(Clint::new(2))).unwrap();
+        cpu.attach_device(FLASH_BASE, 0x1000, Box::new(NorFlash::new(&[], 0x1000).unwrap())).unwrap();
+        let smp = Smp::new(&mut cpu, 10);
+        (cpu, Executor::new(), smp)
+    }
+
+    #[test]
+    fn test_resume_matches_straight_run() {
+        let (mut cpu, mut exec, mut smp) = machine();
+        let total = smp.run(&mut exec, &mut cpu, &mut NoMetrics, 1_000_000).unwrap();
+
+        let (mut cpu1, mut exec1, mut smp1) = machine();
+        assert_eq!(smp1.run(&mut exec1, &mut cpu1, &mut NoMetrics, 12_345).unwrap(), 12_345);
+        let snap = save(&cpu1, &exec1, Some(&smp1), 12_345).unwrap();
+
+        let (mut cpu2, mut exec2, mut smp2) = machine();
+        assert_eq!(restore(&snap, &mut cpu2, &mut exec2, Some(&mut smp2)).unwrap(), 12_345);
+        let rest = smp2.run(&mut exec2, &mut cpu2, &mut NoMetrics, 1_000_000).unwrap();
+        assert_eq!(12_345 + rest, total);
+        assert_eq!(cpu2.mem, cpu.mem);
+        assert_eq!((cpu2.regs, cpu2.pc), (cpu.regs, cpu.pc));
+        // both harts programmed their flash word, and read the same time
+        assert_eq!(cpu2.peek_word(FLASH_BASE + 4), cpu.peek_word(FLASH_BASE + 4));
+        assert_eq!(save(&cpu2, &exec2, Some(&smp2), total as u64), save(&cpu, &exec, Some(&smp), total as u64));
+    }
+
+    #[test]
+    fn test_restore_checks_machine() {
+        let (cpu, exec, smp) = machine();
+        let snap = save(&cpu, &exec, Some(&smp), 0).unwrap();
+
+        let mut other = Cpu::new();
+        other.set_harts(2);
+        let mut smp2 = Smp::new(&mut other, 10);
+        let err = restore(&snap, &mut other, &mut Executor::new(), Some(&mut smp2)).unwrap_err();
+        assert!(err.contains("device"), "{}", err);
+
+        let mut bad = snap.clone();
+        bad[8] = 99;
+        assert!(restore(&bad, &mut Cpu::new(), &mut Executor::new(), None).unwrap_err().contains("version 99"));
+        assert!(restore(&snap[..snap.len() - 3], &mut Cpu::new(), &mut Executor::new(), None).is_err());
+    }
+}
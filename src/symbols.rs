@@ -0,0 +1,222 @@
+// symbol map shared by the debugger, disassembler and metrics
+//
+// filled from an elf .symtab, or from assembler labels for .s inputs.
+// lookups go address -> nearest preceding symbol, so pcs print as <main+0x1c>.
+
+use crate::elf::{self, ElfFile};
+use std::collections::HashMap;
+use std::fmt;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const SHN_UNDEF: u16 = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    // 0 for plain labels; those cover everything up to the next symbol
+    pub size: u32,
+    pub is_func: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolMap {
+    // sorted by address
+    syms: Vec<Symbol>,
+}
+
+// `<name+0xoff>` as printed next to addresses
+pub struct Location<'a> {
+    pub sym: &'a Symbol,
+    pub offset: u32,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.offset == 0 {
+            write!(f, "<{}>", self.sym.name)
+        } else {
+            write!(f, "<{}+0x{:x}>", self.sym.name, self.offset)
+        }
+    }
+}
+
+impl SymbolMap {
+    pub fn new() -> Self {
+        SymbolMap { syms: Vec::new() }
+    }
+
+    pub fn from_elf(file: &ElfFile) -> Result<Self, String> {
+        let mut syms = Vec::new();
+        for sec in file.sections.iter().filter(|s| s.sh_type == SHT_SYMTAB) {
+            let data = file.section_data(sec)?;
+            let strtab = file
+                .sections
+                .get(sec.link as usize)
+                .ok_or("symtab links to a missing string table")?;
+            let strings = file.section_data(strtab)?;
+            let entsize = if sec.entsize == 0 { 16 } else { sec.entsize as usize };
+            if entsize < 16 {
+                return Err(format!("symtab entry size {} is too small", entsize));
+            }
+
+            for ent in data.chunks_exact(entsize) {
+                let name_off = u32::from_le_bytes([ent[0], ent[1], ent[2], ent[3]]);
+                let value = u32::from_le_bytes([ent[4], ent[5], ent[6], ent[7]]);
+                let size = u32::from_le_bytes([ent[8], ent[9], ent[10], ent[11]]);
+                let kind = ent[12] & 0xf;
+                let shndx = u16::from_le_bytes([ent[14], ent[15]]);
+                if shndx == SHN_UNDEF || kind == STT_SECTION || kind == STT_FILE {
+                    continue;
+                }
+                let name = elf::str_at(strings, name_off as usize).unwrap_or("");
+                // skip mapping symbols ($x, $d) and compiler-local labels
+                if name.is_empty() || name.starts_with('$') || name.starts_with(".L") {
+                    continue;
+                }
+                if kind > STT_FUNC {
+                    continue; // tls, common, ifunc: nothing useful to show
+                }
+                // untyped symbols are usually hand-written asm labels like _start
+                syms.push(Symbol {
+                    name: name.to_string(),
+                    addr: value,
+                    size,
+                    is_func: kind != STT_OBJECT,
+                });
+            }
+        }
+        Ok(SymbolMap::sorted(syms))
+    }
+
+    // assembler labels, relocated to wherever the code was loaded
+    pub fn from_labels(labels: &HashMap<String, u32>, base: u32) -> Self {
+        let syms = labels
+            .iter()
+            .map(|(name, off)| Symbol {
+                name: name.clone(),
+                addr: base.wrapping_add(*off),
+                size: 0,
+                is_func: true,
+            })
+            .collect();
+        SymbolMap::sorted(syms)
+    }
+
+    // one sort for a whole table. functions go before data at the same
+    // address, and a symbol that appears twice (the same image loaded
+    // twice, say) is kept once
+    fn sorted(mut syms: Vec<Symbol>) -> Self {
+        syms.sort_by(|a, b| (a.addr, !a.is_func, &a.name).cmp(&(b.addr, !b.is_func, &b.name)));
+        syms.dedup();
+        SymbolMap { syms }
+    }
+
+    pub fn insert(&mut self, sym: Symbol) {
+        let i = self.syms.partition_point(|s| (s.addr, !s.is_func) <= (sym.addr, !sym.is_func));
+        self.syms.insert(i, sym);
+    }
+
+    pub fn extend(&mut self, other: SymbolMap) {
+        let mut syms = std::mem::take(&mut self.syms);
+        syms.extend(other.syms);
+        *self = SymbolMap::sorted(syms);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.syms.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.syms.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.syms.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.syms.iter().find(|s| s.name == name)
+    }
+
+    // nearest symbol at or below addr. functions win over data at the same address
+    pub fn lookup(&self, addr: u32) -> Option<Location<'_>> {
+        let i = self.syms.partition_point(|s| s.addr <= addr);
+        let end = &self.syms[..i];
+        let last_addr = end.last()?.addr;
+        let sym = end
+            .iter()
+            .rev()
+            .take_while(|s| s.addr == last_addr)
+            .last()?;
+        if sym.size != 0 && addr - sym.addr >= sym.size {
+            return None;
+        }
+        Some(Location { sym, offset: addr - sym.addr })
+    }
+
+    // "main" or "main+0x10" (or +16) to an address
+    pub fn resolve(&self, s: &str) -> Option<u32> {
+        let (name, off) = match s.split_once('+') {
+            Some((n, o)) => {
+                let off = match o.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => o.parse().ok()?,
+                };
+                (n, off)
+            }
+            None => (s, 0),
+        };
+        self.get(name).map(|sym| sym.addr.wrapping_add(off))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(name: &str, addr: u32, size: u32) -> Symbol {
+        Symbol { name: name.to_string(), addr, size, is_func: true }
+    }
+
+    #[test]
+    fn test_lookup_nearest() {
+        let mut map = SymbolMap::new();
+        map.insert(sym("main", 0x100, 0x20));
+        map.insert(sym("_start", 0x0, 0));
+        map.insert(sym("helper", 0x200, 0));
+
+        assert_eq!(map.lookup(0x4).unwrap().to_string(), "<_start+0x4>");
+        assert_eq!(map.lookup(0x100).unwrap().to_string(), "<main>");
+        assert_eq!(map.lookup(0x11c).unwrap().to_string(), "<main+0x1c>");
+        assert!(map.lookup(0x120).is_none()); // past the end of main
+        assert_eq!(map.lookup(0x1000).unwrap().to_string(), "<helper+0xe00>");
+    }
+
+    #[test]
+    fn test_resolve_with_offset() {
+        let mut map = SymbolMap::new();
+        map.insert(sym("main", 0x100, 0));
+        assert_eq!(map.resolve("main"), Some(0x100));
+        assert_eq!(map.resolve("main+0x10"), Some(0x110));
+        assert_eq!(map.resolve("main+8"), Some(0x108));
+        assert_eq!(map.resolve("nope"), None);
+    }
+
+    #[test]
+    fn test_extend_sorts_and_drops_duplicates() {
+        let mut map = SymbolMap::new();
+        map.insert(sym("b", 0x200, 0));
+        map.insert(sym("a", 0x100, 0));
+        let mut other = SymbolMap::new();
+        other.insert(sym("a", 0x100, 0));
+        other.insert(sym("c", 0x0, 0));
+        map.extend(other);
+        let names: Vec<_> = map.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["c", "a", "b"]);
+    }
+}
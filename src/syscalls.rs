@@ -0,0 +1,731 @@
+// linux syscall emulation for `run --user`
+//
+// ecall passes the syscall number in a7 and arguments in a0-a5; the result,
+// or -errno, goes back in a0. numbers and struct layouts are the rv32 ones
+// from asm-generic, so time is always 64-bit (clock_gettime64, statx), seeks
+// go through _llseek and mmap is mmap2 with a page offset.
+//
+// files are looked up under a root directory on the host. guest paths are
+// normalised against it and anything that resolves outside (via .. or a
+// symlink) fails with EACCES. the guest cwd is always "/".
+
+use crate::cpu::Cpu;
+use crate::devices::{mem_slice, mem_slice_mut, mem_write_u32};
+use crate::executor::EcallHandler;
+use crate::icache::ICache;
+use crate::process::{self, Layout, PAGE_SIZE};
+use crate::replay::{self, Journal};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const SYS_IOCTL: u32 = 29;
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LLSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_READV: u32 = 65;
+const SYS_WRITEV: u32 = 66;
+const SYS_FSTATAT: u32 = 79;
+const SYS_FSTAT: u32 = 80;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_CLOCK_GETTIME32: u32 = 113;
+const SYS_RT_SIGACTION: u32 = 134;
+const SYS_RT_SIGPROCMASK: u32 = 135;
+const SYS_UNAME: u32 = 160;
+const SYS_GETPID: u32 = 172;
+const SYS_GETUID: u32 = 174;
+const SYS_GETEUID: u32 = 175;
+const SYS_GETGID: u32 = 176;
+const SYS_GETEGID: u32 = 177;
+const SYS_GETTID: u32 = 178;
+const SYS_BRK: u32 = 214;
+const SYS_MUNMAP: u32 = 215;
+const SYS_MMAP2: u32 = 222;
+const SYS_MPROTECT: u32 = 226;
+const SYS_GETRANDOM: u32 = 278;
+const SYS_STATX: u32 = 291;
+const SYS_CLOCK_GETTIME64: u32 = 403;
+
+const ENOENT: i32 = 2;
+const EIO: i32 = 5;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EEXIST: i32 = 17;
+const ENOTDIR: i32 = 20;
+const EISDIR: i32 = 21;
+const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ESPIPE: i32 = 29;
+const ENAMETOOLONG: i32 = 36;
+const ENOSYS: i32 = 38;
+
+const AT_FDCWD: i32 = -100;
+const AT_EMPTY_PATH: u32 = 0x1000;
+
+const O_ACCMODE: u32 = 3;
+const O_WRONLY: u32 = 1;
+const O_RDWR: u32 = 2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const O_DIRECTORY: u32 = 0o200000;
+
+// the host's O_NOFOLLOW, which std doesn't export
+#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+const HOST_O_NOFOLLOW: i32 = 0o100000;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+const HOST_O_NOFOLLOW: i32 = 0o400000;
+
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+
+const S_IFCHR: u32 = 0o020000;
+
+// the pid, and the tid of the only thread
+const GUEST_PID: u32 = 1;
+
+const PATH_MAX: u32 = 4096;
+
+type SysResult = Result<u32, i32>;
+
+enum GuestFd {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+}
+
+// what fstat and statx report, whatever the source
+struct StatInfo {
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    nlink: u32,
+    rdev: u64,
+    size: u64,
+    blksize: u32,
+    blocks: u64,
+    atime: (i64, u32),
+    mtime: (i64, u32),
+    ctime: (i64, u32),
+}
+
+impl StatInfo {
+    fn from_metadata(m: &Metadata) -> Self {
+        StatInfo {
+            dev: m.dev(),
+            ino: m.ino(),
+            mode: m.mode(),
+            nlink: m.nlink() as u32,
+            rdev: m.rdev(),
+            size: m.size(),
+            blksize: m.blksize() as u32,
+            blocks: m.blocks(),
+            atime: (m.atime(), m.atime_nsec() as u32),
+            mtime: (m.mtime(), m.mtime_nsec() as u32),
+            ctime: (m.ctime(), m.ctime_nsec() as u32),
+        }
+    }
+
+    // a terminal-ish character device for stdin/stdout/stderr
+    fn stdio() -> Self {
+        StatInfo {
+            dev: 0,
+            ino: 0,
+            mode: S_IFCHR | 0o620,
+            nlink: 1,
+            rdev: 0,
+            size: 0,
+            blksize: 1024,
+            blocks: 0,
+            atime: (0, 0),
+            mtime: (0, 0),
+            ctime: (0, 0),
+        }
+    }
+
+    // asm-generic struct stat64, 104 bytes
+    fn to_stat64(&self) -> Vec<u8> {
+        let mut b = Vec::with_capacity(104);
+        b.extend_from_slice(&self.dev.to_le_bytes());
+        b.extend_from_slice(&self.ino.to_le_bytes());
+        b.extend_from_slice(&self.mode.to_le_bytes());
+        b.extend_from_slice(&self.nlink.to_le_bytes());
+        b.extend_from_slice(&process::GUEST_UID.to_le_bytes());
+        b.extend_from_slice(&process::GUEST_GID.to_le_bytes());
+        b.extend_from_slice(&self.rdev.to_le_bytes());
+        b.extend_from_slice(&[0; 8]);
+        b.extend_from_slice(&self.size.to_le_bytes());
+        b.extend_from_slice(&self.blksize.to_le_bytes());
+        b.extend_from_slice(&[0; 4]);
+        b.extend_from_slice(&self.blocks.to_le_bytes());
+        for (sec, nsec) in [self.atime, self.mtime, self.ctime] {
+            b.extend_from_slice(&(sec as i32).to_le_bytes());
+            b.extend_from_slice(&nsec.to_le_bytes());
+        }
+        b.extend_from_slice(&[0; 8]);
+        b
+    }
+
+    // struct statx, 256 bytes
+    fn to_statx(&self) -> Vec<u8> {
+        const STATX_BASIC_STATS: u32 = 0x7ff;
+        let mut b = Vec::with_capacity(256);
+        b.extend_from_slice(&STATX_BASIC_STATS.to_le_bytes());
+        b.extend_from_slice(&self.blksize.to_le_bytes());
+        b.extend_from_slice(&0u64.to_le_bytes()); // attributes
+        b.extend_from_slice(&self.nlink.to_le_bytes());
+        b.extend_from_slice(&process::GUEST_UID.to_le_bytes());
+        b.extend_from_slice(&process::GUEST_GID.to_le_bytes());
+        b.extend_from_slice(&(self.mode as u16).to_le_bytes());
+        b.extend_from_slice(&[0; 2]);
+        b.extend_from_slice(&self.ino.to_le_bytes());
+        b.extend_from_slice(&self.size.to_le_bytes());
+        b.extend_from_slice(&self.blocks.to_le_bytes());
+        b.extend_from_slice(&0u64.to_le_bytes()); // attributes mask
+        // atime, btime (unknown, report ctime), ctime, mtime
+        for (sec, nsec) in [self.atime, self.ctime, self.ctime, self.mtime] {
+            b.extend_from_slice(&sec.to_le_bytes());
+            b.extend_from_slice(&nsec.to_le_bytes());
+            b.extend_from_slice(&[0; 4]);
+        }
+        // rdev then dev, as major/minor pairs
+        for d in [self.rdev, self.dev] {
+            b.extend_from_slice(&(((d >> 8) & 0xfff) as u32).to_le_bytes());
+            b.extend_from_slice(&(((d & 0xff) | ((d >> 12) & !0xff)) as u32).to_le_bytes());
+        }
+        b.resize(256, 0);
+        b
+    }
+}
+
+// host error to the errno a linux guest expects
+fn errno(e: &io::Error) -> i32 {
+    if cfg!(target_os = "linux") {
+        if let Some(raw) = e.raw_os_error() {
+            return raw;
+        }
+    }
+    match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::NotADirectory => ENOTDIR,
+        io::ErrorKind::IsADirectory => EISDIR,
+        _ => EIO,
+    }
+}
+
+// collapse "." and ".." lexically. ".." at the root stays at the root
+fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for p in path.split('/') {
+        match p {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            _ => parts.push(p),
+        }
+    }
+    format!("/{}", parts.join("/"))
+}
+
+fn read_cstr(mem: &[u8], addr: u32) -> Result<String, i32> {
+    let tail = mem.get(addr as usize..).ok_or(EFAULT)?;
+    let len = tail.iter().take(PATH_MAX as usize).position(|&b| b == 0).ok_or(
+        if tail.len() < PATH_MAX as usize { EFAULT } else { ENAMETOOLONG },
+    )?;
+    String::from_utf8(tail[..len].to_vec()).map_err(|_| EINVAL)
+}
+
+fn write_guest(mem: &mut [u8], addr: u32, bytes: &[u8]) -> Result<(), i32> {
+    mem_slice_mut(mem, addr as u64, bytes.len() as u32)
+        .ok_or(EFAULT)?
+        .copy_from_slice(bytes);
+    Ok(())
+}
+
+pub struct LinuxSyscalls {
+    root: PathBuf,
+    fds: Vec<Option<GuestFd>>,
+    // guest paths of open files, for openat relative to a directory fd
+    fd_paths: Vec<String>,
+    brk_start: u32,
+    brk: u32,
+    mmap_top: u32,
+    // start -> length, page aligned
+    mappings: BTreeMap<u32, u32>,
+    started: Instant,
+    warned: HashSet<u32>,
+}
+
+impl LinuxSyscalls {
+    pub fn new(root: &Path, layout: Layout) -> Result<Self, String> {
+        let root = root
+            .canonicalize()
+            .map_err(|e| format!("root directory {}: {}", root.display(), e))?;
+        Ok(LinuxSyscalls {
+            root,
+            fds: vec![Some(GuestFd::Stdin), Some(GuestFd::Stdout), Some(GuestFd::Stderr)],
+            fd_paths: vec![String::new(); 3],
+            brk_start: layout.brk_start,
+            brk: layout.brk_start,
+            mmap_top: layout.mmap_top,
+            mappings: BTreeMap::new(),
+            started: Instant::now(),
+            warned: HashSet::new(),
+        })
+    }
+
+    fn fd(&mut self, fd: u32) -> Result<&mut GuestFd, i32> {
+        self.fds.get_mut(fd as usize).and_then(|f| f.as_mut()).ok_or(EBADF)
+    }
+
+    fn file(&mut self, fd: u32) -> Result<&mut File, i32> {
+        match self.fd(fd)? {
+            GuestFd::File(f) => Ok(f),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    // guest path for openat and friends, absolute and normalised
+    fn guest_path(&self, mem: &[u8], dirfd: u32, ptr: u32) -> Result<String, i32> {
+        let path = read_cstr(mem, ptr)?;
+        if path.is_empty() {
+            return Err(ENOENT);
+        }
+        if path.starts_with('/') || dirfd as i32 == AT_FDCWD {
+            return Ok(normalize(&path));
+        }
+        match self.fds.get(dirfd as usize) {
+            Some(Some(GuestFd::File(_))) => Ok(normalize(&format!("{}/{}", self.fd_paths[dirfd as usize], path))),
+            _ => Err(EBADF),
+        }
+    }
+
+    // where a guest path lives on the host. symlinks are resolved so they
+    // can't be used to climb out of the root. the last component of a path
+    // that doesn't resolve is left as it is: it may be a file about to be
+    // created, or a dangling symlink, which openat refuses to follow
+    fn host_path(&self, guest: &str) -> Result<PathBuf, i32> {
+        let host = self.root.join(guest.trim_start_matches('/'));
+        let resolved = match host.canonicalize() {
+            Ok(p) => p,
+            Err(_) => match (host.parent(), host.file_name()) {
+                (Some(dir), Some(name)) => dir.canonicalize().map_err(|e| errno(&e))?.join(name),
+                _ => return Err(EACCES),
+            },
+        };
+        if !resolved.starts_with(&self.root) {
+            return Err(EACCES);
+        }
+        Ok(resolved)
+    }
+
+    fn alloc_fd(&mut self, fd: GuestFd, path: String) -> u32 {
+        let i = match self.fds.iter().position(|f| f.is_none()) {
+            Some(i) => i,
+            None => {
+                self.fds.push(None);
+                self.fd_paths.push(String::new());
+                self.fds.len() - 1
+            }
+        };
+        self.fds[i] = Some(fd);
+        self.fd_paths[i] = path;
+        i as u32
+    }
+
+    fn openat(&mut self, mem: &[u8], dirfd: u32, ptr: u32, flags: u32, mode: u32) -> SysResult {
+        let guest = self.guest_path(mem, dirfd, ptr)?;
+        let host = self.host_path(&guest)?;
+        let acc = flags & O_ACCMODE;
+        let mut opts = OpenOptions::new();
+        opts.read(acc != O_WRONLY)
+            .write(acc == O_WRONLY || acc == O_RDWR)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode & 0o777)
+            // a symlink left in the resolved path is one that didn't resolve
+            // (or appeared since). following it could create a file outside
+            // the root
+            .custom_flags(HOST_O_NOFOLLOW);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                opts.create_new(true);
+            } else {
+                opts.create(true);
+            }
+        }
+        let file = opts.open(&host).map_err(|e| errno(&e))?;
+        if flags & O_DIRECTORY != 0 && !file.metadata().map_err(|e| errno(&e))?.is_dir() {
+            return Err(ENOTDIR);
+        }
+        Ok(self.alloc_fd(GuestFd::File(file), guest))
+    }
+
+    // read, brk and mmap2 write guest memory directly rather than through
+    // store_word, so each drops what the icache had decoded where it wrote
+    fn read(&mut self, cpu: &mut Cpu, fd: u32, buf: u32, len: u32) -> SysResult {
+        let dst = mem_slice_mut(&mut cpu.mem, buf as u64, len).ok_or(EFAULT)?;
+        let n = match self.fd(fd)? {
+            GuestFd::Stdin => replay::stdin(cpu.journal.as_ref(), dst),
+            GuestFd::File(f) => f.read(dst),
+            _ => return Err(EBADF),
+        };
+        let n = n.map_err(|e| errno(&e))?;
+        cpu.icache.invalidate(buf as usize, n);
+        Ok(n as u32)
+    }
+
+    fn write(&mut self, mem: &[u8], fd: u32, buf: u32, len: u32) -> SysResult {
+        let src = mem_slice(mem, buf as u64, len).ok_or(EFAULT)?;
+        let n = match self.fd(fd)? {
+            GuestFd::Stdout => io::stdout().write(src),
+            GuestFd::Stderr => io::stderr().write(src),
+            GuestFd::File(f) => f.write(src),
+            GuestFd::Stdin => return Err(EBADF),
+        };
+        n.map(|n| n as u32).map_err(|e| errno(&e))
+    }
+
+    // readv/writev: one call per iovec, stopping at the first short transfer
+    fn vectored(&mut self, cpu: &mut Cpu, fd: u32, iov: u32, count: u32, writing: bool) -> SysResult {
+        let mut total = 0u32;
+        for i in 0..count {
+            let ent = mem_slice(&cpu.mem, iov as u64 + i as u64 * 8, 8).ok_or(EFAULT)?;
+            let base = u32::from_le_bytes([ent[0], ent[1], ent[2], ent[3]]);
+            let len = u32::from_le_bytes([ent[4], ent[5], ent[6], ent[7]]);
+            let n = if writing {
+                self.write(&cpu.mem, fd, base, len)
+            } else {
+                self.read(cpu, fd, base, len)
+            };
+            match n {
+                Ok(n) => {
+                    total += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                // errors only count if nothing got through
+                Err(e) if total == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+        Ok(total)
+    }
+
+    fn llseek(&mut self, mem: &mut [u8], fd: u32, hi: u32, lo: u32, result: u32, whence: u32) -> SysResult {
+        let off = ((hi as u64) << 32 | lo as u64) as i64;
+        let pos = match whence {
+            0 => SeekFrom::Start(off as u64),
+            1 => SeekFrom::Current(off),
+            2 => SeekFrom::End(off),
+            _ => return Err(EINVAL),
+        };
+        let new = self.file(fd)?.seek(pos).map_err(|e| errno(&e))?;
+        write_guest(mem, result, &new.to_le_bytes())?;
+        Ok(0)
+    }
+
+    fn stat_fd(&mut self, fd: u32) -> Result<StatInfo, i32> {
+        match self.fd(fd)? {
+            GuestFd::File(f) => f.metadata().map(|m| StatInfo::from_metadata(&m)).map_err(|e| errno(&e)),
+            _ => Ok(StatInfo::stdio()),
+        }
+    }
+
+    // fstatat and statx: a path, or the fd itself with AT_EMPTY_PATH
+    fn stat_at(&mut self, mem: &[u8], dirfd: u32, ptr: u32, flags: u32) -> Result<StatInfo, i32> {
+        if flags & AT_EMPTY_PATH != 0 && read_cstr(mem, ptr)?.is_empty() {
+            return self.stat_fd(dirfd);
+        }
+        let host = self.host_path(&self.guest_path(mem, dirfd, ptr)?)?;
+        fs::metadata(host).map(|m| StatInfo::from_metadata(&m)).map_err(|e| errno(&e))
+    }
+
+    fn brk(&mut self, mem: &mut [u8], icache: &mut ICache, addr: u32) -> SysResult {
+        let limit = self.mappings.keys().next().copied().unwrap_or(self.mmap_top);
+        if addr >= self.brk_start && addr <= limit {
+            if addr > self.brk {
+                mem[self.brk as usize..addr as usize].fill(0);
+                icache.invalidate(self.brk as usize, (addr - self.brk) as usize);
+            }
+            self.brk = addr;
+        }
+        // failure is reported by returning the old break
+        Ok(self.brk)
+    }
+
+    fn unmap(&mut self, start: u32, len: u32) -> Result<(), i32> {
+        let end = start.checked_add(len).ok_or(EINVAL)?;
+        let overlapping: Vec<(u32, u32)> = self
+            .mappings
+            .range(..end)
+            .filter(|(&s, &l)| s + l > start)
+            .map(|(&s, &l)| (s, l))
+            .collect();
+        for (s, l) in overlapping {
+            self.mappings.remove(&s);
+            if s < start {
+                self.mappings.insert(s, start - s);
+            }
+            if s + l > end {
+                self.mappings.insert(end, s + l - end);
+            }
+        }
+        Ok(())
+    }
+
+    fn mmap2(&mut self, mem: &mut [u8], icache: &mut ICache, args: [u32; 6]) -> SysResult {
+        let [addr, len, _prot, flags, fd, pgoff] = args;
+        if len == 0 {
+            return Err(EINVAL);
+        }
+        let len = len.checked_add(PAGE_SIZE - 1).ok_or(ENOMEM)? & !(PAGE_SIZE - 1);
+        let floor = (self.brk + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let start = if flags & MAP_FIXED != 0 {
+            if !addr.is_multiple_of(PAGE_SIZE) {
+                return Err(EINVAL);
+            }
+            if addr < floor || addr.checked_add(len).is_none_or(|end| end > self.mmap_top) {
+                return Err(ENOMEM);
+            }
+            self.unmap(addr, len)?;
+            addr
+        } else {
+            // highest gap that fits, working down from below the stack
+            let mut top = self.mmap_top;
+            for (&s, &l) in self.mappings.iter().rev() {
+                if top - (s + l) >= len {
+                    break;
+                }
+                top = s;
+            }
+            match top.checked_sub(len) {
+                Some(a) if a >= floor => a,
+                _ => return Err(ENOMEM),
+            }
+        };
+
+        icache.invalidate(start as usize, len as usize);
+        let dst = &mut mem[start as usize..(start + len) as usize];
+        dst.fill(0);
+        if flags & MAP_ANONYMOUS == 0 {
+            // private file mapping: a copy of the file contents
+            let file = self.file(fd).map_err(|_| EBADF)?;
+            let mut off = pgoff as u64 * PAGE_SIZE as u64;
+            let mut filled = 0;
+            while filled < dst.len() {
+                match file.read_at(&mut dst[filled..], off) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        filled += n;
+                        off += n as u64;
+                    }
+                    Err(e) => return Err(errno(&e)),
+                }
+            }
+        }
+        self.mappings.insert(start, len);
+        Ok(start)
+    }
+
+    fn clock_gettime(&self, mem: &mut [u8], journal: Option<&Journal>, clock: u32, ptr: u32, wide: bool) -> SysResult {
+        let realtime = match clock {
+            // realtime, realtime_coarse
+            0 | 5 => true,
+            // monotonic, cputime, raw, coarse, boottime: time since start
+            1..=4 | 6 | 7 => false,
+            _ => return Err(EINVAL),
+        };
+        let ns = replay::clock(journal, || {
+            let d = if realtime {
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+            } else {
+                self.started.elapsed()
+            };
+            d.as_nanos() as u64
+        });
+        let (sec, nsec) = ((ns / 1_000_000_000) as i64, (ns % 1_000_000_000) as u32);
+        let mut buf = Vec::new();
+        if wide {
+            buf.extend_from_slice(&sec.to_le_bytes());
+            buf.extend_from_slice(&(nsec as i64).to_le_bytes());
+        } else {
+            buf.extend_from_slice(&(sec as i32).to_le_bytes());
+            buf.extend_from_slice(&nsec.to_le_bytes());
+        }
+        write_guest(mem, ptr, &buf)?;
+        Ok(0)
+    }
+
+    fn uname(&self, mem: &mut [u8], ptr: u32) -> SysResult {
+        let fields = ["Linux", "rv32-emu", "6.1.0", "#1", process::PLATFORM, "(none)"];
+        let mut buf = vec![0u8; 65 * fields.len()];
+        for (i, f) in fields.iter().enumerate() {
+            buf[i * 65..i * 65 + f.len()].copy_from_slice(f.as_bytes());
+        }
+        write_guest(mem, ptr, &buf)?;
+        Ok(0)
+    }
+
+    fn dispatch(&mut self, cpu: &mut Cpu, nr: u32, a: [u32; 6]) -> SysResult {
+        let journal = cpu.journal.as_ref();
+        let (mem, icache) = (&mut cpu.mem[..], &mut cpu.icache);
+        match nr {
+            SYS_OPENAT => self.openat(mem, a[0], a[1], a[2], a[3]),
+            SYS_CLOSE => match self.fds.get_mut(a[0] as usize) {
+                Some(f @ Some(_)) => {
+                    *f = None;
+                    Ok(0)
+                }
+                _ => Err(EBADF),
+            },
+            SYS_READ => self.read(cpu, a[0], a[1], a[2]),
+            SYS_WRITE => self.write(mem, a[0], a[1], a[2]),
+            SYS_READV => self.vectored(cpu, a[0], a[1], a[2], false),
+            SYS_WRITEV => self.vectored(cpu, a[0], a[1], a[2], true),
+            SYS_LLSEEK => self.llseek(mem, a[0], a[1], a[2], a[3], a[4]),
+            SYS_FSTAT => {
+                let st = self.stat_fd(a[0])?;
+                write_guest(mem, a[1], &st.to_stat64()).map(|_| 0)
+            }
+            SYS_FSTATAT => {
+                let st = self.stat_at(mem, a[0], a[1], a[3])?;
+                write_guest(mem, a[2], &st.to_stat64()).map(|_| 0)
+            }
+            SYS_STATX => {
+                let st = self.stat_at(mem, a[0], a[1], a[2])?;
+                write_guest(mem, a[4], &st.to_statx()).map(|_| 0)
+            }
+            // no terminal as far as the guest is concerned
+            SYS_IOCTL => {
+                self.fd(a[0])?;
+                Err(ENOTTY)
+            }
+            SYS_BRK => self.brk(mem, icache, a[0]),
+            SYS_MMAP2 => self.mmap2(mem, icache, a),
+            SYS_MUNMAP => {
+                if !a[0].is_multiple_of(PAGE_SIZE) {
+                    return Err(EINVAL);
+                }
+                self.unmap(a[0], a[1].saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1))?;
+                Ok(0)
+            }
+            SYS_MPROTECT => Ok(0),
+            SYS_CLOCK_GETTIME32 => self.clock_gettime(mem, journal, a[0], a[1], false),
+            SYS_CLOCK_GETTIME64 => self.clock_gettime(mem, journal, a[0], a[1], true),
+            SYS_GETRANDOM => {
+                let dst = mem_slice_mut(mem, a[0] as u64, a[1]).ok_or(EFAULT)?;
+                replay::random(journal, dst);
+                Ok(a[1])
+            }
+            SYS_UNAME => self.uname(mem, a[0]),
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(GUEST_PID),
+            SYS_GETUID | SYS_GETEUID => Ok(process::GUEST_UID),
+            SYS_GETGID | SYS_GETEGID => Ok(process::GUEST_GID),
+            // single threaded and no signals get delivered, so accept and ignore
+            SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => {
+                if a[2] != 0 {
+                    mem_write_u32(mem, a[2] as u64, 0).ok_or(EFAULT)?;
+                }
+                Ok(0)
+            }
+            _ => {
+                if self.warned.insert(nr) {
+                    eprintln!("warning: unimplemented syscall {} at pc=0x{:x}", nr, cpu.pc);
+                }
+                Err(ENOSYS)
+            }
+        }
+    }
+}
+
+impl EcallHandler for LinuxSyscalls {
+    fn ecall(&mut self, cpu: &mut Cpu) -> Result<Option<i32>, String> {
+        let nr = cpu.regs[17];
+        let mut args = [0u32; 6];
+        args.copy_from_slice(&cpu.regs[10..16]);
+        if nr == SYS_EXIT || nr == SYS_EXIT_GROUP {
+            let _ = io::stdout().flush();
+            return Ok(Some((args[0] & 0xff) as i32));
+        }
+        cpu.regs[10] = match self.dispatch(cpu, nr, args) {
+            Ok(v) => v,
+            Err(e) => (-e) as u32,
+        };
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::Instruction;
+
+    #[test]
+    fn test_normalize_stays_in_root() {
+        assert_eq!(normalize("a/./b/../c"), "/a/c");
+        assert_eq!(normalize("/../../etc/passwd"), "/etc/passwd");
+        assert_eq!(normalize(""), "/");
+    }
+
+    #[test]
+    fn test_dangling_symlink_stays_in_root() {
+        let base = std::env::temp_dir().join(format!("rv32-syscalls-{}", std::process::id()));
+        let (root, outside) = (base.join("root"), base.join("outside"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(outside.join("created"), root.join("escape")).unwrap();
+
+        let layout = Layout { brk_start: 0x1000, mmap_top: 0x9000 };
+        let mut sys = LinuxSyscalls::new(&root, layout).unwrap();
+        let mut mem = vec![0u8; 0x100];
+        mem[..8].copy_from_slice(b"/escape\0");
+        let res = sys.openat(&mem, AT_FDCWD as u32, 0, O_WRONLY | O_CREAT, 0o644);
+        let created = outside.join("created").exists();
+        fs::remove_dir_all(&base).unwrap();
+        assert!(res.is_err());
+        assert!(!created);
+    }
+
+    #[test]
+    fn test_mmap_and_brk_share_the_gap() {
+        let layout = Layout { brk_start: 0x1000, mmap_top: 0x9000 };
+        let mut sys = LinuxSyscalls::new(Path::new("."), layout).unwrap();
+        let mut mem = vec![0xaau8; 0x10000];
+        let mut icache = ICache::new(mem.len());
+        let anon = |len| [0, len, 3, MAP_ANONYMOUS | 0x2, u32::MAX, 0];
+
+        assert_eq!(sys.mmap2(&mut mem, &mut icache, anon(0x1800)), Ok(0x7000));
+        assert_eq!(mem[0x7000], 0);
+        assert_eq!(sys.mmap2(&mut mem, &mut icache, anon(0x1000)), Ok(0x6000));
+        sys.unmap(0x7000, 0x2000).unwrap();
+        // reuses the hole left at the top, and code decoded from what was
+        // there before goes
+        icache.insert(0x7000, Instruction::decode(0x13));
+        assert_eq!(sys.mmap2(&mut mem, &mut icache, anon(0x2000)), Ok(0x7000));
+        assert!(icache.get(0x7000).is_none());
+
+        // the heap can grow up to the lowest mapping but not past it
+        icache.insert(0x5000, Instruction::decode(0x13));
+        assert_eq!(sys.brk(&mut mem, &mut icache, 0x6000), Ok(0x6000));
+        assert!(icache.get(0x5000).is_none());
+        assert_eq!(sys.brk(&mut mem, &mut icache, 0x6004), Ok(0x6000));
+        assert_eq!(sys.mmap2(&mut mem, &mut icache, anon(0x1000)), Err(ENOMEM));
+    }
+}
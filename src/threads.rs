@@ -0,0 +1,412 @@
+// harts on host threads
+//
+// the alternative to smp.rs's round robin when throughput matters more than
+// getting the same run twice: every hart gets a host thread and they all run
+// at once. ram is copied into an array of atomic words shared by the
+// threads, and the memory model is mapped onto host atomics the way rvwmo
+// allows: plain loads and stores are relaxed, fence is a full host fence,
+// and lr/sc and amos are seqcst, which covers any aq/rl bits. misaligned
+// loads and stores are split into their two words, each byte still being
+// written atomically.
+//
+// every word carries a count of the stores to it alongside its value. lr.w
+// remembers both and sc.w only succeeds if neither has moved, so a store by
+// another hart breaks the reservation even when it writes the same value
+// back.
+//
+// each hart keeps its own predecode cache, so like on hardware code written
+// by another hart needs a fence.i before it runs. only plain ram is
+// supported: no devices, and ecall and ebreak are errors. the interleaving
+// changes from run to run, and with it anything racy in the guest.
+
+use crate::cpu::{Cpu, NREGS};
+use crate::decoder::{amo_value, Instruction, Opcode, CSR_MHARTID};
+use crate::executor::{execute, Memory};
+use crate::icache::ICache;
+use crate::metrics::Instrument;
+use crate::smp::boot_regs;
+use std::sync::atomic::{fence, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+
+// instructions a hart takes from the shared step budget at a time
+const BUDGET_CHUNK: usize = 4096;
+
+// guest ram, a word at a time: the value in the low half and the number of
+// stores to it in the high half
+pub struct SharedRam {
+    words: Vec<AtomicU64>,
+    base: u32,
+}
+
+impl SharedRam {
+    pub fn new(cpu: &Cpu) -> Result<Self, String> {
+        if cpu.ram_base & 3 != 0 || cpu.mem.len() & 3 != 0 {
+            return Err("threaded harts need word-aligned ram".to_string());
+        }
+        let words = cpu.mem
+            .chunks_exact(4)
+            .map(|w| AtomicU64::new(u32::from_le_bytes(w.try_into().unwrap()) as u64))
+            .collect();
+        Ok(SharedRam { words, base: cpu.ram_base })
+    }
+
+    // write everything back into cpu once the threads are done
+    pub fn copy_to(&self, cpu: &mut Cpu) {
+        for (chunk, word) in cpu.mem.chunks_exact_mut(4).zip(&self.words) {
+            chunk.copy_from_slice(&(word.load(Ordering::Relaxed) as u32).to_le_bytes());
+        }
+        let len = cpu.mem.len();
+        cpu.icache.invalidate(0, len);
+    }
+
+    // ram offset of the `size` bytes at `addr`
+    fn offset(&self, addr: u32, size: u32) -> Option<usize> {
+        let off = addr.checked_sub(self.base)? as usize;
+        (off.checked_add(size as usize)? <= self.words.len() * 4).then_some(off)
+    }
+
+    // `size` bytes (1, 2 or 4), zero-extended
+    fn load(&self, addr: u32, size: u32) -> Result<u32, String> {
+        let off = self.offset(addr, size).ok_or_else(|| format!("load access fault at 0x{:x}", addr))?;
+        let i = off >> 2;
+        let shift = (off & 3) as u32 * 8;
+        let mut val = self.words[i].load(Ordering::Relaxed) as u32 >> shift;
+        if (off & 3) as u32 + size > 4 {
+            val |= (self.words[i + 1].load(Ordering::Relaxed) as u32) << (32 - shift);
+        }
+        Ok(val & mask(size))
+    }
+
+    // returns the ram offset written
+    fn store(&self, addr: u32, size: u32, val: u32) -> Result<usize, String> {
+        let off = self.offset(addr, size).ok_or_else(|| format!("store access fault at 0x{:x}", addr))?;
+        let i = off >> 2;
+        let shift = (off & 3) as u32 * 8;
+        // merge into the word(s) without touching their other bytes
+        let merge = |word: &AtomicU64, mask: u32, bits: u32| {
+            let _ = word.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |w| Some(stored(w, mask, bits)));
+        };
+        let (mask, val) = (mask(size), val & mask(size));
+        merge(&self.words[i], mask << shift, val << shift);
+        if (off & 3) as u32 + size > 4 {
+            merge(&self.words[i + 1], mask >> (32 - shift), val >> (32 - shift));
+        }
+        Ok(off)
+    }
+
+    // the word an lr/sc or amo works on, which has to be aligned
+    fn atomic(&self, addr: u32, pc: u32) -> Result<(&AtomicU64, usize), String> {
+        if addr & 3 != 0 {
+            return Err(format!("misaligned atomic at pc=0x{:x}", pc));
+        }
+        let off = self.offset(addr, 4).ok_or_else(|| format!("store access fault at 0x{:x}", addr))?;
+        Ok((&self.words[off >> 2], off))
+    }
+}
+
+// the low `size` bytes of a word
+fn mask(size: u32) -> u32 {
+    u32::MAX >> (32 - size * 8)
+}
+
+// a SharedRam word after a store of `bits` to the bytes under `mask`
+fn stored(word: u64, mask: u32, bits: u32) -> u64 {
+    let stores = (word >> 32).wrapping_add(1);
+    (stores << 32) | ((word as u32 & !mask) | bits) as u64
+}
+
+// a hart's view of the shared ram. its own stores drop what it has
+// predecoded at that address
+struct HartRam<'a> {
+    ram: &'a SharedRam,
+    icache: &'a mut ICache,
+}
+
+impl Memory for HartRam<'_> {
+    fn load(&mut self, addr: u32, size: u32) -> Result<u32, String> {
+        self.ram.load(addr, size)
+    }
+
+    fn store(&mut self, addr: u32, size: u32, val: u32) -> Result<(), String> {
+        let off = self.ram.store(addr, size, val)?;
+        self.icache.invalidate_word(off);
+        self.icache.invalidate_word(off + size as usize - 1);
+        Ok(())
+    }
+}
+
+struct Hart {
+    id: u32,
+    regs: [u32; NREGS],
+    pc: u32,
+    // address of the last lr.w and the SharedRam word it read, store count
+    // and all
+    reservation: Option<(u32, u64)>,
+    icache: ICache,
+}
+
+impl Hart {
+    fn write_reg(&mut self, rd: usize, val: u32) {
+        if rd != 0 {
+            self.regs[rd] = val;
+        }
+    }
+
+    fn fetch(&mut self, ram: &SharedRam) -> Result<Instruction, String> {
+        let off = ram.offset(self.pc, 4).filter(|off| off & 3 == 0)
+            .ok_or_else(|| format!("instruction access fault at 0x{:x}", self.pc))?;
+        if let Some(inst) = self.icache.get(off) {
+            return Ok(inst);
+        }
+        let inst = Instruction::decode(ram.words[off >> 2].load(Ordering::Relaxed) as u32);
+        self.icache.insert(off, inst);
+        Ok(inst)
+    }
+
+    // Executor::step against shared ram: execute() for everything ordinary,
+    // then what only a threaded hart does its own way
+    fn step<M: Instrument>(&mut self, ram: &SharedRam, metrics: &mut M) -> Result<(), String> {
+        let inst = self.fetch(ram)?;
+        metrics.record_instruction(&inst);
+        metrics.record_pc(self.pc);
+
+        let rs1 = self.regs[inst.rs1];
+        let rs2 = self.regs[inst.rs2];
+        let mut mem = HartRam { ram, icache: &mut self.icache };
+        if let Some(out) = execute(&inst, self.pc, rs1, rs2, &mut mem)? {
+            if let Some(taken) = out.taken {
+                metrics.record_branch(taken);
+            }
+            if let Some(val) = out.rd {
+                self.write_reg(inst.rd, val);
+            }
+            self.pc = out.pc;
+            return Ok(());
+        }
+
+        match inst.opcode {
+            Opcode::Fence => fence(Ordering::SeqCst),
+            Opcode::FenceI => {
+                fence(Ordering::SeqCst);
+                self.icache.flush();
+            }
+            Opcode::LrW => {
+                let (word, _) = ram.atomic(rs1, self.pc)?;
+                let val = word.load(Ordering::SeqCst);
+                self.reservation = Some((rs1, val));
+                self.write_reg(inst.rd, val as u32);
+            }
+            Opcode::ScW => {
+                let (word, off) = ram.atomic(rs1, self.pc)?;
+                let ok = match self.reservation.take() {
+                    Some((addr, val)) if addr == rs1 => {
+                        word.compare_exchange(val, stored(val, u32::MAX, rs2), Ordering::SeqCst, Ordering::SeqCst).is_ok()
+                    }
+                    _ => false,
+                };
+                if ok {
+                    self.icache.invalidate_word(off);
+                }
+                self.write_reg(inst.rd, if ok { 0 } else { 1 });
+            }
+            op if op.is_amo() => {
+                let (word, off) = ram.atomic(rs1, self.pc)?;
+                let old = word
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |old| Some(stored(old, u32::MAX, amo_value(op, old as u32, rs2))))
+                    .unwrap();
+                self.icache.invalidate_word(off);
+                self.write_reg(inst.rd, old as u32);
+            }
+            Opcode::Csrrs => {
+                if inst.rs1 != 0 {
+                    return Err(format!("csr writes aren't supported (pc=0x{:x})", self.pc));
+                }
+                let val = match inst.imm as u32 {
+                    CSR_MHARTID => self.id,
+                    csr => return Err(format!("unsupported csr 0x{:x} at pc=0x{:x}", csr, self.pc)),
+                };
+                self.write_reg(inst.rd, val);
+            }
+            Opcode::Unknown => return Err(format!("unknown instruction at pc=0x{:x}", self.pc)),
+            _ => return Err(format!("{} at pc=0x{:x} isn't supported on threaded harts", inst.disassemble(), self.pc)),
+        }
+
+        self.pc = self.pc.wrapping_add(4);
+        Ok(())
+    }
+
+    // run until the hart jumps to 0 (when ram starts there), the shared
+    // budget runs out or another hart fails
+    fn run<M: Instrument>(&mut self, ram: &SharedRam, metrics: &mut M, budget: &AtomicUsize, stop: &AtomicBool) -> Result<usize, String> {
+        let mut steps = 0;
+        while !stop.load(Ordering::Relaxed) {
+            let Ok(left) = budget.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                (left > 0).then(|| left - left.min(BUDGET_CHUNK))
+            }) else {
+                break;
+            };
+            let chunk = left.min(BUDGET_CHUNK);
+            for i in 0..chunk {
+                self.step(ram, metrics)?;
+                steps += 1;
+                if self.pc == 0 && ram.base == 0 {
+                    // hand back what we didn't use
+                    budget.fetch_add(chunk - i - 1, Ordering::Relaxed);
+                    return Ok(steps);
+                }
+            }
+        }
+        Ok(steps)
+    }
+}
+
+// run cpu.harts() harts on their own threads, with one instrument each,
+// until they've all jumped to 0, max_steps instructions have run between
+// them or one fails. ram is written back into cpu afterwards and hart 0's
+// registers are left in cpu.regs/pc. an error names the hart
+pub fn run<M: Instrument + Send>(cpu: &mut Cpu, metrics: &mut [M], max_steps: usize) -> Result<usize, String> {
+    assert_eq!(metrics.len(), cpu.harts(), "one instrument per hart");
+    if !cpu.devices.is_empty() {
+        return Err("threaded harts need a machine without devices".to_string());
+    }
+    if cpu.journal.is_some() {
+        return Err("threaded harts can't be recorded or replayed".to_string());
+    }
+    let ram = SharedRam::new(cpu)?;
+    let mut harts: Vec<Hart> = (0..cpu.harts())
+        .map(|i| Hart {
+            id: i as u32,
+            regs: boot_regs(cpu, i),
+            pc: cpu.pc,
+            reservation: None,
+            icache: ICache::new(cpu.mem.len()),
+        })
+        .collect();
+    let budget = AtomicUsize::new(max_steps);
+    let stop = AtomicBool::new(false);
+
+    let results: Vec<Result<usize, String>> = thread::scope(|s| {
+        let threads: Vec<_> = harts
+            .iter_mut()
+            .zip(metrics.iter_mut())
+            .map(|(hart, metrics)| {
+                let (ram, budget, stop) = (&ram, &budget, &stop);
+                s.spawn(move || {
+                    let result = hart.run(ram, metrics, budget, stop);
+                    if result.is_err() {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    result
+                })
+            })
+            .collect();
+        threads.into_iter().map(|t| t.join().expect("hart thread panicked")).collect()
+    });
+
+    ram.copy_to(cpu);
+    cpu.regs = harts[0].regs;
+    cpu.pc = harts[0].pc;
+    let mut steps = 0;
+    for (i, result) in results.into_iter().enumerate() {
+        steps += result.map_err(|e| format!("hart {}: {}", i, e))?;
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::metrics::{Metrics, NoMetrics};
+
+    // each hart adds 1 to a shared counter `n` times with amoadd.w and
+    // another `n` times with an lr/sc loop
+    fn counter(harts: usize, n: u32) -> Cpu {
+        let src = format!(
+            "lui x8, {}
+            addi x11, x0, 0x100
+            addi x12, x0, 1
+            again:
+            amoadd.w x0, x12, (x11)
+            retry:
+            lr.w x5, (x11)
+            addi x5, x5, 1
+            sc.w x6, x5, (x11)
+            bne x6, x0, retry
+            addi x8, x8, -1
+            bne x8, x0, again
+            jalr x0, 0(x0)",
+            n >> 12
+        );
+        let code = Assembler::new().assemble(&src).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&code, 0x1000);
+        cpu.pc = 0x1000;
+        cpu.set_harts(harts);
+        cpu
+    }
+
+    #[test]
+    fn test_threaded_atomics() {
+        let mut cpu = counter(4, 0x4000);
+        let mut metrics: Vec<Metrics> = (0..4).map(|_| Metrics::new()).collect();
+        let steps = run(&mut cpu, &mut metrics, usize::MAX).unwrap();
+        assert_eq!(cpu.read_word(0x100), 4 * 2 * 0x4000);
+        assert_eq!(cpu.pc, 0);
+        let mut total = Metrics::new();
+        for (hart, m) in metrics.iter().enumerate() {
+            total.merge_hart(hart, m);
+        }
+        assert_eq!(total.inst_count, steps as u64);
+        assert_eq!(total.count(Opcode::AmoaddW), 4 * 0x4000);
+        // failed sc.w retries vary from run to run
+        assert_eq!(total.hart_counts.len(), 4);
+        assert!(total.hart_counts.iter().all(|&n| n >= 7 * 0x4000));
+    }
+
+    #[test]
+    fn test_threaded_budget() {
+        let mut cpu = counter(3, 0x10000);
+        let mut metrics = [NoMetrics, NoMetrics, NoMetrics];
+        assert_eq!(run(&mut cpu, &mut metrics, 10_000).unwrap(), 10_000);
+    }
+
+    #[test]
+    fn test_misaligned_store_keeps_neighbours() {
+        let mut cpu = counter(1, 0x1000);
+        let ram = SharedRam::new(&cpu).unwrap();
+        ram.store(0x200, 4, 0x11223344).unwrap();
+        ram.store(0x204, 4, 0x55667788).unwrap();
+        ram.store(0x202, 4, 0xaabbccdd).unwrap();
+        assert_eq!(ram.load(0x201, 4).unwrap(), 0xbbccdd33);
+        ram.store(0x203, 2, 0xeeff).unwrap();
+        ram.store(0x207, 1, 0x99).unwrap();
+        assert_eq!(ram.load(0x203, 2).unwrap(), 0xeeff);
+        assert_eq!(ram.load(0x202, 1).unwrap(), 0xdd);
+        ram.copy_to(&mut cpu);
+        assert_eq!((cpu.read_word(0x200), cpu.read_word(0x204)), (0xffdd3344, 0x9966aaee));
+    }
+
+    #[test]
+    fn test_sc_fails_after_same_value_store() {
+        let code = Assembler::new()
+            .assemble("lr.w x5, (x11)\nsc.w x6, x5, (x11)\nlr.w x5, (x11)\nsc.w x6, x5, (x11)")
+            .unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_program(&code, 0x1000);
+        cpu.write_word(0x100, 7);
+        let ram = SharedRam::new(&cpu).unwrap();
+        let mut regs = [0; NREGS];
+        regs[11] = 0x100;
+        let mut hart = Hart { id: 0, regs, pc: 0x1000, reservation: None, icache: ICache::new(cpu.mem.len()) };
+        // another hart writes the value back between lr.w and sc.w (aba)
+        hart.step(&ram, &mut NoMetrics).unwrap();
+        ram.store(0x100, 4, 7).unwrap();
+        hart.step(&ram, &mut NoMetrics).unwrap();
+        assert_eq!(hart.regs[6], 1);
+        // undisturbed, it succeeds
+        hart.step(&ram, &mut NoMetrics).unwrap();
+        hart.step(&ram, &mut NoMetrics).unwrap();
+        assert_eq!(hart.regs[6], 0);
+    }
+}
@@ -0,0 +1,326 @@
+// ns16550a uart
+//
+// the console on the boot machine. registers are spaced 4 bytes apart
+// (reg-shift = 2 in the device tree) since the executor only does word
+// accesses; the 8250 driver in linux and opensbi's uart8250 both handle that.
+// transmit is immediate, receive comes from a host thread reading stdin.
+
+use crate::devices::{Device, DeviceInfo, GuestRam};
+use crate::fdt::{FdtBuilder, PLIC_PHANDLE};
+use crate::replay::{self, Journal, Source};
+use crate::snapshot::{Reader, Writer};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// qemu virt puts it here, on plic source 10
+pub const UART_BASE: u32 = 0x1000_0000;
+pub const UART_SIZE: u32 = 0x100;
+pub const UART_IRQ: u32 = 10;
+
+// the divisor latch is there for drivers that program it, nothing uses it
+const UART_CLOCK: u32 = 3_686_400;
+
+const REG_RBR_THR: u32 = 0; // dll when dlab is set
+const REG_IER: u32 = 1; // dlm when dlab is set
+const REG_IIR_FCR: u32 = 2;
+const REG_LCR: u32 = 3;
+const REG_MCR: u32 = 4;
+const REG_LSR: u32 = 5;
+const REG_MSR: u32 = 6;
+const REG_SCR: u32 = 7;
+
+const IER_RDI: u32 = 1 << 0;
+const IER_THRI: u32 = 1 << 1;
+
+const IIR_NO_INT: u32 = 0x01;
+const IIR_THRI: u32 = 0x02;
+const IIR_RDI: u32 = 0x04;
+const IIR_FIFO_ENABLED: u32 = 0xc0;
+
+const LCR_DLAB: u32 = 1 << 7;
+
+const LSR_DR: u32 = 1 << 0;
+const LSR_THRE: u32 = 1 << 5;
+const LSR_TEMT: u32 = 1 << 6;
+
+// msr with cts, dsr and dcd asserted, so drivers that check modem lines
+// think someone's listening
+const MSR_CONNECTED: u32 = 0xb0;
+
+// how often tick() looks for input from the host
+const INPUT_POLL: u32 = 1024;
+
+pub struct Ns16550 {
+    output: Box<dyn Write>,
+    input: Option<Receiver<u8>>,
+    rx: VecDeque<u8>,
+    ier: u32,
+    lcr: u32,
+    mcr: u32,
+    scr: u32,
+    fcr: u32,
+    divisor: u16,
+    // reading iir acknowledges a thr empty interrupt until the next write
+    thri_acked: bool,
+    poll_countdown: u32,
+    journal: Option<Journal>,
+}
+
+impl Ns16550 {
+    pub fn new(output: Box<dyn Write>, input: Option<Receiver<u8>>) -> Self {
+        Ns16550 {
+            output,
+            input,
+            rx: VecDeque::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            fcr: 0,
+            divisor: 0,
+            thri_acked: false,
+            poll_countdown: 0,
+            journal: None,
+        }
+    }
+
+    // console on the host terminal. stdin is read on its own thread so the
+    // guest never blocks waiting for a key
+    pub fn stdio() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut byte = [0u8; 1];
+            let mut stdin = std::io::stdin();
+            while stdin.read_exact(&mut byte).is_ok() && tx.send(byte[0]).is_ok() {}
+        });
+        Self::new(Box::new(std::io::stdout()), Some(rx))
+    }
+
+    // queue bytes as if they'd been typed
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+
+    fn poll_input(&mut self) {
+        let Some(input) = &self.input else {
+            return;
+        };
+        match &self.journal {
+            Some(journal) => self.rx.extend(journal.poll(Source::Console, || input.try_iter().collect())),
+            None => self.rx.extend(input.try_iter()),
+        }
+    }
+
+    fn iir(&self) -> u32 {
+        let id = if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && !self.thri_acked {
+            IIR_THRI
+        } else {
+            IIR_NO_INT
+        };
+        if self.fcr & 1 != 0 {
+            id | IIR_FIFO_ENABLED
+        } else {
+            id
+        }
+    }
+}
+
+impl Device for Ns16550 {
+    fn read(&mut self, offset: u32) -> u32 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset >> 2 {
+            REG_RBR_THR if dlab => self.divisor as u32 & 0xff,
+            REG_RBR_THR => {
+                if self.rx.is_empty() {
+                    self.poll_input();
+                }
+                self.rx.pop_front().unwrap_or(0) as u32
+            }
+            REG_IER if dlab => self.divisor as u32 >> 8,
+            REG_IER => self.ier,
+            REG_IIR_FCR => {
+                let iir = self.iir();
+                if iir & 0x0f == IIR_THRI {
+                    self.thri_acked = true;
+                }
+                iir
+            }
+            REG_LCR => self.lcr,
+            REG_MCR => self.mcr,
+            REG_LSR => {
+                // a guest polling lsr for input shouldn't have to wait for
+                // the next tick to see it
+                if self.rx.is_empty() {
+                    self.poll_input();
+                }
+                let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+                dr | LSR_THRE | LSR_TEMT
+            }
+            REG_MSR => MSR_CONNECTED,
+            REG_SCR => self.scr,
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _ram: &mut GuestRam) {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset >> 2 {
+            REG_RBR_THR if dlab => self.divisor = (self.divisor & 0xff00) | (val & 0xff) as u16,
+            REG_RBR_THR => {
+                // a console that's gone away isn't the guest's problem
+                if !replay::muted(self.journal.as_ref()) {
+                    let _ = self.output.write_all(&[val as u8]);
+                    let _ = self.output.flush();
+                }
+                self.thri_acked = false;
+            }
+            REG_IER if dlab => self.divisor = (self.divisor & 0xff) | ((val & 0xff) as u16) << 8,
+            REG_IER => {
+                self.ier = val & 0x0f;
+                self.thri_acked = false;
+            }
+            REG_IIR_FCR => {
+                // bit 1 clears the receive fifo
+                if val & 2 != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = val & 0xc9;
+            }
+            REG_LCR => self.lcr = val & 0xff,
+            REG_MCR => self.mcr = val & 0x1f,
+            REG_SCR => self.scr = val & 0xff,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, _ram: &mut GuestRam) {
+        if self.poll_countdown > 0 {
+            self.poll_countdown -= 1;
+            return;
+        }
+        self.poll_countdown = INPUT_POLL;
+        self.poll_input();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.iir() & IIR_NO_INT == 0
+    }
+
+    fn irq(&self) -> Option<u32> {
+        Some(UART_IRQ)
+    }
+
+    fn info(&self) -> Option<DeviceInfo> {
+        Some(DeviceInfo { name: "serial", compatible: "ns16550a" })
+    }
+
+    fn fdt_props(&self, fdt: &mut FdtBuilder) {
+        fdt.prop_u32("clock-frequency", UART_CLOCK);
+        fdt.prop_u32("reg-shift", 2);
+        fdt.prop_u32("reg-io-width", 4);
+        fdt.prop_u32("interrupt-parent", PLIC_PHANDLE);
+        fdt.prop_u32("interrupts", UART_IRQ);
+    }
+
+    // bytes already taken from stdin but not yet read by the guest go
+    // along; the reader thread stays with this process
+    fn save(&self, w: &mut Writer) {
+        w.put_bytes(&self.rx.iter().copied().collect::<Vec<u8>>());
+        w.put_u32(self.ier);
+        w.put_u32(self.lcr);
+        w.put_u32(self.mcr);
+        w.put_u32(self.scr);
+        w.put_u32(self.fcr);
+        w.put_u16(self.divisor);
+        w.put_bool(self.thri_acked);
+        w.put_u32(self.poll_countdown);
+    }
+
+    fn restore(&mut self, r: &mut Reader) -> Result<(), String> {
+        self.rx = r.get_bytes()?.iter().copied().collect();
+        self.ier = r.get_u32()?;
+        self.lcr = r.get_u32()?;
+        self.mcr = r.get_u32()?;
+        self.scr = r.get_u32()?;
+        self.fcr = r.get_u32()?;
+        self.divisor = r.get_u16()?;
+        self.thri_acked = r.get_bool()?;
+        self.poll_countdown = r.get_u32()?;
+        Ok(())
+    }
+    fn set_journal(&mut self, journal: &Journal) {
+        self.journal = Some(journal.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // a Write that the test can look at after handing it to the uart
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transmit_and_receive() {
+        let out = Capture::default();
+        let mut uart = Ns16550::new(Box::new(out.clone()), None);
+        let mut mem = GuestRam::new(0, &mut []);
+        for b in b"ok\n" {
+            assert_ne!(uart.read(REG_LSR << 2) & LSR_THRE, 0);
+            uart.write(REG_RBR_THR << 2, *b as u32, &mut mem);
+        }
+        assert_eq!(out.0.borrow().as_slice(), b"ok\n");
+
+        assert_eq!(uart.read(REG_LSR << 2) & LSR_DR, 0);
+        uart.push_input(b"x");
+        assert_ne!(uart.read(REG_LSR << 2) & LSR_DR, 0);
+        assert_eq!(uart.read(REG_RBR_THR << 2), b'x' as u32);
+        assert_eq!(uart.read(REG_LSR << 2) & LSR_DR, 0);
+    }
+
+    #[test]
+    fn test_divisor_latch_hides_data_registers() {
+        let out = Capture::default();
+        let mut uart = Ns16550::new(Box::new(out.clone()), None);
+        let mut mem = GuestRam::new(0, &mut []);
+        uart.write(REG_LCR << 2, LCR_DLAB, &mut mem);
+        uart.write(REG_RBR_THR << 2, 0x12, &mut mem);
+        uart.write(REG_IER << 2, 0x34, &mut mem);
+        assert_eq!(uart.read(REG_IER << 2), 0x34);
+        uart.write(REG_LCR << 2, 0x03, &mut mem);
+        assert_eq!(uart.divisor, 0x3412);
+        assert_eq!(uart.read(REG_IER << 2), 0);
+        assert!(out.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_rx_interrupt() {
+        let mut uart = Ns16550::new(Box::new(Capture::default()), None);
+        let mut mem = GuestRam::new(0, &mut []);
+        uart.write(REG_IER << 2, IER_RDI, &mut mem);
+        assert!(!uart.irq_pending());
+        uart.push_input(b"a");
+        assert!(uart.irq_pending());
+        assert_eq!(uart.read(REG_IIR_FCR << 2), IIR_RDI);
+        uart.read(REG_RBR_THR << 2);
+        assert!(!uart.irq_pending());
+    }
+}
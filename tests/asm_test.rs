@@ -1,4 +1,5 @@
 use rv32_emu::assembler::Assembler;
+use rv32_emu::{cpu, executor, hexfile, metrics};
 
 #[test]
 fn test_simple_program() {
@@ -56,3 +57,23 @@ lw x3, 4(x1)
     let code = asm.assemble(source).unwrap();
     assert_eq!(code.len(), 12);
 }
+
+#[test]
+fn test_hex_and_srec_output_loads_back() {
+    let mut asm = Assembler::new();
+    let code = asm.assemble("addi x1, x0, 7\naddi x2, x1, 1").unwrap();
+
+    for text in [
+        hexfile::write_ihex(&code, 0x2000, Some(0x2000)),
+        hexfile::write_srec(&code, 0x2000, Some(0x2000)),
+    ] {
+        let mut cpu = cpu::Cpu::new();
+        let mut exec = executor::Executor::new();
+        let mut metrics = metrics::Metrics::new();
+        hexfile::HexImage::parse(&text).unwrap().load(&mut cpu).unwrap();
+        assert_eq!(cpu.pc, 0x2000);
+        exec.step(&mut cpu, &mut metrics).unwrap();
+        exec.step(&mut cpu, &mut metrics).unwrap();
+        assert_eq!(cpu.regs[2], 8);
+    }
+}
@@ -0,0 +1,205 @@
+use rv32_emu::*;
+
+// mmio device tests, driven through the same load/store path the executor uses
+
+#[test]
+fn test_unmapped_access_faults() {
+    let mut cpu = cpu::Cpu::new();
+    assert!(cpu.load_word(0x2000_0000).is_err());
+    assert!(cpu.store_word(0x2000_0000, 1).is_err());
+}
+
+#[test]
+fn test_narrow_mmio_access() {
+    let mut cpu = cpu::Cpu::new();
+    let rom = flash::Rom::new(&[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88]);
+    cpu.attach_device(0x2000_0000, 8, Box::new(rom)).unwrap();
+    let nor = flash::NorFlash::new(&[], flash::FLASH_SECTOR_SIZE).unwrap();
+    cpu.attach_device(0x3000_0000, flash::FLASH_SECTOR_SIZE, Box::new(nor)).unwrap();
+    // a byte or halfword comes out of the word around it
+    assert_eq!(cpu.load(0x2000_0001, 1).unwrap(), 0x22);
+    assert_eq!(cpu.load(0x2000_0006, 2).unwrap(), 0x8877);
+    // but can't straddle two words, and devices only take whole-word stores
+    assert!(cpu.load(0x2000_0003, 2).is_err());
+    assert!(cpu.store(0x3000_0000, 1, 0xf0).is_err());
+    assert!(cpu.store(0x3000_0000, 4, 0xf0).is_ok());
+}
+
+#[test]
+fn test_virtio_net_pcap_capture() {
+    let path = std::env::temp_dir().join(format!("rv32emu-test-{}.pcap", std::process::id()));
+    let backend = virtio_net::PcapBackend::new(&path).unwrap();
+    let dev = virtio_net::VirtioNet::new(Box::new(backend), [2, 0, 0, 0, 0, 1]);
+
+    let mut cpu = cpu::Cpu::new();
+    let base = virtio_net::VIRTIO_NET_BASE;
+    cpu.attach_device(base, virtio_net::VIRTIO_MMIO_SIZE, Box::new(dev)).unwrap();
+    assert_eq!(cpu.load_word(base).unwrap(), 0x74726976);
+
+    // tx queue: descriptors at 0x1000, avail ring at 0x1100, used ring at 0x1200
+    cpu.store_word(base + 0x30, 1).unwrap();
+    cpu.store_word(base + 0x38, 4).unwrap();
+    cpu.store_word(base + 0x80, 0x1000).unwrap();
+    cpu.store_word(base + 0x90, 0x1100).unwrap();
+    cpu.store_word(base + 0xa0, 0x1200).unwrap();
+    cpu.store_word(base + 0x44, 1).unwrap();
+
+    // one descriptor: 12 byte virtio header followed by an 8 byte frame
+    let hdr = virtio_net::NET_HDR_LEN as u32;
+    cpu.write_word(0x1000, 0x3000);
+    cpu.write_word(0x1008, hdr + 8);
+    cpu.write_word(0x3000 + hdr, 0xdeadbeef);
+    cpu.write_word(0x3000 + hdr + 4, 0xcafebabe);
+    cpu.write_word(0x1100, 1 << 16); // avail.idx = 1, ring[0] = 0
+    cpu.store_word(base + 0x50, 1).unwrap();
+
+    // used.idx advanced and the interrupt status bit is set
+    assert_eq!(cpu.read_word(0x1200) >> 16, 1);
+    assert_eq!(cpu.load_word(base + 0x60).unwrap(), 1);
+
+    drop(cpu);
+    let pcap = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&pcap[0..4], &0xa1b2c3d4u32.to_le_bytes());
+    assert_eq!(pcap.len(), 24 + 16 + 8);
+    assert_eq!(&pcap[40..44], &0xdeadbeefu32.to_le_bytes());
+}
+
+#[test]
+fn test_virtual_rtc_is_deterministic() {
+    let run = || {
+        let mut cpu = cpu::Cpu::new();
+        let mut exec = executor::Executor::new();
+        let mut metrics = metrics::Metrics::new();
+        let mode = rtc::RtcMode::Virtual { epoch_ns: 0 };
+        cpu.attach_device(rtc::RTC_BASE, rtc::RTC_SIZE, Box::new(rtc::GoldfishRtc::new(mode))).unwrap();
+
+        let mut asm = assembler::Assembler::new();
+        let code = asm.assemble("lui x1, 0x101\naddi x3, x0, 0\nlw x2, 0(x1)").unwrap();
+        cpu.load_program(&code, 0);
+        for _ in 0..3 {
+            exec.step(&mut cpu, &mut metrics).unwrap();
+        }
+        cpu.regs[2]
+    };
+    // three ticks, including the one for the lw itself
+    assert_eq!(run(), 3 * rtc::NS_PER_INSTRUCTION as u32);
+    assert_eq!(run(), run());
+}
+
+#[test]
+fn test_framebuffer_dump_on_request() {
+    let dir = std::env::temp_dir().join(format!("rv32emu-fb-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = framebuffer::FbConfig::parse("2x1").unwrap();
+    let fb = framebuffer::Framebuffer::new(config, &dir.join("f.ppm"), 0);
+
+    let mut cpu = cpu::Cpu::new();
+    let base = framebuffer::FB_BASE;
+    cpu.attach_device(base, config.region_size(), Box::new(fb)).unwrap();
+    assert_eq!(cpu.load_word(base).unwrap(), 2);
+
+    cpu.store_word(base + framebuffer::FB_PIXELS, 0x00ff0000).unwrap(); // red
+    cpu.store_word(base + framebuffer::FB_PIXELS + 4, 0x000000ff).unwrap(); // blue
+    cpu.store_word(base + 0x10, 1).unwrap();
+    assert_eq!(cpu.load_word(base + 0x18).unwrap(), 1);
+
+    let ppm = std::fs::read(dir.join("f-00000.ppm")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(&ppm[..11], b"P6\n2 1\n255\n");
+    assert_eq!(&ppm[11..], &[255, 0, 0, 0, 0, 255]);
+}
+
+#[test]
+fn test_boot_from_rom_into_flash() {
+    let mut asm = assembler::Assembler::new();
+    let rom = asm.assemble("lui x1, 0x21000\njalr x0, 0(x1)").unwrap();
+    let mut asm = assembler::Assembler::new();
+    let payload = asm.assemble("addi x10, x0, 7\nlui x2, 0x20000\nsw x10, 0(x2)").unwrap();
+
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    let rom = flash::Rom::new(&rom);
+    cpu.attach_device(0x2000_0000, rom.size(), Box::new(rom)).unwrap();
+    let nor = flash::NorFlash::new(&payload, 0).unwrap();
+    cpu.attach_device(0x2100_0000, nor.size(), Box::new(nor)).unwrap();
+    cpu.reset_vector = 0x2000_0000;
+    cpu.reset();
+
+    for _ in 0..4 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    assert_eq!(cpu.regs[10], 7);
+    assert_eq!(cpu.pc, 0x2100_0008);
+
+    // last instruction stores into the rom
+    let err = exec.step(&mut cpu, &mut metrics).unwrap_err();
+    assert!(err.contains("store access fault"), "{}", err);
+}
+
+// console input arrives on another thread whenever it arrives, and the
+// guest stamps each byte with the host clock. a replay has to land every
+// byte on the same instruction and hand back the same times
+#[test]
+fn test_replay_reproduces_console_timing() {
+    let program = "lui x1, 0x10000
+        lui x2, 0x101
+        addi x5, x0, 3
+        addi x6, x0, 0
+        wait:
+        lw x7, 0x14(x1)
+        andi x7, x7, 1
+        beq x7, x0, wait
+        lw x7, 0(x1)
+        add x6, x6, x7
+        lw x8, 0(x2)
+        add x6, x6, x8
+        addi x5, x5, -1
+        bne x5, x0, wait
+        jalr x0, 0(x0)";
+    let code = assembler::Assembler::new().assemble(program).unwrap();
+    let machine = |input| {
+        let mut cpu = cpu::Cpu::new();
+        cpu.load_program(&code, 0);
+        let console = uart::Ns16550::new(Box::new(std::io::sink()), Some(input));
+        cpu.attach_device(uart::UART_BASE, uart::UART_SIZE, Box::new(console)).unwrap();
+        cpu.attach_device(rtc::RTC_BASE, rtc::RTC_SIZE, Box::new(rtc::GoldfishRtc::new(rtc::RtcMode::Host))).unwrap();
+        cpu
+    };
+    let run = |cpu: &mut cpu::Cpu, journal: &replay::Journal| {
+        cpu.set_journal(journal.clone());
+        journal.begin(cpu).unwrap();
+        let mut exec = executor::Executor::new();
+        let steps = exec.run(cpu, &mut metrics::NoMetrics, 100_000_000).unwrap();
+        assert!(exec.halted);
+        (steps, cpu.regs)
+    };
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let typist = std::thread::spawn(move || {
+        for b in b"abc" {
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            tx.send(*b).unwrap();
+        }
+    });
+    let recorder = replay::Journal::recorder();
+    let recorded = run(&mut machine(rx), &recorder);
+    typist.join().unwrap();
+    let events = recorder.events();
+    assert_eq!(events.iter().filter(|e| e.source == replay::Source::Clock).count(), 3);
+
+    // nobody types this time
+    let (_tx, rx) = std::sync::mpsc::channel();
+    let replayer = replay::Journal::replayer(events.clone());
+    assert_eq!(run(&mut machine(rx), &replayer), recorded);
+    assert_eq!(replayer.finish(), Ok(0));
+
+    // a different program doesn't get started
+    let (_tx, rx) = std::sync::mpsc::channel();
+    let mut other = machine(rx);
+    other.write_word(0x400, 1);
+    let replayer = replay::Journal::replayer(events);
+    other.set_journal(replayer.clone());
+    assert!(replayer.begin(&other).is_err());
+}
@@ -0,0 +1,549 @@
+use rv32_emu::*;
+
+// hand-built elf32 images, since there's no risc-v linker on most machines
+
+const EM_RISCV: u16 = 243;
+
+struct Seg {
+    paddr: u32,
+    data: Vec<u8>,
+    memsz: u32,
+}
+
+fn build_elf(entry: u32, flags: u32, machine: u16, segs: &[Seg]) -> Vec<u8> {
+    let phoff = 52u32;
+    let mut data_off = phoff + 32 * segs.len() as u32;
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"\x7fELF");
+    out.extend_from_slice(&[1, 1, 1, 0]); // 32-bit, little-endian, version 1
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    out.extend_from_slice(&machine.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&entry.to_le_bytes());
+    out.extend_from_slice(&phoff.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes()); // no section headers
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&52u16.to_le_bytes());
+    out.extend_from_slice(&32u16.to_le_bytes());
+    out.extend_from_slice(&(segs.len() as u16).to_le_bytes());
+    out.extend_from_slice(&40u16.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+
+    for s in segs {
+        out.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+        out.extend_from_slice(&data_off.to_le_bytes());
+        out.extend_from_slice(&s.paddr.to_le_bytes()); // vaddr
+        out.extend_from_slice(&s.paddr.to_le_bytes());
+        out.extend_from_slice(&(s.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&s.memsz.to_le_bytes());
+        out.extend_from_slice(&5u32.to_le_bytes()); // r-x
+        out.extend_from_slice(&4u32.to_le_bytes());
+        data_off += s.data.len() as u32;
+    }
+    for s in segs {
+        out.extend_from_slice(&s.data);
+    }
+    out
+}
+
+#[test]
+fn test_load_segments_and_entry() {
+    let mut asm = assembler::Assembler::new();
+    let code = asm.assemble("addi x1, x0, 5\nlui x2, 0x3\nlw x3, 0(x2)").unwrap();
+    let elf = build_elf(
+        0x1000,
+        0,
+        EM_RISCV,
+        &[
+            Seg { paddr: 0x1000, memsz: code.len() as u32, data: code },
+            // 4 bytes of data followed by 12 bytes of bss
+            Seg { paddr: 0x3000, data: vec![0xaa; 4], memsz: 16 },
+        ],
+    );
+
+    let mut cpu = cpu::Cpu::new();
+    // garbage where the bss goes, to check it gets cleared
+    cpu.write_word(0x3004, 0xffffffff);
+    let file = elf::ElfFile::parse(&elf).unwrap();
+    file.load(&mut cpu).unwrap();
+
+    assert_eq!(cpu.pc, 0x1000);
+    assert_eq!(cpu.read_word(0x3000), 0xaaaaaaaa);
+    assert_eq!(cpu.read_word(0x3004), 0);
+
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    for _ in 0..3 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    assert_eq!(cpu.regs[1], 5);
+    assert_eq!(cpu.regs[3], 0xaaaaaaaa);
+}
+
+#[test]
+fn test_reject_wrong_machine() {
+    let elf = build_elf(0, 0, 62, &[]); // x86-64
+    assert!(elf::ElfFile::parse(&elf).is_err());
+}
+
+#[test]
+fn test_reject_compressed_and_hard_float() {
+    let mut cpu = cpu::Cpu::new();
+    let rvc = build_elf(0, 0x1, EM_RISCV, &[]);
+    let err = elf::ElfFile::parse(&rvc).unwrap().load(&mut cpu).unwrap_err();
+    assert!(err.contains("compressed"), "{}", err);
+
+    let double = build_elf(0, 0x4, EM_RISCV, &[]);
+    let err = elf::ElfFile::parse(&double).unwrap().load(&mut cpu).unwrap_err();
+    assert!(err.contains("hard-float"), "{}", err);
+}
+
+#[test]
+fn test_segment_outside_ram() {
+    let elf = build_elf(
+        0x8000_0000,
+        0,
+        EM_RISCV,
+        &[Seg { paddr: 0x8000_0000, data: vec![0; 4], memsz: 4 }],
+    );
+    let mut cpu = cpu::Cpu::new();
+    let err = elf::ElfFile::parse(&elf).unwrap().load(&mut cpu).unwrap_err();
+    assert!(err.contains("doesn't fit"), "{}", err);
+}
+
+#[test]
+fn test_load_replaces_cached_code() {
+    let mut asm = assembler::Assembler::new();
+    let old = asm.assemble("addi x1, x0, 1").unwrap();
+    let new = asm.assemble("addi x1, x0, 2").unwrap();
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    cpu.load_program(&old, 0x100);
+    cpu.pc = 0x100;
+    exec.step(&mut cpu, &mut metrics).unwrap(); // decodes 0x100 into the cache
+
+    let elf = build_elf(0x100, 0, EM_RISCV, &[Seg { paddr: 0x100, memsz: 4, data: new }]);
+    elf::ElfFile::parse(&elf).unwrap().load(&mut cpu).unwrap();
+    exec.step(&mut cpu, &mut metrics).unwrap();
+    assert_eq!(cpu.regs[1], 2);
+}
+
+// give segment `i` of an image from build_elf a virtual address of its own
+fn set_vaddr(mut elf: Vec<u8>, i: usize, vaddr: u32) -> Vec<u8> {
+    let off = 52 + 32 * i + 8;
+    elf[off..off + 4].copy_from_slice(&vaddr.to_le_bytes());
+    elf
+}
+
+// append .symtab/.strtab/.shstrtab section headers to an image from build_elf
+fn add_symtab(mut elf: Vec<u8>, syms: &[(&str, u32, u32, u8)]) -> Vec<u8> {
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; 16]; // null symbol
+    for (name, value, size, kind) in syms {
+        let off = strtab.len() as u32;
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+        symtab.extend_from_slice(&off.to_le_bytes());
+        symtab.extend_from_slice(&value.to_le_bytes());
+        symtab.extend_from_slice(&size.to_le_bytes());
+        symtab.push(0x10 | kind); // global
+        symtab.push(0);
+        symtab.extend_from_slice(&1u16.to_le_bytes()); // some defined section
+    }
+    let shstrtab = b"\0.symtab\0.strtab\0.shstrtab\0".to_vec();
+
+    let symtab_off = elf.len() as u32;
+    elf.extend_from_slice(&symtab);
+    let strtab_off = elf.len() as u32;
+    elf.extend_from_slice(&strtab);
+    let shstrtab_off = elf.len() as u32;
+    elf.extend_from_slice(&shstrtab);
+
+    let shoff = elf.len() as u32;
+    let mut sh = |name: u32, kind: u32, off: u32, size: u32, link: u32, entsize: u32| {
+        for v in [name, kind, 0, 0, off, size, link, 0, 4, entsize] {
+            elf.extend_from_slice(&v.to_le_bytes());
+        }
+    };
+    sh(0, 0, 0, 0, 0, 0);
+    sh(1, 2, symtab_off, symtab.len() as u32, 2, 16);
+    sh(9, 3, strtab_off, strtab.len() as u32, 0, 0);
+    sh(17, 3, shstrtab_off, shstrtab.len() as u32, 0, 0);
+
+    elf[32..36].copy_from_slice(&shoff.to_le_bytes());
+    elf[48..50].copy_from_slice(&4u16.to_le_bytes());
+    elf[50..52].copy_from_slice(&3u16.to_le_bytes());
+    elf
+}
+
+#[test]
+fn test_symbols_from_symtab() {
+    let elf = build_elf(0x100, 0, EM_RISCV, &[]);
+    let elf = add_symtab(elf, &[("main", 0x100, 0x20, 2), ("table", 0x400, 16, 1), ("$x", 0x100, 0, 0)]);
+    let file = elf::ElfFile::parse(&elf).unwrap();
+    let syms = symbols::SymbolMap::from_elf(&file).unwrap();
+
+    assert_eq!(syms.len(), 2); // mapping symbol dropped
+    assert_eq!(syms.resolve("main+0x1c"), Some(0x11c));
+    assert_eq!(syms.lookup(0x11c).unwrap().to_string(), "<main+0x1c>");
+    assert_eq!(syms.lookup(0x404).unwrap().to_string(), "<table+0x4>");
+}
+
+#[test]
+fn test_symtab_entsize_too_small() {
+    let elf = build_elf(0x100, 0, EM_RISCV, &[]);
+    let mut elf = add_symtab(elf, &[("main", 0x100, 0x20, 2)]);
+    // the .symtab header's sh_entsize
+    let shoff = u32::from_le_bytes(elf[32..36].try_into().unwrap()) as usize;
+    elf[shoff + 40 + 36..shoff + 40 + 40].copy_from_slice(&8u32.to_le_bytes());
+    let file = elf::ElfFile::parse(&elf).unwrap();
+    let err = symbols::SymbolMap::from_elf(&file).unwrap_err();
+    assert!(err.contains("entry size"), "{}", err);
+}
+
+#[test]
+fn test_metrics_per_function() {
+    let mut asm = assembler::Assembler::new();
+    let code = asm.assemble("start:\naddi x1, x0, 3\nloop:\naddi x1, x1, -1\nbne x1, x0, loop").unwrap();
+    let syms = symbols::SymbolMap::from_labels(asm.labels(), 0);
+
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    metrics.set_symbols(std::sync::Arc::new(syms));
+    cpu.load_program(&code, 0);
+    for _ in 0..7 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    assert_eq!(
+        metrics.function_counts(),
+        vec![("loop".to_string(), 6), ("start".to_string(), 1)]
+    );
+}
+
+fn read_cstr(cpu: &cpu::Cpu, addr: u32) -> String {
+    let tail = &cpu.mem[addr as usize..];
+    let end = tail.iter().position(|&b| b == 0).unwrap();
+    String::from_utf8(tail[..end].to_vec()).unwrap()
+}
+
+#[test]
+fn test_user_stack_layout() {
+    let mut asm = assembler::Assembler::new();
+    let code = asm.assemble("lw x10, 0(x2)\nlw x11, 4(x2)").unwrap();
+    let elf = build_elf(
+        0x10000,
+        0,
+        EM_RISCV,
+        &[Seg { paddr: 0x10000, memsz: code.len() as u32, data: code }],
+    );
+    let file = elf::ElfFile::parse(&elf).unwrap();
+    let argv: Vec<String> = ["prog", "-v", "input.txt"].iter().map(|s| s.to_string()).collect();
+    let envp = vec!["HOME=/".to_string()];
+
+    let mut cpu = cpu::Cpu::new();
+    process::start(&mut cpu, &file, &argv, &envp).unwrap();
+    let sp = cpu.regs[2];
+    assert_eq!(sp % 16, 0);
+    assert_eq!(cpu.pc, 0x10000);
+
+    let word = |i: u32| cpu.read_word(sp + 4 * i);
+    assert_eq!(word(0), 3);
+    assert_eq!(read_cstr(&cpu, word(1)), "prog");
+    assert_eq!(read_cstr(&cpu, word(3)), "input.txt");
+    assert_eq!(word(4), 0);
+    assert_eq!(read_cstr(&cpu, word(5)), "HOME=/");
+    assert_eq!(word(6), 0);
+
+    let mut auxv = std::collections::HashMap::new();
+    let mut i = 7;
+    while word(i) != 0 {
+        auxv.insert(word(i), word(i + 1));
+        i += 2;
+    }
+    assert_eq!(auxv[&6], 4096); // AT_PAGESZ
+    assert_eq!(auxv[&9], 0x10000); // AT_ENTRY
+    assert_eq!(read_cstr(&cpu, auxv[&15]), "riscv32"); // AT_PLATFORM
+    assert_eq!(read_cstr(&cpu, auxv[&31]), "prog"); // AT_EXECFN
+    assert!(auxv.contains_key(&25)); // AT_RANDOM
+
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    exec.step(&mut cpu, &mut metrics).unwrap();
+    exec.step(&mut cpu, &mut metrics).unwrap();
+    assert_eq!(cpu.regs[10], 3);
+    assert_eq!(read_cstr(&cpu, cpu.regs[11]), "prog");
+}
+
+#[test]
+fn test_user_syscalls() {
+    let root = std::env::temp_dir().join(format!("rv32-emu-user-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("hello.txt"), "hi there").unwrap();
+
+    let mut asm = assembler::Assembler::new();
+    let code = asm
+        .assemble(
+            "addi x10, x0, -100
+            lui x11, 0x20
+            addi x12, x0, 0
+            addi x17, x0, 56
+            ecall
+            lui x11, 0x21
+            addi x12, x0, 64
+            addi x17, x0, 63
+            ecall
+            addi x9, x10, 0
+            addi x10, x0, -100
+            lui x11, 0x20
+            addi x11, x11, 16
+            addi x12, x0, 0
+            addi x17, x0, 56
+            ecall
+            addi x8, x10, 0
+            lui x10, 0xfffff
+            lui x11, 0x2
+            addi x17, x0, 215
+            ecall
+            addi x7, x10, 0
+            addi x10, x9, 0
+            addi x17, x0, 93
+            ecall",
+        )
+        .unwrap();
+    let mut data = b"/hello.txt".to_vec();
+    data.resize(16, 0);
+    data.extend_from_slice(b"../missing\0");
+    let elf = build_elf(
+        0x10000,
+        0,
+        EM_RISCV,
+        &[
+            Seg { paddr: 0x10000, memsz: code.len() as u32, data: code },
+            Seg { paddr: 0x20000, data, memsz: 0x2000 },
+        ],
+    );
+
+    let mut cpu = cpu::Cpu::new();
+    let file = elf::ElfFile::parse(&elf).unwrap();
+    let layout = process::start(&mut cpu, &file, &["prog".to_string()], &[]).unwrap();
+    let mut exec = executor::Executor::new();
+    exec.ecall = Some(Box::new(syscalls::LinuxSyscalls::new(&root, layout).unwrap()));
+    let mut metrics = metrics::Metrics::new();
+    exec.run(&mut cpu, &mut metrics, 100).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(exec.exit_code, Some(8));
+    assert_eq!(&cpu.mem[0x21000..0x21008], b"hi there");
+    assert_eq!(cpu.regs[8] as i32, -2); // ENOENT, and .. didn't leave the root
+    assert_eq!(cpu.regs[7] as i32, -22); // EINVAL: munmap(0xfffff000, 0x2000) wraps
+}
+
+#[test]
+fn test_boot_kernel_entry_is_physical() {
+    // linked at 0xc000_0000, loaded at the kernel address, with an entry
+    // point past the start of the segment
+    let code = [0x13, 0, 0, 0].repeat(8);
+    let elf = build_elf(
+        0xc000_0010,
+        0,
+        EM_RISCV,
+        &[Seg { paddr: 0x8040_0000, memsz: code.len() as u32, data: code }],
+    );
+    let elf = set_vaddr(elf, 0, 0xc000_0000);
+    let images = boot::BootImages { kernel: Some(&elf), ..Default::default() };
+    let console = uart::Ns16550::new(Box::new(std::io::sink()), None);
+    let (cpu, info) = boot::build(8 * 1024 * 1024, 1, &images, console).unwrap();
+    assert_eq!(info.kernel, Some(0x8040_0010));
+    assert_eq!(cpu.pc, 0x8040_0010);
+}
+
+#[test]
+fn test_boot_initrd_past_the_top_of_memory() {
+    // ram right up to 4g and a kernel near the top, so the initrd's place
+    // above it wraps
+    let elf = build_elf(0xfff0_0000, 0, EM_RISCV, &[Seg { paddr: 0xfff0_0000, memsz: 4, data: vec![0x13, 0, 0, 0] }]);
+    let initrd = [0xaa; 16];
+    let images = boot::BootImages { kernel: Some(&elf), initrd: Some(&initrd), ..Default::default() };
+    let console = uart::Ns16550::new(Box::new(std::io::sink()), None);
+    let err = boot::build(0x8000_0000, 1, &images, console).err().unwrap();
+    assert!(err.starts_with("initrd doesn't fit in ram"), "{}", err);
+}
+
+#[test]
+fn test_htif_tohost_from_symbols() {
+    // riscv-tests style: fail test 2 through tohost, then spin
+    let mut asm = assembler::Assembler::new();
+    let code = asm
+        .assemble(
+            "lui x1, 0x80001
+            addi x2, x0, 5
+            sw x2, 0(x1)
+            sw x0, 4(x1)
+            spin:
+            jal x0, spin",
+        )
+        .unwrap();
+    let elf = build_elf(
+        0x8000_0000,
+        0,
+        EM_RISCV,
+        &[
+            Seg { paddr: 0x8000_0000, memsz: code.len() as u32, data: code },
+            Seg { paddr: 0x8000_1000, data: vec![0; 16], memsz: 16 },
+        ],
+    );
+    let elf = add_symtab(elf, &[("tohost", 0x8000_1000, 8, 1), ("fromhost", 0x8000_1008, 8, 1)]);
+    let file = elf::ElfFile::parse(&elf).unwrap();
+    let syms = symbols::SymbolMap::from_elf(&file).unwrap();
+
+    let mut cpu = cpu::Cpu::with_ram(0x8000_0000, cpu::MEM_SIZE);
+    file.load(&mut cpu).unwrap();
+    let mut exec = executor::Executor::new();
+    exec.htif = htif::Htif::from_symbols(&syms);
+    let mut metrics = metrics::Metrics::new();
+    let steps = exec.run(&mut cpu, &mut metrics, 100).unwrap();
+
+    assert_eq!(steps, 4);
+    assert_eq!(exec.exit_code, Some(2));
+}
+
+// an image laid out like riscv-tests' -p environment (env/p/riscv_test.h):
+// reset_vector pokes csrs that may not exist with mtvec pointing just past
+// them, installs trap_vector, mrets into the tests, and RVTEST_PASS/FAIL
+// ecall into trap_vector, which writes the test number to tohost
+fn p_env_elf(tests: &str) -> Vec<u8> {
+    let src = format!(
+        "jal x0, reset_vector
+        trap_vector:
+        csrr x30, mcause
+        addi x31, x0, 8
+        beq x30, x31, write_tohost
+        addi x31, x0, 9
+        beq x30, x31, write_tohost
+        addi x31, x0, 11
+        beq x30, x31, write_tohost
+        ori x3, x3, 1337
+        write_tohost:
+        lui x30, 0x80001
+        sw x3, 0(x30)
+        sw x0, 4(x30)
+        jal x0, write_tohost
+        reset_vector:
+        csrr x10, mhartid
+        bne x10, x0, reset_vector
+        auipc x5, 0
+        addi x5, x5, 16
+        csrw mtvec, x5
+        csrwi 0x180, 0
+        auipc x5, 0
+        addi x5, x5, 28
+        csrw mtvec, x5
+        addi x5, x0, -1
+        csrw 0x3b0, x5
+        addi x5, x0, 31
+        csrw 0x3a0, x5
+        csrwi mie, 0
+        auipc x5, 0
+        addi x5, x5, 20
+        csrw mtvec, x5
+        csrwi 0x302, 0
+        csrwi 0x303, 0
+        addi x3, x0, 0
+        lui x5, 0x80000
+        addi x5, x5, 4
+        csrw mtvec, x5
+        csrwi mstatus, 0
+        auipc x5, 0
+        addi x5, x5, 20
+        csrw mepc, x5
+        csrr x10, mhartid
+        mret
+        {}
+        bne x0, x3, pass
+        fail:
+        fence
+        failed:
+        beq x3, x0, failed
+        slli x3, x3, 1
+        ori x3, x3, 1
+        addi x17, x0, 93
+        addi x10, x3, 0
+        ecall
+        pass:
+        fence
+        addi x3, x0, 1
+        addi x17, x0, 93
+        addi x10, x0, 0
+        ecall",
+        tests
+    );
+    let code = assembler::Assembler::new().assemble(&src).unwrap();
+    let elf = build_elf(
+        0x8000_0000,
+        0,
+        EM_RISCV,
+        &[
+            Seg { paddr: 0x8000_0000, memsz: code.len() as u32, data: code },
+            Seg { paddr: 0x8000_1000, data: vec![0; 16], memsz: 16 },
+        ],
+    );
+    add_symtab(elf, &[("tohost", 0x8000_1000, 8, 1), ("fromhost", 0x8000_1008, 8, 1)])
+}
+
+// run an image from p_env_elf to its tohost write, returning the code
+fn run_p_env(tests: &str) -> Option<i32> {
+    let elf = p_env_elf(tests);
+    let file = elf::ElfFile::parse(&elf).unwrap();
+    let syms = symbols::SymbolMap::from_elf(&file).unwrap();
+    let mut cpu = cpu::Cpu::with_ram(0x8000_0000, cpu::MEM_SIZE);
+    file.load(&mut cpu).unwrap();
+    let mut exec = executor::Executor::new();
+    exec.htif = htif::Htif::from_symbols(&syms);
+    exec.run(&mut cpu, &mut metrics::Metrics::new(), 1000).unwrap();
+    assert!(exec.halted);
+    exec.exit_code
+}
+
+#[test]
+fn test_riscv_tests_p_env() {
+    // TEST_RR_OP from rv32ui-p-add and rv32um-p-mul: li x1; li x2; op x14,
+    // x1, x2; li x7, result; li gp, testnum; bne x14, x7, fail
+    let tests = "addi x1, x0, 1
+        addi x2, x0, 1
+        add x14, x1, x2
+        addi x7, x0, 2
+        addi x3, x0, 2
+        bne x14, x7, fail
+        addi x1, x0, 3
+        addi x2, x0, 7
+        add x14, x1, x2
+        addi x7, x0, 10
+        addi x3, x0, 3
+        bne x14, x7, fail
+        lui x1, 0x80000
+        lui x2, 0xffff8
+        add x14, x1, x2
+        lui x7, 0x7fff8
+        addi x3, x0, 4
+        bne x14, x7, fail
+        addi x1, x0, -7
+        addi x2, x0, 6
+        mul x14, x1, x2
+        addi x7, x0, -42
+        addi x3, x0, 5
+        bne x14, x7, fail";
+    assert_eq!(run_p_env(tests), Some(0));
+
+    // a failing test reports its number
+    let failing = "addi x1, x0, 1
+        add x14, x1, x1
+        addi x7, x0, 3
+        addi x3, x0, 2
+        bne x14, x7, fail";
+    assert_eq!(run_p_env(failing), Some(2));
+}
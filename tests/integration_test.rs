@@ -111,6 +111,92 @@ fn test_lw_sw() {
     assert_eq!(cpu.regs[3], 0xdeadbeef);
 }
 
+#[test]
+fn test_byte_halfword_access() {
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    let code = assembler::Assembler::new().assemble("
+        addi x1, x0, 0x100
+        addi x2, x0, -2
+        sh x2, 2(x1)
+        sb x0, 3(x1)
+        lb x3, 2(x1)
+        lbu x4, 2(x1)
+        lh x5, 2(x1)
+        lhu x6, 2(x1)
+        lw x7, 0(x1)
+    ").unwrap();
+    cpu.load_program(&code, 0);
+    for _ in 0..9 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    assert_eq!(cpu.regs[3], 0xffff_fffe);
+    assert_eq!(cpu.regs[4], 0xfe);
+    assert_eq!(cpu.regs[5], 0xfe);
+    assert_eq!(cpu.regs[6], 0xfe);
+    assert_eq!(cpu.regs[7], 0x00fe_0000);
+}
+
+#[test]
+fn test_set_less_than_and_unsigned_branches() {
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    let code = assembler::Assembler::new().assemble("
+        addi x1, x0, -1
+        addi x2, x0, 1
+        slt x3, x1, x2
+        sltu x4, x1, x2
+        slti x5, x1, 0
+        sltiu x6, x2, -1
+        bltu x1, x2, below
+        addi x7, x0, 1
+        below:
+        bgeu x1, x2, above
+        addi x8, x0, 1
+        above:
+    ").unwrap();
+    cpu.load_program(&code, 0);
+    for _ in 0..9 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    assert_eq!((cpu.regs[3], cpu.regs[4], cpu.regs[5], cpu.regs[6]), (1, 0, 1, 1));
+    // -1 is the biggest unsigned value, so bltu falls through and bgeu jumps
+    assert_eq!((cpu.regs[7], cpu.regs[8]), (1, 0));
+    assert_eq!(cpu.pc, 40);
+}
+
+#[test]
+fn test_multiply_divide() {
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    let code = assembler::Assembler::new().assemble("
+        lui x1, 0x80000
+        addi x2, x0, -1
+        addi x3, x0, 7
+        mulhu x4, x2, x2
+        mulh x5, x1, x1
+        div x6, x1, x2
+        rem x7, x1, x2
+        divu x8, x3, x0
+        rem x9, x3, x0
+        div x10, x2, x3
+        remu x11, x2, x3
+    ").unwrap();
+    cpu.load_program(&code, 0);
+    for _ in 0..11 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    assert_eq!((cpu.regs[4], cpu.regs[5]), (0xffff_fffe, 0x4000_0000));
+    // the overflow and divide-by-zero cases don't trap
+    assert_eq!((cpu.regs[6], cpu.regs[7]), (0x8000_0000, 0));
+    assert_eq!((cpu.regs[8], cpu.regs[9]), (u32::MAX, 7));
+    // -1 / 7 rounds towards zero; 0xffffffff % 7 is 3
+    assert_eq!((cpu.regs[10], cpu.regs[11]), (0, 3));
+}
+
 #[test]
 fn test_beq_taken() {
     let mut cpu = cpu::Cpu::new();
@@ -338,3 +424,586 @@ fn test_memory_isolation() {
 
 // TODO: test misaligned memory access trap (not yet implemented)
 // TODO: test instruction fetch from invalid address
+
+#[test]
+fn test_semihosting_exit_extended() {
+    let mut asm = assembler::Assembler::new();
+    let code = asm
+        .assemble(
+            "lui x11, 0x20
+            addi x11, x11, 0x26
+            addi x12, x0, 7
+            sw x11, 0x100(x0)
+            sw x12, 0x104(x0)
+            addi x11, x0, 0x100
+            addi x10, x0, 0x20
+            slli x0, x0, 31
+            ebreak
+            srai x0, x0, 7",
+        )
+        .unwrap();
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    cpu.load_program(&code, 0);
+
+    // a plain ebreak is still an error without a handler
+    exec.run(&mut cpu, &mut metrics, 100).unwrap_err();
+
+    cpu.pc = 0;
+    exec.semihosting = Some(semihosting::Semihosting::new("prog"));
+    exec.run(&mut cpu, &mut metrics, 100).unwrap();
+    assert_eq!(exec.exit_code, Some(7));
+}
+
+#[test]
+fn test_register_names() {
+    assert_eq!(cpu::reg_index("a0"), Some(10));
+    assert_eq!(cpu::reg_index("x31"), Some(31));
+    assert_eq!(cpu::reg_index("fp"), cpu::reg_index("s0"));
+    assert_eq!(cpu::reg_index("zero"), Some(0));
+    assert_eq!(cpu::reg_index("x32"), None);
+    assert_eq!(cpu::reg_index("pc"), None);
+}
+
+#[test]
+fn test_store_over_cached_code() {
+    // the loop body runs once from the cache, then patches its own addi
+    let mut asm = assembler::Assembler::new();
+    let code = asm
+        .assemble(
+            "addi x5, x0, 2
+            lui x6, 0x508
+            addi x6, x6, 0x93
+            loop:
+            addi x1, x1, 1
+            sw x6, 12(x0)
+            addi x5, x5, -1
+            bne x5, x0, loop
+            fence.i",
+        )
+        .unwrap();
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    cpu.load_program(&code, 0);
+    for _ in 0..11 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    // second time round it's addi x1, x1, 5
+    assert_eq!(cpu.regs[1], 6);
+    assert!(cpu.icache.cached_pages() > 0);
+    exec.step(&mut cpu, &mut metrics).unwrap();
+    assert_eq!(cpu.icache.cached_pages(), 0);
+}
+
+#[test]
+fn test_block_run_matches_stepping() {
+    // a loop that patches its own body partway through, run both ways
+    let src = "addi x5, x0, 2
+        lui x6, 0x508
+        addi x6, x6, 0x93
+        loop:
+        addi x1, x1, 1
+        sw x6, 12(x0)
+        addi x5, x5, -1
+        bne x5, x0, loop
+        addi x7, x0, 100
+        inner:
+        addi x7, x7, -1
+        bne x7, x0, inner
+        addi x8, x0, -3
+        sh x8, 0x202(x0)
+        lb x9, 0x203(x0)
+        lhu x10, 0x202(x0)
+        slt x11, x8, x5
+        sltiu x12, x8, 5
+        bltu x8, x5, done
+        addi x13, x0, 1
+        done:";
+    let code = assembler::Assembler::new().assemble(src).unwrap();
+    let mut stepped = cpu::Cpu::new();
+    let mut blocked = cpu::Cpu::new();
+    stepped.load_program(&code, 0);
+    blocked.load_program(&code, 0);
+    let (mut m1, mut m2) = (metrics::Metrics::new(), metrics::Metrics::new());
+
+    let mut exec = executor::Executor::new();
+    let mut steps = 0;
+    while stepped.pc != code.len() as u32 {
+        exec.step(&mut stepped, &mut m1).unwrap();
+        steps += 1;
+    }
+    let mut exec = executor::Executor::new();
+    assert_eq!(exec.run(&mut blocked, &mut m2, steps).unwrap(), steps);
+    assert!(!exec.blocks.is_empty());
+
+    assert_eq!(blocked.regs, stepped.regs);
+    assert_eq!(blocked.pc, stepped.pc);
+    assert_eq!(blocked.regs[1], 6);
+    assert_eq!(&blocked.regs[9..14], &[u32::MAX, 0xfffd, 1, 0, 1]);
+    assert_eq!(m2.inst_count, m1.inst_count);
+    assert_eq!(m2.inst_mix, m1.inst_mix);
+    assert_eq!((m2.branch_taken, m2.branch_not_taken), (m1.branch_taken, m1.branch_not_taken));
+}
+
+#[test]
+fn test_smp_stepping_matches_run() {
+    // each hart bumps a shared counter 20 times under lr/sc and stores its
+    // mhartid in its own slot; the debugger's tick() keeps the same schedule
+    let src = "addi x8, x0, 20
+        addi x11, x0, 0x100
+        again:
+        lr.w x5, (x11)
+        addi x5, x5, 1
+        sc.w x6, x5, (x11)
+        bne x6, x0, again
+        addi x8, x8, -1
+        bne x8, x0, again
+        csrr x7, mhartid
+        slli x9, x7, 2
+        sw x7, 0x104(x9)
+        jalr x0, 0(x0)";
+    let code = assembler::Assembler::new().assemble(src).unwrap();
+    let machine = || {
+        let mut cpu = cpu::Cpu::new();
+        cpu.load_program(&code, 0x1000);
+        cpu.pc = 0x1000;
+        cpu.set_harts(4);
+        cpu
+    };
+
+    let mut run = machine();
+    let mut smp = smp::Smp::new(&mut run, 5);
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    let steps = smp.run(&mut exec, &mut run, &mut metrics, 100_000).unwrap();
+    assert_eq!(run.read_word(0x100), 80);
+    for h in 1..4 {
+        assert_eq!(run.read_word(0x104 + 4 * h), h);
+    }
+    assert_eq!(metrics.hart_counts.len(), 4);
+    assert_eq!(metrics.hart_counts.iter().sum::<u64>(), steps as u64);
+
+    let mut stepped = machine();
+    let mut smp2 = smp::Smp::new(&mut stepped, 5);
+    let mut exec2 = executor::Executor::new();
+    let mut m2 = metrics::Metrics::new();
+    for _ in 0..steps {
+        exec2.step(&mut stepped, &mut m2).unwrap();
+        smp2.tick(&mut stepped, &mut exec2);
+    }
+    assert_eq!(stepped.mem, run.mem);
+    assert_eq!(m2.inst_count, metrics.inst_count);
+}
+
+#[test]
+fn test_amo_returns_old_value() {
+    let code = assembler::Assembler::new()
+        .assemble(
+            "addi x11, x0, 0x100
+            addi x5, x0, -7
+            sw x5, 0(x11)
+            addi x6, x0, 3
+            amomax.w x7, x6, (x11)
+            amominu.w x8, x6, (x11)
+            amoswap.w x9, x5, (x11)",
+        )
+        .unwrap();
+    let mut cpu = cpu::Cpu::new();
+    let mut exec = executor::Executor::new();
+    let mut metrics = metrics::Metrics::new();
+    cpu.load_program(&code, 0);
+    for _ in 0..7 {
+        exec.step(&mut cpu, &mut metrics).unwrap();
+    }
+    assert_eq!(cpu.regs[7], -7i32 as u32);
+    assert_eq!(cpu.regs[8], 3);
+    assert_eq!(cpu.regs[9], 3);
+    assert_eq!(cpu.read_word(0x100), -7i32 as u32);
+}
+
+#[test]
+fn test_debugger_reverse_execution() {
+    // long enough to go past the first checkpoint, so going back means
+    // restoring one and running forward again
+    let src = "addi x6, x0, 0
+        lui x7, 2
+        loop:
+        addi x5, x5, 1
+        sw x5, 0x100(x0)
+        bne x5, x7, loop
+        addi x6, x0, 7
+        done:
+        jal x0, done";
+    let code = assembler::Assembler::new().assemble(src).unwrap();
+    let mut cpu = cpu::Cpu::new();
+    cpu.load_program(&code, 0);
+    let mut metrics = metrics::Metrics::new();
+    let mut dbg = debugger::Debugger::new();
+    dbg.start_history(&mut cpu).unwrap();
+
+    dbg.command(&mut cpu, &mut metrics, "b 0x18");
+    dbg.command(&mut cpu, &mut metrics, "c");
+    assert_eq!((cpu.pc, dbg.steps), (0x18, 2 + 3 * 0x2000 + 1));
+    assert_eq!(cpu.regs[6], 7);
+
+    dbg.command(&mut cpu, &mut metrics, "rs");
+    assert_eq!((cpu.pc, cpu.regs[6]), (0x14, 0));
+
+    // back to just before the last store of the loop
+    dbg.command(&mut cpu, &mut metrics, "last 0x100");
+    assert_eq!((cpu.pc, dbg.steps), (0xc, 2 + 3 * 0x1fff + 1));
+    assert_eq!(cpu.read_word(0x100), 0x1fff);
+    assert_eq!(cpu.regs[5], 0x2000);
+
+    dbg.command(&mut cpu, &mut metrics, "last x5");
+    assert_eq!((cpu.pc, cpu.regs[5]), (0x8, 0x1fff));
+
+    // nothing to stop at on the way back, and the same run going forward
+    dbg.command(&mut cpu, &mut metrics, "rc");
+    assert_eq!((cpu.pc, dbg.steps, cpu.regs[5]), (0, 0, 0));
+    dbg.command(&mut cpu, &mut metrics, "c");
+    assert_eq!((cpu.pc, dbg.steps, cpu.regs[6]), (0x18, 2 + 3 * 0x2000 + 1, 7));
+
+    dbg.command(&mut cpu, &mut metrics, "w 0x100");
+    dbg.command(&mut cpu, &mut metrics, "rc");
+    assert_eq!((cpu.pc, cpu.read_word(0x100)), (0xc, 0x1fff));
+}
+
+#[test]
+fn test_watchpoint_sees_byte_store() {
+    let src = "addi x1, x0, 5
+        sb x1, 0x0ff(x0)
+        sh x1, 0x103(x0)
+        addi x2, x0, 1
+        done:
+        jal x0, done";
+    let code = assembler::Assembler::new().assemble(src).unwrap();
+    let mut cpu = cpu::Cpu::new();
+    cpu.load_program(&code, 0);
+    let mut metrics = metrics::Metrics::new();
+    let mut dbg = debugger::Debugger::new();
+
+    // the byte just below the word doesn't touch it, the halfword in its top byte does
+    dbg.command(&mut cpu, &mut metrics, "w 0x100");
+    dbg.command(&mut cpu, &mut metrics, "c");
+    assert_eq!(cpu.pc, 0xc);
+    assert_eq!(cpu.read_word(0x100), 0x0500_0000);
+}
+
+#[test]
+fn test_watchpoint_through_page_tables() {
+    // s-mode running at virtual 0x40_0000, a megapage onto physical 0. the
+    // store writes the value already there, so only decoding the store at
+    // the virtual pc and translating its address finds the write
+    let src = "lui x5, 0x400
+        sw x0, 0x100(x5)
+        addi x1, x0, 1
+        done:
+        jal x0, done";
+    let code = assembler::Assembler::new().assemble(src).unwrap();
+    let mut cpu = cpu::Cpu::new();
+    cpu.load_program(&code, 0);
+    cpu.write_word(0x1000 + 4, 0xcf);
+    cpu.csrs.satp = 1 << 31 | 1;
+    cpu.csrs.privilege = csr::PRV_S;
+    cpu.pc = 0x40_0000;
+    let mut metrics = metrics::Metrics::new();
+    let mut dbg = debugger::Debugger::new();
+
+    dbg.command(&mut cpu, &mut metrics, "w 0x100");
+    dbg.command(&mut cpu, &mut metrics, "b 0x40000c");
+    dbg.command(&mut cpu, &mut metrics, "c");
+    assert_eq!(cpu.pc, 0x40_0008);
+}
+
+// stands in for the host side of an ecall in the recompile test
+struct AddThousand;
+
+impl executor::EcallHandler for AddThousand {
+    fn ecall(&mut self, cpu: &mut cpu::Cpu) -> Result<Option<i32>, String> {
+        cpu.regs[10] += 1000;
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_recompiled_c_matches_emulator() {
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    if std::process::Command::new(&cc).arg("--version").output().is_err() {
+        eprintln!("no c compiler ({}), skipping", cc);
+        return;
+    }
+    // a jump table dispatch loop, a direct call, an indirect one through a
+    // pointer in memory, atomics, multiply and divide, an ecall for the host
+    // and an ebreak to finish
+    let src = "jal x6, handlers_end
+        h0:
+        addi x8, x8, 3
+        jalr x0, 0(x1)
+        h1:
+        slli x8, x8, 1
+        jalr x0, 0(x1)
+        h2:
+        xori x8, x8, 0x55
+        jalr x0, 0(x1)
+        handlers_end:
+        sw x6, 0x500(x0)
+        addi x7, x6, 8
+        sw x7, 0x504(x0)
+        addi x7, x6, 16
+        sw x7, 0x508(x0)
+        addi x8, x0, 1
+        addi x9, x0, 8
+        addi x5, x0, 0
+        dispatch:
+        lw x7, 0x500(x5)
+        jalr x1, 0(x7)
+        addi x5, x5, 4
+        sltiu x7, x5, 12
+        bne x7, x0, next
+        addi x5, x0, 0
+        next:
+        addi x9, x9, -1
+        bge x9, x0, dispatch
+        sw x8, 0x50c(x0)
+        addi x2, x0, 0x700
+        addi x10, x0, 10
+        jal x1, sum
+        sw x10, 0x400(x0)
+        jal x6, skip
+        twice:
+        add x10, x10, x10
+        jalr x0, 0(x1)
+        skip:
+        sw x6, 0x404(x0)
+        lw x7, 0x404(x0)
+        addi x10, x0, 21
+        jalr x1, 0(x7)
+        sw x10, 0x408(x0)
+        addi x11, x0, 0x40c
+        addi x12, x0, 5
+        amoadd.w x13, x12, (x11)
+        amoadd.w x13, x12, (x11)
+        lr.w x14, (x11)
+        addi x14, x14, 1
+        sc.w x15, x14, (x11)
+        sc.w x16, x14, (x11)
+        csrr x17, mhartid
+        addi x18, x0, -8
+        srai x19, x18, 1
+        srli x20, x18, 28
+        sh x18, 0x402(x0)
+        lb x22, 0x403(x0)
+        lhu x23, 0x402(x0)
+        sltu x24, x18, x12
+        slti x25, x18, -7
+        bgeu x18, x12, over
+        addi x26, x0, 1
+        over:
+        mul x27, x18, x13
+        mulh x28, x18, x12
+        mulhsu x29, x18, x12
+        div x30, x18, x12
+        rem x31, x18, x0
+        addi x10, x0, 7
+        ecall
+        addi x21, x10, 0
+        ebreak
+        sum:
+        addi x5, x0, 0
+        sum_loop:
+        add x5, x5, x10
+        addi x10, x10, -1
+        bne x10, x0, sum_loop
+        addi x10, x5, 0
+        jalr x0, 0(x1)";
+    let mut asm = assembler::Assembler::new();
+    let code = asm.assemble(src).unwrap();
+    let syms = symbols::SymbolMap::from_labels(asm.labels(), 0);
+    let mut cpu = cpu::Cpu::new();
+    cpu.load_program(&code, 0);
+    let opts = recompile::Options {
+        prefix: "prog".to_string(),
+        header: "prog.h".to_string(),
+        source: "test".to_string(),
+        entries: Vec::new(),
+    };
+    let out = recompile::recompile(&cpu, &syms, &opts).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("rv32-recompile-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("prog.c"), &out.source).unwrap();
+    std::fs::write(dir.join("prog.h"), &out.header).unwrap();
+    std::fs::write(dir.join("main.c"), r#"
+#include <stdio.h>
+#include "prog.h"
+
+static uint8_t mem[PROG_RAM_SIZE];
+
+int main(void)
+{
+    struct prog_state s;
+    int why, i;
+    prog_init(&s, mem);
+    while ((why = prog_run(&s)) == PROG_ECALL) {
+        s.regs[10] += 1000;
+        s.pc += 4;
+    }
+    printf("%08x", s.pc);
+    for (i = 1; i < 32; i++)
+        printf(" %08x", s.regs[i]);
+    for (i = 0x400; i < 0x410; i++)
+        printf(" %02x", mem[i]);
+    printf("\n%d", why == PROG_EBREAK);
+    fwrite(mem, 1, sizeof mem, fopen("mem.bin", "wb"));
+    s.regs[10] = 100;
+    why = prog_call(&s, PROG_SYM_sum);
+    printf(" %d %08x %u\n", why, s.pc, s.regs[10]);
+    return 0;
+}
+"#).unwrap();
+    let exe = dir.join("prog");
+    let status = std::process::Command::new(&cc)
+        .args(["-O2", "-Wall", "-Werror", "-o"])
+        .arg(&exe)
+        .arg(dir.join("main.c"))
+        .arg(dir.join("prog.c"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let output = std::process::Command::new(&exe).current_dir(&dir).output().unwrap();
+    let mem = std::fs::read(dir.join("mem.bin")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut exec = executor::Executor::new();
+    exec.ecall = Some(Box::new(AddThousand));
+    let mut metrics = metrics::Metrics::new();
+    while exec.step(&mut cpu, &mut metrics).is_ok() {}
+    let mut expected = format!("{:08x}", cpu.pc);
+    for r in 1..32 {
+        expected += &format!(" {:08x}", cpu.regs[r]);
+    }
+    for b in &cpu.mem[0x400..0x410] {
+        expected += &format!(" {:02x}", b);
+    }
+    // then sum(100) on its own, back to the caller
+    expected += "\n1 0 fffffffc 5050\n";
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    // all of ram, code and stack included
+    assert!(mem == cpu.mem, "recompiled code left ram different from the executor");
+    assert_eq!(cpu.read_word(0x50c), 0x165);
+    assert_eq!(cpu.regs[21], 1007);
+    assert_eq!((cpu.regs[15], cpu.regs[16]), (0, 1));
+    assert_eq!(cpu.read_word(0x408), 42);
+    assert_eq!((cpu.regs[22], cpu.regs[23], cpu.regs[24], cpu.regs[25]), (u32::MAX, 0xfff8, 0, 1));
+}
+
+// every instruction a threaded hart runs through the shared semantics, plus
+// the ones it handles itself, in a loop that ends by jumping to 0
+const DIFFERENTIAL: &str = "
+    addi x1, x0, 100
+    lui x2, 0x12345
+    addi x2, x2, 0x678
+    addi x10, x0, 0x400
+    auipc x3, 0
+loop:
+    add x4, x4, x2
+    sub x5, x5, x1
+    xor x6, x6, x4
+    or x7, x7, x5
+    and x8, x4, x6
+    sll x9, x4, x1
+    srl x11, x4, x1
+    sra x12, x5, x1
+    slli x13, x4, 7
+    srli x14, x5, 3
+    srai x15, x5, 3
+    andi x16, x4, -16
+    ori x17, x5, 0x55
+    xori x18, x6, -1
+    sw x4, 0(x10)
+    sw x5, 5(x10)
+    lw x19, 2(x10)
+    add x20, x20, x19
+    sb x4, 1(x10)
+    sh x5, 3(x10)
+    lb x30, 1(x10)
+    lbu x31, 2(x10)
+    add x20, x20, x30
+    add x20, x20, x31
+    lh x30, 3(x10)
+    lhu x31, 6(x10)
+    add x20, x20, x30
+    add x20, x20, x31
+    slt x30, x5, x4
+    sltu x31, x5, x4
+    add x20, x20, x30
+    add x20, x20, x31
+    slti x30, x5, -7
+    sltiu x31, x4, 0x7ff
+    add x20, x20, x30
+    add x20, x20, x31
+    amoadd.w x21, x1, (x10)
+    amomaxu.w x29, x4, (x10)
+    lr.w x22, (x10)
+    add x22, x22, x1
+    sc.w x23, x22, (x10)
+    jal x24, func
+    blt x5, x0, negative
+    addi x25, x25, 1
+negative:
+    bge x4, x2, above
+    addi x26, x26, 1
+above:
+    bltu x4, x5, below
+    addi x26, x26, 2
+below:
+    bgeu x2, x4, not_above
+    addi x25, x25, 3
+not_above:
+    beq x4, x5, loop
+    addi x10, x10, 8
+    addi x1, x1, -1
+    bne x1, x0, loop
+    csrr x27, mhartid
+    fence
+    fence.i
+    jalr x0, 0(x0)
+func:
+    addi x28, x28, 3
+    jalr x0, 0(x24)";
+
+fn differential_cpu(src: &str) -> cpu::Cpu {
+    let code = assembler::Assembler::new().assemble(src).unwrap();
+    let mut cpu = cpu::Cpu::new();
+    cpu.load_program(&code, 0x100);
+    cpu.pc = 0x100;
+    cpu
+}
+
+#[test]
+fn test_executor_and_threaded_hart_agree() {
+    let mut stepped = differential_cpu(DIFFERENTIAL);
+    let mut exec = executor::Executor::new();
+    let mut steps = 0;
+    while stepped.pc != 0 {
+        exec.step(&mut stepped, &mut metrics::NoMetrics).unwrap();
+        steps += 1;
+    }
+
+    let mut blocks = differential_cpu(DIFFERENTIAL);
+    assert_eq!(executor::Executor::new().run(&mut blocks, &mut metrics::NoMetrics, 1_000_000).unwrap(), steps);
+
+    let mut threaded = differential_cpu(DIFFERENTIAL);
+    assert_eq!(threads::run(&mut threaded, &mut [metrics::NoMetrics], 1_000_000).unwrap(), steps);
+
+    for other in [&blocks, &threaded] {
+        assert_eq!(other.regs, stepped.regs);
+        assert_eq!(other.pc, 0);
+        assert_eq!(other.mem[0x400..0x800], stepped.mem[0x400..0x800]);
+    }
+    // the loop did go round
+    assert_eq!(stepped.regs[28], 300);
+}